@@ -4,6 +4,7 @@ use std::path::Path;
 use std::{fs, io, net};
 
 use crate::collections::HashMap;
+use crate::service::message::Address;
 use crate::LocalTime;
 use nonempty::NonEmpty;
 use serde::{Deserialize, Serialize};
@@ -78,7 +79,7 @@ impl<K, V> DerefMut for AddressBook<K, V> {
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct KnownAddress {
     /// Network address.
-    pub addr: net::SocketAddr,
+    pub addr: Address,
     /// Address of the peer who sent us this address.
     pub source: Source,
     /// Last time this address was used to successfully connect to a peer.
@@ -93,11 +94,17 @@ pub struct KnownAddress {
     /// Last time this peer was seen alive.
     #[serde(with = "local_time")]
     pub last_active: Option<LocalTime>,
+    /// Number of consecutive failed connection attempts.
+    #[serde(default)]
+    pub failures: usize,
+    /// If set, the address is banned until this time.
+    #[serde(default, with = "local_time")]
+    pub banned_until: Option<LocalTime>,
 }
 
 impl KnownAddress {
     /// Create a new known address.
-    pub fn new(addr: net::SocketAddr, source: Source, last_active: Option<LocalTime>) -> Self {
+    pub fn new(addr: Address, source: Source, last_active: Option<LocalTime>) -> Self {
         Self {
             addr,
             source,
@@ -105,6 +112,8 @@ impl KnownAddress {
             last_attempt: None,
             last_sampled: None,
             last_active,
+            failures: 0,
+            banned_until: None,
         }
     }
 }
@@ -134,7 +143,7 @@ impl std::fmt::Display for Source {
 /// A file-backed address cache.
 #[derive(Debug)]
 pub struct Cache {
-    addrs: std::collections::HashMap<net::IpAddr, KnownAddress>,
+    addrs: std::collections::HashMap<Address, KnownAddress>,
     file: fs::File,
 }
 
@@ -180,23 +189,23 @@ impl Cache {
 }
 
 impl Store for Cache {
-    fn get_mut(&mut self, ip: &net::IpAddr) -> Option<&mut KnownAddress> {
-        self.addrs.get_mut(ip)
+    fn get_mut(&mut self, addr: &Address) -> Option<&mut KnownAddress> {
+        self.addrs.get_mut(addr)
     }
 
-    fn get(&self, ip: &net::IpAddr) -> Option<&KnownAddress> {
-        self.addrs.get(ip)
+    fn get(&self, addr: &Address) -> Option<&KnownAddress> {
+        self.addrs.get(addr)
     }
 
-    fn remove(&mut self, ip: &net::IpAddr) -> Option<KnownAddress> {
-        self.addrs.remove(ip)
+    fn remove(&mut self, addr: &Address) -> Option<KnownAddress> {
+        self.addrs.remove(addr)
     }
 
-    fn insert(&mut self, ip: net::IpAddr, ka: KnownAddress) -> bool {
-        <std::collections::HashMap<_, _> as Store>::insert(&mut self.addrs, ip, ka)
+    fn insert(&mut self, addr: Address, ka: KnownAddress) -> bool {
+        <std::collections::HashMap<_, _> as Store>::insert(&mut self.addrs, addr, ka)
     }
 
-    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&net::IpAddr, &KnownAddress)> + 'a> {
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&Address, &KnownAddress)> + 'a> {
         Box::new(self.addrs.iter())
     }
 
@@ -229,20 +238,20 @@ impl Store for Cache {
 /// Used to store peer addresses and metadata.
 pub trait Store {
     /// Get a known peer address.
-    fn get(&self, ip: &net::IpAddr) -> Option<&KnownAddress>;
+    fn get(&self, addr: &Address) -> Option<&KnownAddress>;
 
     /// Get a known peer address mutably.
-    fn get_mut(&mut self, ip: &net::IpAddr) -> Option<&mut KnownAddress>;
+    fn get_mut(&mut self, addr: &Address) -> Option<&mut KnownAddress>;
 
     /// Insert a *new* address into the store. Returns `true` if the address was inserted,
     /// or `false` if it was already known.
-    fn insert(&mut self, ip: net::IpAddr, ka: KnownAddress) -> bool;
+    fn insert(&mut self, addr: Address, ka: KnownAddress) -> bool;
 
     /// Remove an address from the store.
-    fn remove(&mut self, ip: &net::IpAddr) -> Option<KnownAddress>;
+    fn remove(&mut self, addr: &Address) -> Option<KnownAddress>;
 
     /// Return an iterator over the known addresses.
-    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&net::IpAddr, &KnownAddress)> + 'a>;
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&Address, &KnownAddress)> + 'a>;
 
     /// Returns the number of addresses.
     fn len(&self) -> usize;
@@ -266,8 +275,8 @@ pub trait Store {
             match seed.to_socket_addrs() {
                 Ok(addrs) => {
                     success = true;
-                    for addr in addrs {
-                        self.insert(addr.ip(), KnownAddress::new(addr, source, None));
+                    for addr in addrs.map(Address::from) {
+                        self.insert(addr.clone(), KnownAddress::new(addr, source, None));
                     }
                 }
                 Err(err) => error = Some(err),
@@ -294,23 +303,23 @@ pub trait Store {
 }
 
 /// Implementation of [`Store`] for [`std::collections::HashMap`].
-impl Store for std::collections::HashMap<net::IpAddr, KnownAddress> {
-    fn get_mut(&mut self, ip: &net::IpAddr) -> Option<&mut KnownAddress> {
-        self.get_mut(ip)
+impl Store for std::collections::HashMap<Address, KnownAddress> {
+    fn get_mut(&mut self, addr: &Address) -> Option<&mut KnownAddress> {
+        self.get_mut(addr)
     }
 
-    fn get(&self, ip: &net::IpAddr) -> Option<&KnownAddress> {
-        self.get(ip)
+    fn get(&self, addr: &Address) -> Option<&KnownAddress> {
+        self.get(addr)
     }
 
-    fn remove(&mut self, ip: &net::IpAddr) -> Option<KnownAddress> {
-        self.remove(ip)
+    fn remove(&mut self, addr: &Address) -> Option<KnownAddress> {
+        self.remove(addr)
     }
 
-    fn insert(&mut self, ip: net::IpAddr, ka: KnownAddress) -> bool {
+    fn insert(&mut self, addr: Address, ka: KnownAddress) -> bool {
         use std::collections::hash_map::Entry;
 
-        match self.entry(ip) {
+        match self.entry(addr) {
             Entry::Vacant(v) => {
                 v.insert(ka);
             }
@@ -319,7 +328,7 @@ impl Store for std::collections::HashMap<net::IpAddr, KnownAddress> {
         true
     }
 
-    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&net::IpAddr, &KnownAddress)> + 'a> {
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&Address, &KnownAddress)> + 'a> {
         Box::new(self.iter())
     }
 
@@ -337,23 +346,23 @@ impl Store for std::collections::HashMap<net::IpAddr, KnownAddress> {
 }
 
 /// Implementation of [`Store`] for [`crate::collections::HashMap`].
-impl Store for crate::collections::HashMap<net::IpAddr, KnownAddress> {
-    fn get_mut(&mut self, ip: &net::IpAddr) -> Option<&mut KnownAddress> {
-        self.get_mut(ip)
+impl Store for crate::collections::HashMap<Address, KnownAddress> {
+    fn get_mut(&mut self, addr: &Address) -> Option<&mut KnownAddress> {
+        self.get_mut(addr)
     }
 
-    fn get(&self, ip: &net::IpAddr) -> Option<&KnownAddress> {
-        self.get(ip)
+    fn get(&self, addr: &Address) -> Option<&KnownAddress> {
+        self.get(addr)
     }
 
-    fn remove(&mut self, ip: &net::IpAddr) -> Option<KnownAddress> {
-        self.remove(ip)
+    fn remove(&mut self, addr: &Address) -> Option<KnownAddress> {
+        self.remove(addr)
     }
 
-    fn insert(&mut self, ip: net::IpAddr, ka: KnownAddress) -> bool {
+    fn insert(&mut self, addr: Address, ka: KnownAddress) -> bool {
         use std::collections::hash_map::Entry;
 
-        match self.entry(ip) {
+        match self.entry(addr) {
             Entry::Vacant(v) => {
                 v.insert(ka);
             }
@@ -362,7 +371,7 @@ impl Store for crate::collections::HashMap<net::IpAddr, KnownAddress> {
         true
     }
 
-    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&net::IpAddr, &KnownAddress)> + 'a> {
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&Address, &KnownAddress)> + 'a> {
         Box::new(self.iter())
     }
 
@@ -379,7 +388,7 @@ impl Store for crate::collections::HashMap<net::IpAddr, KnownAddress> {
     }
 }
 
-mod local_time {
+pub(crate) mod local_time {
     use super::LocalTime;
     use serde::{Deserialize, Deserializer, Serializer};
 
@@ -410,6 +419,8 @@ mod local_time {
 
 #[cfg(test)]
 mod test {
+    use std::str::FromStr;
+
     use super::*;
 
     #[test]
@@ -433,22 +444,27 @@ mod test {
             let mut cache = Cache::create(&path).unwrap();
 
             for i in 32..48 {
-                let ip = net::IpAddr::from([127, 0, 0, i]);
-                let addr = net::SocketAddr::from((ip, 8333));
+                let addr = if i % 2 == 0 {
+                    Address::from(net::SocketAddr::from(([127, 0, 0, i], 8333)))
+                } else {
+                    Address::from_str(&format!("seed{}.radicle.xyz:8333", i)).unwrap()
+                };
                 let ka = KnownAddress {
-                    addr,
+                    addr: addr.clone(),
                     source: Source::Dns,
                     last_success: Some(LocalTime::from_secs(i as u64)),
                     last_sampled: Some(LocalTime::from_secs((i + 1) as u64)),
                     last_attempt: None,
                     last_active: None,
+                    failures: i as usize % 3,
+                    banned_until: (i == 32).then(|| LocalTime::from_secs(i as u64)),
                 };
-                cache.insert(ip, ka);
+                cache.insert(addr, ka);
             }
             cache.flush().unwrap();
 
-            for (ip, ka) in cache.iter() {
-                expected.push((*ip, ka.clone()));
+            for (addr, ka) in cache.iter() {
+                expected.push((addr.clone(), ka.clone()));
             }
         }
 
@@ -456,11 +472,11 @@ mod test {
             let cache = Cache::open(&path).unwrap();
             let mut actual = cache
                 .iter()
-                .map(|(i, ka)| (*i, ka.clone()))
+                .map(|(addr, ka)| (addr.clone(), ka.clone()))
                 .collect::<Vec<_>>();
 
-            actual.sort_by_key(|(i, _)| *i);
-            expected.sort_by_key(|(i, _)| *i);
+            actual.sort_by_key(|(addr, _)| addr.to_string());
+            expected.sort_by_key(|(addr, _)| addr.to_string());
 
             assert_eq!(actual, expected);
         }
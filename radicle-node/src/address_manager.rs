@@ -1,12 +1,591 @@
-use crate::address_book::Store;
+use std::net;
 
+use serde::{Deserialize, Serialize};
+
+use crate::address_book::{local_time, KnownAddress, Source, Store};
+use crate::collections::HashMap;
+use crate::service::message::Address;
+use crate::service::NodeId;
+use crate::{LocalDuration, LocalTime};
+
+/// Time to wait before retrying an address we failed to connect to.
+pub const RETRY_DELAY: LocalDuration = LocalDuration::from_mins(10);
+/// Number of consecutive connection failures after which an address is banned.
+pub const MAX_FAILURES: usize = 3;
+/// How long an address stays banned for.
+pub const BAN_DURATION: LocalDuration = LocalDuration::from_mins(24 * 60);
+/// Maximum number of addresses we keep from a single source.
+pub const MAX_ADDRESSES_PER_SOURCE: usize = 64;
+/// Maximum number of addresses we keep in total.
+pub const MAX_ADDRESSES: usize = 4096;
+/// Misbehaviour score at which a peer is banned.
+pub const MAX_PENALTY: u32 = 100;
+/// Maximum number of misbehaving peers we keep track of.
+pub const MAX_MISBEHAVING: usize = 4096;
+
+/// Misbehaviour of the peer at an IP address. This is kept apart from the known addresses,
+/// since peers that connected to us did so from an ephemeral port we can't dial.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Misbehaviour {
+    /// Misbehaviour score, accumulated across sessions.
+    #[serde(default)]
+    pub penalty: u32,
+    /// If set, the peer is banned until this time.
+    #[serde(default, with = "local_time")]
+    pub until: Option<LocalTime>,
+    /// Node id of the peer, if known. The node is banned along with the address.
+    #[serde(default)]
+    pub node: Option<NodeId>,
+}
+
+impl Misbehaviour {
+    /// Whether the peer is banned at the given time.
+    pub fn is_banned(&self, now: LocalTime) -> bool {
+        self.until.map_or(false, |t| now < t)
+    }
+}
+
+/// A peer banned for misbehaving.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ban {
+    /// Address of the banned peer.
+    pub ip: net::IpAddr,
+    /// Node id of the banned peer, if known.
+    pub node: Option<NodeId>,
+    /// Until when the peer is banned.
+    pub until: LocalTime,
+}
+
+/// Bans to lift.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Unban {
+    /// Lift all bans.
+    All,
+    /// Lift the ban on an address.
+    Ip(net::IpAddr),
+    /// Lift the ban on a node, and the addresses it was banned with.
+    Node(NodeId),
+}
+
+/// Manages known peer addresses: keeps track of connection attempts, successes and
+/// failures, and decides which addresses to connect to.
 #[derive(Debug)]
 pub struct AddressManager<S> {
     store: S,
+    /// Number of known addresses shared by each peer, by the peer's IP address.
+    sources: HashMap<net::IpAddr, usize>,
+    /// Misbehaving peers, by IP address.
+    misbehaviour: HashMap<net::IpAddr, Misbehaviour>,
+    rng: fastrand::Rng,
 }
 
 impl<S: Store> AddressManager<S> {
-    pub fn new(store: S) -> Self {
-        Self { store }
+    pub fn new(store: S, rng: fastrand::Rng) -> Self {
+        let mut sources = HashMap::with_hasher(rng.clone().into());
+
+        for (_, ka) in store.iter() {
+            if let Source::Peer(from) = ka.source {
+                *sources.entry(from.ip()).or_default() += 1;
+            }
+        }
+        Self {
+            store,
+            sources,
+            misbehaviour: HashMap::with_hasher(rng.clone().into()),
+            rng,
+        }
+    }
+
+    /// Get a known address.
+    pub fn get(&self, addr: &Address) -> Option<&KnownAddress> {
+        self.store.get(addr)
+    }
+
+    /// Number of known addresses.
+    pub fn len(&self) -> usize {
+        self.store.len()
+    }
+
+    /// Whether there are any known addresses.
+    pub fn is_empty(&self) -> bool {
+        self.store.is_empty()
+    }
+
+    /// Whether the given address is currently banned, either because we failed to connect
+    /// to it too many times, or because the peer at this address misbehaved.
+    pub fn is_banned(&self, addr: &Address, now: LocalTime) -> bool {
+        self.store.get(addr).map_or(false, |ka| is_banned(ka, now))
+            || self.is_misbehaving_at(addr, now)
+    }
+
+    /// Whether the peer at the given address, or with the given node id, is banned for
+    /// misbehaving.
+    pub fn is_misbehaving(&self, ip: &net::IpAddr, node: Option<&NodeId>, now: LocalTime) -> bool {
+        if self
+            .misbehaviour
+            .get(ip)
+            .map_or(false, |m| m.is_banned(now))
+        {
+            return true;
+        }
+        node.map_or(false, |node| {
+            self.misbehaviour
+                .values()
+                .any(|m| m.node.as_ref() == Some(node) && m.is_banned(now))
+        })
+    }
+
+    /// Whether the peer at the given address is banned for misbehaving. Only IP addresses
+    /// can be checked, since that's how misbehaving peers are tracked.
+    fn is_misbehaving_at(&self, addr: &Address, now: LocalTime) -> bool {
+        addr.socket_addr()
+            .map_or(false, |a| self.is_misbehaving(&a.ip(), None, now))
+    }
+
+    /// Whether the given address is known, and can be connected to: ie. it isn't banned,
+    /// and we haven't recently failed to connect to it.
+    pub fn is_available(&self, addr: &Address, now: LocalTime) -> bool {
+        self.store.get(addr).map_or(false, |ka| {
+            !is_banned(ka, now) && !is_recently_failed(ka, now)
+        }) && !self.is_misbehaving_at(addr, now)
+    }
+
+    /// Insert a new address. Returns `false` if the address was already known, or
+    /// if it was rejected.
+    ///
+    /// Addresses shared by peers are bucketed by the peer's IP, and each bucket
+    /// is capped, so that a single peer can't flood our address table. When the
+    /// table is full, the least valuable address shared by a peer is evicted to make
+    /// room. Addresses from other sources are never evicted.
+    pub fn insert(&mut self, addr: Address, source: Source, now: LocalTime) -> bool {
+        if self.store.get(&addr).is_some() {
+            return false;
+        }
+        if let Source::Peer(from) = source {
+            let bucket = self.sources.get(&from.ip()).copied().unwrap_or_default();
+
+            if bucket >= MAX_ADDRESSES_PER_SOURCE {
+                return false;
+            }
+        }
+        if self.store.len() >= MAX_ADDRESSES {
+            // Bans are kept until they expire.
+            let worst = self
+                .store
+                .iter()
+                .filter(|(_, ka)| matches!(ka.source, Source::Peer(_)) && !is_banned(ka, now))
+                .min_by_key(|(_, ka)| score(ka, now))
+                .map(|(addr, _)| addr.clone());
+
+            match worst {
+                Some(worst) => {
+                    self.remove(&worst);
+                }
+                None => return false,
+            }
+        }
+        if !self
+            .store
+            .insert(addr.clone(), KnownAddress::new(addr, source, None))
+        {
+            return false;
+        }
+        if let Source::Peer(from) = source {
+            *self.sources.entry(from.ip()).or_default() += 1;
+        }
+        true
+    }
+
+    /// Remove an address, and stop counting it towards the addresses of its source.
+    fn remove(&mut self, addr: &Address) -> Option<KnownAddress> {
+        let ka = self.store.remove(addr)?;
+
+        if let Source::Peer(from) = ka.source {
+            if let Some(count) = self.sources.get_mut(&from.ip()) {
+                *count = count.saturating_sub(1);
+
+                if *count == 0 {
+                    self.sources.remove(&from.ip());
+                }
+            }
+        }
+        Some(ka)
+    }
+
+    /// Penalize the peer at the given IP address for misbehaving. Once its penalty reaches
+    /// [`MAX_PENALTY`], the peer is banned for the given duration, by address and node id.
+    /// Returns whether the peer was banned.
+    pub fn penalize(
+        &mut self,
+        ip: &net::IpAddr,
+        node: Option<NodeId>,
+        penalty: u32,
+        duration: LocalDuration,
+        now: LocalTime,
+    ) -> bool {
+        if !self.misbehaviour.contains_key(ip) && self.misbehaviour.len() >= MAX_MISBEHAVING {
+            // Bans are kept until they expire. Otherwise, forget the least misbehaving peer.
+            let least = self
+                .misbehaviour
+                .iter()
+                .filter(|(_, m)| !m.is_banned(now))
+                .min_by_key(|(_, m)| m.penalty)
+                .map(|(ip, _)| *ip);
+
+            match least {
+                Some(least) => {
+                    self.misbehaviour.remove(&least);
+                }
+                None => return false,
+            }
+        }
+        let m = self.misbehaviour.entry(*ip).or_default();
+
+        if node.is_some() {
+            m.node = node;
+        }
+        m.penalty = m.penalty.saturating_add(penalty);
+
+        if m.penalty < MAX_PENALTY {
+            return false;
+        }
+        m.penalty = 0;
+        m.until = Some(now + duration);
+
+        true
+    }
+
+    /// Peers currently banned for misbehaving.
+    pub fn bans(&self, now: LocalTime) -> Vec<Ban> {
+        self.misbehaviour
+            .iter()
+            .filter_map(|(ip, m)| match m.until {
+                Some(until) if now < until => Some(Ban {
+                    ip: *ip,
+                    node: m.node,
+                    until,
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Misbehaving peers, by IP address.
+    pub fn misbehaviour(&self) -> impl Iterator<Item = (&net::IpAddr, &Misbehaviour)> {
+        self.misbehaviour.iter()
+    }
+
+    /// Restore the misbehaviour of peers, eg. from a previous run.
+    pub fn restore(&mut self, misbehaviour: impl IntoIterator<Item = (net::IpAddr, Misbehaviour)>) {
+        self.misbehaviour.extend(misbehaviour);
+    }
+
+    /// Lift bans for misbehaviour, and forget the penalties of the unbanned peers.
+    /// Returns the number of addresses unbanned.
+    pub fn unban(&mut self, unban: &Unban, now: LocalTime) -> usize {
+        let ips = self
+            .bans(now)
+            .into_iter()
+            .filter(|ban| match unban {
+                Unban::All => true,
+                Unban::Ip(ip) => ban.ip == *ip,
+                Unban::Node(node) => ban.node.as_ref() == Some(node),
+            })
+            .map(|ban| ban.ip)
+            .collect::<Vec<_>>();
+
+        for ip in &ips {
+            self.misbehaviour.remove(ip);
+        }
+        ips.len()
+    }
+
+    /// Called when we're attempting to connect to an address.
+    pub fn attempted(&mut self, addr: &Address, now: LocalTime) {
+        if let Some(ka) = self.store.get_mut(addr) {
+            ka.last_attempt = Some(now);
+        }
+    }
+
+    /// Called when we've successfully connected to an address.
+    pub fn connected(&mut self, addr: &Address, now: LocalTime) {
+        if let Some(ka) = self.store.get_mut(addr) {
+            ka.last_success = Some(now);
+            ka.last_active = Some(now);
+            ka.failures = 0;
+            ka.banned_until = None;
+        }
+    }
+
+    /// Called when we failed to connect to an address. Addresses that keep failing
+    /// are banned for [`BAN_DURATION`].
+    pub fn failed(&mut self, addr: &Address, now: LocalTime) {
+        if let Some(ka) = self.store.get_mut(addr) {
+            ka.failures += 1;
+
+            if ka.failures >= MAX_FAILURES {
+                ka.banned_until = Some(now + BAN_DURATION);
+            }
+        }
+    }
+
+    /// Called when a previously established connection was closed.
+    pub fn disconnected(&mut self, addr: &Address, now: LocalTime) {
+        if let Some(ka) = self.store.get_mut(addr) {
+            ka.last_active = Some(now);
+        }
+    }
+
+    /// Pick a random address to connect to, matching the given predicate.
+    ///
+    /// Addresses are weighted by their [`score`], so that addresses we recently
+    /// connected to successfully are preferred. Banned addresses and addresses that
+    /// we recently failed to connect to are skipped.
+    pub fn sample(
+        &mut self,
+        now: LocalTime,
+        mut predicate: impl FnMut(&KnownAddress) -> bool,
+    ) -> Option<KnownAddress> {
+        let candidates = self
+            .store
+            .iter()
+            .filter(|(addr, ka)| {
+                !is_banned(ka, now)
+                    && !is_recently_failed(ka, now)
+                    && !self.is_misbehaving_at(addr, now)
+            })
+            .filter(|(_, ka)| predicate(ka))
+            .map(|(addr, ka)| (addr.clone(), score(ka, now)))
+            .collect::<Vec<_>>();
+        let total = candidates.iter().map(|(_, s)| s).sum::<usize>();
+        if total == 0 {
+            return None;
+        }
+
+        let mut n = self.rng.usize(..total);
+        let (addr, _) = candidates.into_iter().find(|(_, s)| {
+            if n < *s {
+                true
+            } else {
+                n -= s;
+                false
+            }
+        })?;
+        let ka = self.store.get_mut(&addr)?;
+
+        ka.last_sampled = Some(now);
+
+        Some(ka.clone())
+    }
+
+    /// Flush the underlying store.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.store.flush()
+    }
+}
+
+/// Score an address, for the purpose of sampling. Higher is better.
+///
+/// Addresses we recently connected to are preferred over addresses we've never
+/// connected to, which are preferred over addresses that have failed.
+fn score(ka: &KnownAddress, now: LocalTime) -> usize {
+    let base = match ka.last_success {
+        Some(t) if now - t < LocalDuration::from_mins(60) => 64,
+        Some(t) if now - t < LocalDuration::from_mins(24 * 60) => 32,
+        Some(_) => 16,
+        None => 8,
+    };
+    // Halve the score for every consecutive failure.
+    (base >> ka.failures.min(6)).max(1)
+}
+
+/// Whether the address is banned because we failed to connect to it too many times.
+fn is_banned(ka: &KnownAddress, now: LocalTime) -> bool {
+    ka.banned_until.map_or(false, |t| now < t)
+}
+
+/// Whether our last attempt to connect to this address failed, and was recent enough that
+/// we shouldn't try again just yet.
+fn is_recently_failed(ka: &KnownAddress, now: LocalTime) -> bool {
+    match (ka.last_attempt, ka.last_success) {
+        (Some(attempt), Some(success)) if success >= attempt => false,
+        (Some(attempt), _) => now - attempt < RETRY_DELAY,
+        (None, _) => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::collections::HashMap;
+    use crate::test::arbitrary;
+
+    fn manager() -> AddressManager<HashMap<Address, KnownAddress>> {
+        let rng = fastrand::Rng::with_seed(42);
+        AddressManager::new(HashMap::with_hasher(rng.clone().into()), rng)
+    }
+
+    #[test]
+    fn test_ban_after_failures() {
+        let mut addrmgr = manager();
+        let addr = Address::from(net::SocketAddr::from(([8, 8, 8, 8], 8776)));
+        let now = LocalTime::from_secs(1000);
+
+        addrmgr.insert(addr.clone(), Source::Dns, now);
+
+        for _ in 0..MAX_FAILURES {
+            assert!(!addrmgr.is_banned(&addr, now));
+            addrmgr.attempted(&addr, now);
+            addrmgr.failed(&addr, now);
+        }
+        assert!(addrmgr.is_banned(&addr, now));
+        assert!(addrmgr.sample(now + RETRY_DELAY, |_| true).is_none());
+        assert!(!addrmgr.is_banned(&addr, now + BAN_DURATION));
+        assert!(addrmgr.sample(now + BAN_DURATION, |_| true).is_some());
+
+        addrmgr.connected(&addr, now + BAN_DURATION);
+        assert_eq!(addrmgr.get(&addr).unwrap().failures, 0);
+    }
+
+    #[test]
+    fn test_ban_misbehaving() {
+        let mut addrmgr = manager();
+        let addr = net::SocketAddr::from(([8, 8, 8, 8], 8776));
+        let node = arbitrary::gen::<NodeId>(1);
+        let now = LocalTime::from_secs(1000);
+
+        addrmgr.insert(addr.into(), Source::Dns, now);
+
+        assert!(!addrmgr.penalize(&addr.ip(), Some(node), MAX_PENALTY - 1, BAN_DURATION, now));
+        assert!(!addrmgr.is_misbehaving(&addr.ip(), None, now));
+        assert!(addrmgr.penalize(&addr.ip(), None, 1, BAN_DURATION, now));
+
+        // The peer is banned by address and node id, and isn't connected to.
+        let other = net::IpAddr::from([9, 9, 9, 9]);
+        assert!(addrmgr.is_misbehaving(&addr.ip(), None, now));
+        assert!(addrmgr.is_misbehaving(&other, Some(&node), now));
+        assert!(addrmgr.sample(now, |_| true).is_none());
+        assert_eq!(
+            addrmgr.bans(now),
+            vec![Ban {
+                ip: addr.ip(),
+                node: Some(node),
+                until: now + BAN_DURATION
+            }]
+        );
+        assert!(!addrmgr.is_misbehaving(&addr.ip(), Some(&node), now + BAN_DURATION));
+
+        assert_eq!(addrmgr.unban(&Unban::Node(node), now), 1);
+        assert!(!addrmgr.is_misbehaving(&addr.ip(), Some(&node), now));
+        assert!(addrmgr.bans(now).is_empty());
+    }
+
+    #[test]
+    fn test_penalize_unknown() {
+        let mut addrmgr = manager();
+        let inbound = net::SocketAddr::from(([8, 8, 8, 8], 49152));
+        let now = LocalTime::from_secs(1000);
+
+        // Peers that connected to us are penalized without their ephemeral port
+        // making it into the address table.
+        assert!(addrmgr.penalize(&inbound.ip(), None, MAX_PENALTY, BAN_DURATION, now));
+        assert!(addrmgr.is_misbehaving(&inbound.ip(), None, now));
+        assert!(addrmgr.is_empty());
+    }
+
+    #[test]
+    fn test_source_bucket_limit() {
+        let mut addrmgr = manager();
+        let source = Source::Peer(net::SocketAddr::from(([9, 9, 9, 9], 8776)));
+        let now = LocalTime::from_secs(1000);
+
+        for i in 0..MAX_ADDRESSES_PER_SOURCE {
+            let addr = net::SocketAddr::from(([10, 0, (i / 256) as u8, (i % 256) as u8], 8776));
+            assert!(addrmgr.insert(addr.into(), source, now));
+        }
+        let addr = Address::from(net::SocketAddr::from(([11, 0, 0, 1], 8776)));
+
+        assert!(!addrmgr.insert(addr.clone(), source, now));
+        assert!(addrmgr.insert(addr, Source::Dns, now));
+        assert_eq!(addrmgr.len(), MAX_ADDRESSES_PER_SOURCE + 1);
+
+        // Counts are restored along with the addresses.
+        let rng = fastrand::Rng::with_seed(42);
+        let mut addrmgr = AddressManager::new(addrmgr.store, rng);
+        let addr = Address::from(net::SocketAddr::from(([11, 0, 0, 2], 8776)));
+
+        assert!(!addrmgr.insert(addr, source, now));
+    }
+
+    #[test]
+    fn test_evict_peer_addresses() {
+        let mut addrmgr = manager();
+        let now = LocalTime::from_secs(1000);
+        let addrs = (0..MAX_ADDRESSES).map(|i| {
+            Address::from(net::SocketAddr::from((
+                [
+                    10,
+                    (i / 65536) as u8,
+                    (i / 256 % 256) as u8,
+                    (i % 256) as u8,
+                ],
+                8776,
+            )))
+        });
+
+        // Fill the table with imported addresses, and one shared by a peer.
+        let source = Source::Peer(net::SocketAddr::from(([9, 9, 9, 9], 8776)));
+        let mut addrs = addrs.collect::<Vec<_>>();
+        let shared = addrs.pop().unwrap();
+
+        for addr in addrs {
+            assert!(addrmgr.insert(addr, Source::Imported, now));
+        }
+        assert!(addrmgr.insert(shared.clone(), source, now));
+        assert_eq!(addrmgr.len(), MAX_ADDRESSES);
+
+        // Only the address shared by a peer can be evicted.
+        let addr = Address::from(net::SocketAddr::from(([11, 0, 0, 1], 8776)));
+        assert!(addrmgr.insert(addr.clone(), Source::Dns, now));
+        assert!(addrmgr.get(&shared).is_none());
+        assert!(addrmgr.get(&addr).is_some());
+
+        let addr = Address::from(net::SocketAddr::from(([11, 0, 0, 2], 8776)));
+        assert!(!addrmgr.insert(addr, source, now));
+        assert_eq!(addrmgr.len(), MAX_ADDRESSES);
+    }
+
+    #[test]
+    fn test_sample_prefers_successful() {
+        let mut addrmgr = manager();
+        let good = Address::from(net::SocketAddr::from(([8, 8, 8, 8], 8776)));
+        let bad = Address::from(net::SocketAddr::from(([9, 9, 9, 9], 8776)));
+        let now = LocalTime::from_secs(1000);
+
+        addrmgr.insert(good.clone(), Source::Dns, now);
+        addrmgr.insert(bad.clone(), Source::Dns, now);
+        addrmgr.connected(&good, now);
+        addrmgr.failed(&bad, now);
+        addrmgr.failed(&bad, now);
+
+        let picks = (0..100)
+            .filter_map(|_| addrmgr.sample(now, |_| true))
+            .filter(|ka| ka.addr == good)
+            .count();
+        assert!(picks > 80, "good address was picked {} times", picks);
+    }
+
+    #[test]
+    fn test_hostnames() {
+        let mut addrmgr = manager();
+        let source = Source::Peer(net::SocketAddr::from(([9, 9, 9, 9], 8776)));
+        let host = Address::from_str("seed.radicle.xyz:8776").unwrap();
+        let now = LocalTime::from_secs(1000);
+
+        assert!(addrmgr.insert(host.clone(), source, now));
+        assert!(addrmgr.is_available(&host, now));
+        assert_eq!(addrmgr.sample(now, |_| true).map(|ka| ka.addr), Some(host));
     }
 }
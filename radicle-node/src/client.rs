@@ -1,17 +1,23 @@
-use std::net;
+use std::path::Path;
+use std::{fs, io, net};
 
 use crossbeam_channel as chan;
-use nakamoto_net::{LocalTime, Reactor};
+use nakamoto_net::{LocalTime, Reactor, Waker};
 
 use crate::clock::RefClock;
 use crate::collections::HashMap;
 use crate::profile::Profile;
 use crate::service;
+use crate::storage;
 use crate::transport::Transport;
+use crate::wire::stream;
 use crate::wire::Wire;
 
 pub mod handle;
 
+/// Name of the file the service state is cached in, under the node directory.
+pub const CACHE_FILE_NAME: &str = "cache.json";
+
 /// Client configuration.
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -73,27 +79,43 @@ impl<R: Reactor> Client<R> {
         })
     }
 
-    pub fn run(mut self, config: Config) -> Result<(), nakamoto_net::error::Error> {
+    pub fn run(mut self, config: Config) -> Result<(), nakamoto_net::error::Error>
+    where
+        R::Waker: Send + Sync + 'static,
+    {
         let network = config.service.network;
         let rng = fastrand::Rng::new();
         let time = LocalTime::now();
         let storage = self.profile.storage;
         let signer = self.profile.signer;
         let addresses = HashMap::with_hasher(rng.clone().into());
-
+        let cache = match self::cache(&self.profile.home.join("node")) {
+            Ok(cache) => Some(cache),
+            Err(err) => {
+                log::warn!("Unable to open service state cache: {}", err);
+                None
+            }
+        };
+
+        // Git fetches are carried over peer connections, using the `rad://` transport.
+        if let Err(err) = storage::git::transport::register() {
+            log::warn!("Unable to register git transport: {}", err);
+        }
         log::info!("Initializing client ({:?})..", network);
 
+        let waker = self.reactor.waker();
         let service = service::Service::new(
             config.service,
             RefClock::from(time),
             storage,
             addresses,
+            cache,
             signer,
             rng,
         );
         self.reactor.run(
             &config.listen,
-            Transport::new(Wire::new(service)),
+            Transport::new(Wire::new(service, stream::Waker::new(move || waker.wake()))),
             self.events,
             self.commands,
         )?;
@@ -112,6 +134,18 @@ impl<R: Reactor> Client<R> {
     }
 }
 
+/// Open the service state cache in the given directory, creating it if necessary.
+fn cache(dir: &Path) -> io::Result<service::cache::Cache> {
+    let path = dir.join(CACHE_FILE_NAME);
+
+    fs::create_dir_all(dir)?;
+
+    match service::cache::Cache::open(&path) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => service::cache::Cache::create(&path),
+        result => result,
+    }
+}
+
 pub struct Events {}
 
 impl nakamoto_net::Publisher<service::Event> for Events {
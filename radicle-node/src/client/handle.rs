@@ -4,8 +4,10 @@ use crossbeam_channel as chan;
 use nakamoto_net::Waker;
 use thiserror::Error;
 
+use crate::address_manager::{Ban, Unban};
 use crate::identity::Id;
 use crate::service;
+use crate::service::peer::SessionInfo;
 use crate::service::{CommandError, FetchLookup};
 
 /// An error resulting from a handle method.
@@ -80,6 +82,27 @@ impl<W: Waker> traits::Handle for Handle<W> {
         self.command(service::Command::AnnounceRefs(id))
     }
 
+    /// Get the peers banned for misbehaving.
+    fn bans(&self) -> Result<Vec<Ban>, Error> {
+        let (sender, receiver) = chan::bounded(1);
+        self.command(service::Command::Bans(sender))?;
+        receiver.recv().map_err(Error::from)
+    }
+
+    /// Lift bans on misbehaving peers.
+    fn unban(&self, unban: Unban) -> Result<usize, Error> {
+        let (sender, receiver) = chan::bounded(1);
+        self.command(service::Command::Unban(unban, sender))?;
+        receiver.recv().map_err(Error::from)
+    }
+
+    /// Get the current and recent peer sessions.
+    fn sessions(&self) -> Result<Vec<SessionInfo>, Error> {
+        let (sender, receiver) = chan::bounded(1);
+        self.command(service::Command::Sessions(sender))?;
+        receiver.recv().map_err(Error::from)
+    }
+
     /// Send a command to the command channel, and wake up the event loop.
     fn command(&self, cmd: service::Command) -> Result<(), Error> {
         self.commands.send(cmd)?;
@@ -110,6 +133,13 @@ pub mod traits {
         fn untrack(&self, id: Id) -> Result<bool, Error>;
         /// Notify the client that a project has been updated.
         fn announce_refs(&self, id: Id) -> Result<(), Error>;
+        /// Get the peers banned for misbehaving.
+        fn bans(&self) -> Result<Vec<Ban>, Error>;
+        /// Lift bans on misbehaving peers. Returns the number of addresses unbanned.
+        fn unban(&self, unban: Unban) -> Result<usize, Error>;
+        /// Get the current and recent peer sessions, including when we'll next try to
+        /// reconnect to disconnected persistent peers.
+        fn sessions(&self) -> Result<Vec<SessionInfo>, Error>;
         /// Send a command to the command channel, and wake up the event loop.
         fn command(&self, cmd: service::Command) -> Result<(), Error>;
         /// Ask the client to shutdown.
@@ -7,6 +7,7 @@ use std::os::unix::net::UnixStream;
 use std::path::Path;
 use std::{fs, io, net};
 
+use crate::address_manager::Unban;
 use crate::client;
 use crate::client::handle::traits::Handle;
 use crate::identity::Id;
@@ -100,13 +101,69 @@ fn drain<H: Handle>(stream: &UnixStream, handle: &H) -> Result<(), DrainError> {
                     return Err(DrainError::InvalidCommandArg(arg.to_owned()));
                 }
             }
+            Some(("unban", arg)) => {
+                let unban = if arg == "all" {
+                    Unban::All
+                } else if let Ok(ip) = arg.parse() {
+                    Unban::Ip(ip)
+                } else if let Ok(node) = arg.parse() {
+                    Unban::Node(node)
+                } else {
+                    return Err(DrainError::InvalidCommandArg(arg.to_owned()));
+                };
+                let unbanned = handle.unban(unban)?;
+
+                writeln!(LineWriter::new(stream), "ok: {} ban(s) lifted", unbanned)?;
+            }
             Some((cmd, _)) => return Err(DrainError::UnknownCommand(cmd.to_owned())),
+            None if line == "bans" => bans(LineWriter::new(stream), handle)?,
+            None if line == "sessions" => sessions(LineWriter::new(stream), handle)?,
             None => return Err(DrainError::InvalidCommand),
         }
     }
     Ok(())
 }
 
+fn bans<W: Write, H: Handle>(mut writer: W, handle: &H) -> Result<(), DrainError> {
+    for ban in handle.bans()? {
+        match ban.node {
+            Some(node) => writeln!(writer, "{} {} {}", ban.ip, node, ban.until.as_secs())?,
+            None => writeln!(writer, "{} - {}", ban.ip, ban.until.as_secs())?,
+        }
+    }
+    Ok(())
+}
+
+fn sessions<W: Write, H: Handle>(mut writer: W, handle: &H) -> Result<(), DrainError> {
+    for session in handle.sessions()? {
+        let node = session
+            .node
+            .map_or_else(|| String::from("-"), |n| n.to_string());
+        let link = if session.link.is_outbound() {
+            "outbound"
+        } else {
+            "inbound"
+        };
+        let state = if session.negotiated {
+            "negotiated"
+        } else if session.connected {
+            "connecting"
+        } else {
+            "disconnected"
+        };
+        let retry_at = session
+            .retry_at
+            .map_or_else(|| String::from("-"), |t| t.as_secs().to_string());
+
+        writeln!(
+            writer,
+            "{} {} {} {} {} {}",
+            session.addr, node, link, state, session.attempts, retry_at
+        )?;
+    }
+    Ok(())
+}
+
 fn fetch<W: Write, H: Handle>(id: Id, mut writer: W, handle: &H) -> Result<(), DrainError> {
     match handle.fetch(id) {
         Err(e) => {
@@ -162,8 +219,11 @@ mod tests {
     use std::{net, thread};
 
     use super::*;
+    use crate::address_manager::Ban;
     use crate::identity::Id;
+    use crate::service::peer::SessionInfo;
     use crate::test;
+    use crate::{Link, LocalTime};
 
     #[test]
     fn test_control_socket() {
@@ -197,4 +257,84 @@ mod tests {
             assert!(handle.updates.lock().unwrap().contains(proj));
         }
     }
+
+    #[test]
+    fn test_control_socket_unban() {
+        let tmp = tempfile::tempdir().unwrap();
+        let handle = test::handle::Handle::default();
+        let socket = tmp.path().join("alice.sock");
+        let ip = net::IpAddr::from([8, 8, 8, 8]);
+
+        handle.bans.lock().unwrap().push(Ban {
+            ip,
+            node: None,
+            until: LocalTime::from_secs(60),
+        });
+
+        thread::spawn({
+            let socket = socket.clone();
+            let handle = handle.clone();
+
+            move || listen(socket, handle)
+        });
+
+        let mut stream = loop {
+            if let Ok(stream) = UnixStream::connect(&socket) {
+                break stream;
+            }
+        };
+        writeln!(&stream, "bans").unwrap();
+        writeln!(&stream, "unban {}", ip).unwrap();
+
+        let mut output = String::new();
+        stream.shutdown(net::Shutdown::Write).unwrap();
+        stream.read_to_string(&mut output).unwrap();
+
+        assert_eq!(
+            output.lines().collect::<Vec<_>>(),
+            vec!["8.8.8.8 - 60", "ok: 1 ban(s) lifted", "ok"]
+        );
+        assert!(handle.bans.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_control_socket_sessions() {
+        let tmp = tempfile::tempdir().unwrap();
+        let handle = test::handle::Handle::default();
+        let socket = tmp.path().join("alice.sock");
+
+        handle.sessions.lock().unwrap().push(SessionInfo {
+            addr: net::SocketAddr::from(([8, 8, 8, 8], 8776)),
+            node: None,
+            link: Link::Outbound,
+            persistent: true,
+            connected: false,
+            negotiated: false,
+            attempts: 3,
+            retry_at: Some(LocalTime::from_secs(60)),
+        });
+
+        thread::spawn({
+            let socket = socket.clone();
+            let handle = handle.clone();
+
+            move || listen(socket, handle)
+        });
+
+        let mut stream = loop {
+            if let Ok(stream) = UnixStream::connect(&socket) {
+                break stream;
+            }
+        };
+        writeln!(&stream, "sessions").unwrap();
+
+        let mut output = String::new();
+        stream.shutdown(net::Shutdown::Write).unwrap();
+        stream.read_to_string(&mut output).unwrap();
+
+        assert_eq!(
+            output.lines().collect::<Vec<_>>(),
+            vec!["8.8.8.8:8776 - outbound disconnected 3 60", "ok"]
+        );
+    }
 }
@@ -10,6 +10,8 @@ use crate::wire;
 #[derive(Debug)]
 pub struct Decoder<D = Envelope> {
     unparsed: Vec<u8>,
+    /// Maximum number of bytes to buffer until an item can be decoded.
+    limit: usize,
     item: PhantomData<D>,
 }
 
@@ -17,6 +19,7 @@ impl<D> From<Vec<u8>> for Decoder<D> {
     fn from(unparsed: Vec<u8>) -> Self {
         Self {
             unparsed,
+            limit: usize::MAX,
             item: PhantomData,
         }
     }
@@ -25,8 +28,15 @@ impl<D> From<Vec<u8>> for Decoder<D> {
 impl<D: wire::Decode> Decoder<D> {
     /// Create a new stream decoder.
     pub fn new(capacity: usize) -> Self {
+        Self::with_limit(capacity, usize::MAX)
+    }
+
+    /// Create a new stream decoder that buffers at most `limit` bytes until an item
+    /// can be decoded. Items that are larger cause [`wire::Error::MessageTooLarge`].
+    pub fn with_limit(capacity: usize, limit: usize) -> Self {
         Self {
             unparsed: Vec::with_capacity(capacity),
+            limit,
             item: PhantomData,
         }
     }
@@ -37,6 +47,10 @@ impl<D: wire::Decode> Decoder<D> {
     }
 
     /// Decode and return the next message. Returns [`None`] if nothing was decoded.
+    ///
+    /// If decoding fails for a reason other than missing data, the bytes read so far are
+    /// discarded, so that messages which can be skipped, eg. of unknown type, don't prevent
+    /// the following ones from being decoded.
     pub fn decode_next(&mut self) -> Result<Option<D>, wire::Error> {
         let mut reader = io::Cursor::new(self.unparsed.as_mut_slice());
 
@@ -47,8 +61,18 @@ impl<D: wire::Decode> Decoder<D> {
 
                 Ok(Some(msg))
             }
-            Err(err) if err.is_eof() => Ok(None),
-            Err(err) => Err(err),
+            Err(err) if err.is_eof() => {
+                if self.unparsed.len() > self.limit {
+                    return Err(wire::Error::MessageTooLarge(self.unparsed.len()));
+                }
+                Ok(None)
+            }
+            Err(err) => {
+                let pos = reader.position() as usize;
+                self.unparsed.drain(..pos);
+
+                Err(err)
+            }
         }
     }
 }
@@ -78,8 +102,8 @@ mod test {
     use super::*;
     use quickcheck_macros::quickcheck;
 
-    const MSG_HELLO: &[u8] = &[5, b'h', b'e', b'l', b'l', b'o'];
-    const MSG_BYE: &[u8] = &[3, b'b', b'y', b'e'];
+    const MSG_HELLO: &[u8] = &[0, 5, b'h', b'e', b'l', b'l', b'o'];
+    const MSG_BYE: &[u8] = &[0, 3, b'b', b'y', b'e'];
 
     #[quickcheck]
     fn prop_decode_next(chunk_size: usize) {
@@ -105,4 +129,21 @@ mod test {
         assert_eq!(msgs[0], String::from("hello"));
         assert_eq!(msgs[1], String::from("bye"));
     }
+
+    #[test]
+    fn test_decode_limit() {
+        let mut decoder = Decoder::<String>::with_limit(8, 4);
+
+        decoder.input(&MSG_HELLO[..4]);
+        assert!(matches!(decoder.decode_next(), Ok(None)));
+
+        decoder.input(&MSG_HELLO[4..]);
+        assert_eq!(decoder.decode_next().unwrap(), Some(String::from("hello")));
+
+        decoder.input(&MSG_HELLO[..6]);
+        assert!(matches!(
+            decoder.decode_next(),
+            Err(wire::Error::MessageTooLarge(6))
+        ));
+    }
 }
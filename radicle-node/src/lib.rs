@@ -10,6 +10,7 @@ pub mod service;
 #[cfg(test)]
 pub mod test;
 pub mod transport;
+pub mod upload_pack;
 pub mod wire;
 
 pub use nakamoto_net::{Io, Link, LocalDuration, LocalTime};
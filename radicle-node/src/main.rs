@@ -12,6 +12,9 @@ struct Options {
     connect: Vec<Address>,
     listen: Vec<net::SocketAddr>,
     git_url: git::Url,
+    alias: Option<String>,
+    proxy: Option<net::SocketAddr>,
+    proxy_all: bool,
 }
 
 impl Options {
@@ -21,6 +24,9 @@ impl Options {
         let mut connect = Vec::new();
         let mut listen = Vec::new();
         let mut git_url = None;
+        let mut alias = None;
+        let mut proxy = None;
+        let mut proxy_all = false;
 
         while let Some(arg) = parser.next()? {
             match arg {
@@ -37,8 +43,22 @@ impl Options {
                         .map_err(|e| format!("invalid URL: {}", e))?;
                     git_url = Some(url);
                 }
+                Long("alias") => {
+                    let name = parser.value()?.into_string()?;
+                    alias = Some(name);
+                }
+                Long("proxy") => {
+                    let addr = parser.value()?.parse()?;
+                    proxy = Some(addr);
+                }
+                Long("proxy-all") => {
+                    proxy_all = true;
+                }
                 Long("help") => {
-                    println!("usage: radicle-node [--connect <addr>]..");
+                    println!(
+                        "usage: radicle-node --git-url <url> [--connect <addr>].. \
+                         [--listen <addr>].. [--alias <name>] [--proxy <addr> [--proxy-all]]"
+                    );
                     process::exit(0);
                 }
                 _ => return Err(arg.unexpected()),
@@ -48,6 +68,9 @@ impl Options {
             connect,
             listen,
             git_url: git_url.ok_or("a Git URL must be specified with `--git-url`")?,
+            alias,
+            proxy,
+            proxy_all,
         })
     }
 }
@@ -57,11 +80,18 @@ fn main() -> anyhow::Result<()> {
     let profile = radicle::Profile::load()?;
     let client = client::Client::<Reactor>::new(profile)?;
     let handle = client.handle();
+    let defaults = service::Config::default();
     let config = client::Config {
         service: service::Config {
             connect: options.connect,
+            listen: options.listen.iter().map(|a| Address::from(*a)).collect(),
             git_url: options.git_url,
-            ..service::Config::default()
+            alias: options.alias.unwrap_or_else(|| defaults.alias.clone()),
+            proxy: options.proxy.map(|addr| service::config::Proxy {
+                addr,
+                all: options.proxy_all,
+            }),
+            ..defaults
         },
         listen: options.listen,
     };
@@ -1,12 +1,16 @@
+pub mod cache;
 pub mod config;
+pub mod fetch;
 pub mod filter;
+pub mod gossip_store;
 pub mod message;
 pub mod peer;
 pub mod reactor;
+pub mod routing;
 
 use std::collections::BTreeMap;
 use std::ops::{Deref, DerefMut};
-use std::{fmt, net, net::IpAddr};
+use std::{cmp, fmt, io, net};
 
 use crossbeam_channel as chan;
 use fastrand::Rng;
@@ -16,50 +20,69 @@ use nakamoto_net as nakamoto;
 use nakamoto_net::Link;
 use nonempty::NonEmpty;
 use radicle::storage::ReadStorage;
+use serde::{Deserialize, Serialize};
 
 use crate::address_book;
-use crate::address_book::AddressBook;
-use crate::address_manager::AddressManager;
+use crate::address_book::{AddressBook, Source};
+use crate::address_manager::{AddressManager, Ban, Unban};
 use crate::clock::{RefClock, Timestamp};
-use crate::collections::{HashMap, HashSet};
+use crate::collections::HashMap;
 use crate::crypto;
 use crate::crypto::{Signer, Verified};
 use crate::git;
-use crate::git::Url;
 use crate::identity::{Doc, Id};
+use crate::service::cache::{Cache, Snapshot};
 use crate::service::config::ProjectTracking;
-use crate::service::message::Address;
-use crate::service::message::{NodeAnnouncement, RefsAnnouncement};
-use crate::service::peer::{Session, SessionError, SessionState};
+use crate::service::fetch::{Fetch, Fetcher, Namespaces};
+use crate::service::gossip_store::GossipStore;
+use crate::service::message::{Address, Hostname};
+use crate::service::message::{Extensions, NodeAnnouncement, RefsAnnouncement};
+use crate::service::message::{MAX_ADDRESSES, MAX_INVENTORY_SIZE, MAX_REFS_SIZE};
+use crate::service::peer::{Session, SessionError, SessionInfo, SessionState};
 use crate::storage;
+use crate::storage::refs::Refs;
 use crate::storage::{Inventory, ReadRepository, RefUpdate, WriteRepository, WriteStorage};
+use crate::transport;
 
 pub use crate::service::config::{Config, Network};
 pub use crate::service::message::{Envelope, Message};
+pub use crate::service::routing::Routing;
 
 use self::message::{InventoryAnnouncement, NodeFeatures};
 use self::reactor::Reactor;
 
 pub const DEFAULT_PORT: u16 = 8776;
-pub const PROTOCOL_VERSION: u32 = 1;
+/// Protocol version spoken by this node. Must be bumped on wire-incompatible changes.
+pub const PROTOCOL_VERSION: u32 = 2;
+/// Lowest protocol version we're still able to speak.
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+/// Features supported by this node.
+pub const NODE_FEATURES: NodeFeatures = [0; 32];
 pub const TARGET_OUTBOUND_PEERS: usize = 8;
 pub const IDLE_INTERVAL: LocalDuration = LocalDuration::from_secs(30);
 pub const ANNOUNCE_INTERVAL: LocalDuration = LocalDuration::from_secs(30);
 pub const SYNC_INTERVAL: LocalDuration = LocalDuration::from_secs(60);
 pub const PRUNE_INTERVAL: LocalDuration = LocalDuration::from_mins(30);
-pub const MAX_CONNECTION_ATTEMPTS: usize = 3;
+pub const FLUSH_INTERVAL: LocalDuration = LocalDuration::from_mins(5);
+/// Delay before the first attempt at reconnecting to a persistent peer.
+/// The delay doubles with every consecutive failed attempt.
+pub const RECONNECT_BACKOFF_BASE: LocalDuration = LocalDuration::from_secs(1);
+/// Maximum delay between attempts at reconnecting to a persistent peer.
+pub const RECONNECT_BACKOFF_MAX: LocalDuration = LocalDuration::from_mins(10);
 pub const MAX_TIME_DELTA: LocalDuration = LocalDuration::from_mins(60);
+/// Minimum time between two replays of past announcements to the same peer.
+pub const REPLAY_INTERVAL: LocalDuration = LocalDuration::from_mins(1);
+/// Maximum number of past announcements replayed to a peer when it subscribes.
+pub const MAX_REPLAY_SIZE: usize = 1024;
 
 /// Network node identifier.
 pub type NodeId = crypto::PublicKey;
-/// Network routing table. Keeps track of where projects are hosted.
-pub type Routing = HashMap<Id, HashSet<NodeId>>;
 
 /// A service event.
 #[derive(Debug, Clone)]
 pub enum Event {
     RefsFetched {
-        from: Url,
+        from: net::SocketAddr,
         project: Id,
         updated: Vec<RefUpdate>,
     },
@@ -74,6 +97,10 @@ pub enum FetchError {
     Storage(#[from] storage::Error),
     #[error(transparent)]
     Fetch(#[from] storage::FetchError),
+    #[error("peer is not connected")]
+    NotConnected,
+    #[error("fetch timed out")]
+    Timeout,
 }
 
 /// Result of looking up seeds in our routing table.
@@ -116,6 +143,12 @@ pub enum Command {
     Fetch(Id, chan::Sender<FetchLookup>),
     Track(Id, chan::Sender<bool>),
     Untrack(Id, chan::Sender<bool>),
+    /// Get the peers banned for misbehaving.
+    Bans(chan::Sender<Vec<Ban>>),
+    /// Lift bans, and get the number of addresses unbanned.
+    Unban(Unban, chan::Sender<usize>),
+    /// Get the current and recent peer sessions.
+    Sessions(chan::Sender<Vec<SessionInfo>>),
 }
 
 /// Command-related errors.
@@ -142,10 +175,19 @@ pub struct Service<A, S, G> {
     reactor: Reactor,
     /// Peer address manager.
     addrmgr: AddressManager<A>,
+    /// Queued and ongoing fetches.
+    fetcher: Fetcher,
+    /// Latest announcements, replayed to subscribers.
+    gossip: GossipStore,
+    /// Persistent cache of routing and peer state, if any.
+    cache: Option<Cache>,
     /// Source of entropy.
     rng: Rng,
     /// Whether our local inventory no long represents what we have announced to the network.
     out_of_sync: bool,
+    /// Our local inventory, as of the last sync. Used where reading it from storage
+    /// would be too costly.
+    inventory: Inventory,
     /// Last time the service was idle.
     last_idle: LocalTime,
     /// Last time the service synced.
@@ -154,6 +196,8 @@ pub struct Service<A, S, G> {
     last_prune: LocalTime,
     /// Last time the service announced its inventory.
     last_announce: LocalTime,
+    /// Last time the service state was flushed to the cache.
+    last_flush: LocalTime,
     /// Time when the service was initialized.
     start_time: LocalTime,
 }
@@ -169,30 +213,39 @@ where
         clock: RefClock,
         storage: S,
         addresses: A,
+        cache: Option<Cache>,
         signer: G,
         rng: Rng,
     ) -> Self {
-        let addrmgr = AddressManager::new(addresses);
-        let routing = HashMap::with_hasher(rng.clone().into());
+        let addrmgr = AddressManager::new(addresses, rng.clone());
+        let routing = Routing::new(rng.clone());
         let sessions = Sessions::new(rng.clone());
+        let fetcher = Fetcher::new(config.limits.fetch_concurrency, rng.clone());
+        let gossip = GossipStore::new(config.limits.gossip_max_size, rng.clone());
         let network = config.network;
+        let proxy = config.proxy.clone();
 
         Self {
             config,
             storage,
             addrmgr,
+            cache,
             signer,
             rng,
             clock,
             routing,
+            fetcher,
+            gossip,
             peers: BTreeMap::new(),
-            reactor: Reactor::new(network),
+            reactor: Reactor::new(network, proxy),
             sessions,
             out_of_sync: false,
+            inventory: Inventory::default(),
             last_idle: LocalTime::default(),
             last_sync: LocalTime::default(),
             last_prune: LocalTime::default(),
             last_announce: LocalTime::default(),
+            last_flush: LocalTime::default(),
             start_time: LocalTime::default(),
         }
     }
@@ -202,15 +255,11 @@ where
     }
 
     pub fn seeds(&self, id: &Id) -> Box<dyn Iterator<Item = (&NodeId, &Session)> + '_> {
-        if let Some(peers) = self.routing.get(id) {
-            Box::new(
-                peers
-                    .iter()
-                    .filter_map(|id| self.sessions.by_id(id).map(|p| (id, p))),
-            )
-        } else {
-            Box::new(std::iter::empty())
-        }
+        Box::new(
+            self.routing
+                .seeds(id)
+                .filter_map(|id| self.sessions.by_id(id).map(|p| (id, p))),
+        )
     }
 
     pub fn tracked(&self) -> Result<Vec<Id>, storage::Error> {
@@ -243,12 +292,75 @@ where
         self.config.untrack(id)
     }
 
+    /// Prune the remotes of a project that we aren't tracking as per our remote tracking
+    /// policy, eg. after the policy changed. Our own remote is always kept.
+    /// Returns the deleted refs.
+    pub fn prune_remotes(&mut self, id: Id) -> Result<Vec<RefUpdate>, storage::Error> {
+        let delegates = if let Some(delegates) = self.delegates(id) {
+            delegates
+        } else {
+            // Without the project identity, we don't know who the delegates are.
+            return Ok(vec![]);
+        };
+        let node = self.node_id();
+        let mut repo = self.storage.repository(id)?;
+
+        repo.prune_remotes(|r| *r == node || self.config.is_tracking_remote(r, &delegates))
+    }
+
     /// Find the closest `n` peers by proximity in tracking graphs.
     /// Returns a sorted list from the closest peer to the furthest.
     /// Peers with more trackings in common score score higher.
-    #[allow(unused)]
     pub fn closest_peers(&self, n: usize) -> Vec<NodeId> {
-        todo!()
+        let mut peers = self.proximity().into_iter().collect::<Vec<_>>();
+
+        // Sort by descending score, breaking ties by node id.
+        peers.sort_by_key(|(node, score)| (cmp::Reverse(*score), *node));
+        peers.into_iter().take(n).map(|(node, _)| node).collect()
+    }
+
+    /// Score peers by proximity in tracking graphs. Peers with more trackings in common
+    /// score higher. Peers we have nothing in common with aren't scored.
+    ///
+    /// When tracking all projects, our inventory as of the last sync is used, so that
+    /// storage isn't read every time.
+    fn proximity(&self) -> HashMap<NodeId, usize> {
+        let tracked: Box<dyn Iterator<Item = &Id> + '_> = match &self.config.project_tracking {
+            ProjectTracking::All { blocked } => Box::new(
+                self.inventory
+                    .iter()
+                    .filter(move |id| !blocked.contains(id)),
+            ),
+            ProjectTracking::Allowed(projs) => Box::new(projs.iter()),
+        };
+        let mut scores: HashMap<NodeId, usize> = HashMap::with_hasher(self.rng.clone().into());
+
+        for id in tracked {
+            // Peers that host projects we track are the closest.
+            for node in self.routing.seeds(id) {
+                *scores.entry(*node).or_default() += 2;
+            }
+            // Peers that are interested in projects we track are close too.
+            for (_, session) in self.sessions.negotiated() {
+                if let (SessionState::Negotiated { id: node, .. }, Some(subscribe)) =
+                    (&session.state, &session.subscribe)
+                {
+                    if subscribe.filter.contains(id) {
+                        *scores.entry(*node).or_default() += 1;
+                    }
+                }
+            }
+        }
+        scores.remove(&self.node_id());
+        scores
+    }
+
+    /// Read our local inventory from storage, and keep it around until the next sync.
+    fn sync_inventory(&mut self) {
+        match self.storage.inventory() {
+            Ok(inventory) => self.inventory = inventory,
+            Err(err) => error!("Error reading local inventory: {}", err),
+        }
     }
 
     /// Get the connected peers.
@@ -301,6 +413,16 @@ where
         &self.routing
     }
 
+    /// Get the address manager.
+    pub fn addresses(&self) -> &AddressManager<A> {
+        &self.addrmgr
+    }
+
+    /// Get information on a peer, if known.
+    pub fn peer(&self, id: &NodeId) -> Option<&Peer> {
+        self.peers.get(id)
+    }
+
     /// Get I/O reactor.
     pub fn reactor(&mut self) -> &mut Reactor {
         &mut self.reactor
@@ -309,10 +431,7 @@ where
     pub fn lookup(&self, id: Id) -> Lookup {
         Lookup {
             local: self.storage.get(&self.node_id(), id).unwrap(),
-            remote: self
-                .routing
-                .get(&id)
-                .map_or(vec![], |r| r.iter().cloned().collect()),
+            remote: self.routing.seeds(&id).cloned().collect(),
         }
     }
 
@@ -320,12 +439,23 @@ where
         trace!("Init {}", time.as_secs());
 
         self.start_time = time;
+        self.last_idle = time;
+        self.last_flush = time;
+
+        // Restore our routing table and peer state from the cache, if any.
+        if let Err(err) = self.load() {
+            error!("Error loading service state from cache: {}", err);
+        }
+        self.sync_inventory();
 
         // Connect to configured peers.
         let addrs = self.config.connect.clone();
         for addr in addrs {
             self.reactor.connect(addr);
         }
+        // Connect to peers from our address book, if needed.
+        self.maintain_connections();
+        self.reactor.wakeup(IDLE_INTERVAL);
     }
 
     pub fn tick(&mut self, now: nakamoto::LocalTime) {
@@ -339,6 +469,26 @@ where
 
         trace!("Wake +{}", now - self.start_time);
 
+        for fetch in self
+            .fetcher
+            .timed_out(now, self.config.limits.fetch_timeout)
+        {
+            error!("Fetch of {} from {} timed out", fetch.id, fetch.remote);
+            self.fetcher.record(&fetch, false, now);
+            self.reactor.cancel(fetch.remote, fetch.id);
+
+            if let Some(results) = fetch.results {
+                results
+                    .send(FetchResult::Error {
+                        from: fetch.remote,
+                        error: FetchError::Timeout,
+                    })
+                    .ok();
+            }
+        }
+        self.dispatch_fetches();
+        self.reconnect(now);
+
         if now - self.last_idle >= IDLE_INTERVAL {
             debug!("Running 'idle' task...");
 
@@ -349,7 +499,7 @@ where
         if now - self.last_sync >= SYNC_INTERVAL {
             debug!("Running 'sync' task...");
 
-            // TODO: What do we do here?
+            self.sync_inventory();
             self.reactor.wakeup(SYNC_INTERVAL);
             self.last_sync = now;
         }
@@ -367,6 +517,15 @@ where
             self.reactor.wakeup(PRUNE_INTERVAL);
             self.last_prune = now;
         }
+        if now - self.last_flush >= FLUSH_INTERVAL {
+            debug!("Running 'flush' task...");
+
+            if let Err(err) = self.flush() {
+                error!("Error flushing service state: {}", err);
+            }
+            self.reactor.wakeup(FLUSH_INTERVAL);
+            self.last_flush = now;
+        }
     }
 
     pub fn command(&mut self, cmd: Command) {
@@ -380,7 +539,23 @@ where
                     return;
                 }
 
-                let seeds = self.seeds(&id).collect::<Vec<_>>();
+                let now = self.clock.local_time();
+                let proximity = self.proximity();
+                let mut seeds = self
+                    .seeds(&id)
+                    .map(|(node, peer)| (*node, peer.addr))
+                    .collect::<Vec<_>>();
+
+                // Pick a random subset of seeds, preferring the ones closest to us, and
+                // avoiding the ones that failed us recently.
+                self.rng.shuffle(&mut seeds);
+                seeds.sort_by_key(|(node, addr)| {
+                    let score = proximity.get(node).copied().unwrap_or_default();
+
+                    (self.fetcher.is_backing_off(addr, now), cmp::Reverse(score))
+                });
+                seeds.truncate(self.config.limits.fetch_max_seeds);
+
                 let seeds = if let Some(seeds) = NonEmpty::from_vec(seeds) {
                     seeds
                 } else {
@@ -391,51 +566,33 @@ where
                 };
                 log::debug!("Found {} seeds for {}", seeds.len(), id);
 
-                let mut repo = match self.storage.repository(id) {
-                    Ok(repo) => repo,
-                    Err(err) => {
-                        log::error!("Error opening repo for {}: {}", id, err);
-                        resp.send(FetchLookup::Error(err.into())).ok();
+                if let Err(err) = self.storage.repository(id) {
+                    log::error!("Error opening repo for {}: {}", id, err);
+                    resp.send(FetchLookup::Error(err.into())).ok();
 
-                        return;
-                    }
-                };
+                    return;
+                }
 
                 let (results_, results) = chan::bounded(seeds.len());
                 resp.send(FetchLookup::Found {
-                    seeds: seeds.clone().map(|(_, peer)| peer.addr),
+                    seeds: seeds.clone().map(|(_, addr)| addr),
                     results,
                 })
                 .ok();
 
-                // TODO: Limit the number of seeds we fetch from? Randomize?
-                for (_, peer) in seeds {
-                    match repo.fetch(&Url {
-                        scheme: git::url::Scheme::Git,
-                        host: Some(peer.addr.ip().to_string()),
-                        port: Some(peer.addr.port()),
-                        // TODO: Fix upstream crate so that it adds a `/` when needed.
-                        path: format!("/{}", id).into(),
-                        ..Url::default()
-                    }) {
-                        Ok(updated) => {
-                            results_
-                                .send(FetchResult::Fetched {
-                                    from: peer.addr,
-                                    updated,
-                                })
-                                .ok();
-                        }
-                        Err(err) => {
-                            results_
-                                .send(FetchResult::Error {
-                                    from: peer.addr,
-                                    error: err.into(),
-                                })
-                                .ok();
-                        }
-                    }
+                let namespaces = self.namespaces(id);
+
+                for (_, addr) in seeds {
+                    self.fetcher.queue(
+                        Fetch {
+                            namespaces: namespaces.clone(),
+                            results: Some(results_.clone()),
+                            ..Fetch::new(id, addr)
+                        },
+                        now,
+                    );
                 }
+                self.dispatch_fetches();
             }
             Command::Track(id, resp) => {
                 resp.send(self.track(id)).ok();
@@ -445,34 +602,108 @@ where
             }
             Command::AnnounceRefs(id) => {
                 let node = self.node_id();
-                let repo = self.storage.repository(id).unwrap();
-                let remote = repo.remote(&node).unwrap();
+                let repo = match self.storage.repository(id) {
+                    Ok(repo) => repo,
+                    Err(err) => {
+                        error!("Unable to announce refs of {}: {}", id, err);
+                        return;
+                    }
+                };
+                let remote = match repo.remote(&node) {
+                    Ok(remote) => remote,
+                    Err(err) => {
+                        error!("Unable to announce refs of {}: {}", id, err);
+                        return;
+                    }
+                };
                 let peers = self.sessions.negotiated().map(|(_, p)| p);
-                let refs = remote.refs.into();
-                let message = RefsAnnouncement { id, refs };
-                let signature = message.sign(&self.signer);
+                let refs: Refs = remote.refs.into();
+
+                if refs.len() > MAX_REFS_SIZE {
+                    error!(
+                        "Unable to announce refs of {}: {} refs exceed the maximum of {}",
+                        id,
+                        refs.len(),
+                        MAX_REFS_SIZE
+                    );
+                    return;
+                }
+                let timestamp = self.clock.timestamp();
+                let message = RefsAnnouncement {
+                    id,
+                    refs,
+                    timestamp,
+                    extensions: Extensions::default(),
+                };
+                let signature = match message.sign(&self.signer) {
+                    Ok(signature) => signature,
+                    Err(err) => {
+                        error!("Unable to sign refs announcement of {}: {}", id, err);
+                        return;
+                    }
+                };
+                let msg = Message::RefsAnnouncement {
+                    node,
+                    message,
+                    signature,
+                };
 
-                self.reactor.broadcast(
-                    Message::RefsAnnouncement {
-                        node,
-                        message,
-                        signature,
-                    },
-                    peers,
-                );
+                self.gossip.insert(msg.clone());
+                self.reactor.broadcast(msg, peers);
+            }
+            Command::Bans(resp) => {
+                resp.send(self.addrmgr.bans(self.clock.local_time())).ok();
+            }
+            Command::Unban(unban, resp) => {
+                let unbanned = self.addrmgr.unban(&unban, self.clock.local_time());
+
+                debug!("Lifted {} ban(s) ({:?})", unbanned, unban);
+
+                resp.send(unbanned).ok();
+            }
+            Command::Sessions(resp) => {
+                resp.send(self.sessions.values().map(Session::info).collect())
+                    .ok();
             }
         }
     }
 
     pub fn attempted(&mut self, addr: &std::net::SocketAddr) {
-        let address = Address::from(*addr);
-        let ip = addr.ip();
+        let address = self.reactor.target(addr);
         let persistent = self.config.is_persistent(&address);
-        let peer = self
-            .sessions
-            .entry(ip)
-            .or_insert_with(|| Session::new(*addr, Link::Outbound, persistent));
 
+        self.addrmgr.attempted(&address, self.clock.local_time());
+
+        // Reconnections to a hostname, or through the proxy, may be made from another
+        // address. The session they replace is carried over, so that backoff keeps growing.
+        let previous = self
+            .sessions
+            .iter()
+            .find(|(a, p)| {
+                *a != addr && p.target == address && p.link.is_outbound() && p.is_disconnected()
+            })
+            .map(|(a, _)| *a);
+        let previous = previous.and_then(|a| self.sessions.remove(&a));
+        let peer = self.sessions.entry(*addr).or_insert_with(|| {
+            let session = Session::new(*addr, address.clone(), Link::Outbound, persistent);
+
+            match &previous {
+                Some(previous) => session.resumed(previous),
+                None => session,
+            }
+        });
+
+        // Addresses of relays and resolved hostnames may be reused for another peer once
+        // closed. If the previous peer was waiting to be reconnected, it is queued again.
+        if peer.target != address {
+            if let SessionState::Disconnected {
+                retry_at: Some(_), ..
+            } = peer.state
+            {
+                self.reactor.connect(peer.target.clone());
+            }
+            *peer = Session::new(*addr, address, Link::Outbound, persistent);
+        }
         peer.attempted();
     }
 
@@ -483,34 +714,100 @@ where
         link: Link,
     ) {
         let ip = addr.ip();
-        let address = addr.into();
+        let address = self.reactor.target(&addr);
+
+        debug!("Connected to {} ({:?})", addr, link);
 
-        debug!("Connected to {} ({:?})", ip, link);
+        if self
+            .addrmgr
+            .is_misbehaving(&ip, None, self.clock.local_time())
+        {
+            debug!("Disconnecting banned peer {}", ip);
 
+            self.reactor
+                .disconnect(addr, DisconnectReason::Error(SessionError::Banned));
+            return;
+        }
         // For outbound connections, we are the first to say "Hello".
         // For inbound connections, we wait for the remote to say "Hello" first.
-        // TODO: How should we deal with multiple peers connecting from the same IP address?
         if link.is_outbound() {
-            if let Some(peer) = self.sessions.get_mut(&ip) {
-                if link.is_outbound() {
-                    self.reactor.write_all(
-                        addr,
-                        gossip::handshake(
-                            self.clock.timestamp(),
-                            &self.storage,
-                            &self.signer,
-                            &self.config,
-                        ),
-                    );
-                }
+            if let Some(peer) = self.sessions.get_mut(&addr) {
+                self.reactor
+                    .write(addr, gossip::init(&self.signer, &self.config));
                 peer.connected(link);
             }
+            self.addrmgr.connected(&address, self.clock.local_time());
         } else {
+            // Peers behind the same IP address, eg. a NAT, each get their own session.
+            // Limit how many there can be, so that a single host can't take up all our
+            // connections.
+            if self.sessions.connected_from(&ip).count()
+                >= self.config.limits.max_connections_per_ip
+            {
+                debug!("Disconnecting {}: too many connections from {}", addr, ip);
+
+                self.reactor.disconnect(
+                    addr,
+                    DisconnectReason::Error(SessionError::ConnectionLimit(ip)),
+                );
+                return;
+            }
             self.sessions.insert(
-                ip,
-                Session::new(addr, Link::Inbound, self.config.is_persistent(&address)),
+                addr,
+                Session::new(
+                    addr,
+                    address.clone(),
+                    Link::Inbound,
+                    self.config.is_persistent(&address),
+                ),
+            );
+        }
+    }
+
+    /// Called by the transport once a peer has proven that it owns the given node id.
+    pub fn authenticated(&mut self, addr: &net::SocketAddr, id: NodeId) {
+        if self
+            .addrmgr
+            .is_misbehaving(&addr.ip(), Some(&id), self.clock.local_time())
+        {
+            debug!("Disconnecting banned node {} ({})", id, addr);
+
+            self.reactor
+                .disconnect(*addr, DisconnectReason::Error(SessionError::Banned));
+            return;
+        }
+        if let Some(peer) = self.sessions.get_mut(addr) {
+            peer.authenticated = Some(id);
+        }
+    }
+
+    /// Called when a peer breaks the protocol. The peer is penalized according to the
+    /// error, and banned if it misbehaved too often, before being disconnected.
+    pub fn session_error(&mut self, addr: &net::SocketAddr, err: SessionError) {
+        let now = self.clock.local_time();
+        let node = self.sessions.get(addr).and_then(|peer| match &peer.state {
+            SessionState::Initialized { id, .. } | SessionState::Negotiated { id, .. } => Some(*id),
+            _ => peer.authenticated,
+        });
+        let penalty = err.penalty();
+
+        if penalty > 0
+            && self.addrmgr.penalize(
+                &addr.ip(),
+                node,
+                penalty,
+                self.config.limits.ban_duration,
+                now,
+            )
+        {
+            info!(
+                "Banned peer {} for {} ({})",
+                addr.ip(),
+                self.config.limits.ban_duration,
+                err
             );
         }
+        self.reactor.disconnect(*addr, DisconnectReason::Error(err));
     }
 
     pub fn disconnected(
@@ -519,37 +816,171 @@ where
         reason: nakamoto::DisconnectReason<DisconnectReason>,
     ) {
         let since = self.local_time();
-        let address = Address::from(*addr);
-        let ip = addr.ip();
+        let address = self.reactor.target(addr);
 
-        debug!("Disconnected from {} ({})", ip, reason);
+        debug!("Disconnected from {} ({})", addr, reason);
 
-        if let Some(peer) = self.sessions.get_mut(&ip) {
-            peer.state = SessionState::Disconnected { since };
+        if reason.is_dial_err() {
+            self.addrmgr.failed(&address, since);
+        } else {
+            self.addrmgr.disconnected(&address, since);
+        }
 
-            // Attempt to re-connect to persistent peers.
-            if self.config.is_persistent(&address) && peer.attempts() < MAX_CONNECTION_ATTEMPTS {
-                if reason.is_dial_err() {
-                    return;
+        if let Some(peer) = self.sessions.get_mut(addr) {
+            // Attempt to re-connect to persistent peers, unless we disconnected them on
+            // purpose.
+            let reconnect = match &reason {
+                nakamoto::DisconnectReason::Protocol(r) => r.is_transient(),
+                _ => true,
+            };
+            let retry_at = if self.config.is_persistent(&address) && reconnect {
+                let delay = reconnect_delay(peer.attempts(), &mut self.rng);
+
+                debug!(
+                    "Reconnecting to {} in {} (attempts={})...",
+                    addr,
+                    delay,
+                    peer.attempts()
+                );
+                self.reactor.wakeup(delay);
+
+                Some(since + delay)
+            } else {
+                None
+            };
+            peer.state = SessionState::Disconnected { since, retry_at };
+
+            // Inbound peers connect from ephemeral ports, so their sessions are never reused.
+            if peer.link.is_inbound() && retry_at.is_none() {
+                self.sessions.remove(addr);
+            }
+        }
+        self.reactor.disconnected(addr);
+    }
+
+    /// Called by the reactor when a resolution started with [`reactor::Io::Resolve`]
+    /// completes. We connect to the first address the hostname resolved to.
+    pub fn resolved(
+        &mut self,
+        host: Hostname,
+        port: u16,
+        result: Result<Vec<net::SocketAddr>, io::Error>,
+    ) {
+        match result.map(|addrs| addrs.into_iter().next()) {
+            Ok(Some(addr)) => {
+                debug!("Resolved {} to {}", host, addr);
+
+                self.reactor.connect_resolved(addr, host, port);
+            }
+            Ok(None) => {
+                error!("Hostname {} did not resolve to any address", host);
+
+                self.reactor.failed(&Address::Hostname { host, port });
+            }
+            Err(err) => {
+                error!("Error resolving hostname {}: {}", host, err);
+
+                self.reactor.failed(&Address::Hostname { host, port });
+            }
+        }
+    }
+
+    /// Called by the reactor when a relay to the proxy, requested with
+    /// [`reactor::Io::Relay`], is listening.
+    pub fn relayed(&mut self, target: Address, result: Result<net::SocketAddr, io::Error>) {
+        match result {
+            Ok(relay) => {
+                debug!("Connecting to {} through relay {}", target, relay);
+
+                self.reactor.connect_relayed(relay, target);
+            }
+            Err(err) => {
+                error!("Error relaying connection to {}: {}", target, err);
+
+                self.addrmgr.failed(&target, self.clock.local_time());
+                self.reactor.failed(&target);
+            }
+        }
+    }
+
+    /// The address the proxy should connect us to, if the given remote is a relay to
+    /// the proxy.
+    pub fn proxied(&self, remote: &net::SocketAddr) -> Option<&Address> {
+        self.reactor.proxied(remote)
+    }
+
+    /// Called by the reactor when a fetch started with [`reactor::Io::Fetch`] completes.
+    pub fn fetched(
+        &mut self,
+        remote: net::SocketAddr,
+        id: Id,
+        result: Result<Vec<RefUpdate>, FetchError>,
+    ) {
+        let fetch = if let Some(fetch) = self.fetcher.completed(&id, &remote) {
+            fetch
+        } else {
+            debug!("Ignoring result of stale fetch of {} from {}", id, remote);
+            // If the fetch timed out, it made room for others by stopping.
+            self.dispatch_fetches();
+            return;
+        };
+        self.fetcher
+            .record(&fetch, result.is_ok(), self.clock.local_time());
+
+        let result = match result {
+            Ok(updated) => {
+                debug!(
+                    "Fetched {} from {}: {} ref(s) updated",
+                    id,
+                    remote,
+                    updated.len()
+                );
+
+                // When fetching all remotes, we may have fetched remotes we don't track.
+                if fetch.namespaces == Namespaces::All {
+                    match self.prune_remotes(id) {
+                        Ok(pruned) if !pruned.is_empty() => {
+                            debug!("Pruned {} untracked ref(s) of {}", pruned.len(), id);
+                        }
+                        Ok(_) => {}
+                        Err(err) => error!("Error pruning remotes of {}: {}", id, err),
+                    }
                 }
-                if let nakamoto::DisconnectReason::Protocol(r) = reason {
-                    if !r.is_transient() {
-                        return;
+                if !updated.is_empty() {
+                    if let Some(msg) = fetch.announcement {
+                        let negotiated = self
+                            .sessions
+                            .negotiated()
+                            .filter(|(a, _)| **a != remote)
+                            .map(|(_, p)| p);
+
+                        self.reactor.relay(msg, negotiated);
                     }
                 }
-                // TODO: Eventually we want a delay before attempting a reconnection,
-                // with exponential back-off.
-                debug!("Reconnecting to {} (attempts={})...", ip, peer.attempts());
-
-                // TODO: Try to reconnect only if the peer was attempted. A disconnect without
-                // even a successful attempt means that we're unlikely to be able to reconnect.
+                self.reactor.event(Event::RefsFetched {
+                    from: remote,
+                    project: id,
+                    updated: updated.clone(),
+                });
+
+                FetchResult::Fetched {
+                    from: remote,
+                    updated,
+                }
+            }
+            Err(error) => {
+                error!("Error fetching {} from {}: {}", id, remote, error);
 
-                self.reactor.connect(*addr);
-            } else {
-                // TODO: Non-persistent peers should be removed from the
-                // map here or at some later point.
+                FetchResult::Error {
+                    from: remote,
+                    error,
+                }
             }
+        };
+        if let Some(results) = fetch.results {
+            results.send(result).ok();
         }
+        self.dispatch_fetches();
     }
 
     pub fn received_message(&mut self, addr: &net::SocketAddr, envelope: Envelope) {
@@ -559,19 +990,19 @@ where
                     let negotiated = self
                         .sessions
                         .negotiated()
-                        .filter(|(ip, _)| **ip != addr.ip())
+                        .filter(|(a, _)| *a != addr)
                         .map(|(_, p)| p);
 
                     self.reactor.relay(msg, negotiated.clone());
                 }
             }
-            Err(SessionError::NotFound(ip)) => {
-                error!("Session not found for {}", ip);
+            Err(SessionError::NotFound(addr)) => {
+                error!("Session not found for {}", addr);
             }
             Err(err) => {
                 // If there's an error, stop processing messages from this peer.
                 // However, we still relay messages returned up to this point.
-                self.reactor.disconnect(*addr, DisconnectReason::Error(err));
+                self.session_error(addr, err);
 
                 // FIXME: The peer should be set in a state such that we don'that
                 // process further messages.
@@ -585,16 +1016,16 @@ where
         envelope: Envelope,
     ) -> Result<Option<Message>, peer::SessionError> {
         let peer_ip = remote.ip();
-        let peer = if let Some(peer) = self.sessions.get_mut(&peer_ip) {
+        let peer = if let Some(peer) = self.sessions.get_mut(remote) {
             peer
         } else {
-            return Err(SessionError::NotFound(remote.ip()));
+            return Err(SessionError::NotFound(*remote));
         };
 
         if envelope.magic != self.config.network.magic() {
             return Err(SessionError::WrongMagic(envelope.magic));
         }
-        debug!("Received {:?} from {}", &envelope.msg, peer.ip());
+        debug!("Received {:?} from {}", &envelope.msg, peer.addr);
 
         match (&peer.state, envelope.msg) {
             (
@@ -602,45 +1033,110 @@ where
                 Message::Initialize {
                     id,
                     version,
+                    min_version,
+                    features,
                     addrs,
                     git,
                 },
             ) => {
-                if version != PROTOCOL_VERSION {
-                    return Err(SessionError::WrongVersion(version));
+                if self
+                    .addrmgr
+                    .is_misbehaving(&peer_ip, Some(&id), self.clock.local_time())
+                {
+                    debug!("Disconnecting banned node {} ({})", id, peer.addr);
+                    return Err(SessionError::Banned);
                 }
-                // Nb. This is a very primitive handshake. Eventually we should have anyhow
-                // extra "acknowledgment" message sent when the `Initialize` is well received.
+                // If the transport authenticated the peer, it must claim the same identity.
+                if let Some(authenticated) = peer.authenticated {
+                    if authenticated != id {
+                        debug!(
+                            "Disconnecting peer {} for claiming to be {}, while authenticated as {}",
+                            peer.addr,
+                            id,
+                            authenticated
+                        );
+                        return Err(SessionError::Misbehavior);
+                    }
+                }
+                let (version, features) = negotiate(version, min_version, features)?;
+
+                // For inbound connections, we reply with our own `Initialize` message
+                // before acknowledging theirs.
                 if peer.link.is_inbound() {
-                    self.reactor.write_all(
-                        peer.addr,
-                        gossip::handshake(
-                            self.clock.timestamp(),
-                            &self.storage,
-                            &self.signer,
-                            &self.config,
-                        ),
-                    );
+                    self.reactor
+                        .write(peer.addr, gossip::init(&self.signer, &self.config));
                 }
-                // Nb. we don't set the peer timestamp here, since it is going to be
-                // set after the first message is received only. Setting it here would
-                // mean that messages received right after the handshake could be ignored.
-                peer.state = SessionState::Negotiated {
+                self.reactor
+                    .write(peer.addr, Message::ack(version, features));
+
+                peer.state = SessionState::Initialized {
                     id,
-                    since: self.clock.local_time(),
                     addrs,
                     git,
+                    version,
+                    features,
                 };
             }
             (SessionState::Initial, _) => {
                 debug!(
                     "Disconnecting peer {} for sending us a message before handshake",
-                    peer.ip()
+                    peer.addr
+                );
+                return Err(SessionError::Misbehavior);
+            }
+            (
+                SessionState::Initialized {
+                    id,
+                    addrs,
+                    git,
+                    version,
+                    features,
+                },
+                Message::InitializeAck {
+                    version: ack_version,
+                    features: ack_features,
+                },
+            ) => {
+                // Both sides compute the same agreement from the exchanged `Initialize`
+                // messages, so the acknowledgment must match ours.
+                if ack_version != *version || ack_features != *features {
+                    debug!(
+                        "Disconnecting peer {} for acknowledging a different version or feature set",
+                        peer.addr
+                    );
+                    return Err(SessionError::Misbehavior);
+                }
+                // Nb. we don't set the peer timestamp here, since it is going to be
+                // set after the first message is received only. Setting it here would
+                // mean that messages received right after the handshake could be ignored.
+                let negotiated = SessionState::Negotiated {
+                    id: *id,
+                    since: self.clock.local_time(),
+                    addrs: addrs.clone(),
+                    git: git.clone(),
+                    version: *version,
+                    features: *features,
+                };
+                peer.negotiated(negotiated);
+
+                let timestamp = self.clock.timestamp();
+                // Ask for the announcements we may have missed while we were offline.
+                let since = last_seen(&self.peers).unwrap_or(timestamp);
+
+                self.reactor.write_all(
+                    peer.addr,
+                    gossip::negotiated(timestamp, since, &self.storage, &self.signer, &self.config),
+                );
+            }
+            (SessionState::Initialized { .. }, _) => {
+                debug!(
+                    "Disconnecting peer {} for sending us a message before acknowledging the handshake",
+                    peer.addr
                 );
                 return Err(SessionError::Misbehavior);
             }
             (
-                SessionState::Negotiated { git, .. },
+                SessionState::Negotiated { .. },
                 Message::InventoryAnnouncement {
                     node,
                     message,
@@ -648,9 +1144,12 @@ where
                 },
             ) => {
                 let now = self.clock.local_time();
+
+                if !message.verify(node, &signature) {
+                    return Err(SessionError::InvalidSignature);
+                }
                 let peer = self.peers.entry(node).or_insert_with(Peer::default);
                 let relay = self.config.relay;
-                let git = git.clone();
 
                 // Don't allow messages from too far in the future.
                 if message.timestamp.saturating_sub(now.as_secs()) > MAX_TIME_DELTA.as_secs() {
@@ -663,51 +1162,106 @@ where
                 } else {
                     return Ok(None);
                 }
-                self.process_inventory(&message.inventory, node, &git);
+                self.process_inventory(&message.inventory, node, message.timestamp, *remote);
+
+                let msg = Message::InventoryAnnouncement {
+                    node,
+                    message,
+                    signature,
+                };
+                self.gossip.insert(msg.clone());
 
                 if relay {
-                    return Ok(Some(Message::InventoryAnnouncement {
-                        node,
-                        message,
-                        signature,
-                    }));
+                    return Ok(Some(msg));
                 }
             }
             // Process a peer inventory update announcement by (maybe) fetching.
             (
-                SessionState::Negotiated { git, .. },
+                SessionState::Negotiated { .. },
                 Message::RefsAnnouncement {
                     node,
                     message,
                     signature,
                 },
             ) => {
-                // FIXME: Check message timestamp.
+                let now = self.clock.local_time();
 
                 if message.verify(&node, &signature) {
-                    // TODO: Buffer/throttle fetches.
-                    // TODO: Check that we're tracking this user as well.
+                    // Don't allow messages from too far in the future.
+                    if message.timestamp.saturating_sub(now.as_secs()) > MAX_TIME_DELTA.as_secs() {
+                        return Err(SessionError::InvalidTimestamp(message.timestamp));
+                    }
+                    // Discard announcements that are too old for us to remember whether
+                    // we've seen them.
+                    if message.timestamp < self.refs_expiry(now) {
+                        return Ok(None);
+                    }
+                    let peer = self.peers.entry(node).or_insert_with(Peer::default);
+
+                    // Discard announcements we've already seen, or that are older than the
+                    // latest one we have for this project. Otherwise, a captured announcement
+                    // could be replayed to us indefinitely.
+                    match peer.last_refs.get(&message.id) {
+                        Some(last) if message.timestamp <= *last => return Ok(None),
+                        _ => {
+                            peer.last_refs.insert(message.id, message.timestamp);
+                        }
+                    }
+                    self.gossip.insert(Message::RefsAnnouncement {
+                        node,
+                        message: message.clone(),
+                        signature,
+                    });
+
                     if self.config.is_tracking(&message.id) {
-                        // TODO: Check refs to see if we should try to fetch or not.
-                        let updated = self.storage.fetch(message.id, git).unwrap();
-                        let is_updated = !updated.is_empty();
-
-                        self.reactor.event(Event::RefsFetched {
-                            from: git.clone(),
-                            project: message.id,
-                            updated,
-                        });
-
-                        if is_updated {
-                            return Ok(Some(Message::RefsAnnouncement {
+                        // If we don't have the project yet, we can't tell whether we're
+                        // tracking this node; remotes we don't track are pruned after the
+                        // fetch.
+                        if let Some(delegates) = self.delegates(message.id) {
+                            if !self.config.is_tracking_remote(&node, &delegates) {
+                                debug!(
+                                    "Ignoring refs of {} announced by untracked node {}",
+                                    message.id, node
+                                );
+                                return Ok(None);
+                            }
+                        }
+                        // Only fetch if the announced refs differ from the ones we have
+                        // of this node.
+                        let known = self
+                            .storage
+                            .repository(message.id)
+                            .ok()
+                            .and_then(|repo| repo.remote(&node).ok());
+
+                        if known.map_or(false, |r| *r.refs == message.refs) {
+                            debug!(
+                                "Skipping fetch of {} from {}: refs of {} are up to date",
+                                message.id, remote, node
+                            );
+                            return Ok(None);
+                        }
+                        // The announcement is relayed once the fetch completes, if it
+                        // updated any of our refs.
+                        let id = message.id;
+                        let fetch = Fetch {
+                            namespaces: Namespaces::Only(vec![node]),
+                            announcement: Some(Message::RefsAnnouncement {
                                 node,
                                 message,
                                 signature,
-                            }));
+                            }),
+                            ..Fetch::new(id, *remote)
+                        };
+
+                        if self.fetcher.queue(fetch, now) {
+                            self.dispatch_fetches();
+                        } else {
+                            debug!("Skipping redundant fetch of {} from {}", id, remote);
                         }
                     }
                 } else {
-                    return Err(SessionError::Misbehavior);
+                    return Err(SessionError::InvalidSignature);
                 }
             }
             (
@@ -718,43 +1272,173 @@ where
                     signature,
                 },
             ) => {
-                // FIXME: Check message timestamp.
+                let now = self.clock.local_time();
 
                 if !message.verify(&node, &signature) {
-                    return Err(SessionError::Misbehavior);
+                    return Err(SessionError::InvalidSignature);
+                }
+                // Don't allow messages from too far in the future.
+                if message.timestamp.saturating_sub(now.as_secs()) > MAX_TIME_DELTA.as_secs() {
+                    return Err(SessionError::InvalidTimestamp(message.timestamp));
+                }
+                let alias = match node_alias(&message.alias) {
+                    Some(alias) => alias,
+                    None => return Err(SessionError::Misbehavior),
+                };
+                let peer = self.peers.entry(node).or_insert_with(Peer::default);
+
+                // Discard announcements we've already seen, or that are older than the
+                // latest one we have.
+                if message.timestamp <= peer.last_announcement {
+                    return Ok(None);
+                }
+                peer.last_announcement = message.timestamp;
+                peer.alias = alias;
+                peer.features = message.features;
+                // Addresses we don't know can't be connected to, nor stored.
+                peer.addresses = message
+                    .addresses
+                    .iter()
+                    .filter(|addr| addr.is_known())
+                    .cloned()
+                    .collect();
+
+                for addr in &peer.addresses {
+                    if self.config.listen.contains(addr) || !self.reactor.can_connect(addr) {
+                        continue;
+                    }
+                    if self
+                        .addrmgr
+                        .insert(addr.clone(), Source::Peer(*remote), now)
+                    {
+                        debug!("Added address {} of node {} to address book", addr, node);
+                    }
+                }
+
+                let msg = Message::NodeAnnouncement {
+                    node,
+                    message,
+                    signature,
+                };
+                self.gossip.insert(msg.clone());
+
+                if self.config.relay {
+                    return Ok(Some(msg));
                 }
-                log::warn!("Node announcement handling is not implemented");
             }
-            (SessionState::Negotiated { .. }, Message::Subscribe(subscribe)) => {
+            (SessionState::Negotiated { id, .. }, Message::Subscribe(subscribe)) => {
+                // Replay the latest announcements the peer asked for, except its own.
+                // Replays are costly, so peers can't ask for them too often.
+                let id = *id;
+                let now = self.clock.local_time();
+                let replay = match peer.replayed {
+                    Some(t) if now - t < REPLAY_INTERVAL => {
+                        debug!("Not replaying announcements to {}: too soon", peer.addr);
+                        vec![]
+                    }
+                    _ => {
+                        peer.replayed = Some(now);
+
+                        let mut replay = self
+                            .gossip
+                            .filtered(&subscribe.filter, subscribe.since, subscribe.until)
+                            .rev()
+                            .filter(|msg| msg.node() != Some(id))
+                            .take(MAX_REPLAY_SIZE)
+                            .cloned()
+                            .collect::<Vec<_>>();
+
+                        replay.reverse();
+                        replay
+                    }
+                };
+
+                if !replay.is_empty() {
+                    debug!(
+                        "Replaying {} announcement(s) to {}",
+                        replay.len(),
+                        peer.addr
+                    );
+                    self.reactor.write_all(peer.addr, replay);
+                }
                 peer.subscribe = Some(subscribe);
             }
-            (SessionState::Negotiated { .. }, Message::Initialize { .. }) => {
+            (
+                SessionState::Negotiated { .. },
+                Message::Initialize { .. } | Message::InitializeAck { .. },
+            ) => {
                 debug!(
                     "Disconnecting peer {} for sending us a redundant handshake message",
-                    peer.ip()
+                    peer.addr
                 );
                 return Err(SessionError::Misbehavior);
             }
             (SessionState::Disconnected { .. }, msg) => {
-                debug!("Ignoring {:?} from disconnected peer {}", msg, peer.ip());
+                debug!("Ignoring {:?} from disconnected peer {}", msg, peer.addr);
             }
         }
         Ok(None)
     }
 
+    /// Start queued fetches, as long as we're below the concurrency limit. Fetches are
+    /// carried out by the reactor, which reports back via [`Service::fetched`].
+    fn dispatch_fetches(&mut self) {
+        let now = self.clock.local_time();
+        let mut dispatched = false;
+
+        while let Some((id, remote, namespaces)) = self.fetcher.dequeue(now) {
+            debug!("Fetching {} from {}..", id, remote);
+
+            self.reactor.fetch(remote, id, namespaces);
+            dispatched = true;
+        }
+        if dispatched {
+            // Make sure we're woken up to give up on fetches that take too long.
+            self.reactor.wakeup(self.config.limits.fetch_timeout);
+        }
+    }
+
+    /// The delegates of a project, if we have its identity.
+    fn delegates(&self, id: Id) -> Option<Vec<crypto::PublicKey>> {
+        let repo = self.storage.repository(id).ok()?;
+        let (_, doc) = repo.project_identity().ok()?;
+
+        Some(doc.delegates.iter().map(|d| *d.id).collect())
+    }
+
+    /// The remotes of a project to fetch, as per our remote tracking policy. If we don't have
+    /// the project yet, all remotes are fetched, and the ones we don't track are pruned.
+    fn namespaces(&self, id: Id) -> Namespaces {
+        self.delegates(id)
+            .map_or(Namespaces::All, |d| self.config.tracked_remotes(&d))
+    }
+
     /// Process a peer inventory announcement by updating our routing table.
-    fn process_inventory(&mut self, inventory: &Inventory, from: NodeId, remote: &Url) {
+    fn process_inventory(
+        &mut self,
+        inventory: &Inventory,
+        from: NodeId,
+        timestamp: Timestamp,
+        remote: net::SocketAddr,
+    ) {
         for proj_id in inventory {
-            let inventory = self
-                .routing
-                .entry(*proj_id)
-                .or_insert_with(|| HashSet::with_hasher(self.rng.clone().into()));
-
             // TODO: Fire an event on routing update.
-            if inventory.insert(from) && self.config.is_tracking(proj_id) {
-                self.storage.fetch(*proj_id, remote).unwrap();
+            if self.routing.insert(*proj_id, from, timestamp) && self.config.is_tracking(proj_id) {
+                let fetch = Fetch {
+                    namespaces: self.namespaces(*proj_id),
+                    ..Fetch::new(*proj_id, remote)
+                };
+                self.fetcher.queue(fetch, self.clock.local_time());
             }
         }
+        self.dispatch_fetches();
+
+        // Since this announcement is newer than any we've seen from this node, projects
+        // missing from it are no longer hosted by the node.
+        let removed = self.routing.retain_inventory(&from, inventory);
+        if removed > 0 {
+            debug!("Removed {} routing entries for node {}", removed, from);
+        }
     }
 
     ////////////////////////////////////////////////////////////////////////////
@@ -764,10 +1448,16 @@ where
     /// Announce our inventory to all connected peers.
     fn announce_inventory(&mut self) -> Result<(), storage::Error> {
         let inventory = self.storage().inventory()?;
-        let inv = Message::inventory(
+        let inv = match Message::inventory(
             gossip::inventory(self.clock.timestamp(), inventory),
             &self.signer,
-        );
+        ) {
+            Ok(inv) => inv,
+            Err(err) => {
+                error!("Unable to sign inventory announcement: {}", err);
+                return Ok(());
+            }
+        };
 
         for addr in self.sessions.negotiated().map(|(_, p)| p.addr) {
             self.reactor.write(addr, inv.clone());
@@ -775,17 +1465,176 @@ where
         Ok(())
     }
 
+    /// Load the routing table, peer state and misbehaving peers from the cache.
+    fn load(&mut self) -> Result<(), io::Error> {
+        let cache = if let Some(cache) = &mut self.cache {
+            cache
+        } else {
+            return Ok(());
+        };
+        let Snapshot {
+            routing,
+            peers,
+            misbehaviour,
+        } = cache.read()?;
+
+        for (id, nodes) in routing {
+            for (node, timestamp) in nodes {
+                self.routing.insert(id, node, timestamp);
+            }
+        }
+        self.peers.extend(peers);
+        self.addrmgr.restore(misbehaviour);
+
+        debug!(
+            "Loaded {} routing entries and {} peers from cache",
+            self.routing.len(),
+            self.peers.len()
+        );
+        Ok(())
+    }
+
+    /// Flush the routing table, peer state and known addresses to permanent storage.
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.addrmgr.flush()?;
+
+        let cache = if let Some(cache) = &mut self.cache {
+            cache
+        } else {
+            return Ok(());
+        };
+        let mut snapshot = Snapshot::default();
+
+        for (id, node, timestamp) in self.routing.iter() {
+            snapshot
+                .routing
+                .entry(*id)
+                .or_default()
+                .insert(*node, *timestamp);
+        }
+        snapshot.peers = self.peers.clone();
+        snapshot.misbehaviour = self
+            .addrmgr
+            .misbehaviour()
+            .map(|(ip, m)| (*ip, m.clone()))
+            .collect();
+
+        cache.write(&snapshot)
+    }
+
+    /// Remove routing entries that haven't been confirmed recently, and make sure the
+    /// routing table doesn't grow past its maximum size.
     fn prune_routing_entries(&mut self) {
-        // TODO
+        let now = self.clock.local_time();
+        let oldest = now
+            .as_secs()
+            .saturating_sub(self.config.limits.routing_max_age.as_secs());
+        let removed = self
+            .routing
+            .prune(oldest, self.config.limits.routing_max_size);
+
+        debug!(
+            "Pruned {} routing entries ({} remaining)",
+            removed,
+            self.routing.len()
+        );
+
+        // Refs announcements older than this are discarded, so there's no need to remember
+        // the ones we've seen.
+        let expiry = self.refs_expiry(now);
+        for peer in self.peers.values_mut() {
+            peer.last_refs.retain(|_, t| *t >= expiry);
+        }
+    }
+
+    /// Timestamp before which refs announcements are discarded. The latest announcements
+    /// are remembered until then, so that they can't be replayed to us.
+    fn refs_expiry(&self, now: LocalTime) -> Timestamp {
+        now.as_secs()
+            .saturating_sub(self.config.limits.routing_max_age.as_secs() + MAX_TIME_DELTA.as_secs())
     }
 
+    /// Reconnect to the persistent peers whose reconnection delay has elapsed.
+    fn reconnect(&mut self, now: LocalTime) {
+        for (addr, peer) in self.sessions.iter_mut() {
+            let attempts = peer.attempts();
+
+            if let SessionState::Disconnected { retry_at, .. } = &mut peer.state {
+                if retry_at.map_or(false, |t| t <= now) {
+                    debug!("Reconnecting to {} (attempts={})..", addr, attempts);
+
+                    *retry_at = None;
+                    self.reactor.connect(peer.target.clone());
+                }
+            }
+        }
+    }
+
+    /// Make sure we're connected to enough outbound peers, by connecting to
+    /// peers from our address book.
     fn maintain_connections(&mut self) {
-        // TODO: Connect to all potential seeds.
-        if self.sessions.len() < TARGET_OUTBOUND_PEERS {
-            let delta = TARGET_OUTBOUND_PEERS - self.sessions.len();
+        let now = self.clock.local_time();
+        // Addresses being resolved or relayed will be connected to shortly.
+        let outbound = self.sessions.outbound().count() + self.reactor.pending();
+        let mut wanted = TARGET_OUTBOUND_PEERS.saturating_sub(outbound);
 
-            for _ in 0..delta {
-                // TODO: Connect to random peer.
+        if wanted == 0 {
+            return;
+        }
+        debug!(
+            "Connecting to {} peer(s) (outbound={}, target={})..",
+            wanted, outbound, TARGET_OUTBOUND_PEERS
+        );
+
+        // Prefer connecting to the peers closest to us, if we know their addresses.
+        for node in self.closest_peers(usize::MAX) {
+            if wanted == 0 {
+                return;
+            }
+            if self.sessions.by_id(&node).is_some() {
+                continue;
+            }
+            let addr = self.peers.get(&node).and_then(|peer| {
+                peer.addresses
+                    .iter()
+                    .find(|addr| self.reactor.can_connect(addr))
+                    .cloned()
+            });
+            if let Some(addr) = addr {
+                if self.sessions.is_connected_to(&addr)
+                    || self.reactor.is_pending(&addr)
+                    || !self.addrmgr.is_available(&addr, now)
+                {
+                    continue;
+                }
+                debug!("Connecting to close peer {} ({})..", node, addr);
+
+                self.addrmgr.attempted(&addr, now);
+                self.reactor.connect(addr);
+                wanted -= 1;
+            }
+        }
+
+        for _ in 0..wanted {
+            let sessions = &self.sessions;
+            let reactor = &self.reactor;
+            let listen = &self.config.listen;
+
+            // Skip peers we're already connected or connecting to, as well as ourselves.
+            let sampled = self.addrmgr.sample(now, |ka| {
+                !sessions.is_connected_to(&ka.addr)
+                    && !reactor.is_pending(&ka.addr)
+                    && !listen.contains(&ka.addr)
+            });
+
+            if let Some(ka) = sampled {
+                // Nb. We mark the address as attempted right away, so that we don't try
+                // to connect to it again before the reactor notifies us of the attempt.
+                self.addrmgr.attempted(&ka.addr, now);
+                self.reactor.connect(ka.addr);
+            } else {
+                debug!("No more peers available to connect to");
+                break;
             }
         }
     }
@@ -795,6 +1644,7 @@ where
 pub enum DisconnectReason {
     User,
     Error(SessionError),
+    Transport(transport::Error),
 }
 
 impl DisconnectReason {
@@ -802,6 +1652,7 @@ impl DisconnectReason {
         match self {
             Self::User => false,
             Self::Error(..) => false,
+            Self::Transport(err) => matches!(err, transport::Error::Timeout),
         }
     }
 }
@@ -812,11 +1663,24 @@ impl From<DisconnectReason> for nakamoto_net::DisconnectReason<DisconnectReason>
     }
 }
 
+/// Delay before reconnecting to a peer, given the number of consecutive failed attempts.
+/// The delay grows exponentially up to [`RECONNECT_BACKOFF_MAX`], and is jittered, so that
+/// peers that were disconnected at the same time don't all reconnect at once.
+fn reconnect_delay(attempts: usize, rng: &mut Rng) -> LocalDuration {
+    let delay = RECONNECT_BACKOFF_BASE
+        .as_millis()
+        .saturating_mul(1 << attempts.min(16))
+        .min(RECONNECT_BACKOFF_MAX.as_millis());
+
+    LocalDuration::from_millis(rng.u128(delay / 2..=delay))
+}
+
 impl fmt::Display for DisconnectReason {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::User => write!(f, "user"),
             Self::Error(err) => write!(f, "error: {}", err),
+            Self::Transport(err) => write!(f, "transport error: {}", err),
         }
     }
 }
@@ -839,15 +1703,69 @@ pub struct Lookup {
 }
 
 /// Information on a peer, that we may or may not be connected to.
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct Peer {
     /// Timestamp of the last message received from peer.
     pub last_message: Timestamp,
+    /// Timestamp of the last node announcement received from peer.
+    pub last_announcement: Timestamp,
+    /// Timestamp of the last refs announcement received from peer, per project.
+    #[serde(default)]
+    pub last_refs: BTreeMap<Id, Timestamp>,
+    /// Peer alias, as announced by the peer.
+    pub alias: String,
+    /// Features announced by the peer.
+    pub features: NodeFeatures,
+    /// Addresses announced by the peer.
+    #[serde(default)]
+    pub addresses: Vec<Address>,
 }
 
+/// Negotiate the protocol version and features to use with a peer, given what the
+/// peer supports. We use the highest version supported by both nodes, and the features
+/// supported by both nodes.
+fn negotiate(
+    version: u32,
+    min_version: u32,
+    features: NodeFeatures,
+) -> Result<(u32, NodeFeatures), SessionError> {
+    let agreed = version.min(PROTOCOL_VERSION);
+
+    if agreed < min_version.max(MIN_PROTOCOL_VERSION) {
+        return Err(SessionError::WrongVersion(version));
+    }
+    let mut common = NODE_FEATURES;
+    for (a, b) in common.iter_mut().zip(features.iter()) {
+        *a &= b;
+    }
+    Ok((agreed, common))
+}
+
+/// Timestamp of the latest message received from any peer, if any.
+fn last_seen(peers: &BTreeMap<NodeId, Peer>) -> Option<Timestamp> {
+    peers
+        .values()
+        .flat_map(|p| {
+            [p.last_message, p.last_announcement]
+                .into_iter()
+                .chain(p.last_refs.values().copied())
+        })
+        .filter(|t| *t > 0)
+        .max()
+}
+
+/// Decode a node alias. Returns `None` if the alias isn't valid UTF-8.
+fn node_alias(alias: &[u8; 32]) -> Option<String> {
+    let len = alias.iter().position(|b| *b == 0).unwrap_or(alias.len());
+    let alias = std::str::from_utf8(&alias[..len]).ok()?;
+
+    Some(alias.to_owned())
+}
+
+/// Peer sessions, by peer address. Once the handshake is done, sessions can also be looked
+/// up by node id.
 #[derive(Debug)]
-/// Holds currently (or recently) connected peers.
-pub struct Sessions(AddressBook<IpAddr, Session>);
+pub struct Sessions(AddressBook<net::SocketAddr, Session>);
 
 impl Sessions {
     pub fn new(rng: Rng) -> Self {
@@ -865,13 +1783,38 @@ impl Sessions {
     }
 
     /// Iterator over fully negotiated peers.
-    pub fn negotiated(&self) -> impl Iterator<Item = (&IpAddr, &Session)> + Clone {
+    pub fn negotiated(&self) -> impl Iterator<Item = (&net::SocketAddr, &Session)> + Clone {
         self.0.iter().filter(move |(_, p)| p.is_negotiated())
     }
+
+    /// Iterator over outbound peers we're connected or connecting to.
+    pub fn outbound(&self) -> impl Iterator<Item = (&net::SocketAddr, &Session)> + Clone {
+        self.0
+            .iter()
+            .filter(move |(_, p)| p.link.is_outbound() && !p.is_disconnected())
+    }
+
+    /// Iterator over the peers we're connected or connecting to at the given IP address.
+    pub fn connected_from<'a>(
+        &'a self,
+        ip: &'a net::IpAddr,
+    ) -> impl Iterator<Item = (&net::SocketAddr, &Session)> + Clone + 'a {
+        self.0
+            .iter()
+            .filter(move |(addr, p)| addr.ip() == *ip && !p.is_disconnected())
+    }
+
+    /// Whether we're connected or connecting to the peer at the given address, which may
+    /// be a hostname or an address reached through the proxy.
+    pub fn is_connected_to(&self, addr: &Address) -> bool {
+        self.0
+            .values()
+            .any(|p| p.target == *addr && !p.is_disconnected())
+    }
 }
 
 impl Deref for Sessions {
-    type Target = AddressBook<IpAddr, Session>;
+    type Target = AddressBook<net::SocketAddr, Session>;
 
     fn deref(&self) -> &Self::Target {
         &self.0
@@ -887,40 +1830,86 @@ impl DerefMut for Sessions {
 mod gossip {
     use super::*;
 
-    pub fn handshake<G: Signer, S: ReadStorage>(
+    pub fn init<G: Signer>(signer: &G, config: &Config) -> Message {
+        let git = config.git_url.clone();
+
+        Message::init(
+            *signer.public_key(),
+            NODE_FEATURES,
+            config.listen.iter().take(MAX_ADDRESSES).cloned().collect(),
+            git,
+        )
+    }
+
+    /// Messages sent to a peer once the session is negotiated.
+    /// We subscribe to announcements made since the given time.
+    /// Announcements that can't be signed are left out.
+    pub fn negotiated<G: Signer, S: ReadStorage>(
         timestamp: Timestamp,
+        since: Timestamp,
         storage: &S,
         signer: &G,
         config: &Config,
-    ) -> [Message; 4] {
-        let git = config.git_url.clone();
+    ) -> Vec<Message> {
         let inventory = storage.inventory().unwrap();
-
-        [
-            Message::init(*signer.public_key(), config.listen.clone(), git),
+        let announcements = [
             Message::node(gossip::node(timestamp, config), signer),
             Message::inventory(gossip::inventory(timestamp, inventory), signer),
-            Message::subscribe(config.filter(), timestamp, Timestamp::MAX),
-        ]
+        ];
+
+        announcements
+            .into_iter()
+            .filter_map(|msg| {
+                msg.map_err(|err| error!("Unable to sign announcement: {}", err))
+                    .ok()
+            })
+            .chain([Message::subscribe(config.filter(), since, Timestamp::MAX)])
+            .collect()
     }
 
     pub fn node(timestamp: Timestamp, config: &Config) -> NodeAnnouncement {
-        let features = NodeFeatures::default();
+        let features = NODE_FEATURES;
         let alias = config.alias();
-        let addresses = vec![]; // TODO
+        let addresses = config
+            .listen
+            .iter()
+            .filter(|a| is_routable(a))
+            .take(MAX_ADDRESSES)
+            .cloned()
+            .collect();
 
         NodeAnnouncement {
             features,
             timestamp,
             alias,
             addresses,
+            extensions: Extensions::default(),
         }
     }
 
-    pub fn inventory(timestamp: Timestamp, inventory: Vec<Id>) -> InventoryAnnouncement {
+    /// Whether an address is worth announcing to other nodes.
+    fn is_routable(addr: &Address) -> bool {
+        match addr {
+            Address::Ipv4 { ip, .. } => !ip.is_unspecified() && !ip.is_loopback(),
+            Address::Ipv6 { ip, .. } => !ip.is_unspecified() && !ip.is_loopback(),
+            Address::Hostname { .. } | Address::Onion { .. } => true,
+            Address::Unknown { .. } => false,
+        }
+    }
+
+    pub fn inventory(timestamp: Timestamp, mut inventory: Vec<Id>) -> InventoryAnnouncement {
+        if inventory.len() > MAX_INVENTORY_SIZE {
+            warn!(
+                "Inventory of {} projects exceeds the maximum of {}, truncating..",
+                inventory.len(),
+                MAX_INVENTORY_SIZE
+            );
+            inventory.truncate(MAX_INVENTORY_SIZE);
+        }
         InventoryAnnouncement {
             inventory,
             timestamp,
+            extensions: Extensions::default(),
         }
     }
 }
@@ -0,0 +1,182 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::{fs, io, net};
+
+use serde::{Deserialize, Serialize};
+
+use crate::address_manager::Misbehaviour;
+use crate::clock::Timestamp;
+use crate::identity::Id;
+use crate::service::{NodeId, Peer};
+
+/// Service state that is persisted across restarts.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Routing table entries, with the time they were last confirmed.
+    pub routing: HashMap<Id, HashMap<NodeId, Timestamp>>,
+    /// Known peers.
+    pub peers: BTreeMap<NodeId, Peer>,
+    /// Misbehaving peers, by IP address, so that bans persist across restarts.
+    #[serde(default)]
+    pub misbehaviour: HashMap<net::IpAddr, Misbehaviour>,
+}
+
+/// A file-backed service state cache.
+#[derive(Debug)]
+pub struct Cache {
+    path: PathBuf,
+}
+
+impl Cache {
+    /// Open an existing cache.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+
+        if !fs::metadata(path)?.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("cache {} is not a file", path.display()),
+            ));
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Create a new cache.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+
+        fs::OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(path)?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Read the cached state. Returns an empty snapshot if nothing was cached yet.
+    pub fn read(&mut self) -> io::Result<Snapshot> {
+        let bytes = fs::read(&self.path)?;
+        if bytes.is_empty() {
+            return Ok(Snapshot::default());
+        }
+        let snapshot = serde_json::from_slice(&bytes)?;
+
+        Ok(snapshot)
+    }
+
+    /// Write the given state to the cache, replacing what was there.
+    ///
+    /// The state is written to a temporary file which then replaces the cache, so that
+    /// the cache is left intact if we crash while writing.
+    pub fn write(&mut self, snapshot: &Snapshot) -> io::Result<()> {
+        use io::Write;
+
+        let tmp = self.tmp_path();
+        let mut s = serde_json::to_string(snapshot)?;
+        s.push('\n');
+
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(s.as_bytes())?;
+        file.sync_all()?;
+
+        fs::rename(&tmp, &self.path)
+    }
+
+    /// Path of the temporary file used when writing the cache.
+    fn tmp_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".tmp");
+        path.into()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::service::message::Address;
+    use crate::test::arbitrary;
+    use crate::LocalTime;
+
+    #[test]
+    fn test_save_and_load() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("cache");
+        let proj = arbitrary::gen::<Id>(1);
+        let node = arbitrary::gen::<NodeId>(1);
+        let ip = net::IpAddr::from([8, 8, 8, 8]);
+        let misbehaviour = Misbehaviour {
+            penalty: 25,
+            until: Some(LocalTime::from_secs(1664)),
+            node: Some(node),
+        };
+        let addresses = vec![
+            Address::from_str("127.0.0.1:8776").unwrap(),
+            Address::from_str("[::1]:8776").unwrap(),
+            Address::from_str("seed.radicle.xyz:8776").unwrap(),
+            Address::onion(node, 8776),
+        ];
+
+        {
+            let mut cache = Cache::create(&path).unwrap();
+            let mut snapshot = cache.read().unwrap();
+
+            assert!(snapshot.routing.is_empty());
+            assert!(snapshot.peers.is_empty());
+
+            snapshot.routing.entry(proj).or_default().insert(node, 1664);
+            snapshot.peers.insert(
+                node,
+                Peer {
+                    last_message: 1664,
+                    alias: String::from("alice"),
+                    addresses: addresses.clone(),
+                    ..Peer::default()
+                },
+            );
+            snapshot.misbehaviour.insert(ip, misbehaviour.clone());
+            cache.write(&snapshot).unwrap();
+        }
+
+        {
+            let mut cache = Cache::open(&path).unwrap();
+            let snapshot = cache.read().unwrap();
+
+            assert_eq!(snapshot.routing[&proj][&node], 1664);
+            assert_eq!(snapshot.peers[&node].last_message, 1664);
+            assert_eq!(snapshot.peers[&node].alias, "alice");
+            assert_eq!(snapshot.peers[&node].addresses, addresses);
+            assert_eq!(snapshot.misbehaviour[&ip], misbehaviour);
+        }
+    }
+
+    #[test]
+    fn test_write_replaces() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("cache");
+        let mut cache = Cache::create(&path).unwrap();
+        let mut snapshot = Snapshot::default();
+
+        for alias in ["alice", "bob"] {
+            snapshot.peers.insert(
+                arbitrary::gen::<NodeId>(1),
+                Peer {
+                    alias: String::from(alias),
+                    ..Peer::default()
+                },
+            );
+            cache.write(&snapshot).unwrap();
+
+            assert_eq!(cache.read().unwrap().peers.len(), snapshot.peers.len());
+            assert!(
+                !cache.tmp_path().exists(),
+                "The temporary file is moved into place"
+            );
+        }
+    }
+}
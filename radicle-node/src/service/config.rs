@@ -1,9 +1,13 @@
+use std::net;
+
 use crate::collections::HashSet;
 use crate::git;
 use crate::git::Url;
 use crate::identity::{Id, PublicKey};
+use crate::service::fetch::Namespaces;
 use crate::service::filter::Filter;
 use crate::service::message::{Address, Envelope, Message};
+use crate::LocalDuration;
 
 /// Peer-to-peer network.
 #[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
@@ -58,6 +62,60 @@ pub enum RemoteTracking {
     Allowed(HashSet<PublicKey>),
 }
 
+/// Configuration parameters defining attributes of minima and maxima.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// Number of routing table entries before we start pruning.
+    pub routing_max_size: usize,
+    /// How long to keep a routing table entry before it is pruned.
+    pub routing_max_age: LocalDuration,
+    /// Maximum number of fetches running at the same time.
+    pub fetch_concurrency: usize,
+    /// How long a fetch may run before it is considered failed.
+    pub fetch_timeout: LocalDuration,
+    /// Maximum number of seeds to fetch from, when fetching a project on request.
+    pub fetch_max_seeds: usize,
+    /// Maximum number of announcements kept around to replay to subscribers.
+    pub gossip_max_size: usize,
+    /// How long misbehaving peers are banned for.
+    pub ban_duration: LocalDuration,
+    /// Maximum number of inbound connections from a single IP address.
+    pub max_connections_per_ip: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            routing_max_size: 1000,
+            routing_max_age: LocalDuration::from_mins(7 * 24 * 60),
+            fetch_concurrency: 4,
+            fetch_timeout: LocalDuration::from_mins(3),
+            fetch_max_seeds: 3,
+            gossip_max_size: 10000,
+            ban_duration: LocalDuration::from_mins(24 * 60),
+            max_connections_per_ip: 16,
+        }
+    }
+}
+
+/// SOCKS5 proxy configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Proxy {
+    /// Address of the proxy, eg. a local Tor daemon's `SocksPort`.
+    pub addr: net::SocketAddr,
+    /// Whether to connect to all peers through the proxy. If `false`, the proxy is only
+    /// used to reach onion addresses.
+    pub all: bool,
+}
+
+impl Proxy {
+    /// Whether connections to the given address should go through the proxy. Addresses
+    /// of unknown types can't be connected to at all.
+    pub fn is_used_for(&self, addr: &Address) -> bool {
+        addr.is_known() && (self.all || matches!(addr, Address::Onion { .. }))
+    }
+}
+
 /// Service configuration.
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -76,6 +134,12 @@ pub struct Config {
     pub listen: Vec<Address>,
     /// Our Git URL for fetching projects.
     pub git_url: Url,
+    /// Our node alias, announced to the network. Truncated to 32 bytes.
+    pub alias: String,
+    /// Configured service limits.
+    pub limits: Limits,
+    /// SOCKS5 proxy to connect to peers through.
+    pub proxy: Option<Proxy>,
 }
 
 impl Default for Config {
@@ -92,6 +156,9 @@ impl Default for Config {
                 path: "/dev/null".to_owned().into(),
                 ..Url::default()
             },
+            alias: String::from("anonymous"),
+            limits: Limits::default(),
+            proxy: None,
         }
     }
 }
@@ -124,6 +191,35 @@ impl Config {
         }
     }
 
+    /// Check whether we're tracking the given remote of a project, given the project
+    /// delegates.
+    pub fn is_tracking_remote(&self, remote: &PublicKey, delegates: &[PublicKey]) -> bool {
+        if delegates.contains(remote) {
+            return true;
+        }
+        match &self.remote_tracking {
+            RemoteTracking::DelegatesOnly => false,
+            RemoteTracking::All { blocked } => !blocked.contains(remote),
+            RemoteTracking::Allowed(keys) => keys.contains(remote),
+        }
+    }
+
+    /// The remotes of a project to fetch, given the project delegates.
+    pub fn tracked_remotes(&self, delegates: &[PublicKey]) -> Namespaces {
+        match &self.remote_tracking {
+            RemoteTracking::DelegatesOnly => Namespaces::Only(delegates.to_vec()),
+            // Blocked remotes are pruned after the fetch.
+            RemoteTracking::All { .. } => Namespaces::All,
+            RemoteTracking::Allowed(keys) => Namespaces::Only(
+                delegates
+                    .iter()
+                    .chain(keys.iter().filter(|k| !delegates.contains(*k)))
+                    .copied()
+                    .collect(),
+            ),
+        }
+    }
+
     pub fn filter(&self) -> Filter {
         match &self.project_tracking {
             ProjectTracking::All { .. } => Filter::default(),
@@ -131,10 +227,16 @@ impl Config {
         }
     }
 
+    /// Our alias, as a fixed-size, zero-padded byte array.
     pub fn alias(&self) -> [u8; 32] {
         let mut alias = [0u8; 32];
+        let mut len = self.alias.len().min(alias.len());
 
-        alias[..9].copy_from_slice("anonymous".as_bytes());
+        // Make sure we don't truncate in the middle of a character.
+        while !self.alias.is_char_boundary(len) {
+            len -= 1;
+        }
+        alias[..len].copy_from_slice(&self.alias.as_bytes()[..len]);
         alias
     }
 }
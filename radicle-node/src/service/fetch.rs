@@ -0,0 +1,471 @@
+use std::collections::VecDeque;
+use std::net;
+
+use crossbeam_channel as chan;
+use nakamoto_net::{LocalDuration, LocalTime};
+
+use crate::collections::HashMap;
+use crate::identity::Id;
+use crate::service::{FetchResult, Message, NodeId};
+use crate::storage::refs::Refs;
+use crate::storage::RemoteId;
+
+/// How long to wait before fetching again from a seed that failed us once.
+/// The delay doubles with every consecutive failure.
+pub const BACKOFF_BASE: LocalDuration = LocalDuration::from_secs(30);
+/// Maximum delay before fetching again from a failing seed.
+pub const BACKOFF_MAX: LocalDuration = LocalDuration::from_mins(60);
+/// Maximum number of fetches waiting to be started. Once reached, the oldest fetch is
+/// dropped to make room.
+pub const MAX_QUEUED_FETCHES: usize = 1024;
+/// Maximum number of announcements being fetched that are remembered. Once reached, the
+/// oldest one is forgotten to make room.
+pub const MAX_ANNOUNCED: usize = 4096;
+
+/// The remotes of a repository to fetch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Namespaces {
+    /// All remotes the seed has.
+    All,
+    /// Only the given remotes.
+    Only(Vec<RemoteId>),
+}
+
+/// A fetch request, either queued or in progress.
+#[derive(Debug)]
+pub struct Fetch {
+    /// The repository to fetch.
+    pub id: Id,
+    /// The peer to fetch from.
+    pub remote: net::SocketAddr,
+    /// The remotes to fetch.
+    pub namespaces: Namespaces,
+    /// Where to send the result, if anyone is waiting on it.
+    pub results: Option<chan::Sender<FetchResult>>,
+    /// Announcement that triggered the fetch. Relayed if the fetch updates any refs.
+    pub announcement: Option<Message>,
+}
+
+impl Fetch {
+    pub fn new(id: Id, remote: net::SocketAddr) -> Self {
+        Self {
+            id,
+            remote,
+            namespaces: Namespaces::All,
+            results: None,
+            announcement: None,
+        }
+    }
+
+    /// The announcing node and announced refs, if the fetch was triggered by an announcement.
+    fn announced(&self) -> Option<(NodeId, &Refs)> {
+        match &self.announcement {
+            Some(Message::RefsAnnouncement { node, message, .. }) => Some((*node, &message.refs)),
+            _ => None,
+        }
+    }
+}
+
+/// Consecutive failures of a seed.
+#[derive(Debug)]
+struct Backoff {
+    /// Number of consecutive failed fetches.
+    failures: u32,
+    /// Until when we shouldn't fetch from the seed.
+    until: LocalTime,
+}
+
+/// Schedules fetches. Fetches are carried out by the reactor, off the service thread;
+/// this decides which fetches are worth doing, when they should start, and when they
+/// should be given up on.
+///
+/// Fetches triggered by gossip are deduplicated: pending fetches of the same project are
+/// coalesced, announcements of refs we're already fetching are ignored, and seeds that
+/// recently failed are avoided.
+#[derive(Debug)]
+pub struct Fetcher {
+    /// Fetches waiting to be started, in order of arrival.
+    queue: VecDeque<Fetch>,
+    /// Ongoing fetches, and when they were started. At most one per repository, since
+    /// fetches into the same repository can't run concurrently.
+    active: HashMap<Id, (Fetch, LocalTime)>,
+    /// Fetches that timed out, by repository, and the peer they were from. Their workers
+    /// are cancelled, but until the reactor reports them as completed, they still count
+    /// towards the concurrency limit, and other fetches of the same repository wait.
+    cancelled: HashMap<Id, net::SocketAddr>,
+    /// Maximum number of ongoing fetches.
+    concurrency: usize,
+    /// Seeds that failed to serve a fetch recently.
+    backoff: HashMap<net::SocketAddr, Backoff>,
+    /// Refs of the announcements being fetched, by project and announcing node, and when
+    /// they were announced. Entries are removed once their fetch completes or times out,
+    /// since we then compare announcements with the refs in storage.
+    announced: HashMap<(Id, NodeId), (Refs, LocalTime)>,
+}
+
+impl Fetcher {
+    /// Create a new fetcher, running at most `concurrency` fetches at a time.
+    pub fn new(concurrency: usize, rng: fastrand::Rng) -> Self {
+        Self {
+            queue: VecDeque::new(),
+            active: HashMap::with_hasher(rng.clone().into()),
+            cancelled: HashMap::with_hasher(rng.clone().into()),
+            concurrency,
+            backoff: HashMap::with_hasher(rng.clone().into()),
+            announced: HashMap::with_hasher(rng.into()),
+        }
+    }
+
+    /// Queue a fetch. Returns `false` if the fetch was found to be redundant, and was
+    /// dropped or merged with a pending fetch.
+    ///
+    /// Fetches that someone is waiting on are always queued.
+    pub fn queue(&mut self, mut fetch: Fetch, now: LocalTime) -> bool {
+        if fetch.results.is_some() {
+            self.push(fetch);
+            return true;
+        }
+        if self.is_backing_off(&fetch.remote, now) {
+            return false;
+        }
+        if let Some((node, refs)) = fetch.announced() {
+            if self.is_announced(fetch.id, node, refs) {
+                return false;
+            }
+            if self.announced.len() >= MAX_ANNOUNCED {
+                let oldest = self
+                    .announced
+                    .iter()
+                    .min_by_key(|(_, (_, t))| *t)
+                    .map(|(key, _)| *key);
+
+                if let Some(oldest) = oldest {
+                    self.announced.remove(&oldest);
+                }
+            }
+            self.announced.insert((fetch.id, node), (refs.clone(), now));
+        }
+        if let Some(pending) = self
+            .queue
+            .iter_mut()
+            .find(|f| f.id == fetch.id && f.namespaces == fetch.namespaces && f.results.is_none())
+        {
+            // The latest announcement is the most up to date one. If neither fetch was
+            // triggered by an announcement, the pending one is good enough.
+            if fetch.announcement.is_some() {
+                pending.remote = fetch.remote;
+
+                let replaced = std::mem::replace(&mut pending.announcement, fetch.announcement);
+                if let Some(Message::RefsAnnouncement { node, message, .. }) = replaced {
+                    self.forget(fetch.id, node, &message.refs);
+                }
+            }
+            return false;
+        }
+        self.push(fetch);
+
+        true
+    }
+
+    /// Add a fetch to the back of the queue. If the queue is full, the oldest fetch that
+    /// no one is waiting on is dropped, or the oldest fetch if everyone is waiting.
+    fn push(&mut self, fetch: Fetch) {
+        if self.queue.len() >= MAX_QUEUED_FETCHES {
+            let ix = self
+                .queue
+                .iter()
+                .position(|f| f.results.is_none())
+                .unwrap_or(0);
+
+            if let Some(dropped) = self.queue.remove(ix) {
+                if let Some((node, refs)) = dropped.announced() {
+                    self.forget(dropped.id, node, refs);
+                }
+            }
+        }
+        self.queue.push_back(fetch);
+    }
+
+    /// Whether the given refs announced by a node are being fetched.
+    fn is_announced(&self, id: Id, node: NodeId, refs: &Refs) -> bool {
+        self.announced.get(&(id, node)).map(|(r, _)| r) == Some(refs)
+    }
+
+    /// Forget the given refs announced by a node. Newer announcements of the same node
+    /// are left alone.
+    fn forget(&mut self, id: Id, node: NodeId, refs: &Refs) {
+        if self.is_announced(id, node, refs) {
+            self.announced.remove(&(id, node));
+        }
+    }
+
+    /// Start the next queued fetch, if we're below the concurrency limit.
+    /// Returns the repository, the peer to fetch from, and the remotes to fetch.
+    pub fn dequeue(&mut self, now: LocalTime) -> Option<(Id, net::SocketAddr, Namespaces)> {
+        if self.active.len() + self.cancelled.len() >= self.concurrency {
+            return None;
+        }
+        let ix = self.queue.iter().position(|f| {
+            !self.active.contains_key(&f.id) && !self.cancelled.contains_key(&f.id)
+        })?;
+        let fetch = self.queue.remove(ix)?;
+        let next = (fetch.id, fetch.remote, fetch.namespaces.clone());
+
+        self.active.insert(fetch.id, (fetch, now));
+
+        Some(next)
+    }
+
+    /// Mark a fetch as completed. Returns `None` if there was no such fetch in progress,
+    /// eg. because it timed out.
+    pub fn completed(&mut self, id: &Id, remote: &net::SocketAddr) -> Option<Fetch> {
+        match self.active.get(id) {
+            Some((fetch, _)) if fetch.remote == *remote => self.active.remove(id).map(|(f, _)| f),
+            _ => {
+                if self.cancelled.get(id) == Some(remote) {
+                    self.cancelled.remove(id);
+                }
+                None
+            }
+        }
+    }
+
+    /// Record the outcome of a completed or timed out fetch. Seeds that fail are backed off
+    /// from, and announcements whose refs we failed to fetch may be fetched again.
+    pub fn record(&mut self, fetch: &Fetch, success: bool, now: LocalTime) {
+        if let Some((node, refs)) = fetch.announced() {
+            self.forget(fetch.id, node, refs);
+        }
+        if success {
+            self.backoff.remove(&fetch.remote);
+            return;
+        }
+        let backoff = self.backoff.entry(fetch.remote).or_insert(Backoff {
+            failures: 0,
+            until: now,
+        });
+        let delay = BACKOFF_BASE
+            .as_millis()
+            .saturating_mul(1 << backoff.failures.min(16))
+            .min(BACKOFF_MAX.as_millis());
+
+        backoff.failures = backoff.failures.saturating_add(1);
+        backoff.until = now + LocalDuration::from_millis(delay);
+    }
+
+    /// Check whether we should avoid fetching from the given seed, because it failed us
+    /// recently.
+    pub fn is_backing_off(&self, remote: &net::SocketAddr, now: LocalTime) -> bool {
+        self.backoff.get(remote).map_or(false, |b| now < b.until)
+    }
+
+    /// Remove and return the fetches that have been running for longer than `timeout`.
+    /// They're expected to be cancelled, and keep their slot until they complete.
+    pub fn timed_out(&mut self, now: LocalTime, timeout: LocalDuration) -> Vec<Fetch> {
+        let expired = self
+            .active
+            .iter()
+            .filter(|(_, (_, since))| now - *since >= timeout)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+
+        expired
+            .iter()
+            .filter_map(|id| self.active.remove(id))
+            .map(|(fetch, _)| {
+                self.cancelled.insert(fetch.id, fetch.remote);
+                fetch
+            })
+            .collect()
+    }
+
+    /// Check whether a repository is being fetched.
+    pub fn is_fetching(&self, id: &Id) -> bool {
+        self.active.contains_key(id)
+    }
+
+    /// Number of ongoing fetches.
+    pub fn active(&self) -> usize {
+        self.active.len()
+    }
+
+    /// Number of queued fetches.
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::Signer;
+    use crate::service::message::{Extensions, RefsAnnouncement};
+    use crate::test::arbitrary;
+    use crate::test::signer::MockSigner;
+
+    #[test]
+    fn test_fetcher_concurrency() {
+        let now = LocalTime::now();
+        let remote = net::SocketAddr::from(([8, 8, 8, 8], 8776));
+        let other = net::SocketAddr::from(([9, 9, 9, 9], 8776));
+        let ids = arbitrary::set::<Id>(3..=3).into_iter().collect::<Vec<_>>();
+        let mut fetcher = Fetcher::new(2, fastrand::Rng::new());
+
+        let (results, _receiver) = chan::unbounded();
+
+        fetcher.queue(Fetch::new(ids[0], remote), now);
+        fetcher.queue(
+            Fetch {
+                results: Some(results),
+                ..Fetch::new(ids[0], other)
+            },
+            now,
+        );
+        fetcher.queue(Fetch::new(ids[1], remote), now);
+        fetcher.queue(Fetch::new(ids[2], remote), now);
+
+        // The second fetch of the same repository has to wait for the first one.
+        assert_eq!(
+            fetcher.dequeue(now),
+            Some((ids[0], remote, Namespaces::All))
+        );
+        assert_eq!(
+            fetcher.dequeue(now),
+            Some((ids[1], remote, Namespaces::All))
+        );
+        assert_eq!(fetcher.dequeue(now), None, "Concurrency limit reached");
+        assert_eq!(fetcher.active(), 2);
+        assert_eq!(fetcher.queued(), 2);
+
+        assert!(fetcher.completed(&ids[0], &other).is_none());
+        assert!(fetcher.completed(&ids[0], &remote).is_some());
+        assert_eq!(fetcher.dequeue(now), Some((ids[0], other, Namespaces::All)));
+
+        let timed_out = fetcher.timed_out(
+            now + LocalDuration::from_secs(60),
+            LocalDuration::from_secs(60),
+        );
+        assert_eq!(timed_out.len(), 2);
+        assert_eq!(
+            fetcher.dequeue(now),
+            None,
+            "Timed out fetches count until they complete"
+        );
+
+        assert!(fetcher.completed(&ids[1], &remote).is_none());
+        assert_eq!(
+            fetcher.dequeue(now),
+            Some((ids[2], remote, Namespaces::All))
+        );
+        assert_eq!(fetcher.dequeue(now), None);
+
+        assert!(fetcher.completed(&ids[0], &other).is_none());
+        assert_eq!(fetcher.active(), 1);
+        assert_eq!(fetcher.queued(), 0);
+    }
+
+    #[test]
+    fn test_fetcher_dedup() {
+        let now = LocalTime::now();
+        let remote = net::SocketAddr::from(([8, 8, 8, 8], 8776));
+        let other = net::SocketAddr::from(([9, 9, 9, 9], 8776));
+        let id = arbitrary::gen::<Id>(1);
+        let signer = MockSigner::default();
+        let refs = arbitrary::gen::<Refs>(3);
+        let node = *signer.public_key();
+        let announced = |refs: Refs, remote: net::SocketAddr| {
+            let message = RefsAnnouncement {
+                id,
+                refs,
+                timestamp: now.as_secs(),
+                extensions: Extensions::default(),
+            };
+            let signature = message.sign(&signer).unwrap();
+
+            Fetch {
+                namespaces: Namespaces::Only(vec![node]),
+                announcement: Some(Message::RefsAnnouncement {
+                    node,
+                    message,
+                    signature,
+                }),
+                ..Fetch::new(id, remote)
+            }
+        };
+        let mut fetcher = Fetcher::new(1, fastrand::Rng::new());
+
+        assert!(fetcher.queue(announced(arbitrary::gen::<Refs>(3), remote), now));
+        assert!(
+            !fetcher.queue(announced(refs.clone(), other), now),
+            "Pending fetches of the same remotes are coalesced"
+        );
+        assert_eq!(fetcher.queued(), 1);
+        assert_eq!(
+            fetcher.dequeue(now),
+            Some((id, other, Namespaces::Only(vec![node]))),
+            "The latest announcement wins"
+        );
+
+        assert!(
+            !fetcher.queue(announced(refs, remote), now),
+            "Refs that are being fetched aren't fetched again"
+        );
+        assert_eq!(fetcher.queued(), 0);
+
+        let fetch = fetcher.completed(&id, &other).unwrap();
+        fetcher.record(&fetch, true, now);
+        assert!(
+            fetcher.announced.is_empty(),
+            "Announcements are forgotten once fetched"
+        );
+
+        // Failing seeds are avoided for a while.
+        assert!(fetcher.queue(Fetch::new(id, remote), now));
+        assert_eq!(fetcher.dequeue(now), Some((id, remote, Namespaces::All)));
+        let fetch = fetcher.completed(&id, &remote).unwrap();
+        fetcher.record(&fetch, false, now);
+
+        assert!(fetcher.is_backing_off(&remote, now));
+        assert!(!fetcher.queue(Fetch::new(id, remote), now));
+        assert!(
+            !fetcher.is_backing_off(&net::SocketAddr::new(remote.ip(), 8777), now),
+            "Other peers behind the same IP aren't backed off from"
+        );
+        assert!(!fetcher.is_backing_off(&remote, now + BACKOFF_BASE));
+        assert!(fetcher.queue(Fetch::new(id, remote), now + BACKOFF_BASE));
+    }
+
+    #[test]
+    fn test_fetcher_limits() {
+        let now = LocalTime::now();
+        let remote = net::SocketAddr::from(([8, 8, 8, 8], 8776));
+        let ids = arbitrary::set::<Id>(MAX_QUEUED_FETCHES + 1..=MAX_QUEUED_FETCHES + 1)
+            .into_iter()
+            .collect::<Vec<_>>();
+        let mut fetcher = Fetcher::new(1, fastrand::Rng::new());
+        let (results, _receiver) = chan::unbounded();
+
+        fetcher.queue(
+            Fetch {
+                results: Some(results),
+                ..Fetch::new(ids[0], remote)
+            },
+            now,
+        );
+        for id in &ids[1..] {
+            assert!(fetcher.queue(Fetch::new(*id, remote), now));
+        }
+        assert_eq!(fetcher.queued(), MAX_QUEUED_FETCHES);
+        assert_eq!(
+            fetcher.dequeue(now),
+            Some((ids[0], remote, Namespaces::All)),
+            "Fetches someone is waiting on are dropped last"
+        );
+        assert!(fetcher.completed(&ids[0], &remote).is_some());
+        assert_eq!(
+            fetcher.dequeue(now),
+            Some((ids[2], remote, Namespaces::All)),
+            "The oldest fetch was dropped"
+        );
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+use crate::clock::Timestamp;
+use crate::collections::HashMap;
+use crate::identity::Id;
+use crate::service::filter::Filter;
+use crate::service::{Message, NodeId};
+
+/// Kind of announcement. We only keep the latest announcement of each kind, per node.
+/// Refs are announced per project, so we keep the latest one for every project.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AnnouncementKind {
+    Node,
+    Inventory,
+    Refs(Id),
+}
+
+/// Stores the latest signed announcements we've seen, so that they can be replayed to
+/// peers that subscribe to messages from the past, eg. after being offline for a while.
+///
+/// The store is bounded: once full, the oldest announcements are evicted first.
+#[derive(Debug)]
+pub struct GossipStore {
+    /// Announcements, by node and kind, with their position in the index.
+    announcements: HashMap<(NodeId, AnnouncementKind), (Position, Message)>,
+    /// Announcement keys, ordered by timestamp.
+    index: BTreeMap<Position, (NodeId, AnnouncementKind)>,
+    /// Sequence number of the next announcement stored, to order announcements with
+    /// the same timestamp.
+    seq: u64,
+    /// Maximum number of announcements stored.
+    capacity: usize,
+}
+
+/// Position of an announcement in the index: its timestamp, and when it was stored.
+type Position = (Timestamp, u64);
+
+impl GossipStore {
+    /// Create a new store, holding at most `capacity` announcements.
+    pub fn new(capacity: usize, rng: fastrand::Rng) -> Self {
+        Self {
+            announcements: HashMap::with_hasher(rng.into()),
+            index: BTreeMap::new(),
+            seq: 0,
+            capacity,
+        }
+    }
+
+    /// Store an announcement. Returns `false` if the message isn't an announcement, or if
+    /// we already have a newer or equal announcement of the same kind from the same node.
+    pub fn insert(&mut self, msg: Message) -> bool {
+        let (node, kind, timestamp) = match &msg {
+            Message::NodeAnnouncement { node, message, .. } => {
+                (*node, AnnouncementKind::Node, message.timestamp)
+            }
+            Message::InventoryAnnouncement { node, message, .. } => {
+                (*node, AnnouncementKind::Inventory, message.timestamp)
+            }
+            Message::RefsAnnouncement { node, message, .. } => {
+                (*node, AnnouncementKind::Refs(message.id), message.timestamp)
+            }
+            _ => return false,
+        };
+        let key = (node, kind);
+
+        match self.announcements.get(&key) {
+            Some(((t, _), _)) if *t >= timestamp => return false,
+            Some((position, _)) => {
+                self.index.remove(position);
+            }
+            None => {
+                if self.capacity == 0 {
+                    return false;
+                }
+                if self.announcements.len() >= self.capacity {
+                    self.evict();
+                }
+            }
+        }
+        let position = (timestamp, self.seq);
+
+        self.seq += 1;
+        self.index.insert(position, key);
+        self.announcements.insert(key, (position, msg));
+
+        true
+    }
+
+    /// Announcements with a timestamp in the given range, that match the filter, ordered by
+    /// timestamp. Refs announcements match if the filter contains their project; other
+    /// announcements always match.
+    pub fn filtered<'a>(
+        &'a self,
+        filter: &'a Filter,
+        since: Timestamp,
+        until: Timestamp,
+    ) -> impl DoubleEndedIterator<Item = &'a Message> + 'a {
+        let start = (since, u64::MIN);
+        let end = if since <= until {
+            Bound::Included((until, u64::MAX))
+        } else {
+            // An empty range.
+            Bound::Excluded(start)
+        };
+        self.index
+            .range((Bound::Included(start), end))
+            .filter(move |(_, (_, kind))| match kind {
+                AnnouncementKind::Refs(id) => filter.contains(id),
+                AnnouncementKind::Node | AnnouncementKind::Inventory => true,
+            })
+            .filter_map(|(_, key)| self.announcements.get(key))
+            .map(|(_, msg)| msg)
+    }
+
+    /// Number of announcements stored.
+    pub fn len(&self) -> usize {
+        self.announcements.len()
+    }
+
+    /// Whether the store is empty.
+    pub fn is_empty(&self) -> bool {
+        self.announcements.is_empty()
+    }
+
+    /// Evict the oldest announcement.
+    fn evict(&mut self) {
+        if let Some(position) = self.index.keys().next().copied() {
+            if let Some(key) = self.index.remove(&position) {
+                self.announcements.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::Signer;
+    use crate::service::message::{Extensions, InventoryAnnouncement, RefsAnnouncement};
+    use crate::test::arbitrary;
+    use crate::test::signer::MockSigner;
+
+    #[test]
+    fn test_gossip_store() {
+        let alice = MockSigner::new(&mut fastrand::Rng::new());
+        let bob = MockSigner::new(&mut fastrand::Rng::new());
+        let ids = arbitrary::set::<Id>(2..=2).into_iter().collect::<Vec<_>>();
+        let inventory = |timestamp, signer: &MockSigner| {
+            Message::inventory(
+                InventoryAnnouncement {
+                    inventory: vec![],
+                    timestamp,
+                    extensions: Extensions::default(),
+                },
+                signer,
+            )
+            .unwrap()
+        };
+        let refs = |id, timestamp, signer: &MockSigner| {
+            let message = RefsAnnouncement {
+                id,
+                refs: arbitrary::gen(1),
+                timestamp,
+                extensions: Extensions::default(),
+            };
+            let signature = message.sign(signer).unwrap();
+
+            Message::RefsAnnouncement {
+                node: *signer.public_key(),
+                message,
+                signature,
+            }
+        };
+        let mut store = GossipStore::new(4, fastrand::Rng::new());
+
+        assert!(store.insert(inventory(2, &alice)));
+        assert!(
+            !store.insert(inventory(2, &alice)),
+            "Duplicates are ignored"
+        );
+        assert!(
+            !store.insert(inventory(1, &alice)),
+            "Older messages are ignored"
+        );
+        assert!(
+            store.insert(inventory(3, &alice)),
+            "Newer messages replace older ones"
+        );
+        assert!(store.insert(inventory(1, &bob)));
+        assert!(store.insert(refs(ids[0], 4, &alice)));
+        assert!(store.insert(refs(ids[1], 5, &alice)));
+        assert_eq!(store.len(), 4);
+
+        // Only refs of projects in the filter, within the time range, are returned.
+        let filter = Filter::new([&ids[0]]);
+        let timestamps = store
+            .filtered(&filter, 2, 5)
+            .map(|msg| match msg {
+                Message::InventoryAnnouncement { message, .. } => message.timestamp,
+                Message::RefsAnnouncement { message, .. } => message.timestamp,
+                _ => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(timestamps, vec![3, 4]);
+        assert_eq!(store.filtered(&filter, 5, 2).count(), 0);
+
+        // When full, the oldest message is evicted.
+        assert!(store.insert(inventory(6, &MockSigner::default())));
+        assert_eq!(store.len(), 4);
+        assert_eq!(store.filtered(&Filter::default(), 0, 1).count(), 0);
+    }
+}
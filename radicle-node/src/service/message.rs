@@ -1,15 +1,19 @@
+use std::collections::BTreeMap;
 use std::str::FromStr;
 use std::{fmt, io, net};
 
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
 use thiserror::Error;
 
 use crate::crypto;
 use crate::git;
 use crate::identity::Id;
 use crate::service::filter::Filter;
-use crate::service::{NodeId, Timestamp, PROTOCOL_VERSION};
+use crate::service::{NodeId, Timestamp, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
 use crate::storage::refs::Refs;
 use crate::wire;
+use crate::wire::{Decode, Encode};
 
 /// Message envelope. All messages sent over the network are wrapped in this type.
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -23,18 +27,162 @@ pub struct Envelope {
 /// Advertized node feature. Signals what services the node supports.
 pub type NodeFeatures = [u8; 32];
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-// TODO: We should check the length and charset when deserializing.
+/// Tor onion service address version we support.
+pub const ONION_VERSION: u8 = 3;
+
+/// Maximum length of a hostname, in bytes.
+pub const MAX_HOSTNAME_LENGTH: usize = 253;
+/// Maximum length of a hostname label, in bytes.
+pub const MAX_HOSTNAME_LABEL_LENGTH: usize = 63;
+
+/// Maximum number of addresses a node can advertise.
+pub const MAX_ADDRESSES: usize = 16;
+/// Maximum number of projects in an inventory announcement.
+pub const MAX_INVENTORY_SIZE: usize = 4096;
+/// Maximum number of refs in a refs announcement.
+pub const MAX_REFS_SIZE: usize = 1024;
+/// Maximum size of the extension fields of an announcement, in bytes.
+pub const MAX_EXTENSIONS_SIZE: usize = 1024;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum HostnameError {
+    #[error("hostname is empty")]
+    Empty,
+    #[error("hostname is longer than {MAX_HOSTNAME_LENGTH} bytes")]
+    TooLong,
+    #[error("invalid hostname label `{0}`")]
+    InvalidLabel(String),
+}
+
+/// A DNS hostname, eg. `seed.radicle.xyz`.
+///
+/// Hostnames are made of dot-separated labels of ASCII letters, digits and hyphens.
+/// Labels can't start or end with a hyphen.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Hostname(String);
 
+impl Hostname {
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl TryFrom<String> for Hostname {
+    type Error = HostnameError;
+
+    fn try_from(host: String) -> Result<Self, Self::Error> {
+        if host.is_empty() {
+            return Err(HostnameError::Empty);
+        }
+        if host.len() > MAX_HOSTNAME_LENGTH {
+            return Err(HostnameError::TooLong);
+        }
+        // A single trailing dot denotes a fully qualified name.
+        let labels = host.strip_suffix('.').unwrap_or(&host);
+
+        for label in labels.split('.') {
+            let valid = !label.is_empty()
+                && label.len() <= MAX_HOSTNAME_LABEL_LENGTH
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+
+            if !valid {
+                return Err(HostnameError::InvalidLabel(label.to_owned()));
+            }
+        }
+        Ok(Self(host))
+    }
+}
+
+impl FromStr for Hostname {
+    type Err = HostnameError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::try_from(s.to_owned())
+    }
+}
+
 impl fmt::Display for Hostname {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.0)
     }
 }
 
+/// Compute the checksum of a Tor onion service address, as specified in `rend-spec-v3`:
+/// the first two bytes of `SHA3-256(".onion checksum" | key | version)`.
+pub fn onion_checksum(key: &crypto::PublicKey, version: u8) -> u16 {
+    let mut hasher = Sha3_256::new();
+
+    hasher.update(b".onion checksum");
+    hasher.update(key.as_ref());
+    hasher.update([version]);
+
+    let digest = hasher.finalize();
+
+    u16::from_be_bytes([digest[0], digest[1]])
+}
+
+/// Alphabet used to encode onion addresses, as per RFC 4648, in lower-case.
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Encode bytes as unpadded, lower-case base32.
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity((bytes.len() * 8 + 4) / 5);
+    let mut buffer: u16 = 0;
+    let mut bits = 0;
+
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u16;
+        bits += 8;
+
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    encoded
+}
+
+/// Decode unpadded base32, in either case. Returns `None` if the input isn't valid base32.
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(s.len() * 5 / 8);
+    let mut buffer: u16 = 0;
+    let mut bits = 0;
+
+    for c in s.bytes() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|a| *a == c.to_ascii_lowercase())?;
+
+        buffer = (buffer << 5) | value as u16;
+        bits += 5;
+
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+/// Domain name of a Tor onion service, eg. `<base32(key | checksum | version)>.onion`.
+pub fn onion_domain(key: &crypto::PublicKey, checksum: u16, version: u8) -> String {
+    let mut bytes = Vec::with_capacity(35);
+
+    bytes.extend_from_slice(key.as_ref());
+    bytes.extend_from_slice(&checksum.to_be_bytes());
+    bytes.push(version);
+
+    format!("{}.onion", base32_encode(&bytes))
+}
+
 /// Peer public protocol address.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
 pub enum Address {
     Ipv4 {
         ip: net::Ipv4Addr,
@@ -55,6 +203,13 @@ pub enum Address {
         checksum: u16,
         version: u8,
     },
+    /// Address of a type we don't know, eg. introduced in a later protocol version.
+    /// It can't be connected to, but is kept as-is, so that announcements carrying it
+    /// can still be verified and relayed.
+    Unknown {
+        kind: u8,
+        data: Vec<u8>,
+    },
 }
 
 impl From<net::SocketAddr> for Address {
@@ -68,10 +223,42 @@ impl From<net::SocketAddr> for Address {
     }
 }
 
+impl Address {
+    /// Create a Tor V3 onion address from the service's public key.
+    pub fn onion(key: crypto::PublicKey, port: u16) -> Self {
+        Self::Onion {
+            checksum: onion_checksum(&key, ONION_VERSION),
+            key,
+            port,
+            version: ONION_VERSION,
+        }
+    }
+
+    /// The socket address, if this is an IP address.
+    pub fn socket_addr(&self) -> Option<net::SocketAddr> {
+        match self {
+            Self::Ipv4 { ip, port } => Some(net::SocketAddr::from((*ip, *port))),
+            Self::Ipv6 { ip, port } => Some(net::SocketAddr::from((*ip, *port))),
+            Self::Hostname { .. } | Self::Onion { .. } | Self::Unknown { .. } => None,
+        }
+    }
+
+    /// Whether this is an address of a type we know.
+    pub fn is_known(&self) -> bool {
+        !matches!(self, Self::Unknown { .. })
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum AddressParseError {
     #[error("unsupported address type `{0}`")]
     Unsupported(String),
+    #[error("invalid port in address `{0}`")]
+    InvalidPort(String),
+    #[error("invalid hostname: {0}")]
+    InvalidHostname(#[from] HostnameError),
+    #[error("invalid onion address `{0}`")]
+    InvalidOnion(String),
 }
 
 impl FromStr for Address {
@@ -89,12 +276,56 @@ impl FromStr for Address {
                     port: addr.port(),
                 }),
             }
+        } else if let Some((host, port)) = s.rsplit_once(':') {
+            let port = port
+                .parse()
+                .map_err(|_| Self::Err::InvalidPort(s.to_owned()))?;
+
+            if let Some(encoded) = host.strip_suffix(".onion") {
+                let invalid = || Self::Err::InvalidOnion(s.to_owned());
+                let bytes = base32_decode(encoded)
+                    .filter(|b| encoded.len() == 56 && b.len() == 35)
+                    .ok_or_else(invalid)?;
+                let key: [u8; 32] = bytes[..32].try_into().map_err(|_| invalid())?;
+                let key = crypto::PublicKey::try_from(key).map_err(|_| invalid())?;
+                let checksum = u16::from_be_bytes([bytes[32], bytes[33]]);
+                let version = bytes[34];
+
+                if version != ONION_VERSION || checksum != onion_checksum(&key, version) {
+                    return Err(invalid());
+                }
+                Ok(Self::Onion {
+                    key,
+                    port,
+                    checksum,
+                    version,
+                })
+            } else {
+                Ok(Self::Hostname {
+                    host: Hostname::from_str(host)?,
+                    port,
+                })
+            }
         } else {
             Err(Self::Err::Unsupported(s.to_owned()))
         }
     }
 }
 
+impl From<Address> for String {
+    fn from(other: Address) -> Self {
+        other.to_string()
+    }
+}
+
+impl TryFrom<String> for Address {
+    type Error = AddressParseError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::from_str(&value)
+    }
+}
+
 impl fmt::Display for Address {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -102,13 +333,21 @@ impl fmt::Display for Address {
                 write!(f, "{}:{}", ip, port)
             }
             Self::Ipv6 { ip, port } => {
-                write!(f, "{}:{}", ip, port)
+                write!(f, "[{}]:{}", ip, port)
             }
             Self::Hostname { host, port } => {
                 write!(f, "{}:{}", host, port)
             }
-            Self::Onion { key, port, .. } => {
-                write!(f, "{}:{}", key, port)
+            Self::Onion {
+                key,
+                port,
+                checksum,
+                version,
+            } => {
+                write!(f, "{}:{}", onion_domain(key, *checksum, *version), port)
+            }
+            Self::Unknown { kind, .. } => {
+                write!(f, "<unknown address type {}>", kind)
             }
         }
     }
@@ -124,6 +363,36 @@ pub struct Subscribe {
     pub until: Timestamp,
 }
 
+/// Optional fields appended to an announcement, keyed by type.
+///
+/// Extensions are encoded after the other fields of an announcement as type-length-value
+/// records, in ascending type order. They are covered by the announcement signature, and
+/// nodes relay extensions they don't understand as-is. This allows new fields to be
+/// introduced without breaking older nodes.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Extensions(BTreeMap<u16, Vec<u8>>);
+
+impl Extensions {
+    /// Get the value of an extension field.
+    pub fn get(&self, ty: u16) -> Option<&[u8]> {
+        self.0.get(&ty).map(|v| v.as_slice())
+    }
+
+    /// Set the value of an extension field. Returns the previous value, if any.
+    pub fn insert(&mut self, ty: u16, value: Vec<u8>) -> Option<Vec<u8>> {
+        self.0.insert(ty, value)
+    }
+
+    /// Iterate over extension fields, in ascending type order.
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &[u8])> {
+        self.0.iter().map(|(ty, v)| (*ty, v.as_slice()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct NodeAnnouncement {
     /// Advertized features.
@@ -134,24 +403,26 @@ pub struct NodeAnnouncement {
     pub alias: [u8; 32],
     /// Announced addresses.
     pub addresses: Vec<Address>,
+    /// Extension fields.
+    pub extensions: Extensions,
 }
 
 impl NodeAnnouncement {
     /// Verify a signature on this message.
     pub fn verify(&self, signer: &NodeId, signature: &crypto::Signature) -> bool {
-        let msg = wire::serialize(self);
-        signer.verify(&msg, signature).is_ok()
+        wire::serialize(self).map_or(false, |msg| signer.verify(&msg, signature).is_ok())
     }
 }
 
 impl wire::Encode for NodeAnnouncement {
-    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, wire::Error> {
         let mut n = 0;
 
         n += self.features.encode(writer)?;
         n += self.timestamp.encode(writer)?;
         n += self.alias.encode(writer)?;
         n += self.addresses.as_slice().encode(writer)?;
+        n += self.extensions.encode(writer)?;
 
         Ok(n)
     }
@@ -162,13 +433,15 @@ impl wire::Decode for NodeAnnouncement {
         let features = NodeFeatures::decode(reader)?;
         let timestamp = Timestamp::decode(reader)?;
         let alias = wire::Decode::decode(reader)?;
-        let addresses = Vec::<Address>::decode(reader)?;
+        let addresses = wire::decode_bounded(reader, MAX_ADDRESSES)?;
+        let extensions = Extensions::decode(reader)?;
 
         Ok(Self {
             features,
             timestamp,
             alias,
             addresses,
+            extensions,
         })
     }
 }
@@ -179,19 +452,22 @@ pub struct RefsAnnouncement {
     pub id: Id,
     /// Updated refs.
     pub refs: Refs,
+    /// Time of announcement.
+    pub timestamp: Timestamp,
+    /// Extension fields.
+    pub extensions: Extensions,
 }
 
 impl RefsAnnouncement {
     /// Verify a signature on this message.
     pub fn verify(&self, signer: &NodeId, signature: &crypto::Signature) -> bool {
-        let msg = wire::serialize(self);
-        signer.verify(&msg, signature).is_ok()
+        wire::serialize(self).map_or(false, |msg| signer.verify(&msg, signature).is_ok())
     }
 
-    /// Sign this announcement.
-    pub fn sign<S: crypto::Signer>(&self, signer: S) -> crypto::Signature {
-        let msg = wire::serialize(self);
-        signer.sign(&msg)
+    /// Sign this announcement. Fails if the announcement can't be encoded.
+    pub fn sign<S: crypto::Signer>(&self, signer: S) -> Result<crypto::Signature, wire::Error> {
+        let msg = wire::serialize(self)?;
+        Ok(signer.sign(&msg))
     }
 }
 
@@ -199,13 +475,13 @@ impl RefsAnnouncement {
 pub struct InventoryAnnouncement {
     pub inventory: Vec<Id>,
     pub timestamp: Timestamp,
+    pub extensions: Extensions,
 }
 
 impl InventoryAnnouncement {
     /// Verify a signature on this message.
     pub fn verify(&self, signer: NodeId, signature: &crypto::Signature) -> bool {
-        let msg = wire::serialize(self);
-        signer.verify(&msg, signature).is_ok()
+        wire::serialize(self).map_or(false, |msg| signer.verify(&msg, signature).is_ok())
     }
 }
 
@@ -217,13 +493,28 @@ impl InventoryAnnouncement {
 pub enum Message {
     /// The first message sent to a peer after connection.
     Initialize {
-        // TODO: This is currently untrusted.
+        /// Node id of the sender. Must match the id the peer authenticated
+        /// with during the transport handshake.
         id: NodeId,
+        /// Highest protocol version supported by the sender.
         version: u32,
+        /// Lowest protocol version supported by the sender.
+        min_version: u32,
+        /// Features supported by the sender.
+        features: NodeFeatures,
         addrs: Vec<Address>,
         git: git::Url,
     },
 
+    /// Sent in response to a well received `Initialize` message. Confirms the
+    /// protocol version and features that were agreed upon.
+    InitializeAck {
+        /// Negotiated protocol version.
+        version: u32,
+        /// Negotiated features, ie. the features supported by both nodes.
+        features: NodeFeatures,
+    },
+
     /// Subscribe to gossip messages matching the filter and time range.
     /// timestamp.
     Subscribe(Subscribe),
@@ -261,36 +552,58 @@ pub enum Message {
 }
 
 impl Message {
-    pub fn init(id: NodeId, addrs: Vec<Address>, git: git::Url) -> Self {
+    pub fn init(id: NodeId, features: NodeFeatures, addrs: Vec<Address>, git: git::Url) -> Self {
         Self::Initialize {
             id,
             version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            features,
             git,
             addrs,
         }
     }
 
-    pub fn node<S: crypto::Signer>(message: NodeAnnouncement, signer: S) -> Self {
-        let msg = wire::serialize(&message);
+    pub fn ack(version: u32, features: NodeFeatures) -> Self {
+        Self::InitializeAck { version, features }
+    }
+
+    pub fn node<S: crypto::Signer>(
+        message: NodeAnnouncement,
+        signer: S,
+    ) -> Result<Self, wire::Error> {
+        let msg = wire::serialize(&message)?;
         let signature = signer.sign(&msg);
         let node = *signer.public_key();
 
-        Self::NodeAnnouncement {
+        Ok(Self::NodeAnnouncement {
             node,
             signature,
             message,
-        }
+        })
     }
 
-    pub fn inventory<S: crypto::Signer>(message: InventoryAnnouncement, signer: S) -> Self {
-        let msg = wire::serialize(&message);
+    pub fn inventory<S: crypto::Signer>(
+        message: InventoryAnnouncement,
+        signer: S,
+    ) -> Result<Self, wire::Error> {
+        let msg = wire::serialize(&message)?;
         let signature = signer.sign(&msg);
         let node = *signer.public_key();
 
-        Self::InventoryAnnouncement {
+        Ok(Self::InventoryAnnouncement {
             node,
             signature,
             message,
+        })
+    }
+
+    /// The node that signed this message, if it is an announcement.
+    pub fn node(&self) -> Option<NodeId> {
+        match self {
+            Self::InventoryAnnouncement { node, .. }
+            | Self::NodeAnnouncement { node, .. }
+            | Self::RefsAnnouncement { node, .. } => Some(*node),
+            Self::Initialize { .. } | Self::InitializeAck { .. } | Self::Subscribe(_) => None,
         }
     }
 
@@ -307,6 +620,7 @@ impl fmt::Debug for Message {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Initialize { id, .. } => write!(f, "Initialize({})", id),
+            Self::InitializeAck { version, .. } => write!(f, "InitializeAck({})", version),
             Self::Subscribe(Subscribe { since, until, .. }) => {
                 write!(f, "Subscribe({}..{})", since, until)
             }
@@ -329,8 +643,8 @@ impl fmt::Debug for Message {
             Self::RefsAnnouncement { node, message, .. } => {
                 write!(
                     f,
-                    "RefsAnnouncement({}, {}, {:?})",
-                    node, message.id, message.refs
+                    "RefsAnnouncement({}, {}, {:?}, {})",
+                    node, message.id, message.refs, message.timestamp
                 )
             }
         }
@@ -346,11 +660,56 @@ mod tests {
     use crate::test::signer::MockSigner;
 
     #[quickcheck]
-    fn prop_refs_announcement_signing(id: Id, refs: Refs) {
+    fn prop_refs_announcement_signing(id: Id, refs: Refs, timestamp: Timestamp) {
         let signer = MockSigner::new(&mut fastrand::Rng::new());
-        let message = RefsAnnouncement { id, refs };
-        let signature = message.sign(&signer);
+        let message = RefsAnnouncement {
+            id,
+            refs,
+            timestamp,
+            extensions: Extensions::default(),
+        };
+        let signature = message.sign(&signer).unwrap();
 
         assert!(message.verify(signer.public_key(), &signature));
     }
+
+    #[test]
+    fn test_address_from_str() {
+        let signer = MockSigner::new(&mut fastrand::Rng::new());
+        let onion = Address::onion(*signer.public_key(), 8776);
+        let s = onion.to_string();
+        let (domain, _) = s.rsplit_once(':').unwrap();
+
+        assert_eq!(domain.len(), 56 + ".onion".len());
+        assert_eq!(Address::from_str(&s).unwrap(), onion);
+
+        let mut corrupted = s.into_bytes();
+        corrupted[0] = if corrupted[0] == b'a' { b'b' } else { b'a' };
+        assert!(matches!(
+            Address::from_str(std::str::from_utf8(&corrupted).unwrap()),
+            Err(AddressParseError::InvalidOnion(_))
+        ));
+
+        assert_eq!(
+            Address::from_str("seed.radicle.xyz:8776").unwrap(),
+            Address::Hostname {
+                host: Hostname::from_str("seed.radicle.xyz").unwrap(),
+                port: 8776
+            }
+        );
+        assert_eq!(
+            Address::from_str("127.0.0.1:8776").unwrap(),
+            Address::from(net::SocketAddr::from(([127, 0, 0, 1], 8776)))
+        );
+        assert!(matches!(
+            Address::from_str("seed.radicle.xyz:http"),
+            Err(AddressParseError::InvalidPort(_))
+        ));
+        assert!(Address::from_str("seed.radicle.xyz").is_err());
+    }
+
+    #[quickcheck]
+    fn prop_address_to_string(addr: Address) {
+        assert_eq!(Address::from_str(&addr.to_string()).unwrap(), addr);
+    }
 }
@@ -10,6 +10,19 @@ pub enum SessionState {
     /// to us.
     #[default]
     Initial,
+    /// State after the peer's `Initialize` message was received and accepted.
+    /// We're waiting for the peer to acknowledge the negotiated parameters.
+    Initialized {
+        /// The peer's unique identifier.
+        id: NodeId,
+        /// Addresses this peer is reachable on.
+        addrs: Vec<Address>,
+        git: Url,
+        /// Negotiated protocol version.
+        version: u32,
+        /// Negotiated features.
+        features: NodeFeatures,
+    },
     /// State after successful handshake.
     Negotiated {
         /// The peer's unique identifier.
@@ -18,9 +31,17 @@ pub enum SessionState {
         /// Addresses this peer is reachable on.
         addrs: Vec<Address>,
         git: Url,
+        /// Protocol version used with this peer.
+        version: u32,
+        /// Features supported by both us and this peer.
+        features: NodeFeatures,
     },
     /// When a peer is disconnected.
-    Disconnected { since: LocalTime },
+    Disconnected {
+        since: LocalTime,
+        /// When we'll next try to reconnect to the peer, if at all.
+        retry_at: Option<LocalTime>,
+    },
 }
 
 #[derive(thiserror::Error, Debug, Clone)]
@@ -31,17 +52,69 @@ pub enum SessionError {
     WrongVersion(u32),
     #[error("invalid announcement timestamp: {0}")]
     InvalidTimestamp(u64),
+    #[error("invalid message signature")]
+    InvalidSignature,
+    #[error("invalid message")]
+    InvalidMessage,
+    #[error("message too large: {0} bytes")]
+    MessageTooLarge(usize),
     #[error("session not found for address `{0}`")]
-    NotFound(net::IpAddr),
+    NotFound(net::SocketAddr),
+    #[error("peer is banned")]
+    Banned,
+    #[error("too many connections from `{0}`")]
+    ConnectionLimit(net::IpAddr),
     #[error("peer misbehaved")]
     Misbehavior,
 }
 
+impl SessionError {
+    /// Misbehaviour score of this error. Peers are banned once their score reaches
+    /// [`crate::address_manager::MAX_PENALTY`]. Errors that aren't the peer's fault, or that
+    /// an honest peer could run into, aren't penalized, or only lightly.
+    pub fn penalty(&self) -> u32 {
+        match self {
+            Self::WrongMagic(_)
+            | Self::WrongVersion(_)
+            | Self::NotFound(_)
+            | Self::Banned
+            | Self::ConnectionLimit(_) => 0,
+            Self::InvalidTimestamp(_) => 25,
+            Self::InvalidMessage | Self::MessageTooLarge(_) | Self::Misbehavior => 50,
+            Self::InvalidSignature => 100,
+        }
+    }
+}
+
+/// Summary of a peer session, as reported to the user.
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    /// Peer address.
+    pub addr: net::SocketAddr,
+    /// Node id of the peer, if known.
+    pub node: Option<NodeId>,
+    /// Connection direction.
+    pub link: Link,
+    /// Whether we reconnect to this peer upon disconnection.
+    pub persistent: bool,
+    /// Whether the peer is connected or connecting.
+    pub connected: bool,
+    /// Whether the handshake with the peer completed.
+    pub negotiated: bool,
+    /// Connection attempts since the last successful handshake.
+    pub attempts: usize,
+    /// When we'll next try to reconnect to the peer, if at all.
+    pub retry_at: Option<LocalTime>,
+}
+
 /// A peer session. Each connected peer will have one session.
 #[derive(Debug)]
 pub struct Session {
     /// Peer address.
     pub addr: net::SocketAddr,
+    /// Address we dialed to reach the peer, eg. a hostname or an address reached through
+    /// the proxy. For inbound peers, this is the peer address.
+    pub target: Address,
     /// Connection direction.
     pub link: Link,
     /// Whether we should attempt to re-connect
@@ -51,25 +124,39 @@ pub struct Session {
     pub state: SessionState,
     /// Peer subscription.
     pub subscribe: Option<Subscribe>,
+    /// When past announcements were last replayed to the peer.
+    pub replayed: Option<LocalTime>,
+    /// Node id the peer proved to own during the transport handshake, if any.
+    pub authenticated: Option<NodeId>,
 
     /// Connection attempts. For persistent peers, Tracks
     /// how many times we've attempted to connect. We reset this to zero
-    /// upon successful connection.
+    /// once the handshake completes, so that peers which accept connections
+    /// but then drop them keep backing off.
     attempts: usize,
 }
 
 impl Session {
-    pub fn new(addr: net::SocketAddr, link: Link, persistent: bool) -> Self {
+    pub fn new(addr: net::SocketAddr, target: Address, link: Link, persistent: bool) -> Self {
         Self {
             addr,
+            target,
             state: SessionState::default(),
             link,
             subscribe: None,
+            replayed: None,
+            authenticated: None,
             persistent,
             attempts: 0,
         }
     }
 
+    /// Carry over the connection attempts of a previous session with the same peer.
+    pub fn resumed(mut self, previous: &Session) -> Self {
+        self.attempts = previous.attempts;
+        self
+    }
+
     pub fn ip(&self) -> IpAddr {
         self.addr.ip()
     }
@@ -78,15 +165,47 @@ impl Session {
         matches!(self.state, SessionState::Negotiated { .. })
     }
 
+    pub fn is_disconnected(&self) -> bool {
+        matches!(self.state, SessionState::Disconnected { .. })
+    }
+
     pub fn attempts(&self) -> usize {
         self.attempts
     }
 
+    pub fn info(&self) -> SessionInfo {
+        let (node, retry_at) = match &self.state {
+            SessionState::Negotiated { id, .. } => (Some(*id), None),
+            SessionState::Disconnected { retry_at, .. } => (self.authenticated, *retry_at),
+            _ => (self.authenticated, None),
+        };
+        SessionInfo {
+            addr: self.addr,
+            node,
+            link: self.link,
+            persistent: self.persistent,
+            connected: !self.is_disconnected(),
+            negotiated: self.is_negotiated(),
+            attempts: self.attempts,
+            retry_at,
+        }
+    }
+
     pub fn attempted(&mut self) {
         self.attempts += 1;
     }
 
     pub fn connected(&mut self, _link: Link) {
+        // A reconnected peer starts over with a new handshake.
+        self.state = SessionState::Initial;
+        self.subscribe = None;
+        self.replayed = None;
+    }
+
+    pub fn negotiated(&mut self, state: SessionState) {
+        debug_assert!(matches!(state, SessionState::Negotiated { .. }));
+
+        self.state = state;
         self.attempts = 0;
     }
 }
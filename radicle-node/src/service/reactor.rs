@@ -3,7 +3,11 @@ use std::net;
 
 use log::*;
 
+use crate::collections::{HashMap, HashSet};
 use crate::prelude::*;
+use crate::service::config::Proxy;
+use crate::service::fetch::Namespaces;
+use crate::service::message::Hostname;
 use crate::service::peer::Session;
 
 /// Output of a state transition.
@@ -13,12 +17,34 @@ pub enum Io {
     Write(net::SocketAddr, Vec<Envelope>),
     /// Connect to a peer.
     Connect(net::SocketAddr),
+    /// Connect to a peer through the proxy. Connections are identified by their remote
+    /// address, so rather than connecting to the proxy directly, each connection goes
+    /// through its own local relay to the proxy. The relay's address is passed back to the
+    /// service once the relay is listening.
+    Relay {
+        proxy: net::SocketAddr,
+        target: Address,
+    },
+    /// Resolve a hostname. Resolution may block, so it shouldn't be done on the service
+    /// thread: the result is passed back to the service once it completes.
+    Resolve { host: Hostname, port: u16 },
     /// Disconnect from a peer.
     Disconnect(net::SocketAddr, DisconnectReason),
     /// Ask for a wakeup in a specified amount of time.
     Wakeup(LocalDuration),
     /// Emit an event.
     Event(Event),
+    /// Fetch a repository from a connected peer, over a git stream multiplexed
+    /// on the peer connection. The fetch shouldn't block the service: the result
+    /// is passed back to the service once the fetch completes.
+    Fetch {
+        remote: net::SocketAddr,
+        id: Id,
+        namespaces: Namespaces,
+    },
+    /// Cancel a fetch started with [`Io::Fetch`] that is taking too long. The fetch
+    /// still completes, with an error, once it has stopped.
+    Cancel { remote: net::SocketAddr, id: Id },
 }
 
 /// Interface to the network reactor.
@@ -26,14 +52,27 @@ pub enum Io {
 pub struct Reactor {
     /// The network we're on.
     network: Network,
+    /// Proxy to connect to peers through.
+    proxy: Option<Proxy>,
+    /// Hostnames we dialed, keyed by the socket address they resolved to.
+    targets: HashMap<net::SocketAddr, Address>,
+    /// Addresses we're connecting to through the proxy, keyed by the address of their relay.
+    proxied: HashMap<net::SocketAddr, Address>,
+    /// Addresses being resolved, or waiting for their relay to the proxy. They don't have
+    /// a session yet.
+    pending: HashSet<Address>,
     /// Outgoing I/O queue.
     io: VecDeque<Io>,
 }
 
 impl Reactor {
-    pub fn new(network: Network) -> Self {
+    pub fn new(network: Network, proxy: Option<Proxy>) -> Self {
         Self {
             network,
+            proxy,
+            targets: HashMap::default(),
+            proxied: HashMap::default(),
+            pending: HashSet::default(),
             io: VecDeque::new(),
         }
     }
@@ -46,7 +85,26 @@ impl Reactor {
     /// Connect to a peer.
     pub fn connect(&mut self, addr: impl Into<Address>) {
         // TODO: Make sure we don't try to connect more than once to the same address.
-        match addr.into() {
+        let addr = addr.into();
+
+        if self.pending.contains(&addr) {
+            return;
+        }
+        if let Some(proxy) = self.proxy.as_ref().filter(|p| p.is_used_for(&addr)) {
+            debug!("Connecting to {} through proxy {}", addr, proxy.addr);
+
+            self.pending.insert(addr.clone());
+            self.io.push_back(Io::Relay {
+                proxy: proxy.addr,
+                target: addr,
+            });
+
+            return;
+        }
+        if let Address::Hostname { .. } = addr {
+            self.pending.insert(addr.clone());
+        }
+        match addr {
             Address::Ipv4 { ip, port } => {
                 self.io
                     .push_back(Io::Connect(net::SocketAddr::new(net::IpAddr::V4(ip), port)));
@@ -55,12 +113,81 @@ impl Reactor {
                 self.io
                     .push_back(Io::Connect(net::SocketAddr::new(net::IpAddr::V6(ip), port)));
             }
-            other => {
-                log::error!("Unsupported address type `{}`", other);
+            Address::Hostname { host, port } => {
+                self.io.push_back(Io::Resolve { host, port });
+            }
+            Address::Onion { .. } => {
+                error!("Unable to connect to `{}`: no proxy configured", addr);
+            }
+            Address::Unknown { .. } => {
+                error!("Unable to connect to `{}`", addr);
             }
         }
     }
 
+    /// Whether we're able to connect to the given address. Onion addresses can only be
+    /// reached through the proxy.
+    pub fn can_connect(&self, addr: &Address) -> bool {
+        match addr {
+            Address::Onion { .. } => self.proxy.is_some(),
+            Address::Unknown { .. } => false,
+            _ => true,
+        }
+    }
+
+    /// Connect to a peer at an address resolved from a hostname.
+    pub fn connect_resolved(&mut self, addr: net::SocketAddr, host: Hostname, port: u16) {
+        let target = Address::Hostname { host, port };
+
+        self.pending.remove(&target);
+        self.targets.insert(addr, target);
+        self.io.push_back(Io::Connect(addr));
+    }
+
+    /// Connect to a peer through the relay to the proxy that was set up for it.
+    pub fn connect_relayed(&mut self, relay: net::SocketAddr, target: Address) {
+        self.pending.remove(&target);
+        self.proxied.insert(relay, target);
+        self.io.push_back(Io::Connect(relay));
+    }
+
+    /// Give up on connecting to an address that couldn't be resolved or relayed.
+    pub fn failed(&mut self, target: &Address) {
+        self.pending.remove(target);
+    }
+
+    /// Number of addresses being resolved or relayed, that we'll connect to once done.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Whether the given address is being resolved or relayed.
+    pub fn is_pending(&self, addr: &Address) -> bool {
+        self.pending.contains(addr)
+    }
+
+    /// The address we dialed to connect to the given remote. This is the remote
+    /// address itself, unless we connected through a proxy or to a hostname.
+    pub fn target(&self, remote: &net::SocketAddr) -> Address {
+        self.proxied(remote)
+            .or_else(|| self.targets.get(remote))
+            .cloned()
+            .unwrap_or_else(|| Address::from(*remote))
+    }
+
+    /// The address the proxy should connect us to, if the given remote is a relay to
+    /// the proxy.
+    pub fn proxied(&self, remote: &net::SocketAddr) -> Option<&Address> {
+        self.proxied.get(remote)
+    }
+
+    /// Forget the address we dialed to connect to the given remote, once the connection
+    /// is closed.
+    pub fn disconnected(&mut self, remote: &net::SocketAddr) {
+        self.targets.remove(remote);
+        self.proxied.remove(remote);
+    }
+
     /// Disconnect a peer.
     pub fn disconnect(&mut self, addr: net::SocketAddr, reason: DisconnectReason) {
         self.io.push_back(Io::Disconnect(addr, reason));
@@ -81,6 +208,20 @@ impl Reactor {
         self.io.push_back(Io::Write(remote, envelopes));
     }
 
+    /// Fetch a repository from a connected peer.
+    pub fn fetch(&mut self, remote: net::SocketAddr, id: Id, namespaces: Namespaces) {
+        self.io.push_back(Io::Fetch {
+            remote,
+            id,
+            namespaces,
+        });
+    }
+
+    /// Cancel an ongoing fetch.
+    pub fn cancel(&mut self, remote: net::SocketAddr, id: Id) {
+        self.io.push_back(Io::Cancel { remote, id });
+    }
+
     pub fn wakeup(&mut self, after: LocalDuration) {
         self.io.push_back(Io::Wakeup(after));
     }
@@ -0,0 +1,191 @@
+use fastrand::Rng;
+
+use crate::clock::Timestamp;
+use crate::collections::HashMap;
+use crate::identity::Id;
+use crate::service::NodeId;
+
+/// Network routing table. Keeps track of where projects are hosted, and when
+/// each location was last confirmed.
+#[derive(Debug)]
+pub struct Routing {
+    entries: HashMap<Id, HashMap<NodeId, Timestamp>>,
+    rng: Rng,
+}
+
+impl Routing {
+    /// Create a new, empty routing table.
+    pub fn new(rng: Rng) -> Self {
+        Self {
+            entries: HashMap::with_hasher(rng.clone().into()),
+            rng,
+        }
+    }
+
+    /// Record that a node hosts the given project, as of `time`.
+    /// Returns `true` if the entry is new.
+    pub fn insert(&mut self, id: Id, node: NodeId, time: Timestamp) -> bool {
+        let nodes = self
+            .entries
+            .entry(id)
+            .or_insert_with(|| HashMap::with_hasher(self.rng.clone().into()));
+
+        match nodes.get_mut(&node) {
+            Some(t) => {
+                *t = (*t).max(time);
+                false
+            }
+            None => {
+                nodes.insert(node, time);
+                true
+            }
+        }
+    }
+
+    /// Remove the entries of a node for projects that aren't in the given inventory.
+    /// Returns the number of entries removed.
+    pub fn retain_inventory(&mut self, node: &NodeId, inventory: &[Id]) -> usize {
+        let mut removed = 0;
+
+        for (id, nodes) in self.entries.iter_mut() {
+            if !inventory.contains(id) && nodes.remove(node).is_some() {
+                removed += 1;
+            }
+        }
+        self.entries.retain(|_, nodes| !nodes.is_empty());
+
+        removed
+    }
+
+    /// Check whether a node is known to host the given project.
+    pub fn contains(&self, id: &Id, node: &NodeId) -> bool {
+        self.entries
+            .get(id)
+            .map_or(false, |nodes| nodes.contains_key(node))
+    }
+
+    /// Get the nodes known to host the given project.
+    pub fn seeds(&self, id: &Id) -> impl Iterator<Item = &NodeId> {
+        self.entries
+            .get(id)
+            .into_iter()
+            .flat_map(|nodes| nodes.keys())
+    }
+
+    /// Get the time at which an entry was last confirmed.
+    pub fn timestamp(&self, id: &Id, node: &NodeId) -> Option<Timestamp> {
+        self.entries
+            .get(id)
+            .and_then(|nodes| nodes.get(node).copied())
+    }
+
+    /// Iterate over all entries in the table.
+    pub fn iter(&self) -> impl Iterator<Item = (&Id, &NodeId, &Timestamp)> {
+        self.entries
+            .iter()
+            .flat_map(|(id, nodes)| nodes.iter().map(move |(node, t)| (id, node, t)))
+    }
+
+    /// Total number of entries in the table.
+    pub fn len(&self) -> usize {
+        self.entries.values().map(|nodes| nodes.len()).sum()
+    }
+
+    /// Whether the table is empty.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Prune the routing table. Entries older than `oldest` are removed, and if the table
+    /// still has more than `max_size` entries, the oldest entries are removed until it doesn't.
+    /// Returns the number of entries removed.
+    pub fn prune(&mut self, oldest: Timestamp, max_size: usize) -> usize {
+        let mut removed = 0;
+
+        for nodes in self.entries.values_mut() {
+            let len = nodes.len();
+            nodes.retain(|_, t| *t >= oldest);
+            removed += len - nodes.len();
+        }
+
+        let len = self.len();
+        if len > max_size {
+            let mut entries = self
+                .entries
+                .iter()
+                .flat_map(|(id, nodes)| nodes.iter().map(|(node, t)| (*t, *id, *node)))
+                .collect::<Vec<_>>();
+            entries.sort_unstable_by_key(|(t, _, _)| *t);
+
+            for (_, id, node) in entries.into_iter().take(len - max_size) {
+                if let Some(nodes) = self.entries.get_mut(&id) {
+                    nodes.remove(&node);
+                    removed += 1;
+                }
+            }
+        }
+        self.entries.retain(|_, nodes| !nodes.is_empty());
+
+        removed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::arbitrary;
+
+    #[test]
+    fn test_prune_expired() {
+        let mut routing = Routing::new(Rng::new());
+        let proj = arbitrary::gen::<Id>(1);
+        let (alice, bob) = (arbitrary::gen::<NodeId>(1), arbitrary::gen::<NodeId>(1));
+
+        routing.insert(proj, alice, 10);
+        routing.insert(proj, bob, 20);
+
+        assert_eq!(routing.prune(15, usize::MAX), 1);
+        assert!(!routing.contains(&proj, &alice));
+        assert!(routing.contains(&proj, &bob));
+
+        // Re-confirming an entry refreshes it.
+        assert!(!routing.insert(proj, bob, 30));
+        assert_eq!(routing.prune(25, usize::MAX), 0);
+        assert_eq!(routing.prune(35, usize::MAX), 1);
+        assert!(routing.is_empty());
+    }
+
+    #[test]
+    fn test_prune_max_size() {
+        let mut routing = Routing::new(Rng::new());
+        let node = arbitrary::gen::<NodeId>(1);
+        let projs = (0..8).map(|_| arbitrary::gen::<Id>(1)).collect::<Vec<_>>();
+
+        for (i, proj) in projs.iter().enumerate() {
+            routing.insert(*proj, node, i as Timestamp);
+        }
+        assert_eq!(routing.prune(0, 5), 3);
+        assert_eq!(routing.len(), 5);
+
+        for proj in &projs[..3] {
+            assert!(!routing.contains(proj, &node));
+        }
+        for proj in &projs[3..] {
+            assert!(routing.contains(proj, &node));
+        }
+    }
+
+    #[test]
+    fn test_retain_inventory() {
+        let mut routing = Routing::new(Rng::new());
+        let node = arbitrary::gen::<NodeId>(1);
+        let projs = (0..3).map(|_| arbitrary::gen::<Id>(1)).collect::<Vec<_>>();
+
+        for proj in &projs {
+            routing.insert(*proj, node, 1);
+        }
+        assert_eq!(routing.retain_inventory(&node, &projs[1..]), 1);
+        assert!(!routing.contains(&projs[0], &node));
+        assert_eq!(routing.seeds(&projs[1]).collect::<Vec<_>>(), vec![&node]);
+    }
+}
@@ -7,9 +7,10 @@ use crate::crypto;
 use crate::prelude::{Id, NodeId, Refs, Timestamp};
 use crate::service::filter::{Filter, FILTER_SIZE};
 use crate::service::message::{
-    Address, Envelope, InventoryAnnouncement, Message, NodeAnnouncement, RefsAnnouncement,
-    Subscribe,
+    Address, Envelope, Extensions, Hostname, InventoryAnnouncement, Message, NodeAnnouncement,
+    RefsAnnouncement, Subscribe, MAX_ADDRESSES,
 };
+use crate::wire::frame::{Frame, StreamId};
 use crate::wire::message::MessageType;
 
 pub use radicle::test::arbitrary::*;
@@ -25,6 +26,17 @@ impl Arbitrary for Filter {
     }
 }
 
+impl Arbitrary for Extensions {
+    fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+        let mut extensions = Self::default();
+
+        for _ in 0..u8::arbitrary(g) % 3 {
+            extensions.insert(u16::arbitrary(g), Vec::<u8>::arbitrary(g));
+        }
+        extensions
+    }
+}
+
 impl Arbitrary for Envelope {
     fn arbitrary(g: &mut quickcheck::Gen) -> Self {
         Self {
@@ -34,10 +46,34 @@ impl Arbitrary for Envelope {
     }
 }
 
+impl Arbitrary for Frame {
+    fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+        let stream = StreamId::arbitrary(g);
+
+        match u8::arbitrary(g) % 5 {
+            0 => Self::Message(Envelope::arbitrary(g)),
+            1 => Self::Open {
+                stream,
+                id: Id::arbitrary(g),
+            },
+            2 => Self::Data {
+                stream,
+                data: Vec::<u8>::arbitrary(g),
+            },
+            3 => Self::Close { stream },
+            _ => Self::Credit {
+                stream,
+                frames: u32::arbitrary(g),
+            },
+        }
+    }
+}
+
 impl Arbitrary for Message {
     fn arbitrary(g: &mut quickcheck::Gen) -> Self {
         let type_id = g
             .choose(&[
+                MessageType::InitializeAck,
                 MessageType::InventoryAnnouncement,
                 MessageType::NodeAnnouncement,
                 MessageType::RefsAnnouncement,
@@ -46,11 +82,16 @@ impl Arbitrary for Message {
             .unwrap();
 
         match type_id {
+            MessageType::InitializeAck => Self::InitializeAck {
+                version: u32::arbitrary(g),
+                features: ByteArray::<32>::arbitrary(g).into_inner(),
+            },
             MessageType::InventoryAnnouncement => Self::InventoryAnnouncement {
                 node: NodeId::arbitrary(g),
                 message: InventoryAnnouncement {
                     inventory: Vec::<Id>::arbitrary(g),
                     timestamp: Timestamp::arbitrary(g),
+                    extensions: Extensions::arbitrary(g),
                 },
                 signature: crypto::Signature::from(ByteArray::<64>::arbitrary(g).into_inner()),
             },
@@ -59,6 +100,8 @@ impl Arbitrary for Message {
                 message: RefsAnnouncement {
                     id: Id::arbitrary(g),
                     refs: Refs::arbitrary(g),
+                    timestamp: Timestamp::arbitrary(g),
+                    extensions: Extensions::arbitrary(g),
                 },
                 signature: crypto::Signature::from(ByteArray::<64>::arbitrary(g).into_inner()),
             },
@@ -67,7 +110,11 @@ impl Arbitrary for Message {
                     features: ByteArray::<32>::arbitrary(g).into_inner(),
                     timestamp: Timestamp::arbitrary(g),
                     alias: ByteArray::<32>::arbitrary(g).into_inner(),
-                    addresses: Arbitrary::arbitrary(g),
+                    addresses: Vec::<Address>::arbitrary(g)
+                        .into_iter()
+                        .take(MAX_ADDRESSES)
+                        .collect(),
+                    extensions: Extensions::arbitrary(g),
                 };
                 let bytes: ByteArray<64> = Arbitrary::arbitrary(g);
                 let signature = crypto::Signature::from(bytes.into_inner());
@@ -88,20 +135,50 @@ impl Arbitrary for Message {
     }
 }
 
+impl Arbitrary for Hostname {
+    fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+        const CHARSET: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789-";
+
+        let labels = (0..g.choose(&[1, 2, 3]).copied().unwrap())
+            .map(|_| {
+                let len = g.choose(&[1, 2, 8, 16, 63]).copied().unwrap();
+                let mut label = (0..len)
+                    .map(|_| *g.choose(CHARSET).unwrap() as char)
+                    .collect::<String>();
+
+                // Labels can't start or end with a hyphen.
+                label.replace_range(..1, "x");
+                if label.ends_with('-') {
+                    label.replace_range(len - 1.., "x");
+                }
+                label
+            })
+            .collect::<Vec<_>>();
+
+        Hostname::try_from(labels.join(".")).unwrap()
+    }
+}
+
 impl Arbitrary for Address {
     fn arbitrary(g: &mut quickcheck::Gen) -> Self {
-        if bool::arbitrary(g) {
-            Address::Ipv4 {
+        match g.choose(&[1, 2, 3, 4]).copied().unwrap() {
+            1 => Address::Ipv4 {
                 ip: net::Ipv4Addr::from(u32::arbitrary(g)),
                 port: u16::arbitrary(g),
-            }
-        } else {
-            let octets: [u8; 16] = ByteArray::<16>::arbitrary(g).into_inner();
+            },
+            2 => {
+                let octets: [u8; 16] = ByteArray::<16>::arbitrary(g).into_inner();
 
-            Address::Ipv6 {
-                ip: net::Ipv6Addr::from(octets),
-                port: u16::arbitrary(g),
+                Address::Ipv6 {
+                    ip: net::Ipv6Addr::from(octets),
+                    port: u16::arbitrary(g),
+                }
             }
+            3 => Address::Hostname {
+                host: Hostname::arbitrary(g),
+                port: u16::arbitrary(g),
+            },
+            _ => Address::onion(NodeId::arbitrary(g), u16::arbitrary(g)),
         }
     }
 }
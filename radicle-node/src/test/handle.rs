@@ -1,14 +1,18 @@
 use std::sync::{Arc, Mutex};
 
+use crate::address_manager::{Ban, Unban};
 use crate::client::handle::traits;
 use crate::client::handle::Error;
 use crate::identity::Id;
 use crate::service;
+use crate::service::peer::SessionInfo;
 use crate::service::FetchLookup;
 
 #[derive(Default, Clone)]
 pub struct Handle {
     pub updates: Arc<Mutex<Vec<Id>>>,
+    pub bans: Arc<Mutex<Vec<Ban>>>,
+    pub sessions: Arc<Mutex<Vec<SessionInfo>>>,
 }
 
 impl traits::Handle for Handle {
@@ -30,6 +34,26 @@ impl traits::Handle for Handle {
         Ok(())
     }
 
+    fn bans(&self) -> Result<Vec<Ban>, Error> {
+        Ok(self.bans.lock().unwrap().clone())
+    }
+
+    fn unban(&self, unban: Unban) -> Result<usize, Error> {
+        let mut bans = self.bans.lock().unwrap();
+        let before = bans.len();
+
+        bans.retain(|ban| match &unban {
+            Unban::All => false,
+            Unban::Ip(ip) => ban.ip != *ip,
+            Unban::Node(node) => ban.node.as_ref() != Some(node),
+        });
+        Ok(before - bans.len())
+    }
+
+    fn sessions(&self) -> Result<Vec<SessionInfo>, Error> {
+        Ok(self.sessions.lock().unwrap().clone())
+    }
+
     fn command(&self, _cmd: service::Command) -> Result<(), Error> {
         Ok(())
     }
@@ -20,7 +20,7 @@ use crate::test::simulator;
 use crate::{Link, LocalTime};
 
 /// Service instantiation used for testing.
-pub type Service<S> = service::Service<HashMap<net::IpAddr, KnownAddress>, S, MockSigner>;
+pub type Service<S> = service::Service<HashMap<Address, KnownAddress>, S, MockSigner>;
 
 #[derive(Debug)]
 pub struct Peer<S> {
@@ -96,12 +96,12 @@ where
     ) -> Self {
         let addrs = addrs
             .into_iter()
-            .map(|(addr, src)| (addr.ip(), KnownAddress::new(addr, src, None)))
+            .map(|(addr, src)| (addr.into(), KnownAddress::new(addr.into(), src, None)))
             .collect();
         let local_time = LocalTime::now();
         let clock = RefClock::from(local_time);
         let signer = MockSigner::new(&mut rng);
-        let service = Service::new(config, clock, storage, addrs, signer, rng.clone());
+        let service = Service::new(config, clock, storage, addrs, None, signer, rng.clone());
         let ip = ip.into();
         let local_addr = net::SocketAddr::new(ip, rng.u16(..));
 
@@ -156,13 +156,23 @@ where
         self.service.connected(remote, &local, Link::Inbound);
         self.receive(
             &remote,
-            Message::init(peer.node_id(), vec![Address::from(remote)], git),
+            Message::init(
+                peer.node_id(),
+                NODE_FEATURES,
+                vec![Address::from(remote)],
+                git,
+            ),
         );
 
         let mut msgs = self.messages(&remote);
         msgs.find(|m| matches!(m, Message::Initialize { .. }))
             .expect("`initialize` is sent");
-        msgs.find(|m| matches!(m, Message::InventoryAnnouncement { .. }))
+        msgs.find(|m| matches!(m, Message::InitializeAck { .. }))
+            .expect("`initialize-ack` is sent");
+
+        self.receive(&remote, Message::ack(PROTOCOL_VERSION, NODE_FEATURES));
+        self.messages(&remote)
+            .find(|m| matches!(m, Message::InventoryAnnouncement { .. }))
             .expect("`inventory-announcement` is sent");
     }
 
@@ -174,17 +184,28 @@ where
         self.service
             .connected(remote, &self.local_addr, Link::Outbound);
 
-        let mut msgs = self.messages(&remote);
-        msgs.find(|m| matches!(m, Message::Initialize { .. }))
+        self.messages(&remote)
+            .find(|m| matches!(m, Message::Initialize { .. }))
             .expect("`initialize` is sent");
-        msgs.find(|m| matches!(m, Message::InventoryAnnouncement { .. }))
-            .expect("`inventory-announcement` is sent");
 
         let git = peer.config().git_url.clone();
         self.receive(
             &remote,
-            Message::init(peer.node_id(), peer.config().listen.clone(), git),
+            Message::init(
+                peer.node_id(),
+                NODE_FEATURES,
+                peer.config().listen.clone(),
+                git,
+            ),
         );
+        self.messages(&remote)
+            .find(|m| matches!(m, Message::InitializeAck { .. }))
+            .expect("`initialize-ack` is sent");
+
+        self.receive(&remote, Message::ack(PROTOCOL_VERSION, NODE_FEATURES));
+        self.messages(&remote)
+            .find(|m| matches!(m, Message::InventoryAnnouncement { .. }))
+            .expect("`inventory-announcement` is sent");
     }
 
     /// Drain outgoing messages sent from this peer to the remote address.
@@ -13,8 +13,9 @@ use log::*;
 use nakamoto_net as nakamoto;
 use nakamoto_net::{Link, LocalDuration, LocalTime};
 
+use crate::identity::Id;
 use crate::service::reactor::Io;
-use crate::service::{DisconnectReason, Envelope, Event};
+use crate::service::{DisconnectReason, Envelope, Event, FetchError};
 use crate::storage::WriteStorage;
 use crate::test::peer::Service;
 
@@ -60,6 +61,8 @@ pub enum Input {
     ),
     /// Received a message from a remote peer.
     Received(net::SocketAddr, Vec<Envelope>),
+    /// A fetch from a remote peer is ready to be carried out.
+    Fetch(net::SocketAddr, Id),
     /// Used to advance the state machine after some wall time has passed.
     Wake,
 }
@@ -106,6 +109,9 @@ impl fmt::Display for Scheduled {
             Input::Wake => {
                 write!(f, "{}: Tock", self.node)
             }
+            Input::Fetch(remote, id) => {
+                write!(f, "{} <- {}: Fetch {}", self.node, remote, id)
+            }
         }
     }
 }
@@ -361,6 +367,18 @@ impl<S: WriteStorage + 'static> Simulation<S> {
 
             let Scheduled { input, node, .. } = next;
 
+            // Git streams aren't simulated, so fetches are carried out directly against
+            // the remote's storage, if the two nodes are still connected.
+            let fetch_url = match &input {
+                Input::Fetch(remote, _)
+                    if self.connections.contains_key(&(node, remote.ip()))
+                        && !self.is_partitioned(node, remote.ip()) =>
+                {
+                    nodes.get(&remote.ip()).map(|r| r.config().git_url.clone())
+                }
+                _ => None,
+            };
+
             if let Some(ref mut p) = nodes.get_mut(&node) {
                 p.tick(time);
 
@@ -402,6 +420,13 @@ impl<S: WriteStorage + 'static> Simulation<S> {
                             p.received_message(&addr, msg);
                         }
                     }
+                    Input::Fetch(remote, id) => {
+                        let result = match fetch_url {
+                            Some(url) => p.storage().fetch(id, &url).map_err(FetchError::from),
+                            None => Err(FetchError::NotConnected),
+                        };
+                        p.fetched(remote, id, result);
+                    }
                 }
                 for o in p.by_ref() {
                     self.schedule(&node, o);
@@ -579,12 +604,14 @@ impl<S: WriteStorage + 'static> Simulation<S> {
             Io::Wakeup(duration) => {
                 let time = self.time + duration;
 
+                // Nb. Multiple nodes may ask to be woken up at the same time.
                 if !matches!(
                     self.inbox.messages.get(&time),
                     Some(Scheduled {
+                        node: n,
                         input: Input::Wake,
                         ..
-                    })
+                    }) if *n == node
                 ) {
                     self.inbox.insert(
                         time,
@@ -605,6 +632,30 @@ impl<S: WriteStorage + 'static> Simulation<S> {
                     events.push_back(event);
                 }
             }
+            // Remote namespaces aren't simulated: everything is fetched.
+            Io::Fetch { remote, id, .. } => {
+                let latency = self.latency(node, remote.ip());
+
+                self.inbox.insert(
+                    self.time + latency,
+                    Scheduled {
+                        node,
+                        remote,
+                        input: Input::Fetch(remote, id),
+                    },
+                );
+            }
+            // Fetches complete after a fixed latency, and late results are ignored by the
+            // service, so there's nothing to cancel.
+            Io::Cancel { .. } => {}
+            // Hostnames aren't simulated: nodes are only known by their IP addresses.
+            Io::Resolve { host, .. } => {
+                warn!(target: "sim", "Dropping resolution of {}: hostnames aren't simulated", host);
+            }
+            // Proxies aren't simulated either.
+            Io::Relay { target, .. } => {
+                warn!(target: "sim", "Dropping connection to {}: proxies aren't simulated", target);
+            }
         }
     }
 
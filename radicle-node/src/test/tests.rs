@@ -1,17 +1,23 @@
 use std::io;
+use std::net;
+use std::str::FromStr;
 use std::sync::Arc;
 
 use crossbeam_channel as chan;
 use nakamoto_net as nakamoto;
 
+use crate::address_book::{KnownAddress, Source};
+use crate::clock::Timestamp;
 use crate::collections::{HashMap, HashSet};
 use crate::service::config::*;
+use crate::service::filter::Filter;
 use crate::service::message::*;
 use crate::service::peer::*;
 use crate::service::reactor::Io;
 use crate::service::*;
 use crate::storage::git::Storage;
-use crate::storage::ReadStorage;
+use crate::storage::refs::Refs;
+use crate::storage::{ReadRepository, ReadStorage, WriteStorage};
 use crate::test::assert_matches;
 use crate::test::fixtures;
 #[allow(unused)]
@@ -21,8 +27,8 @@ use crate::test::signer::MockSigner;
 use crate::test::simulator;
 use crate::test::simulator::{Peer as _, Simulation};
 use crate::test::storage::MockStorage;
-use crate::LocalTime;
-use crate::{client, git, identity, rad, service, test};
+use crate::{client, clock, git, identity, rad, service, test};
+use crate::{Link, LocalDuration, LocalTime};
 
 // NOTE
 //
@@ -45,7 +51,7 @@ fn test_outbound_connection() {
         .service
         .sessions()
         .negotiated()
-        .map(|(ip, _)| *ip)
+        .map(|(addr, _)| addr.ip())
         .collect::<Vec<_>>();
 
     assert!(peers.contains(&eve.ip));
@@ -65,7 +71,7 @@ fn test_inbound_connection() {
         .service
         .sessions()
         .negotiated()
-        .map(|(ip, _)| *ip)
+        .map(|(addr, _)| addr.ip())
         .collect::<Vec<_>>();
 
     assert!(peers.contains(&eve.ip));
@@ -95,61 +101,916 @@ fn test_persistent_peer_connect() {
     let mut outbox = alice.outbox();
     assert_matches!(outbox.next(), Some(Io::Connect(a)) if a == bob.addr());
     assert_matches!(outbox.next(), Some(Io::Connect(a)) if a == eve.addr());
+    assert_matches!(outbox.next(), Some(Io::Wakeup(d)) if d == IDLE_INTERVAL);
     assert_matches!(outbox.next(), None);
 }
 
 #[test]
-#[ignore]
+fn test_maintain_connections() {
+    let mut bob = Peer::new("bob", [8, 8, 8, 8], MockStorage::empty());
+    let mut eve = Peer::new("eve", [9, 9, 9, 9], MockStorage::empty());
+    let mut carol = Peer::new("carol", [10, 10, 10, 10], MockStorage::empty());
+    let mut alice = Peer::config(
+        "alice",
+        Config::default(),
+        [7, 7, 7, 7],
+        vec![
+            (bob.addr(), Source::Dns),
+            (eve.addr(), Source::Dns),
+            (carol.addr(), Source::Dns),
+        ],
+        MockStorage::empty(),
+        fastrand::Rng::new(),
+    );
+    let outbound = |peer: &Peer<MockStorage>| {
+        peer.sessions()
+            .outbound()
+            .filter(|(_, s)| s.is_negotiated())
+            .map(|(addr, _)| addr.ip())
+            .collect::<HashSet<_>>()
+    };
+
+    let mut sim = Simulation::new(
+        LocalTime::now(),
+        alice.rng.clone(),
+        simulator::Options::default(),
+    )
+    .initialize([&mut alice, &mut bob, &mut eve, &mut carol]);
+
+    // Alice connects to the peers in her address book.
+    sim.run_while([&mut alice, &mut bob, &mut eve, &mut carol], |s| {
+        !s.is_settled()
+    });
+    assert_eq!(
+        outbound(&alice),
+        [bob.ip, eve.ip, carol.ip]
+            .into_iter()
+            .collect::<HashSet<_>>()
+    );
+
+    // Bob and Eve disconnect from Alice.
+    bob.reactor()
+        .disconnect(alice.addr(), DisconnectReason::User);
+    eve.reactor()
+        .disconnect(alice.addr(), DisconnectReason::User);
+
+    sim.run_while([&mut alice, &mut bob, &mut eve, &mut carol], |s| {
+        !s.is_settled()
+    });
+    assert_eq!(
+        outbound(&alice),
+        [carol.ip].into_iter().collect::<HashSet<_>>()
+    );
+
+    // After some time, Alice re-connects to them to maintain her outbound peer count.
+    let deadline = sim.elapsed().as_millis() + IDLE_INTERVAL.as_millis() * 2;
+    sim.run_while([&mut alice, &mut bob, &mut eve, &mut carol], |s| {
+        s.elapsed().as_millis() < deadline
+    });
+    assert_eq!(
+        outbound(&alice),
+        [bob.ip, eve.ip, carol.ip]
+            .into_iter()
+            .collect::<HashSet<_>>()
+    );
+}
+
+#[test]
+fn test_maintain_connections_skips_failed_peers() {
+    let bob = Peer::new("bob", [8, 8, 8, 8], MockStorage::empty());
+    let mut alice = Peer::config(
+        "alice",
+        Config::default(),
+        [7, 7, 7, 7],
+        vec![(bob.addr(), Source::Dns)],
+        MockStorage::empty(),
+        fastrand::Rng::new(),
+    );
+    let error = Arc::new(io::Error::from(io::ErrorKind::ConnectionRefused));
+
+    alice.initialize();
+    assert_matches!(alice.outbox().next(), Some(Io::Connect(a)) if a == bob.addr());
+
+    alice.attempted(&bob.addr());
+    alice.disconnected(&bob.addr(), nakamoto::DisconnectReason::DialError(error));
+    alice.outbox().for_each(drop);
+
+    // Alice doesn't retry right away, since she just failed to connect to Bob.
+    alice
+        .clock()
+        .elapse(LocalDuration::from_secs(IDLE_INTERVAL.as_secs() + 1));
+    alice.wake();
+    assert!(!alice.outbox().any(|o| matches!(o, Io::Connect(_))));
+}
+
+#[test]
 fn test_wrong_peer_version() {
+    let mut alice = Peer::new("alice", [7, 7, 7, 7], MockStorage::empty());
+    let bob = Peer::new("bob", [8, 8, 8, 8], MockStorage::empty());
+
+    alice.initialize();
+    alice.connected(bob.addr(), &alice.local_addr, Link::Inbound);
+    alice.receive(
+        &bob.addr(),
+        Message::Initialize {
+            id: bob.node_id(),
+            version: PROTOCOL_VERSION + 2,
+            min_version: PROTOCOL_VERSION + 1,
+            features: NODE_FEATURES,
+            addrs: vec![],
+            git: bob.git_url(),
+        },
+    );
+    assert_matches!(
+        alice.outbox().next(),
+        Some(Io::Disconnect(addr, DisconnectReason::Error(SessionError::WrongVersion(v))))
+        if addr == bob.addr() && v == PROTOCOL_VERSION + 2
+    );
+}
+
+#[test]
+fn test_old_peer_version() {
+    let mut alice = Peer::new("alice", [7, 7, 7, 7], MockStorage::empty());
+    let bob = Peer::new("bob", [8, 8, 8, 8], MockStorage::empty());
+
+    alice.initialize();
+    alice.connected(bob.addr(), &alice.local_addr, Link::Inbound);
+    alice.receive(
+        &bob.addr(),
+        Message::Initialize {
+            id: bob.node_id(),
+            version: MIN_PROTOCOL_VERSION - 1,
+            min_version: MIN_PROTOCOL_VERSION - 1,
+            features: NODE_FEATURES,
+            addrs: vec![],
+            git: bob.git_url(),
+        },
+    );
+    assert_matches!(
+        alice.outbox().next(),
+        Some(Io::Disconnect(addr, DisconnectReason::Error(SessionError::WrongVersion(v))))
+        if addr == bob.addr() && v == MIN_PROTOCOL_VERSION - 1
+    );
+}
+
+#[test]
+fn test_peers_behind_same_ip() {
+    let mut alice = Peer::config(
+        "alice",
+        Config {
+            limits: Limits {
+                max_connections_per_ip: 2,
+                ..Limits::default()
+            },
+            ..Config::default()
+        },
+        [7, 7, 7, 7],
+        vec![],
+        MockStorage::empty(),
+        fastrand::Rng::new(),
+    );
+    let bob = Peer::new("bob", [8, 8, 8, 8], MockStorage::empty());
+    let eve = Peer::new("eve", [8, 8, 8, 8], MockStorage::empty());
+    let local = alice.local_addr;
+    let addrs = [
+        net::SocketAddr::from(([8, 8, 8, 8], 9000)),
+        net::SocketAddr::from(([8, 8, 8, 8], 9001)),
+        net::SocketAddr::from(([8, 8, 8, 8], 9002)),
+    ];
+
+    alice.initialize();
+    for (addr, peer) in addrs.iter().zip([&bob, &eve]) {
+        alice.connected(*addr, &local, Link::Inbound);
+        alice.receive(
+            addr,
+            Message::init(peer.node_id(), NODE_FEATURES, vec![], peer.git_url()),
+        );
+        alice.receive(addr, Message::ack(PROTOCOL_VERSION, NODE_FEATURES));
+    }
+    alice.outbox().for_each(drop);
+
+    // Both peers have their own session.
+    assert_matches!(
+        alice.sessions().by_id(&bob.node_id()),
+        Some(session) if session.addr == addrs[0]
+    );
+    assert_matches!(
+        alice.sessions().by_id(&eve.node_id()),
+        Some(session) if session.addr == addrs[1]
+    );
+
+    // Further connections from the same IP are refused.
+    alice.connected(addrs[2], &local, Link::Inbound);
+    assert_matches!(
+        alice.outbox().next(),
+        Some(Io::Disconnect(addr, DisconnectReason::Error(SessionError::ConnectionLimit(ip))))
+        if addr == addrs[2] && ip == addrs[2].ip()
+    );
+    assert!(alice.sessions().get(&addrs[2]).is_none());
+}
+
+#[test]
+fn test_handshake_negotiation() {
+    let mut alice = Peer::new("alice", [7, 7, 7, 7], MockStorage::empty());
+    let bob = Peer::new("bob", [8, 8, 8, 8], MockStorage::empty());
+
+    alice.initialize();
+    alice.connected(bob.addr(), &alice.local_addr, Link::Inbound);
+    // Bob supports a newer protocol version than Alice, but can still speak Alice's.
+    alice.receive(
+        &bob.addr(),
+        Message::Initialize {
+            id: bob.node_id(),
+            version: PROTOCOL_VERSION + 1,
+            min_version: PROTOCOL_VERSION,
+            features: [0xff; 32],
+            addrs: vec![],
+            git: bob.git_url(),
+        },
+    );
+
+    let mut msgs = alice.messages(&bob.addr());
+    assert_matches!(msgs.next(), Some(Message::Initialize { .. }));
+    assert_matches!(
+        msgs.next(),
+        Some(Message::InitializeAck { version, features })
+        if version == PROTOCOL_VERSION && features == NODE_FEATURES
+    );
+    assert_matches!(msgs.next(), None);
+    assert!(!alice.sessions().get(&bob.addr()).unwrap().is_negotiated());
+
+    // The session is only negotiated once Bob acknowledges.
+    alice.receive(&bob.addr(), Message::ack(PROTOCOL_VERSION, NODE_FEATURES));
+    assert_matches!(
+        alice.sessions().get(&bob.addr()).unwrap().state,
+        SessionState::Negotiated { version, .. } if version == PROTOCOL_VERSION
+    );
+    assert!(alice
+        .messages(&bob.addr())
+        .any(|m| matches!(m, Message::InventoryAnnouncement { .. })));
+}
+
+#[test]
+fn test_handshake_ack_mismatch() {
+    let mut alice = Peer::new("alice", [7, 7, 7, 7], MockStorage::empty());
+    let bob = Peer::new("bob", [8, 8, 8, 8], MockStorage::empty());
+
+    alice.initialize();
+    alice.connected(bob.addr(), &alice.local_addr, Link::Inbound);
+    alice.receive(
+        &bob.addr(),
+        Message::init(bob.node_id(), NODE_FEATURES, vec![], bob.git_url()),
+    );
+    alice.outbox().for_each(drop);
+    alice.receive(
+        &bob.addr(),
+        Message::ack(PROTOCOL_VERSION + 1, NODE_FEATURES),
+    );
+
+    assert_matches!(
+        alice.outbox().next(),
+        Some(Io::Disconnect(addr, DisconnectReason::Error(SessionError::Misbehavior)))
+        if addr == bob.addr()
+    );
+}
+
+#[test]
+#[ignore]
+fn test_wrong_peer_magic() {
     // TODO
 }
 
 #[test]
-#[ignore]
-fn test_wrong_peer_magic() {
-    // TODO
+fn test_inventory_sync() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut alice = Peer::new(
+        "alice",
+        [7, 7, 7, 7],
+        Storage::open(tmp.path().join("alice")).unwrap(),
+    );
+    let bob_signer = MockSigner::default();
+    let bob_storage = fixtures::storage(tmp.path().join("bob"), bob_signer).unwrap();
+    let bob = Peer::new("bob", [8, 8, 8, 8], bob_storage);
+    let now = LocalTime::now().as_secs();
+    let projs = bob.storage().inventory().unwrap();
+
+    alice.connect_to(&bob);
+    alice.receive(
+        &bob.addr(),
+        Message::inventory(
+            InventoryAnnouncement {
+                inventory: projs.clone(),
+                timestamp: now,
+                extensions: Extensions::default(),
+            },
+            bob.signer(),
+        )
+        .unwrap(),
+    );
+
+    for proj in &projs {
+        assert!(alice.routing().contains(proj, &bob.node_id()));
+    }
+
+    let a = alice
+        .storage()
+        .inventory()
+        .unwrap()
+        .into_iter()
+        .collect::<HashSet<_>>();
+    let b = projs.into_iter().collect::<HashSet<_>>();
+
+    assert_eq!(a, b);
+}
+
+#[test]
+fn test_inventory_pruning() {
+    let mut alice = Peer::new("alice", [7, 7, 7, 7], MockStorage::empty());
+    let bob = Peer::new("bob", [8, 8, 8, 8], MockStorage::empty());
+    let projs = test::arbitrary::set::<identity::Id>(3..=3)
+        .into_iter()
+        .collect::<Vec<_>>();
+    let now = alice.local_time().as_secs();
+
+    alice.connect_to(&bob);
+    alice.receive(
+        &bob.addr(),
+        Message::inventory(
+            InventoryAnnouncement {
+                inventory: projs.clone(),
+                timestamp: now,
+                extensions: Extensions::default(),
+            },
+            bob.signer(),
+        )
+        .unwrap(),
+    );
+    for proj in &projs {
+        assert!(alice.routing().contains(proj, &bob.node_id()));
+    }
+
+    // Bob no longer has the first project.
+    alice.receive(
+        &bob.addr(),
+        Message::inventory(
+            InventoryAnnouncement {
+                inventory: projs[1..].to_vec(),
+                timestamp: now + 1,
+                extensions: Extensions::default(),
+            },
+            bob.signer(),
+        )
+        .unwrap(),
+    );
+    assert!(!alice.routing().contains(&projs[0], &bob.node_id()));
+    assert!(alice.routing().contains(&projs[1], &bob.node_id()));
+
+    // After a while, Bob's entries expire, since he hasn't confirmed them.
+    alice.clock().elapse(alice.config().limits.routing_max_age);
+    alice.clock().elapse(PRUNE_INTERVAL);
+    alice.wake();
+    assert!(alice.routing().is_empty());
+}
+
+#[test]
+fn test_closest_peers() {
+    let projs = test::arbitrary::set::<identity::Id>(4..=4)
+        .into_iter()
+        .collect::<Vec<_>>();
+    let mut alice = Peer::config(
+        "alice",
+        Config {
+            project_tracking: ProjectTracking::Allowed(projs[..3].iter().cloned().collect()),
+            ..Config::default()
+        },
+        [7, 7, 7, 7],
+        vec![],
+        MockStorage::empty(),
+        fastrand::Rng::new(),
+    );
+    let bob = Peer::new("bob", [8, 8, 8, 8], MockStorage::empty());
+    let eve = Peer::new("eve", [9, 9, 9, 9], MockStorage::empty());
+    let carol = Peer::new("carol", [10, 10, 10, 10], MockStorage::empty());
+    let now = alice.local_time().as_secs();
+
+    alice.connect_to(&bob);
+
+    // Bob hosts all the projects Alice tracks, Eve hosts one of them, and Carol hosts
+    // none of them.
+    for (peer, inventory) in [
+        (&bob, projs[..3].to_vec()),
+        (&eve, projs[..1].to_vec()),
+        (&carol, projs[3..].to_vec()),
+    ] {
+        alice.receive(
+            &bob.addr(),
+            Message::inventory(
+                InventoryAnnouncement {
+                    inventory,
+                    timestamp: now,
+                    extensions: Extensions::default(),
+                },
+                peer.signer(),
+            )
+            .unwrap(),
+        );
+    }
+
+    assert_eq!(alice.closest_peers(3), vec![bob.node_id(), eve.node_id()]);
+    assert_eq!(alice.closest_peers(1), vec![bob.node_id()]);
+}
+
+#[test]
+fn test_fetch_concurrency() {
+    let projs = test::arbitrary::set::<identity::Id>(6..=6)
+        .into_iter()
+        .collect::<Vec<_>>();
+    let mut alice = Peer::config(
+        "alice",
+        Config {
+            project_tracking: ProjectTracking::Allowed(projs.iter().cloned().collect()),
+            ..Config::default()
+        },
+        [7, 7, 7, 7],
+        vec![],
+        MockStorage::empty(),
+        fastrand::Rng::new(),
+    );
+    let bob = Peer::new("bob", [8, 8, 8, 8], MockStorage::empty());
+    let concurrency = alice.config().limits.fetch_concurrency;
+    let now = alice.local_time().as_secs();
+
+    alice.connect_to(&bob);
+    alice.receive(
+        &bob.addr(),
+        Message::inventory(
+            InventoryAnnouncement {
+                inventory: projs.clone(),
+                timestamp: now,
+                extensions: Extensions::default(),
+            },
+            bob.signer(),
+        )
+        .unwrap(),
+    );
+
+    let fetching = alice
+        .outbox()
+        .filter_map(|o| match o {
+            Io::Fetch { remote, id, .. } if remote == bob.addr() => Some(id),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(fetching.len(), concurrency, "Fetches are limited in number");
+
+    // A failed fetch makes room for the next one.
+    alice.fetched(bob.addr(), fetching[0], Err(FetchError::NotConnected));
+    assert_eq!(
+        alice
+            .outbox()
+            .filter(|o| matches!(o, Io::Fetch { .. }))
+            .count(),
+        1
+    );
+}
+
+#[test]
+fn test_fetch_timeout() {
+    let proj = test::arbitrary::gen::<identity::Id>(1);
+    let mut alice = Peer::config(
+        "alice",
+        Config {
+            project_tracking: ProjectTracking::Allowed(Default::default()),
+            ..Config::default()
+        },
+        [7, 7, 7, 7],
+        vec![],
+        MockStorage::empty(),
+        fastrand::Rng::new(),
+    );
+    let bob = Peer::new("bob", [8, 8, 8, 8], MockStorage::empty());
+    let now = alice.local_time().as_secs();
+
+    // Alice learns that Bob seeds the project before tracking it, so that she doesn't
+    // fetch it right away.
+    alice.connect_to(&bob);
+    alice.receive(
+        &bob.addr(),
+        Message::inventory(
+            InventoryAnnouncement {
+                inventory: vec![proj],
+                timestamp: now,
+                extensions: Extensions::default(),
+            },
+            bob.signer(),
+        )
+        .unwrap(),
+    );
+    alice.command(Command::Track(proj, chan::bounded(1).0));
+
+    let (sender, receiver) = chan::bounded(1);
+    alice.command(Command::Fetch(proj, sender));
+
+    let results = match receiver.try_recv().unwrap() {
+        FetchLookup::Found { seeds, results } => {
+            assert_eq!(seeds.first(), &bob.addr());
+            results
+        }
+        other => panic!("Unexpected fetch lookup {:?}", other),
+    };
+    assert_matches!(
+        alice.outbox().find(|o| matches!(o, Io::Fetch { .. })),
+        Some(Io::Fetch { remote, id, .. }) if remote == bob.addr() && id == proj
+    );
+    assert!(results.try_recv().is_err(), "The fetch is still ongoing");
+
+    alice.clock().elapse(alice.config().limits.fetch_timeout);
+    alice.wake();
+
+    assert_matches!(
+        results.try_recv(),
+        Ok(FetchResult::Error {
+            error: FetchError::Timeout,
+            ..
+        })
+    );
+    assert_matches!(
+        alice.outbox().find(|o| matches!(o, Io::Cancel { .. })),
+        Some(Io::Cancel { remote, id }) if remote == bob.addr() && id == proj
+    );
+
+    // Until the cancelled fetch stops, the project isn't fetched again.
+    let (sender, _receiver) = chan::bounded(1);
+    alice.command(Command::Fetch(proj, sender));
+    assert_matches!(alice.outbox().find(|o| matches!(o, Io::Fetch { .. })), None);
+
+    // A late result is ignored, but lets the project be fetched again.
+    alice.fetched(bob.addr(), proj, Ok(vec![]));
+    assert!(alice.events().next().is_none());
+    assert_matches!(
+        alice.outbox().find(|o| matches!(o, Io::Fetch { .. })),
+        Some(Io::Fetch { remote, id, .. }) if remote == bob.addr() && id == proj
+    );
+}
+
+#[test]
+fn test_refs_announcement_fetch() {
+    let proj = test::arbitrary::gen::<identity::Id>(1);
+    let mut alice = Peer::config(
+        "alice",
+        Config {
+            project_tracking: ProjectTracking::Allowed([proj].into_iter().collect()),
+            ..Config::default()
+        },
+        [7, 7, 7, 7],
+        vec![],
+        MockStorage::empty(),
+        fastrand::Rng::new(),
+    );
+    let bob = Peer::new("bob", [8, 8, 8, 8], MockStorage::empty());
+    let message = RefsAnnouncement {
+        id: proj,
+        refs: test::arbitrary::gen(3),
+        timestamp: bob.timestamp(),
+        extensions: Extensions::default(),
+    };
+    let signature = message.sign(bob.signer()).unwrap();
+
+    alice.connect_to(&bob);
+    alice.receive(
+        &bob.addr(),
+        Message::RefsAnnouncement {
+            node: bob.node_id(),
+            message,
+            signature,
+        },
+    );
+
+    // Only Bob's refs are fetched, since that's what he announced.
+    assert_matches!(
+        alice.outbox().find(|o| matches!(o, Io::Fetch { .. })),
+        Some(Io::Fetch { remote, id, namespaces: fetch::Namespaces::Only(remotes) })
+        if remote == bob.addr() && id == proj && remotes == vec![bob.node_id()]
+    );
+}
+
+#[test]
+fn test_refs_announcement_up_to_date() {
+    let tmp = tempfile::tempdir().unwrap();
+    let bob = Peer::new("bob", [8, 8, 8, 8], MockStorage::empty());
+    let storage = fixtures::storage(tmp.path().join("alice"), bob.signer().clone()).unwrap();
+    let proj = *storage.inventory().unwrap().first().unwrap();
+    let refs: Refs = storage
+        .repository(proj)
+        .unwrap()
+        .remote(&bob.node_id())
+        .unwrap()
+        .refs
+        .into();
+    let announcement = |refs: Refs, timestamp: Timestamp| {
+        let message = RefsAnnouncement {
+            id: proj,
+            refs,
+            timestamp,
+            extensions: Extensions::default(),
+        };
+        let signature = message.sign(bob.signer()).unwrap();
+
+        Message::RefsAnnouncement {
+            node: bob.node_id(),
+            message,
+            signature,
+        }
+    };
+    let mut alice = Peer::new("alice", [7, 7, 7, 7], storage);
+
+    alice.connect_to(&bob);
+    alice.outbox().for_each(drop);
+
+    // We already have the refs Bob announced.
+    alice.receive(&bob.addr(), announcement(refs, bob.timestamp()));
+    assert_matches!(alice.outbox().find(|o| matches!(o, Io::Fetch { .. })), None);
+
+    // Bob's refs changed.
+    alice.receive(
+        &bob.addr(),
+        announcement(test::arbitrary::gen(3), bob.timestamp() + 1),
+    );
+    assert_matches!(
+        alice.outbox().find(|o| matches!(o, Io::Fetch { .. })),
+        Some(Io::Fetch { id, .. }) if id == proj
+    );
+}
+
+#[test]
+fn test_refs_announcement_replay() {
+    let proj = test::arbitrary::gen::<identity::Id>(1);
+    let mut alice = Peer::config(
+        "alice",
+        Config {
+            project_tracking: ProjectTracking::Allowed([proj].into_iter().collect()),
+            ..Config::default()
+        },
+        [7, 7, 7, 7],
+        vec![],
+        MockStorage::empty(),
+        fastrand::Rng::new(),
+    );
+    let bob = Peer::new("bob", [8, 8, 8, 8], MockStorage::empty());
+    let now = alice.local_time.as_secs();
+    let announcement = |timestamp| {
+        let message = RefsAnnouncement {
+            id: proj,
+            refs: test::arbitrary::gen(3),
+            timestamp,
+            extensions: Extensions::default(),
+        };
+        let signature = message.sign(bob.signer()).unwrap();
+
+        Message::RefsAnnouncement {
+            node: bob.node_id(),
+            message,
+            signature,
+        }
+    };
+
+    alice.connect_to(&bob);
+    alice.receive(&bob.addr(), announcement(now));
+    assert_matches!(
+        alice.outbox().find(|o| matches!(o, Io::Fetch { .. })),
+        Some(Io::Fetch { .. })
+    );
+
+    // Announcements that aren't newer than the last one we got from Bob are dropped.
+    alice.receive(&bob.addr(), announcement(now));
+    alice.receive(&bob.addr(), announcement(now - 1));
+    assert_matches!(alice.outbox().find(|o| matches!(o, Io::Fetch { .. })), None);
+
+    // Announcements from too far in the future aren't accepted.
+    let timestamp = now + MAX_TIME_DELTA.as_secs() + 1;
+    alice.receive(&bob.addr(), announcement(timestamp));
+    assert_matches!(
+        alice.outbox().next(),
+        Some(Io::Disconnect(addr, DisconnectReason::Error(SessionError::InvalidTimestamp(t))))
+        if addr == bob.addr() && t == timestamp
+    );
+}
+
+#[test]
+fn test_refs_announcement_expiry() {
+    let proj = test::arbitrary::gen::<identity::Id>(1);
+    let mut alice = Peer::config(
+        "alice",
+        Config {
+            project_tracking: ProjectTracking::Allowed([proj].into_iter().collect()),
+            ..Config::default()
+        },
+        [7, 7, 7, 7],
+        vec![],
+        MockStorage::empty(),
+        fastrand::Rng::new(),
+    );
+    let bob = Peer::new("bob", [8, 8, 8, 8], MockStorage::empty());
+    let now = alice.local_time.as_secs();
+    let message = RefsAnnouncement {
+        id: proj,
+        refs: test::arbitrary::gen(3),
+        timestamp: now,
+        extensions: Extensions::default(),
+    };
+    let signature = message.sign(bob.signer()).unwrap();
+    let announcement = Message::RefsAnnouncement {
+        node: bob.node_id(),
+        message,
+        signature,
+    };
+
+    alice.connect_to(&bob);
+    alice.receive(&bob.addr(), announcement.clone());
+    assert!(alice
+        .peer(&bob.node_id())
+        .map_or(false, |p| p.last_refs.contains_key(&proj)));
+    alice.outbox().for_each(drop);
+
+    // Once the announcement has expired, it's forgotten, and can't be replayed either.
+    let expiry = alice.config().limits.routing_max_age.as_secs() + MAX_TIME_DELTA.as_secs();
+    alice.clock().elapse(LocalDuration::from_secs(expiry + 1));
+    alice.wake();
+    assert!(alice
+        .peer(&bob.node_id())
+        .map_or(false, |p| p.last_refs.is_empty()));
+
+    alice.receive(&bob.addr(), announcement);
+    assert_matches!(alice.outbox().find(|o| matches!(o, Io::Fetch { .. })), None);
+    assert!(alice
+        .peer(&bob.node_id())
+        .map_or(false, |p| p.last_refs.is_empty()));
+}
+
+#[test]
+fn test_misbehaving_peer_banned() {
+    let mut alice = Peer::new("alice", [7, 7, 7, 7], MockStorage::empty());
+    let bob = Peer::new("bob", [8, 8, 8, 8], MockStorage::empty());
+    let now = alice.local_time();
+    let message = RefsAnnouncement {
+        id: test::arbitrary::gen(1),
+        refs: test::arbitrary::gen(3),
+        timestamp: now.as_secs(),
+        extensions: Extensions::default(),
+    };
+    // Signed by someone other than Bob.
+    let signature = message.sign(MockSigner::default()).unwrap();
+
+    alice.connect_to(&bob);
+    alice.receive(
+        &bob.addr(),
+        Message::RefsAnnouncement {
+            node: bob.node_id(),
+            message,
+            signature,
+        },
+    );
+    assert_matches!(
+        alice.outbox().next(),
+        Some(Io::Disconnect(addr, DisconnectReason::Error(SessionError::InvalidSignature)))
+        if addr == bob.addr()
+    );
+    assert!(alice
+        .addresses()
+        .is_misbehaving(&bob.ip, Some(&bob.node_id()), now));
+
+    // Bob's connections are now refused.
+    let local = alice.local_addr;
+    alice.disconnected(
+        &bob.addr(),
+        nakamoto::DisconnectReason::Protocol(DisconnectReason::Error(
+            SessionError::InvalidSignature,
+        )),
+    );
+    alice.connected(bob.addr(), &local, Link::Inbound);
+    assert_matches!(
+        alice.outbox().next(),
+        Some(Io::Disconnect(addr, DisconnectReason::Error(SessionError::Banned)))
+        if addr == bob.addr()
+    );
+}
+
+#[test]
+fn test_announce_refs_unknown() {
+    let tmp = tempfile::tempdir().unwrap();
+    let storage = fixtures::storage(tmp.path().join("alice"), MockSigner::default()).unwrap();
+    let proj = *storage.inventory().unwrap().first().unwrap();
+    let mut alice = Peer::new("alice", [7, 7, 7, 7], storage);
+    let bob = Peer::new("bob", [8, 8, 8, 8], MockStorage::empty());
+
+    alice.connect_to(&bob);
+    alice.outbox().for_each(drop);
+
+    // We don't have this project.
+    alice.command(Command::AnnounceRefs(test::arbitrary::gen(1)));
+    // We have this project, but not our own remote.
+    alice.command(Command::AnnounceRefs(proj));
+
+    assert_matches!(
+        alice
+            .messages(&bob.addr())
+            .find(|m| matches!(m, Message::RefsAnnouncement { .. })),
+        None
+    );
 }
 
 #[test]
-fn test_inventory_sync() {
+fn test_refs_announcement_remote_tracking() {
     let tmp = tempfile::tempdir().unwrap();
-    let mut alice = Peer::new(
+    let storage = fixtures::storage(tmp.path().join("alice"), MockSigner::default()).unwrap();
+    let proj = *storage.inventory().unwrap().first().unwrap();
+    let path = storage.path().to_path_buf();
+    let bob = Peer::new("bob", [8, 8, 8, 8], MockStorage::empty());
+    let message = RefsAnnouncement {
+        id: proj,
+        refs: test::arbitrary::gen(3),
+        timestamp: bob.timestamp(),
+        extensions: Extensions::default(),
+    };
+    let signature = message.sign(bob.signer()).unwrap();
+    let announcement = Message::RefsAnnouncement {
+        node: bob.node_id(),
+        message,
+        signature,
+    };
+
+    // Bob isn't a delegate of the project, so by default we don't track him.
+    let mut alice = Peer::new("alice", [7, 7, 7, 7], storage);
+    alice.connect_to(&bob);
+    alice.receive(&bob.addr(), announcement.clone());
+    assert_matches!(alice.outbox().find(|o| matches!(o, Io::Fetch { .. })), None);
+
+    // Unless we track all remotes.
+    let mut alice = Peer::config(
         "alice",
+        Config {
+            remote_tracking: RemoteTracking::All {
+                blocked: HashSet::default(),
+            },
+            ..Config::default()
+        },
         [7, 7, 7, 7],
-        Storage::open(tmp.path().join("alice")).unwrap(),
+        vec![],
+        Storage::open(path).unwrap(),
+        fastrand::Rng::new(),
     );
-    let bob_signer = MockSigner::default();
-    let bob_storage = fixtures::storage(tmp.path().join("bob"), bob_signer).unwrap();
-    let bob = Peer::new("bob", [8, 8, 8, 8], bob_storage);
-    let now = LocalTime::now().as_secs();
-    let projs = bob.storage().inventory().unwrap();
-
     alice.connect_to(&bob);
-    alice.receive(
-        &bob.addr(),
-        Message::inventory(
-            InventoryAnnouncement {
-                inventory: projs.clone(),
-                timestamp: now,
-            },
-            bob.signer(),
-        ),
+    alice.receive(&bob.addr(), announcement);
+    assert_matches!(
+        alice.outbox().find(|o| matches!(o, Io::Fetch { .. })),
+        Some(Io::Fetch { remote, id, .. }) if remote == bob.addr() && id == proj
     );
+}
 
-    for proj in &projs {
-        let seeds = alice.routing().get(proj).unwrap();
-        assert!(seeds.contains(&bob.node_id()));
-    }
+#[test]
+fn test_persistent_routing() {
+    let tmp = tempfile::tempdir().unwrap();
+    let path = tmp.path().join("cache.json");
+    let proj = test::arbitrary::gen::<identity::Id>(1);
+    let bob = test::arbitrary::gen::<NodeId>(1);
+    let rng = fastrand::Rng::new();
+    let time = LocalTime::now();
 
-    let a = alice
-        .storage()
-        .inventory()
+    // Populate the cache, as if it was written by a previous run of the node.
+    let mut snapshot = cache::Snapshot::default();
+    snapshot
+        .routing
+        .entry(proj)
+        .or_default()
+        .insert(bob, time.as_secs());
+    cache::Cache::create(&path)
         .unwrap()
-        .into_iter()
-        .collect::<HashSet<_>>();
-    let b = projs.into_iter().collect::<HashSet<_>>();
+        .write(&snapshot)
+        .unwrap();
 
-    assert_eq!(a, b);
+    let mut alice = service::Service::new(
+        Config::default(),
+        clock::RefClock::from(time),
+        MockStorage::empty(),
+        HashMap::<Address, KnownAddress>::with_hasher(rng.clone().into()),
+        Some(cache::Cache::open(&path).unwrap()),
+        MockSigner::default(),
+        rng,
+    );
+    alice.initialize(time);
+
+    assert_eq!(
+        alice.lookup(proj).remote,
+        vec![bob],
+        "The routing table is restored from the cache"
+    );
+
+    // Once flushed, the cache reflects the current routing table.
+    alice.clock().elapse(FLUSH_INTERVAL);
+    alice.wake();
+
+    let snapshot = cache::Cache::open(&path).unwrap().read().unwrap();
+    assert!(snapshot.routing[&proj].contains_key(&bob));
 }
 
 #[test]
@@ -194,15 +1055,18 @@ fn test_inventory_relay_bad_timestamp() {
     let timestamp = alice.local_time.as_secs() + two_hours;
 
     alice.connect_to(&bob);
+    alice.outbox().for_each(drop);
     alice.receive(
         &bob.addr(),
         Message::inventory(
             InventoryAnnouncement {
                 inventory: vec![],
                 timestamp,
+                extensions: Extensions::default(),
             },
             bob.signer(),
-        ),
+        )
+        .unwrap(),
     );
     assert_matches!(
         alice.outbox().next(),
@@ -211,6 +1075,103 @@ fn test_inventory_relay_bad_timestamp() {
     );
 }
 
+#[test]
+fn test_subscribe_replay() {
+    let mut alice = Peer::new("alice", [7, 7, 7, 7], MockStorage::empty());
+    let bob = Peer::new("bob", [8, 8, 8, 8], MockStorage::empty());
+    let eve = Peer::new("eve", [9, 9, 9, 9], MockStorage::empty());
+    let now = alice.local_time.as_secs();
+    let inventory = Message::inventory(
+        InventoryAnnouncement {
+            inventory: test::arbitrary::set::<identity::Id>(3..=3)
+                .into_iter()
+                .collect(),
+            timestamp: now - 10,
+            extensions: Extensions::default(),
+        },
+        bob.signer(),
+    )
+    .unwrap();
+
+    alice.connect_to(&bob);
+    alice.connect_to(&eve);
+    alice.receive(&bob.addr(), inventory.clone());
+    // Drop the relayed announcement.
+    alice.messages(&eve.addr()).for_each(drop);
+
+    // Eve subscribes to messages sent before Bob's announcement.
+    alice.receive(
+        &eve.addr(),
+        Message::subscribe(Filter::default(), now - 20, now - 15),
+    );
+    assert_matches!(alice.messages(&eve.addr()).next(), None);
+
+    // Eve subscribes again too soon, and nothing is replayed.
+    alice.receive(
+        &eve.addr(),
+        Message::subscribe(Filter::default(), now - 20, u64::MAX),
+    );
+    assert_matches!(alice.messages(&eve.addr()).next(), None);
+
+    // Eve subscribes to messages sent while she was offline.
+    alice.clock().elapse(REPLAY_INTERVAL);
+    alice.receive(
+        &eve.addr(),
+        Message::subscribe(Filter::default(), now - 20, u64::MAX),
+    );
+    assert!(alice.messages(&eve.addr()).any(|m| m == inventory));
+
+    // Bob isn't sent his own announcements.
+    alice.receive(
+        &bob.addr(),
+        Message::subscribe(Filter::default(), now - 20, u64::MAX),
+    );
+    assert!(!alice.messages(&bob.addr()).any(|m| m == inventory));
+}
+
+#[test]
+fn test_subscribe_replay_limit() {
+    let mut alice = Peer::new("alice", [7, 7, 7, 7], MockStorage::empty());
+    let bob = Peer::new("bob", [8, 8, 8, 8], MockStorage::empty());
+    let eve = Peer::new("eve", [9, 9, 9, 9], MockStorage::empty());
+    let now = alice.local_time.as_secs();
+
+    alice.connect_to(&bob);
+    alice.connect_to(&eve);
+
+    for i in 0..MAX_REPLAY_SIZE as u64 + 1 {
+        let signer = MockSigner::new(&mut alice.rng);
+        let inventory = Message::inventory(
+            InventoryAnnouncement {
+                inventory: vec![],
+                timestamp: now - 10 + i,
+                extensions: Extensions::default(),
+            },
+            &signer,
+        )
+        .unwrap();
+        alice.receive(&bob.addr(), inventory);
+    }
+    alice.messages(&eve.addr()).for_each(drop);
+
+    // Only the latest announcements are replayed.
+    alice.receive(
+        &eve.addr(),
+        Message::subscribe(Filter::default(), 0, u64::MAX),
+    );
+    let replayed = alice
+        .messages(&eve.addr())
+        .filter_map(|m| match m {
+            Message::InventoryAnnouncement { message, .. } => Some(message.timestamp),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(replayed.len(), MAX_REPLAY_SIZE);
+    assert_eq!(replayed.first(), Some(&(now - 9)));
+    assert_eq!(replayed.last(), Some(&(now - 10 + MAX_REPLAY_SIZE as u64)));
+}
+
 #[test]
 fn test_inventory_relay() {
     // Topology is eve <-> alice <-> bob
@@ -229,9 +1190,11 @@ fn test_inventory_relay() {
             InventoryAnnouncement {
                 inventory: inv.clone(),
                 timestamp: now,
+                extensions: Extensions::default(),
             },
             bob.signer(),
-        ),
+        )
+        .unwrap(),
     );
     assert_matches!(
         alice.messages(&eve.addr()).next(),
@@ -250,9 +1213,11 @@ fn test_inventory_relay() {
             InventoryAnnouncement {
                 inventory: inv.clone(),
                 timestamp: now,
+                extensions: Extensions::default(),
             },
             bob.signer(),
-        ),
+        )
+        .unwrap(),
     );
     assert_matches!(
         alice.messages(&eve.addr()).next(),
@@ -266,9 +1231,11 @@ fn test_inventory_relay() {
             InventoryAnnouncement {
                 inventory: inv.clone(),
                 timestamp: now + 1,
+                extensions: Extensions::default(),
             },
             bob.signer(),
-        ),
+        )
+        .unwrap(),
     );
     assert_matches!(
         alice.messages(&eve.addr()).next(),
@@ -284,9 +1251,11 @@ fn test_inventory_relay() {
             InventoryAnnouncement {
                 inventory: inv,
                 timestamp: now,
+                extensions: Extensions::default(),
             },
             eve.signer(),
-        ),
+        )
+        .unwrap(),
     );
     assert_matches!(
         alice.messages(&bob.addr()).next(),
@@ -295,6 +1264,227 @@ fn test_inventory_relay() {
     );
 }
 
+#[test]
+fn test_node_announcement() {
+    // Topology is eve <-> alice <-> bob
+    let mut alice = Peer::new("alice", [7, 7, 7, 7], MockStorage::empty());
+    let bob = Peer::new("bob", [8, 8, 8, 8], MockStorage::empty());
+    let eve = Peer::new("eve", [9, 9, 9, 9], MockStorage::empty());
+    let carol = net::SocketAddr::from(([10, 10, 10, 10], DEFAULT_PORT));
+    let now = LocalTime::now().as_secs();
+    let announcement = |timestamp, alias: &str| {
+        let mut bytes = [0; 32];
+        bytes[..alias.len()].copy_from_slice(alias.as_bytes());
+
+        NodeAnnouncement {
+            features: NodeFeatures::default(),
+            timestamp,
+            alias: bytes,
+            addresses: vec![Address::from(carol)],
+            extensions: Extensions::default(),
+        }
+    };
+
+    alice.connect_to(&bob);
+    alice.connect_from(&eve);
+    alice.receive(
+        &bob.addr(),
+        Message::node(announcement(now, "carol"), bob.signer()).unwrap(),
+    );
+    assert_matches!(
+        alice.messages(&eve.addr()).next(),
+        Some(Message::NodeAnnouncement { node, message: NodeAnnouncement { timestamp, .. }, .. })
+        if node == bob.node_id() && timestamp == now,
+        "The announcement is relayed to Eve"
+    );
+    assert_eq!(
+        alice.peer(&bob.node_id()).map(|p| p.alias.as_str()),
+        Some("carol")
+    );
+    assert_eq!(
+        alice.addresses().get(&carol.into()).map(|ka| ka.source),
+        Some(Source::Peer(bob.addr())),
+        "The announced address is added to Alice's address book"
+    );
+
+    alice.receive(
+        &bob.addr(),
+        Message::node(announcement(now, "carol"), bob.signer()).unwrap(),
+    );
+    assert_matches!(
+        alice.messages(&eve.addr()).next(),
+        None,
+        "Sending the same announcement again doesn't trigger a relay"
+    );
+
+    alice.receive(
+        &bob.addr(),
+        Message::node(announcement(now + 1, "bob"), bob.signer()).unwrap(),
+    );
+    assert_matches!(
+        alice.messages(&eve.addr()).next(),
+        Some(Message::NodeAnnouncement { .. }),
+        "Sending a newer announcement does trigger the relay"
+    );
+    assert_eq!(
+        alice.peer(&bob.node_id()).map(|p| p.alias.as_str()),
+        Some("bob")
+    );
+}
+
+#[test]
+fn test_node_announcement_hostname() {
+    let mut alice = Peer::new("alice", [7, 7, 7, 7], MockStorage::empty());
+    let bob = Peer::new("bob", [8, 8, 8, 8], MockStorage::empty());
+    let host = Address::from_str("seed.radicle.xyz:8776").unwrap();
+    let onion = Address::onion(test::arbitrary::gen(1), DEFAULT_PORT);
+    let now = LocalTime::now().as_secs();
+    let mut alias = [0; 32];
+    alias[..4].copy_from_slice(b"seed");
+
+    alice.connect_to(&bob);
+    alice.receive(
+        &bob.addr(),
+        Message::node(
+            NodeAnnouncement {
+                features: NodeFeatures::default(),
+                timestamp: now,
+                alias,
+                addresses: vec![host.clone(), onion.clone()],
+                extensions: Extensions::default(),
+            },
+            bob.signer(),
+        )
+        .unwrap(),
+    );
+    assert!(
+        alice.addresses().get(&host).is_some(),
+        "The announced hostname is added to Alice's address book"
+    );
+    assert!(
+        alice.addresses().get(&onion).is_none(),
+        "Onion addresses can't be reached without a proxy"
+    );
+
+    // Alice is looking for more peers, and connects to the announced hostname.
+    alice.outbox().for_each(drop);
+    alice
+        .clock()
+        .elapse(LocalDuration::from_secs(IDLE_INTERVAL.as_secs() + 1));
+    alice.wake();
+    assert!(alice.outbox().any(|o| matches!(
+        o,
+        Io::Resolve { host: h, port } if Address::Hostname { host: h.clone(), port } == host
+    )));
+}
+
+#[test]
+fn test_proxied_peers() {
+    let proxy = net::SocketAddr::from(([127, 0, 0, 1], 9050));
+    let bob = Peer::new("bob", [8, 8, 8, 8], MockStorage::empty());
+    let eve = Peer::new("eve", [9, 9, 9, 9], MockStorage::empty());
+    let mut alice = Peer::config(
+        "alice",
+        Config {
+            proxy: Some(Proxy {
+                addr: proxy,
+                all: true,
+            }),
+            ..Config::default()
+        },
+        [7, 7, 7, 7],
+        vec![],
+        MockStorage::empty(),
+        fastrand::Rng::new(),
+    );
+    alice.initialize();
+    alice.outbox().for_each(drop);
+
+    alice.command(Command::Connect(bob.addr()));
+    alice.command(Command::Connect(eve.addr()));
+    alice.command(Command::Connect(bob.addr()));
+
+    let targets = alice
+        .outbox()
+        .filter_map(|o| match o {
+            Io::Relay { proxy: p, target } if p == proxy => Some(target),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        targets,
+        vec![bob.address(), eve.address()],
+        "Each peer is relayed to once"
+    );
+    assert_eq!(alice.reactor().pending(), 2);
+
+    // Both relays are listening, and Alice connects to both peers at the same time.
+    let relays = [
+        net::SocketAddr::from(([127, 0, 0, 1], 40001)),
+        net::SocketAddr::from(([127, 0, 0, 1], 40002)),
+    ];
+    alice.relayed(bob.address(), Ok(relays[0]));
+    alice.relayed(eve.address(), Ok(relays[1]));
+    assert_eq!(alice.reactor().pending(), 0);
+
+    let connects = alice
+        .outbox()
+        .filter_map(|o| match o {
+            Io::Connect(addr) => Some(addr),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(connects, relays.to_vec());
+
+    let local = alice.local_addr;
+    for relay in &relays {
+        alice.attempted(relay);
+        alice.connected(*relay, &local, Link::Outbound);
+    }
+    assert_eq!(alice.proxied(&relays[0]), Some(&bob.address()));
+    assert_eq!(alice.proxied(&relays[1]), Some(&eve.address()));
+    assert_eq!(alice.sessions().outbound().count(), 2);
+    assert!(alice.sessions().is_connected_to(&bob.address()));
+    assert!(alice.sessions().is_connected_to(&eve.address()));
+}
+
+#[test]
+fn test_proxied_peer_failed() {
+    let proxy = net::SocketAddr::from(([127, 0, 0, 1], 9050));
+    let bob = Peer::new("bob", [8, 8, 8, 8], MockStorage::empty());
+    let mut alice = Peer::config(
+        "alice",
+        Config {
+            proxy: Some(Proxy {
+                addr: proxy,
+                all: true,
+            }),
+            ..Config::default()
+        },
+        [7, 7, 7, 7],
+        vec![],
+        MockStorage::empty(),
+        fastrand::Rng::new(),
+    );
+    alice.initialize();
+
+    alice.command(Command::Connect(bob.addr()));
+    assert!(alice.reactor().is_pending(&bob.address()));
+
+    // The relay couldn't be set up: Bob is no longer pending, and can be dialed again.
+    alice.relayed(
+        bob.address(),
+        Err(io::Error::from(io::ErrorKind::AddrNotAvailable)),
+    );
+    assert_eq!(alice.reactor().pending(), 0);
+
+    alice.outbox().for_each(drop);
+    alice.command(Command::Connect(bob.addr()));
+    assert!(alice
+        .outbox()
+        .any(|o| matches!(o, Io::Relay { target, .. } if target == bob.address())));
+}
+
 #[test]
 fn test_persistent_peer_reconnect() {
     let mut bob = Peer::new("bob", [8, 8, 8, 8], MockStorage::empty());
@@ -323,7 +1513,7 @@ fn test_persistent_peer_reconnect() {
     let ips = alice
         .sessions()
         .negotiated()
-        .map(|(ip, _)| *ip)
+        .map(|(addr, _)| addr.ip())
         .collect::<Vec<_>>();
     assert!(ips.contains(&bob.ip));
     assert!(ips.contains(&eve.ip));
@@ -339,27 +1529,112 @@ fn test_persistent_peer_reconnect() {
     // a reconnection.
     alice.disconnected(
         &eve.addr(),
-        nakamoto::DisconnectReason::DialError(error.clone()),
+        nakamoto::DisconnectReason::Protocol(DisconnectReason::User),
     );
     assert_matches!(alice.outbox().next(), None);
 
-    for _ in 0..MAX_CONNECTION_ATTEMPTS {
+    // Reconnections are delayed, and the delay grows with every failed attempt, up to a
+    // maximum. Alice never gives up on Bob.
+    for attempt in 0..16 {
+        let max = RECONNECT_BACKOFF_BASE
+            .as_millis()
+            .saturating_mul(1 << attempt)
+            .min(RECONNECT_BACKOFF_MAX.as_millis());
+
         alice.disconnected(
             &bob.addr(),
             nakamoto::DisconnectReason::ConnectionError(error.clone()),
         );
-        assert_matches!(alice.outbox().next(), Some(Io::Connect(a)) if a == bob.addr());
-        assert_matches!(alice.outbox().next(), None);
+        let delay = match alice.outbox().next() {
+            Some(Io::Wakeup(delay)) => delay,
+            other => panic!("Unexpected output {:?}", other),
+        };
+        assert!(delay.as_millis() <= max && delay.as_millis() >= max / 2);
+        assert_matches!(
+            alice.sessions().get(&bob.addr()).map(|s| &s.state),
+            Some(SessionState::Disconnected {
+                retry_at: Some(_),
+                ..
+            })
+        );
+        assert!(!alice.outbox().any(|o| matches!(o, Io::Connect(_))));
+
+        alice.clock().elapse(delay);
+        alice.wake();
+        assert!(alice
+            .outbox()
+            .collect::<Vec<_>>()
+            .iter()
+            .any(|o| matches!(o, Io::Connect(a) if *a == bob.addr())));
 
         alice.attempted(&bob.addr());
     }
 
-    // After the max connection attempts, a disconnect doesn't trigger a reconnect.
+    // Once reconnected and the handshake completed, the delay is reset.
+    alice.connect_to(&bob);
+    alice.outbox().for_each(drop);
     alice.disconnected(
         &bob.addr(),
         nakamoto::DisconnectReason::ConnectionError(error),
     );
-    assert_matches!(alice.outbox().next(), None);
+    assert_matches!(
+        alice.outbox().next(),
+        Some(Io::Wakeup(delay)) if delay.as_millis() <= RECONNECT_BACKOFF_BASE.as_millis()
+    );
+}
+
+#[test]
+fn test_persistent_peer_reconnect_before_handshake() {
+    let bob = Peer::new("bob", [8, 8, 8, 8], MockStorage::empty());
+    let mut alice = Peer::config(
+        "alice",
+        Config {
+            connect: vec![bob.address()],
+            ..Config::default()
+        },
+        [7, 7, 7, 7],
+        vec![],
+        MockStorage::empty(),
+        fastrand::Rng::new(),
+    );
+    let local = alice.local_addr;
+    let error = Arc::new(io::Error::from(io::ErrorKind::ConnectionReset));
+
+    alice.initialize();
+    alice.outbox().for_each(drop);
+
+    // Bob accepts the connection every time, but drops it before the handshake
+    // completes. This doesn't count as a successful connection, so the delay keeps
+    // growing.
+    for attempt in 1..8 {
+        let min = RECONNECT_BACKOFF_BASE
+            .as_millis()
+            .saturating_mul(1 << attempt)
+            .min(RECONNECT_BACKOFF_MAX.as_millis())
+            / 2;
+
+        alice.attempted(&bob.addr());
+        alice.connected(bob.addr(), &local, Link::Outbound);
+        alice.outbox().for_each(drop);
+        alice.disconnected(
+            &bob.addr(),
+            nakamoto::DisconnectReason::ConnectionError(error.clone()),
+        );
+        assert_matches!(
+            alice.outbox().next(),
+            Some(Io::Wakeup(delay)) if delay.as_millis() >= min
+        );
+    }
+
+    // The retry state is reported to the user.
+    let (sender, receiver) = chan::bounded(1);
+    alice.command(Command::Sessions(sender));
+
+    let sessions = receiver.recv().unwrap();
+    assert_matches!(
+        sessions.as_slice(),
+        [session] if session.addr == bob.addr() && session.attempts == 7 && session.retry_at.is_some()
+    );
 }
 
 #[test]
@@ -434,7 +1709,7 @@ fn test_push_and_pull() {
     assert_matches!(
         sim.events(&bob.ip).next(),
         Some(service::Event::RefsFetched { from, .. })
-        if from == eve.git_url(),
+        if from == eve.addr(),
         "Bob fetched from Eve"
     );
 }
@@ -446,7 +1721,8 @@ fn prop_inventory_exchange_dense() {
         let alice = Peer::new("alice", [7, 7, 7, 7], alice_inv.clone());
         let mut bob = Peer::new("bob", [8, 8, 8, 8], bob_inv.clone());
         let mut eve = Peer::new("eve", [9, 9, 9, 9], eve_inv.clone());
-        let mut routing = Routing::with_hasher(rng.clone().into());
+        let mut routing: HashMap<identity::Id, HashSet<NodeId>> =
+            HashMap::with_hasher(rng.clone().into());
 
         for (inv, peer) in &[
             (alice_inv.inventory, alice.node_id()),
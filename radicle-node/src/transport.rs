@@ -1,25 +1,233 @@
-use std::net;
+//! Encrypted and authenticated peer transport.
+//!
+//! When a connection is established, both sides send each other an ephemeral X25519 key,
+//! from which symmetric session keys are derived. Each side then proves ownership of its
+//! node id by signing the handshake transcript with its node key. Only once the handshake
+//! is complete is the connection handed over to the inner protocol. From then on, all data
+//! is sent in encrypted, length-prefixed frames.
+//!
+//! Connections made through a SOCKS5 proxy first complete the proxy handshake, before
+//! the ephemeral keys are sent.
+pub mod relay;
+pub mod socks5;
+
+use std::collections::VecDeque;
 use std::ops::{Deref, DerefMut};
+use std::{fmt, io, net};
 
-use nakamoto::LocalTime;
+use byteorder::{BigEndian, ByteOrder};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use ed25519_compact::x25519;
+use nakamoto::{LocalDuration, LocalTime};
 use nakamoto_net as nakamoto;
 use nakamoto_net::{Io, Link};
 
 use crate::address_book;
 use crate::collections::HashMap;
 use crate::crypto;
-use crate::service::{Command, DisconnectReason, Event, Service};
+use crate::crypto::{Signature, Signer};
+use crate::hash::Digest;
+use crate::service::{Command, DisconnectReason, Event, NodeId, Service};
 use crate::storage::WriteStorage;
-use crate::wire::Wire;
+use crate::wire;
+use crate::wire::{Decode, Wire};
+
+/// How long a peer has to complete the handshake before we disconnect.
+pub const HANDSHAKE_TIMEOUT: LocalDuration = LocalDuration::from_secs(10);
+/// Maximum size of the plaintext carried in a single frame.
+pub const MAX_FRAME_SIZE: usize = u16::MAX as usize - TAG_SIZE;
+
+/// Protocol name, mixed into the handshake transcript and key derivation.
+const PROTOCOL_NAME: &[u8] = b"radicle-transport-v1";
+/// Size of an ephemeral public key.
+const KEY_SIZE: usize = 32;
+/// Size of the authentication tag appended to every frame.
+const TAG_SIZE: usize = 16;
+/// Size of a frame's length prefix.
+const LENGTH_SIZE: usize = 2;
+
+/// A transport error.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    #[error("invalid ephemeral key")]
+    InvalidKey,
+    #[error("invalid authentication message")]
+    InvalidAuth,
+    #[error("invalid signature from {0}")]
+    InvalidSignature(NodeId),
+    #[error("frame decryption failed")]
+    Decryption,
+    #[error("handshake timed out")]
+    Timeout,
+    #[error("proxy error: {0}")]
+    Proxy(#[from] socks5::Error),
+}
+
+/// The role a peer plays in the handshake.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Role {
+    /// The peer that opened the connection.
+    Initiator,
+    /// The peer that accepted the connection.
+    Responder,
+}
+
+impl Role {
+    fn as_bytes(&self) -> &'static [u8] {
+        match self {
+            Self::Initiator => b"initiator",
+            Self::Responder => b"responder",
+        }
+    }
+
+    fn other(&self) -> Self {
+        match self {
+            Self::Initiator => Self::Responder,
+            Self::Responder => Self::Initiator,
+        }
+    }
+}
+
+impl From<Link> for Role {
+    fn from(link: Link) -> Self {
+        if link.is_outbound() {
+            Self::Initiator
+        } else {
+            Self::Responder
+        }
+    }
+}
+
+/// Symmetric cipher for one direction of a connection.
+struct Cipher {
+    aead: ChaCha20Poly1305,
+    nonce: u64,
+}
+
+impl Cipher {
+    fn new(key: &Digest) -> Self {
+        let key: &[u8; 32] = key.as_ref();
+
+        Self {
+            aead: ChaCha20Poly1305::new(Key::from_slice(key)),
+            nonce: 0,
+        }
+    }
+
+    /// Get the next nonce. Nonces are never re-used with the same key.
+    fn next_nonce(&mut self) -> [u8; 12] {
+        let mut nonce = [0; 12];
+        nonce[4..].copy_from_slice(&self.nonce.to_le_bytes());
+        self.nonce += 1;
+
+        nonce
+    }
+
+    /// Encrypt data into one or more length-prefixed frames.
+    fn seal(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let mut frames = Vec::with_capacity(plaintext.len() + LENGTH_SIZE + TAG_SIZE);
+
+        for chunk in plaintext.chunks(MAX_FRAME_SIZE) {
+            let nonce = self.next_nonce();
+            let ciphertext = self
+                .aead
+                .encrypt(Nonce::from_slice(&nonce), chunk)
+                .expect("encrypting a frame never fails");
+            let mut len = [0; LENGTH_SIZE];
+
+            BigEndian::write_u16(&mut len, ciphertext.len() as u16);
+            frames.extend_from_slice(&len);
+            frames.extend_from_slice(&ciphertext);
+        }
+        frames
+    }
+
+    /// Decrypt the next frame in the buffer, if it's complete.
+    fn open(&mut self, buffer: &mut Vec<u8>) -> Result<Option<Vec<u8>>, Error> {
+        if buffer.len() < LENGTH_SIZE {
+            return Ok(None);
+        }
+        let len = BigEndian::read_u16(&buffer[..LENGTH_SIZE]) as usize;
+        if buffer.len() < LENGTH_SIZE + len {
+            return Ok(None);
+        }
+        let frame = buffer.drain(..LENGTH_SIZE + len).collect::<Vec<_>>();
+        let nonce = self.next_nonce();
+        let plaintext = self
+            .aead
+            .decrypt(Nonce::from_slice(&nonce), &frame[LENGTH_SIZE..])
+            .map_err(|_| Error::Decryption)?;
+
+        Ok(Some(plaintext))
+    }
+}
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cipher")
+            .field("nonce", &self.nonce)
+            .finish()
+    }
+}
+
+/// Peer connection state.
+#[derive(Debug)]
+enum State {
+    /// Waiting for the proxy to connect us to the remote.
+    Proxying { handshake: socks5::Handshake },
+    /// Waiting for the remote's ephemeral key.
+    AwaitingKey { ephemeral: x25519::KeyPair },
+    /// Keys were exchanged, and all traffic is encrypted.
+    Encrypted {
+        send: Cipher,
+        recv: Cipher,
+        remote: Remote,
+    },
+}
+
+/// Authentication state of the remote peer.
+#[derive(Debug)]
+enum Remote {
+    /// Waiting for the remote to sign the handshake transcript.
+    Unauthenticated { transcript: Digest },
+    /// The remote proved that it owns the given node id.
+    Authenticated { id: NodeId },
+}
 
 #[derive(Debug)]
 struct Peer {
+    /// Remote address.
     addr: net::SocketAddr,
+    /// Local address of the connection.
+    local_addr: net::SocketAddr,
+    /// Connection direction.
+    link: Link,
+    /// Connection state.
+    state: State,
+    /// When the connection was established.
+    since: LocalTime,
+    /// Received bytes that haven't been processed yet.
+    buffer: Vec<u8>,
+}
+
+impl Peer {
+    /// Whether the handshake with this peer is complete.
+    fn is_established(&self) -> bool {
+        matches!(
+            self.state,
+            State::Encrypted {
+                remote: Remote::Authenticated { .. },
+                ..
+            }
+        )
+    }
 }
 
 #[derive(Debug)]
 pub struct Transport<S, T, G> {
-    peers: HashMap<net::IpAddr, Peer>,
+    peers: HashMap<net::SocketAddr, Peer>,
+    outbox: VecDeque<Io<Event, DisconnectReason>>,
     inner: Wire<S, T, G>,
 }
 
@@ -27,14 +235,125 @@ impl<S, T, G> Transport<S, T, G> {
     pub fn new(inner: Wire<S, T, G>) -> Self {
         Self {
             peers: HashMap::default(),
+            outbox: VecDeque::new(),
             inner,
         }
     }
 }
 
+impl<S, T, G> Transport<S, T, G>
+where
+    T: WriteStorage + 'static,
+    T::Repository: Send + 'static,
+    S: address_book::Store,
+    G: crypto::Signer,
+{
+    /// Process buffered data received from a peer.
+    fn process(&mut self, addr: &net::SocketAddr) -> Result<(), Error> {
+        loop {
+            let peer = if let Some(peer) = self.peers.get_mut(addr) {
+                peer
+            } else {
+                return Ok(());
+            };
+
+            match &mut peer.state {
+                State::Proxying { handshake } => match handshake.process(&mut peer.buffer)? {
+                    Some(socks5::Progress::Send(data)) => {
+                        self.outbox.push_back(Io::Write(*addr, data));
+                    }
+                    Some(socks5::Progress::Connected) => {
+                        log::debug!("Connected to {} through proxy {}", handshake.target(), addr);
+
+                        let ephemeral = x25519::KeyPair::generate();
+
+                        self.outbox
+                            .push_back(Io::Write(*addr, ephemeral.pk.as_slice().to_vec()));
+                        peer.state = State::AwaitingKey { ephemeral };
+                    }
+                    None => return Ok(()),
+                },
+                State::AwaitingKey { ephemeral } => {
+                    if peer.buffer.len() < KEY_SIZE {
+                        return Ok(());
+                    }
+                    let remote = peer.buffer.drain(..KEY_SIZE).collect::<Vec<_>>();
+                    let remote =
+                        x25519::PublicKey::from_slice(&remote).map_err(|_| Error::InvalidKey)?;
+                    let shared = remote.dh(&ephemeral.sk).map_err(|_| Error::InvalidKey)?;
+                    let role = Role::from(peer.link);
+                    let (initiator, responder) = match role {
+                        Role::Initiator => (ephemeral.pk.as_slice(), remote.as_slice()),
+                        Role::Responder => (remote.as_slice(), ephemeral.pk.as_slice()),
+                    };
+                    let transcript = Digest::new([PROTOCOL_NAME, initiator, responder].concat());
+                    let t: &[u8; 32] = transcript.as_ref();
+                    let derive = |label: &[u8]| {
+                        Digest::new([PROTOCOL_NAME, shared.as_slice(), t, label].concat())
+                    };
+                    let (i2r, r2i) = (derive(b"i2r"), derive(b"r2i"));
+                    let (mut send, recv) = match role {
+                        Role::Initiator => (Cipher::new(&i2r), Cipher::new(&r2i)),
+                        Role::Responder => (Cipher::new(&r2i), Cipher::new(&i2r)),
+                    };
+
+                    // Prove that we own our node id, by signing the transcript.
+                    let signer = self.inner.signer();
+                    let signature = signer.sign(&[t, role.as_bytes()].concat());
+                    let mut auth =
+                        wire::serialize(signer.public_key()).expect("public keys are fixed-size");
+                    auth.extend(wire::serialize(&signature).expect("signatures are fixed-size"));
+
+                    self.outbox.push_back(Io::Write(*addr, send.seal(&auth)));
+                    peer.state = State::Encrypted {
+                        send,
+                        recv,
+                        remote: Remote::Unauthenticated { transcript },
+                    };
+                }
+                State::Encrypted { recv, remote, .. } => {
+                    let data = if let Some(data) = recv.open(&mut peer.buffer)? {
+                        data
+                    } else {
+                        return Ok(());
+                    };
+
+                    match remote {
+                        Remote::Unauthenticated { transcript } => {
+                            let t: &[u8; 32] = transcript.as_ref();
+                            let mut cursor = io::Cursor::new(data);
+                            let id = NodeId::decode(&mut cursor).map_err(|_| Error::InvalidAuth)?;
+                            let signature =
+                                Signature::decode(&mut cursor).map_err(|_| Error::InvalidAuth)?;
+                            let role = Role::from(peer.link).other();
+
+                            id.verify([t, role.as_bytes()].concat(), &signature)
+                                .map_err(|_| Error::InvalidSignature(id))?;
+
+                            log::debug!("Handshake with {} ({}) completed", addr, id);
+
+                            *remote = Remote::Authenticated { id };
+
+                            // Only now does the inner protocol learn about the connection.
+                            let (local_addr, link) = (peer.local_addr, peer.link);
+
+                            self.inner.connected(*addr, &local_addr, link);
+                            self.inner.authenticated(addr, id);
+                        }
+                        Remote::Authenticated { .. } => {
+                            self.inner.received_bytes(addr, &data);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
 impl<S, T, G> nakamoto::Protocol for Transport<S, T, G>
 where
     T: WriteStorage + 'static,
+    T::Repository: Send + 'static,
     S: address_book::Store,
     G: crypto::Signer,
 {
@@ -51,6 +370,18 @@ where
     }
 
     fn wake(&mut self) {
+        let now = self.inner.local_time();
+
+        for peer in self.peers.values() {
+            if !peer.is_established() && now - peer.since >= HANDSHAKE_TIMEOUT {
+                log::debug!("Handshake with {} timed out", peer.addr);
+
+                self.outbox.push_back(Io::Disconnect(
+                    peer.addr,
+                    DisconnectReason::Transport(Error::Timeout),
+                ));
+            }
+        }
         self.inner.wake()
     }
 
@@ -68,7 +399,37 @@ where
         local_addr: &std::net::SocketAddr,
         link: Link,
     ) {
-        self.inner.connected(addr, local_addr, link)
+        let proxied = self
+            .inner
+            .proxied(&addr)
+            .filter(|_| link.is_outbound())
+            .cloned();
+        let state = if let Some(target) = proxied {
+            let (handshake, greeting) = socks5::Handshake::new(target);
+
+            self.outbox.push_back(Io::Write(addr, greeting));
+
+            State::Proxying { handshake }
+        } else {
+            let ephemeral = x25519::KeyPair::generate();
+
+            self.outbox
+                .push_back(Io::Write(addr, ephemeral.pk.as_slice().to_vec()));
+
+            State::AwaitingKey { ephemeral }
+        };
+        self.outbox.push_back(Io::Wakeup(HANDSHAKE_TIMEOUT));
+        self.peers.insert(
+            addr,
+            Peer {
+                addr,
+                local_addr: *local_addr,
+                link,
+                state,
+                since: self.inner.local_time(),
+                buffer: Vec::new(),
+            },
+        );
     }
 
     fn disconnected(
@@ -76,19 +437,61 @@ where
         addr: &std::net::SocketAddr,
         reason: nakamoto::DisconnectReason<Self::DisconnectReason>,
     ) {
+        self.peers.remove(addr);
         self.inner.disconnected(addr, reason)
     }
 
     fn received_bytes(&mut self, addr: &std::net::SocketAddr, bytes: &[u8]) {
-        self.inner.received_bytes(addr, bytes)
+        if let Some(peer) = self.peers.get_mut(addr) {
+            peer.buffer.extend_from_slice(bytes);
+        } else {
+            log::debug!("Received data from unknown peer {}", addr);
+            return;
+        }
+
+        if let Err(err) = self.process(addr) {
+            log::error!("Transport error with {}: {}", addr, err);
+
+            self.peers.remove(addr);
+            self.outbox
+                .push_back(Io::Disconnect(*addr, DisconnectReason::Transport(err)));
+        }
     }
 }
 
-impl<S, T, G> Iterator for Transport<S, T, G> {
+impl<S, T, G> Iterator for Transport<S, T, G>
+where
+    T: WriteStorage + 'static,
+    T::Repository: Send + 'static,
+    S: address_book::Store,
+    G: crypto::Signer,
+{
     type Item = Io<Event, DisconnectReason>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.inner.next()
+        if let Some(io) = self.outbox.pop_front() {
+            return Some(io);
+        }
+        match self.inner.next() {
+            Some(Io::Write(addr, bytes)) => {
+                if let Some(Peer {
+                    state:
+                        State::Encrypted {
+                            send,
+                            remote: Remote::Authenticated { .. },
+                            ..
+                        },
+                    ..
+                }) = self.peers.get_mut(&addr)
+                {
+                    Some(Io::Write(addr, send.seal(&bytes)))
+                } else {
+                    log::error!("Dropping write to unauthenticated peer {}", addr);
+                    self.next()
+                }
+            }
+            other => other,
+        }
     }
 }
 
@@ -105,3 +508,255 @@ impl<S, T, G> DerefMut for Transport<S, T, G> {
         &mut self.inner
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::service;
+    use crate::service::config::Proxy;
+    use crate::service::message::Address;
+    use crate::service::peer::SessionState;
+    use crate::test::peer;
+    use crate::test::storage::MockStorage;
+    use crate::wire::stream;
+    use nakamoto::Protocol as _;
+
+    type Node = Transport<
+        HashMap<Address, address_book::KnownAddress>,
+        MockStorage,
+        crate::test::signer::MockSigner,
+    >;
+
+    fn node(name: &'static str, ip: [u8; 4]) -> (Node, net::SocketAddr) {
+        node_with(name, ip, service::Config::default())
+    }
+
+    fn node_with(
+        name: &'static str,
+        ip: [u8; 4],
+        config: service::Config,
+    ) -> (Node, net::SocketAddr) {
+        let peer = peer::Peer::config(
+            name,
+            config,
+            ip,
+            vec![],
+            MockStorage::empty(),
+            fastrand::Rng::new(),
+        );
+        let addr = net::SocketAddr::from((ip, 8776));
+
+        (
+            Transport::new(Wire::new(peer.service, stream::Waker::new(|| Ok(())))),
+            addr,
+        )
+    }
+
+    /// Exchange data between two nodes until there's nothing left to exchange.
+    fn exchange(a: (&mut Node, net::SocketAddr), b: (&mut Node, net::SocketAddr)) {
+        let ((a, a_addr), (b, b_addr)) = (a, b);
+
+        loop {
+            let mut idle = true;
+
+            while let Some(io) = a.next() {
+                if let Io::Write(_, bytes) = io {
+                    b.received_bytes(&a_addr, &bytes);
+                    idle = false;
+                }
+            }
+            while let Some(io) = b.next() {
+                if let Io::Write(_, bytes) = io {
+                    a.received_bytes(&b_addr, &bytes);
+                    idle = false;
+                }
+            }
+            if idle {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn test_handshake() {
+        let (mut alice, alice_addr) = node("alice", [7, 7, 7, 7]);
+        let (mut bob, bob_addr) = node("bob", [8, 8, 8, 8]);
+
+        alice.attempted(&bob_addr);
+        alice.connected(bob_addr, &alice_addr, Link::Outbound);
+        bob.connected(alice_addr, &bob_addr, Link::Inbound);
+
+        assert!(
+            alice
+                .sessions()
+                .get(&bob_addr)
+                .unwrap()
+                .authenticated
+                .is_none(),
+            "The inner protocol doesn't know about the peer before the handshake"
+        );
+        exchange((&mut alice, alice_addr), (&mut bob, bob_addr));
+
+        let session = alice.sessions().get(&bob_addr).unwrap();
+        assert_eq!(session.authenticated, Some(bob.node_id()));
+        assert!(
+            matches!(session.state, SessionState::Negotiated { id, .. } if id == bob.node_id())
+        );
+
+        let session = bob.sessions().get(&alice_addr).unwrap();
+        assert_eq!(session.authenticated, Some(alice.node_id()));
+        assert!(
+            matches!(session.state, SessionState::Negotiated { id, .. } if id == alice.node_id())
+        );
+    }
+
+    /// Collect the data written by a node.
+    fn written(node: &mut Node) -> Vec<u8> {
+        node.by_ref()
+            .filter_map(|io| match io {
+                Io::Write(_, bytes) => Some(bytes),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .concat()
+    }
+
+    #[test]
+    fn test_handshake_proxied() {
+        let proxy = net::SocketAddr::from(([127, 0, 0, 1], 9050));
+        let (mut alice, alice_addr) = node_with(
+            "alice",
+            [7, 7, 7, 7],
+            service::Config {
+                proxy: Some(Proxy {
+                    addr: proxy,
+                    all: true,
+                }),
+                ..service::Config::default()
+            },
+        );
+        let (mut bob, bob_addr) = node("bob", [8, 8, 8, 8]);
+
+        alice.command(Command::Connect(bob_addr));
+        let relay = alice
+            .find_map(|io| match io {
+                Io::Connect(addr) => Some(addr),
+                _ => None,
+            })
+            .expect("Alice connects to a relay to the proxy instead of Bob");
+        assert_ne!(relay, bob_addr);
+        assert!(relay.ip().is_loopback());
+
+        alice.attempted(&relay);
+        alice.connected(relay, &alice_addr, Link::Outbound);
+
+        // Play the part of the proxy.
+        assert_eq!(written(&mut alice), vec![5, 1, 0]);
+        alice.received_bytes(&relay, &[5, 0]);
+        assert_eq!(
+            written(&mut alice),
+            [
+                &[5, 1, 0, 1, 8, 8, 8, 8][..],
+                &bob_addr.port().to_be_bytes()
+            ]
+            .concat()
+        );
+        alice.received_bytes(&relay, &[5, 0, 0, 1, 127, 0, 0, 1, 0x23, 0x82]);
+
+        // The proxy relays data between Alice and Bob from now on.
+        bob.connected(alice_addr, &bob_addr, Link::Inbound);
+        exchange((&mut alice, alice_addr), (&mut bob, relay));
+
+        let session = alice.sessions().get(&relay).unwrap();
+        assert_eq!(session.authenticated, Some(bob.node_id()));
+        assert!(
+            matches!(session.state, SessionState::Negotiated { id, .. } if id == bob.node_id())
+        );
+    }
+
+    #[test]
+    fn test_proxied_concurrent() {
+        let proxy = net::SocketAddr::from(([127, 0, 0, 1], 9050));
+        let (mut alice, alice_addr) = node_with(
+            "alice",
+            [7, 7, 7, 7],
+            service::Config {
+                proxy: Some(Proxy {
+                    addr: proxy,
+                    all: true,
+                }),
+                ..service::Config::default()
+            },
+        );
+        let bob = net::SocketAddr::from(([8, 8, 8, 8], 8776));
+        let eve = net::SocketAddr::from(([9, 9, 9, 9], 8776));
+
+        // Each proxied connection goes through its own relay, so they don't wait on
+        // each other.
+        alice.command(Command::Connect(bob));
+        alice.command(Command::Connect(eve));
+        let relays = alice
+            .by_ref()
+            .filter_map(|io| match io {
+                Io::Connect(addr) => Some(addr),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(relays.len(), 2);
+        assert_ne!(relays[0], relays[1]);
+        assert_eq!(alice.proxied(&relays[0]), Some(&Address::from(bob)));
+        assert_eq!(alice.proxied(&relays[1]), Some(&Address::from(eve)));
+
+        for relay in &relays {
+            alice.attempted(relay);
+            alice.connected(*relay, &alice_addr, Link::Outbound);
+        }
+        assert_eq!(written(&mut alice), [5, 1, 0, 5, 1, 0]);
+
+        // Closing one connection leaves the other one alone.
+        alice.disconnected(
+            &relays[0],
+            nakamoto::DisconnectReason::Protocol(DisconnectReason::User),
+        );
+        assert_eq!(alice.proxied(&relays[0]), None);
+        assert_eq!(alice.proxied(&relays[1]), Some(&Address::from(eve)));
+        assert!(alice.sessions().get(&relays[1]).is_some());
+    }
+
+    #[test]
+    fn test_handshake_corrupted() {
+        let (mut alice, alice_addr) = node("alice", [7, 7, 7, 7]);
+        let (mut bob, bob_addr) = node("bob", [8, 8, 8, 8]);
+
+        alice.attempted(&bob_addr);
+        alice.connected(bob_addr, &alice_addr, Link::Outbound);
+        bob.connected(alice_addr, &bob_addr, Link::Inbound);
+
+        // Exchange ephemeral keys.
+        for io in alice.by_ref() {
+            if let Io::Write(_, bytes) = io {
+                bob.received_bytes(&alice_addr, &bytes);
+            }
+        }
+        // Bob's authentication message is tampered with.
+        let mut corrupted = Vec::new();
+        for io in bob.by_ref() {
+            if let Io::Write(_, bytes) = io {
+                corrupted.extend(bytes);
+            }
+        }
+        *corrupted.last_mut().unwrap() ^= 0xff;
+        alice.received_bytes(&bob_addr, &corrupted);
+
+        assert!(alice.any(|io| matches!(
+            io,
+            Io::Disconnect(addr, DisconnectReason::Transport(Error::Decryption))
+            if addr == bob_addr
+        )));
+        assert!(alice
+            .sessions()
+            .negotiated()
+            .all(|(addr, _)| *addr != bob_addr));
+    }
+}
@@ -0,0 +1,115 @@
+//! Local relays to the SOCKS5 proxy.
+//!
+//! The reactor identifies connections by their remote address, so connections made to the
+//! proxy directly couldn't be told apart. Instead, each proxied connection is made to its
+//! own relay, listening on a local port, which forwards the connection to the proxy. The
+//! relay's address is unique, and identifies the connection until it's closed.
+//!
+//! The proxy handshake itself is carried out by the transport, over the relayed connection.
+use std::io::{Read, Write};
+use std::{io, net, thread, time};
+
+/// How long a relay waits for the reactor to connect to it, before giving up.
+pub const ACCEPT_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+/// How often a relay checks for the reactor's connection.
+const ACCEPT_INTERVAL: time::Duration = time::Duration::from_millis(10);
+
+/// Start a relay to the given proxy, on its own thread. Returns the address to connect
+/// to, which only accepts a single connection.
+pub fn spawn(proxy: net::SocketAddr) -> io::Result<net::SocketAddr> {
+    let listener = net::TcpListener::bind((net::Ipv4Addr::LOCALHOST, 0))?;
+    let addr = listener.local_addr()?;
+
+    listener.set_nonblocking(true)?;
+    thread::spawn(move || {
+        if let Err(err) = run(listener, proxy) {
+            log::debug!("Relay {} to proxy {} failed: {}", addr, proxy, err);
+        }
+    });
+    Ok(addr)
+}
+
+/// Accept a connection, and forward it to the proxy until either end closes it.
+fn run(listener: net::TcpListener, proxy: net::SocketAddr) -> io::Result<()> {
+    let started = time::Instant::now();
+    let local = loop {
+        match listener.accept() {
+            Ok((stream, _)) => break stream,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                if started.elapsed() >= ACCEPT_TIMEOUT {
+                    return Err(io::ErrorKind::TimedOut.into());
+                }
+                thread::sleep(ACCEPT_INTERVAL);
+            }
+            Err(err) => return Err(err),
+        }
+    };
+    // Only one connection is relayed.
+    drop(listener);
+    local.set_nonblocking(false)?;
+
+    let remote = net::TcpStream::connect(proxy)?;
+    let (local_r, remote_w) = (local.try_clone()?, remote.try_clone()?);
+    let upstream = thread::spawn(move || forward(local_r, remote_w));
+
+    forward(remote, local)?;
+    upstream.join().unwrap_or(Ok(()))
+}
+
+/// Copy data from one stream to the other, and close the other once done.
+fn forward(mut from: net::TcpStream, mut to: net::TcpStream) -> io::Result<()> {
+    let mut buf = [0; 8192];
+
+    let result = loop {
+        match from.read(&mut buf) {
+            Ok(0) => break Ok(()),
+            Ok(n) => {
+                if let Err(err) = to.write_all(&buf[..n]) {
+                    break Err(err);
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => break Err(err),
+        }
+    };
+    // Let both ends know the relayed connection is closed.
+    to.shutdown(net::Shutdown::Both).ok();
+    from.shutdown(net::Shutdown::Both).ok();
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relay() {
+        let proxy = net::TcpListener::bind((net::Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let relays = [
+            spawn(proxy.local_addr().unwrap()).unwrap(),
+            spawn(proxy.local_addr().unwrap()).unwrap(),
+        ];
+        assert_ne!(relays[0], relays[1]);
+
+        // Both relayed connections stay open at the same time.
+        let mut conns = Vec::new();
+        for (i, relay) in relays.iter().enumerate() {
+            let mut client = net::TcpStream::connect(relay).unwrap();
+            let (mut conn, _) = proxy.accept().unwrap();
+            let mut buf = [0; 1];
+
+            client.write_all(&[i as u8]).unwrap();
+            conn.read_exact(&mut buf).unwrap();
+            conn.write_all(&buf).unwrap();
+
+            client.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, [i as u8]);
+
+            conns.push((client, conn));
+        }
+        // Relays only accept a single connection.
+        assert!(net::TcpStream::connect(relays[0]).is_err());
+    }
+}
@@ -0,0 +1,384 @@
+//! SOCKS5 client handshake, as specified in RFC 1928.
+//!
+//! Only the "no authentication" method and the `CONNECT` command are supported, which is
+//! all that's needed to connect through a local Tor daemon. Once the handshake completes,
+//! the proxy relays all data to and from the target.
+use crate::service::message::{onion_domain, Address};
+
+/// SOCKS protocol version.
+pub const VERSION: u8 = 5;
+
+/// The "no authentication required" method.
+const METHOD_NO_AUTH: u8 = 0x00;
+/// Returned by the proxy when none of our methods are acceptable.
+const METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+/// The `CONNECT` command.
+const COMMAND_CONNECT: u8 = 0x01;
+/// Reply code signaling success.
+const REPLY_SUCCEEDED: u8 = 0x00;
+
+/// Address types.
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// A SOCKS5 error.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    #[error("unsupported SOCKS version `{0}`")]
+    UnsupportedVersion(u8),
+    #[error("no acceptable authentication method")]
+    NoAcceptableMethod,
+    #[error("unexpected authentication method `{0}`")]
+    UnexpectedMethod(u8),
+    #[error("proxy failed to connect: {}", reply(*.0))]
+    ConnectFailed(u8),
+    #[error("unknown address type `{0}`")]
+    UnknownAddressType(u8),
+    #[error("address `{0}` can't be connected to")]
+    UnsupportedTarget(Address),
+}
+
+/// Describe a reply code.
+fn reply(code: u8) -> &'static str {
+    match code {
+        0x01 => "general failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+/// Outcome of processing data received from the proxy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Progress {
+    /// Data should be sent to the proxy.
+    Send(Vec<u8>),
+    /// The proxy is connected to the target. Any further data is relayed.
+    Connected,
+}
+
+/// Handshake state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum State {
+    /// Waiting for the proxy to select an authentication method.
+    AwaitingMethod,
+    /// Waiting for the proxy to reply to our `CONNECT` request.
+    AwaitingReply,
+    /// The handshake is complete.
+    Connected,
+}
+
+/// A SOCKS5 client handshake, connecting to a target through a proxy.
+#[derive(Debug)]
+pub struct Handshake {
+    /// The address the proxy should connect to.
+    target: Address,
+    /// Handshake state.
+    state: State,
+}
+
+impl Handshake {
+    /// Start a handshake. Returns the greeting to send to the proxy.
+    pub fn new(target: Address) -> (Self, Vec<u8>) {
+        (
+            Self {
+                target,
+                state: State::AwaitingMethod,
+            },
+            vec![VERSION, 1, METHOD_NO_AUTH],
+        )
+    }
+
+    /// The address the proxy should connect to.
+    pub fn target(&self) -> &Address {
+        &self.target
+    }
+
+    /// Whether the proxy is connected to the target.
+    pub fn is_connected(&self) -> bool {
+        self.state == State::Connected
+    }
+
+    /// Process data received from the proxy. Returns `None` if more data is needed.
+    /// Processed data is removed from the buffer, and data received after the handshake
+    /// is left untouched.
+    pub fn process(&mut self, buffer: &mut Vec<u8>) -> Result<Option<Progress>, Error> {
+        match self.state {
+            State::AwaitingMethod => {
+                if buffer.len() < 2 {
+                    return Ok(None);
+                }
+                let (version, method) = (buffer[0], buffer[1]);
+                buffer.drain(..2);
+
+                if version != VERSION {
+                    return Err(Error::UnsupportedVersion(version));
+                }
+                match method {
+                    METHOD_NO_AUTH => {}
+                    METHOD_NONE_ACCEPTABLE => return Err(Error::NoAcceptableMethod),
+                    other => return Err(Error::UnexpectedMethod(other)),
+                }
+                self.state = State::AwaitingReply;
+
+                Ok(Some(Progress::Send(request(&self.target)?)))
+            }
+            State::AwaitingReply => {
+                // Version, reply code, reserved byte and address type.
+                if buffer.len() < 4 {
+                    return Ok(None);
+                }
+                let (version, code, atyp) = (buffer[0], buffer[1], buffer[3]);
+
+                if version != VERSION {
+                    return Err(Error::UnsupportedVersion(version));
+                }
+                if code != REPLY_SUCCEEDED {
+                    return Err(Error::ConnectFailed(code));
+                }
+                // The address the proxy bound to, followed by the port. We don't use it.
+                let addr = match atyp {
+                    ATYP_IPV4 => 4,
+                    ATYP_IPV6 => 16,
+                    ATYP_DOMAIN => match buffer.get(4) {
+                        Some(len) => 1 + *len as usize,
+                        None => return Ok(None),
+                    },
+                    other => return Err(Error::UnknownAddressType(other)),
+                };
+                let len = 4 + addr + 2;
+
+                if buffer.len() < len {
+                    return Ok(None);
+                }
+                buffer.drain(..len);
+                self.state = State::Connected;
+
+                Ok(Some(Progress::Connected))
+            }
+            State::Connected => Ok(None),
+        }
+    }
+}
+
+/// Build a `CONNECT` request for the given target.
+fn request(target: &Address) -> Result<Vec<u8>, Error> {
+    let mut req = vec![VERSION, COMMAND_CONNECT, 0x00];
+    let domain = |req: &mut Vec<u8>, domain: &str| {
+        // Hostnames are at most 253 bytes long, and onion domains are shorter.
+        req.push(ATYP_DOMAIN);
+        req.push(domain.len() as u8);
+        req.extend_from_slice(domain.as_bytes());
+    };
+
+    let port = match target {
+        Address::Ipv4 { ip, port } => {
+            req.push(ATYP_IPV4);
+            req.extend_from_slice(&ip.octets());
+            port
+        }
+        Address::Ipv6 { ip, port } => {
+            req.push(ATYP_IPV6);
+            req.extend_from_slice(&ip.octets());
+            port
+        }
+        Address::Hostname { host, port } => {
+            // The proxy resolves the hostname, so that we don't leak DNS requests.
+            domain(&mut req, host.as_str());
+            port
+        }
+        Address::Onion {
+            key,
+            port,
+            checksum,
+            version,
+        } => {
+            domain(&mut req, &onion_domain(key, *checksum, *version));
+            port
+        }
+        Address::Unknown { .. } => return Err(Error::UnsupportedTarget(target.clone())),
+    };
+    req.extend_from_slice(&port.to_be_bytes());
+
+    Ok(req)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read, Write};
+    use std::str::FromStr;
+    use std::{net, thread};
+
+    use super::*;
+    use crate::crypto::Signer;
+    use crate::test::signer::MockSigner;
+
+    /// Connect to a target through a proxy, blocking until the handshake is complete.
+    fn connect(proxy: net::SocketAddr, target: Address) -> io::Result<net::TcpStream> {
+        let mut stream = net::TcpStream::connect(proxy)?;
+        let (mut handshake, greeting) = Handshake::new(target);
+        let mut buffer = Vec::new();
+        let mut chunk = [0; 256];
+
+        stream.write_all(&greeting)?;
+
+        loop {
+            match handshake
+                .process(&mut buffer)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+            {
+                Some(Progress::Send(data)) => stream.write_all(&data)?,
+                Some(Progress::Connected) => return Ok(stream),
+                None => {
+                    let n = stream.read(&mut chunk)?;
+                    if n == 0 {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    buffer.extend_from_slice(&chunk[..n]);
+                }
+            }
+        }
+    }
+
+    /// A local stand-in for a SOCKS5 proxy. Accepts a single connection, and replies
+    /// to the `CONNECT` request with the given code. Once connected, echoes data back.
+    /// Returns the proxy address, and a handle that yields the request it received.
+    fn proxy(code: u8) -> (net::SocketAddr, thread::JoinHandle<Vec<u8>>) {
+        let listener = net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut greeting = [0; 3];
+
+            stream.read_exact(&mut greeting).unwrap();
+            assert_eq!(greeting, [VERSION, 1, METHOD_NO_AUTH]);
+            stream.write_all(&[VERSION, METHOD_NO_AUTH]).unwrap();
+
+            let mut header = [0; 4];
+            stream.read_exact(&mut header).unwrap();
+            let mut request = header.to_vec();
+            let len = match header[3] {
+                ATYP_IPV4 => 4,
+                ATYP_IPV6 => 16,
+                ATYP_DOMAIN => {
+                    let mut len = [0; 1];
+                    stream.read_exact(&mut len).unwrap();
+                    request.push(len[0]);
+                    len[0] as usize
+                }
+                other => panic!("unexpected address type {}", other),
+            };
+            let mut rest = vec![0; len + 2];
+            stream.read_exact(&mut rest).unwrap();
+            request.extend(rest);
+
+            // Reply with a domain bound address, to exercise variable-length replies.
+            stream
+                .write_all(&[VERSION, code, 0x00, ATYP_DOMAIN, 5])
+                .unwrap();
+            stream.write_all(b"proxy\x00\x00").unwrap();
+
+            if code == REPLY_SUCCEEDED {
+                let mut data = [0; 4];
+                stream.read_exact(&mut data).unwrap();
+                stream.write_all(&data).unwrap();
+            }
+            request
+        });
+        (addr, handle)
+    }
+
+    #[test]
+    fn test_connect_onion() {
+        let signer = MockSigner::new(&mut fastrand::Rng::new());
+        let target = Address::onion(*signer.public_key(), 8776);
+        let (addr, proxy) = proxy(REPLY_SUCCEEDED);
+
+        let mut stream = connect(addr, target.clone()).unwrap();
+        let mut pong = [0; 4];
+
+        stream.write_all(b"ping").unwrap();
+        stream.read_exact(&mut pong).unwrap();
+        assert_eq!(
+            &pong, b"ping",
+            "Data is relayed once the handshake completes"
+        );
+
+        let request = proxy.join().unwrap();
+        let domain = target.to_string();
+        let (domain, _) = domain.rsplit_once(':').unwrap();
+
+        assert_eq!(
+            &request[..5],
+            &[
+                VERSION,
+                COMMAND_CONNECT,
+                0x00,
+                ATYP_DOMAIN,
+                domain.len() as u8
+            ]
+        );
+        assert_eq!(&request[5..request.len() - 2], domain.as_bytes());
+        assert_eq!(&request[request.len() - 2..], &8776u16.to_be_bytes());
+    }
+
+    #[test]
+    fn test_connect_refused() {
+        let (addr, proxy) = proxy(0x05);
+        let target = Address::from_str("seed.radicle.xyz:8776").unwrap();
+        let err = connect(addr, target).unwrap_err();
+
+        assert_eq!(err.to_string(), Error::ConnectFailed(0x05).to_string());
+        assert_eq!(
+            proxy.join().unwrap(),
+            [
+                &[VERSION, COMMAND_CONNECT, 0x00, ATYP_DOMAIN, 16][..],
+                b"seed.radicle.xyz",
+                &8776u16.to_be_bytes()
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn test_handshake_partial() {
+        let target = Address::from(net::SocketAddr::from(([8, 8, 8, 8], 8776)));
+        let (mut handshake, _) = Handshake::new(target);
+        let mut buffer = vec![VERSION];
+
+        assert_eq!(handshake.process(&mut buffer), Ok(None));
+        buffer.push(METHOD_NO_AUTH);
+        assert_eq!(
+            handshake.process(&mut buffer),
+            Ok(Some(Progress::Send(vec![
+                VERSION,
+                COMMAND_CONNECT,
+                0x00,
+                ATYP_IPV4,
+                8,
+                8,
+                8,
+                8,
+                0x22,
+                0x48
+            ])))
+        );
+        buffer.extend([VERSION, REPLY_SUCCEEDED, 0x00, ATYP_IPV4, 0, 0, 0]);
+        assert_eq!(handshake.process(&mut buffer), Ok(None));
+
+        // The rest of the reply arrives with data from the target.
+        buffer.extend([0, 0, 0, 0xaa]);
+        assert_eq!(
+            handshake.process(&mut buffer),
+            Ok(Some(Progress::Connected))
+        );
+        assert!(handshake.is_connected());
+        assert_eq!(buffer, vec![0xaa]);
+    }
+}
@@ -0,0 +1,252 @@
+//! Serve repositories to peers, by running `git upload-pack` over a git stream.
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write as _;
+use std::sync::{Arc, Mutex};
+use std::{io, net, process, thread};
+
+use crate::git;
+use crate::storage::refs::SIGNATURE_REF;
+use crate::storage::{ReadRepository, RemoteId, WriteRepository};
+use crate::wire::stream::Stream;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Git(#[from] git::raw::Error),
+    #[error("git upload-pack failed: {0}")]
+    Failed(process::ExitStatus),
+    #[error("ref `{0}` can't be hidden from the remote")]
+    InvalidRef(String),
+}
+
+/// Maximum number of upload-packs served at once.
+pub const MAX_UPLOAD_PACKS: usize = 32;
+/// Maximum number of upload-packs served at once to a single peer.
+pub const MAX_PEER_UPLOAD_PACKS: usize = 4;
+
+/// The upload-packs being served, per peer.
+#[derive(Debug, Default, Clone)]
+pub struct Uploads(Arc<Mutex<HashMap<net::IpAddr, usize>>>);
+
+impl Uploads {
+    /// Start serving an upload-pack to a peer. Returns `None` if too many are being served,
+    /// in total or to that peer. The upload-pack is counted until the returned guard is dropped.
+    pub fn start(&self, peer: net::IpAddr) -> Option<Upload> {
+        let mut uploads = self.0.lock().unwrap();
+        let total = uploads.values().sum::<usize>();
+        let count = uploads.get(&peer).copied().unwrap_or_default();
+
+        if total >= MAX_UPLOAD_PACKS || count >= MAX_PEER_UPLOAD_PACKS {
+            return None;
+        }
+        uploads.insert(peer, count + 1);
+
+        Some(Upload {
+            uploads: self.clone(),
+            peer,
+        })
+    }
+
+    /// Number of upload-packs being served.
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().values().sum()
+    }
+
+    /// Whether no upload-packs are being served.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// An upload-pack being served. Stops counting towards the limits once dropped.
+#[derive(Debug)]
+pub struct Upload {
+    uploads: Uploads,
+    peer: net::IpAddr,
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        let mut uploads = self.uploads.0.lock().unwrap();
+
+        if let Some(count) = uploads.get_mut(&self.peer) {
+            *count -= 1;
+
+            if *count == 0 {
+                uploads.remove(&self.peer);
+            }
+        }
+    }
+}
+
+/// Serve a repository over the given stream, until the remote is done fetching.
+///
+/// Only refs under `refs/remotes/` are advertised, and of those, only the ones that
+/// match the signed refs of their remote.
+pub fn upload_pack<R: WriteRepository>(repo: &R, stream: Stream) -> Result<(), Error> {
+    // There may be any number of refs to hide, too many to pass on the command line, so
+    // they're passed in a config file instead. The file is removed once dropped.
+    let mut config = tempfile::NamedTempFile::new()?;
+    config.write_all(hide_refs(&unverified(repo)?).as_bytes())?;
+
+    let mut child = process::Command::new("git")
+        .current_dir(repo.path())
+        .arg("-c")
+        .arg(format!("include.path={}", config.path().display()))
+        .arg("upload-pack")
+        .arg("--strict") // The path to the git repo must be exact.
+        .arg(".")
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::null())
+        .spawn()?;
+
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let (mut reader, mut writer) = stream.split();
+
+    // Feed the remote's requests to git, until the remote closes the stream.
+    thread::spawn(move || io::copy(&mut reader, &mut stdin));
+    io::copy(&mut stdout, &mut writer)?;
+    // Let the remote know that we're done.
+    drop(writer);
+
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(Error::Failed(status));
+    }
+    Ok(())
+}
+
+/// Get the config hiding all refs from upload-pack, except for the ones under
+/// `refs/remotes/`, minus the given refs. Later entries take precedence over earlier ones.
+fn hide_refs(unverified: &[String]) -> String {
+    let mut config = String::from("[uploadpack]\n\thideRefs = refs\n\thideRefs = !refs/remotes\n");
+
+    for name in unverified {
+        let name = name.replace('\\', "\\\\").replace('"', "\\\"");
+        config.push_str(&format!("\thideRefs = \"{}\"\n", name));
+    }
+    config
+}
+
+/// Get the refs under `refs/remotes/` that shouldn't be advertised: the namespaces of
+/// remotes whose signed refs don't verify, and refs that don't match what was signed.
+fn unverified<R: WriteRepository>(repo: &R) -> Result<Vec<String>, Error> {
+    let mut unverified = Vec::new();
+    let mut remotes = BTreeMap::<RemoteId, Vec<(git::RefString, git::Oid)>>::new();
+
+    for r in repo.raw().references_glob("refs/remotes/*")? {
+        let r = r?;
+        let (name, oid) = match (r.name(), r.target()) {
+            (Some(name), Some(oid)) => (name, oid),
+            // Symbolic refs aren't signed.
+            (Some(name), None) => {
+                unverified.push(name.to_owned());
+                continue;
+            }
+            // Refs that aren't valid UTF-8 can't be hidden by name, so hide the namespace
+            // of their remote instead. If even that isn't possible, don't serve anything.
+            (None, _) => {
+                let name = r.name_bytes();
+                let remote = name
+                    .strip_prefix(b"refs/remotes/")
+                    .and_then(|rest| rest.split(|b| *b == b'/').next())
+                    .and_then(|remote| std::str::from_utf8(remote).ok())
+                    .filter(|remote| !remote.is_empty());
+
+                match remote {
+                    Some(remote) => unverified.push(format!("refs/remotes/{}", remote)),
+                    None => {
+                        return Err(Error::InvalidRef(
+                            String::from_utf8_lossy(name).into_owned(),
+                        ))
+                    }
+                }
+                continue;
+            }
+        };
+        match git::parse_ref::<RemoteId>(name) {
+            Ok((remote, refname)) => {
+                remotes
+                    .entry(remote)
+                    .or_default()
+                    .push((refname, oid.into()));
+            }
+            Err(_) => unverified.push(name.to_owned()),
+        }
+    }
+
+    for (remote, refs) in remotes {
+        match repo.remote(&remote) {
+            Ok(signed) => {
+                for (refname, oid) in refs {
+                    if refname == *SIGNATURE_REF {
+                        continue;
+                    }
+                    if signed.refs.get(&refname) != Some(&oid) {
+                        unverified.push(format!("refs/remotes/{}/{}", remote, refname));
+                    }
+                }
+            }
+            Err(err) => {
+                log::warn!("Not serving unverified remote {}: {}", remote, err);
+                unverified.push(format!("refs/remotes/{}", remote));
+            }
+        }
+    }
+    Ok(unverified)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upload_limits() {
+        let uploads = Uploads::default();
+        let alice = net::IpAddr::from([8, 8, 8, 8]);
+        let mut started = (0..MAX_PEER_UPLOAD_PACKS)
+            .map(|_| uploads.start(alice).unwrap())
+            .collect::<Vec<_>>();
+
+        assert!(uploads.start(alice).is_none(), "The peer limit is reached");
+
+        started.pop();
+        assert!(
+            uploads.start(alice).is_some(),
+            "Finished uploads aren't counted"
+        );
+
+        let mut peers = (1..=u8::MAX).map(|i| net::IpAddr::from([10, 0, 0, i]));
+        while uploads.len() < MAX_UPLOAD_PACKS {
+            started.push(uploads.start(peers.next().unwrap()).unwrap());
+        }
+        assert!(
+            uploads.start(peers.next().unwrap()).is_none(),
+            "The global limit is reached"
+        );
+
+        drop(started);
+        assert!(uploads.is_empty());
+    }
+
+    #[test]
+    fn test_hide_refs() {
+        let config = hide_refs(&[
+            String::from("refs/remotes/alice"),
+            String::from("refs/remotes/bob/heads/\"quoted\""),
+        ]);
+
+        assert_eq!(
+            config,
+            "[uploadpack]\n\
+            \thideRefs = refs\n\
+            \thideRefs = !refs/remotes\n\
+            \thideRefs = \"refs/remotes/alice\"\n\
+            \thideRefs = \"refs/remotes/bob/heads/\\\"quoted\\\"\"\n"
+        );
+    }
+}
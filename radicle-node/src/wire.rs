@@ -1,13 +1,16 @@
+pub mod frame;
 pub mod message;
+pub mod stream;
 
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::convert::TryFrom;
-use std::net::IpAddr;
+use std::net::ToSocketAddrs;
 use std::ops::{Deref, DerefMut};
 use std::string::FromUtf8Error;
-use std::{io, mem};
+use std::{io, mem, net, thread};
 
 use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use crossbeam_channel as chan;
 use nakamoto_net as nakamoto;
 use nakamoto_net::Link;
 
@@ -19,11 +22,20 @@ use crate::git::fmt;
 use crate::hash::Digest;
 use crate::identity::Id;
 use crate::service;
+use crate::service::fetch::Namespaces;
 use crate::service::filter;
+use crate::service::message::{Hostname, HostnameError, MAX_REFS_SIZE};
+use crate::service::peer::SessionError;
 use crate::service::reactor::Io;
+use crate::storage;
+use crate::storage::git::transport::Smart;
 use crate::storage::refs::Refs;
 use crate::storage::refs::SignedRefs;
-use crate::storage::WriteStorage;
+use crate::storage::{ReadRepository, RefUpdate, WriteRepository, WriteStorage};
+use crate::transport::relay;
+use crate::upload_pack;
+use crate::wire::frame::{Frame, StreamId};
+use crate::wire::stream::{Streams, MAX_OUTGOING_FRAMES};
 
 /// The default type we use to represent sizes.
 /// Four bytes is more than enough for anything sent over the wire.
@@ -47,10 +59,28 @@ pub enum Error {
         url: String,
         error: git::url::parse::Error,
     },
-    #[error("unknown address type `{0}`")]
-    UnknownAddressType(u8),
+    #[error("invalid hostname: {0}")]
+    InvalidHostname(#[from] HostnameError),
+    #[error("unsupported onion address version `{0}`")]
+    UnsupportedOnionVersion(u8),
+    #[error("invalid onion address checksum `{0:04x}`")]
+    InvalidOnionChecksum(u16),
     #[error("unknown message type `{0}`")]
     UnknownMessageType(u16),
+    #[error("unknown frame type `{0}`")]
+    UnknownFrameType(u8),
+    #[error("message too large: {0} bytes")]
+    MessageTooLarge(usize),
+    #[error("too many items: expected at most {max}, got {actual}")]
+    TooManyItems { max: usize, actual: usize },
+    #[error("message of {0} bytes is truncated")]
+    TruncatedMessage(usize),
+    #[error("message has {0} trailing byte(s)")]
+    TrailingBytes(usize),
+    #[error("length {len} exceeds the maximum of {max}")]
+    LengthOutOfRange { len: usize, max: usize },
+    #[error("extension of type `{0}` is out of order")]
+    UnorderedExtension(u16),
 }
 
 impl Error {
@@ -61,26 +91,32 @@ impl Error {
     }
 }
 
-/// Things that can be encoded as binary.
+/// Things that can be encoded as binary. Encoding fails with
+/// [`Error::LengthOutOfRange`] if a length doesn't fit in its prefix.
 pub trait Encode {
-    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error>;
+    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, Error>;
 }
 
+/// Maximum number of items preallocated when decoding a collection. Lengths are read
+/// from the network, so they can't be trusted.
+const MAX_PREALLOCATION: usize = 1024;
+
+/// Maximum number of bytes buffered for a peer until a frame can be decoded.
+pub const MAX_INBOX_SIZE: usize = frame::MAX_FRAME_SIZE;
+
 /// Things that can be decoded from binary.
 pub trait Decode: Sized {
     fn decode<R: io::Read + ?Sized>(reader: &mut R) -> Result<Self, Error>;
 }
 
 /// Encode an object into a vector.
-pub fn serialize<T: Encode + ?Sized>(data: &T) -> Vec<u8> {
+pub fn serialize<T: Encode + ?Sized>(data: &T) -> Result<Vec<u8>, Error> {
     let mut buffer = Vec::new();
-    let len = data
-        .encode(&mut buffer)
-        .expect("in-memory writes don't error");
+    let len = data.encode(&mut buffer)?;
 
     debug_assert_eq!(len, buffer.len());
 
-    buffer
+    Ok(buffer)
 }
 
 /// Decode an object from a vector.
@@ -91,7 +127,7 @@ pub fn deserialize<T: Decode>(data: &[u8]) -> Result<T, Error> {
 }
 
 impl Encode for u8 {
-    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, Error> {
         writer.write_u8(*self)?;
 
         Ok(mem::size_of::<Self>())
@@ -99,7 +135,7 @@ impl Encode for u8 {
 }
 
 impl Encode for u16 {
-    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, Error> {
         writer.write_u16::<NetworkEndian>(*self)?;
 
         Ok(mem::size_of::<Self>())
@@ -107,7 +143,7 @@ impl Encode for u16 {
 }
 
 impl Encode for u32 {
-    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, Error> {
         writer.write_u32::<NetworkEndian>(*self)?;
 
         Ok(mem::size_of::<Self>())
@@ -115,7 +151,7 @@ impl Encode for u32 {
 }
 
 impl Encode for u64 {
-    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, Error> {
         writer.write_u64::<NetworkEndian>(*self)?;
 
         Ok(mem::size_of::<Self>())
@@ -125,26 +161,25 @@ impl Encode for u64 {
 impl Encode for usize {
     /// We encode this type to a [`u32`], since there's no need to send larger messages
     /// over the network.
-    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
-        assert!(
-            *self <= u32::MAX as usize,
-            "Cannot encode sizes larger than {}",
-            u32::MAX
-        );
-        writer.write_u32::<NetworkEndian>(*self as u32)?;
-
-        Ok(mem::size_of::<u32>())
+    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, Error> {
+        let size = Size::try_from(*self).map_err(|_| Error::LengthOutOfRange {
+            len: *self,
+            max: Size::MAX as usize,
+        })?;
+        writer.write_u32::<NetworkEndian>(size)?;
+
+        Ok(mem::size_of::<Size>())
     }
 }
 
 impl Encode for PublicKey {
-    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, Error> {
         self.deref().encode(writer)
     }
 }
 
 impl<const T: usize> Encode for &[u8; T] {
-    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, Error> {
         writer.write_all(*self)?;
 
         Ok(mem::size_of::<Self>())
@@ -152,7 +187,7 @@ impl<const T: usize> Encode for &[u8; T] {
 }
 
 impl<const T: usize> Encode for [u8; T] {
-    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, Error> {
         writer.write_all(self)?;
 
         Ok(mem::size_of::<Self>())
@@ -163,8 +198,8 @@ impl<T> Encode for &[T]
 where
     T: Encode,
 {
-    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
-        let mut n = (self.len() as Size).encode(writer)?;
+    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, Error> {
+        let mut n = self.len().encode(writer)?;
 
         for item in self.iter() {
             n += item.encode(writer)?;
@@ -173,11 +208,15 @@ where
     }
 }
 
+/// Strings are prefixed with a two-byte length: URLs and ref names can be longer than
+/// what fits in a single byte.
 impl Encode for &str {
-    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
-        assert!(self.len() <= u8::MAX as usize);
-
-        let n = (self.len() as u8).encode(writer)?;
+    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, Error> {
+        let len = u16::try_from(self.len()).map_err(|_| Error::LengthOutOfRange {
+            len: self.len(),
+            max: u16::MAX as usize,
+        })?;
+        let n = len.encode(writer)?;
         let bytes = self.as_bytes();
 
         // Nb. Don't use the [`Encode`] instance here for &[u8], because we are prefixing the
@@ -189,31 +228,31 @@ impl Encode for &str {
 }
 
 impl Encode for String {
-    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, Error> {
         self.as_str().encode(writer)
     }
 }
 
 impl Encode for git::Url {
-    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, Error> {
         self.to_string().encode(writer)
     }
 }
 
 impl Encode for Digest {
-    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, Error> {
         self.as_ref().encode(writer)
     }
 }
 
 impl Encode for Id {
-    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, Error> {
         self.deref().encode(writer)
     }
 }
 
 impl Encode for Refs {
-    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, Error> {
         let mut n = self.len().encode(writer)?;
 
         for (name, oid) in self.iter() {
@@ -225,13 +264,13 @@ impl Encode for Refs {
 }
 
 impl Encode for Signature {
-    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, Error> {
         self.deref().encode(writer)
     }
 }
 
 impl Encode for git::Oid {
-    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, Error> {
         // Nb. We use length-encoding here to support future SHA-2 object ids.
         self.as_bytes().encode(writer)
     }
@@ -251,6 +290,12 @@ impl Decode for PublicKey {
 impl Decode for Refs {
     fn decode<R: io::Read + ?Sized>(reader: &mut R) -> Result<Self, Error> {
         let len = usize::decode(reader)?;
+        if len > MAX_REFS_SIZE {
+            return Err(Error::TooManyItems {
+                max: MAX_REFS_SIZE,
+                actual: len,
+            });
+        }
         let mut refs = BTreeMap::new();
 
         for _ in 0..len {
@@ -341,20 +386,31 @@ where
     T: Decode,
 {
     fn decode<R: io::Read + ?Sized>(reader: &mut R) -> Result<Self, Error> {
-        let len: Size = Size::decode(reader)?;
-        let mut vec = Vec::with_capacity(len as usize);
+        decode_bounded(reader, Size::MAX as usize)
+    }
+}
 
-        for _ in 0..len {
-            let item = T::decode(reader)?;
-            vec.push(item);
-        }
-        Ok(vec)
+/// Decode a vector that may hold at most `max` items.
+pub fn decode_bounded<T: Decode, R: io::Read + ?Sized>(
+    reader: &mut R,
+    max: usize,
+) -> Result<Vec<T>, Error> {
+    let len = Size::decode(reader)? as usize;
+    if len > max {
+        return Err(Error::TooManyItems { max, actual: len });
+    }
+    let mut vec = Vec::with_capacity(len.min(MAX_PREALLOCATION));
+
+    for _ in 0..len {
+        let item = T::decode(reader)?;
+        vec.push(item);
     }
+    Ok(vec)
 }
 
 impl Decode for String {
     fn decode<R: io::Read + ?Sized>(reader: &mut R) -> Result<Self, Error> {
-        let len = u8::decode(reader)?;
+        let len = u16::decode(reader)?;
         let mut bytes = vec![0; len as usize];
 
         reader.read_exact(&mut bytes)?;
@@ -392,7 +448,7 @@ impl Decode for Digest {
 }
 
 impl Encode for filter::Filter {
-    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, Error> {
         let mut n = 0;
 
         n += self.deref().as_bytes().encode(writer)?;
@@ -417,7 +473,7 @@ impl Decode for filter::Filter {
 }
 
 impl<V> Encode for SignedRefs<V> {
-    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, Error> {
         let mut n = 0;
 
         n += self.refs.encode(writer)?;
@@ -438,14 +494,65 @@ impl Decode for SignedRefs<Unverified> {
 
 #[derive(Debug)]
 pub struct Wire<S, T, G> {
-    inboxes: HashMap<IpAddr, Decoder>,
+    inboxes: HashMap<net::SocketAddr, Decoder<Frame>>,
+    /// Git streams of each connected peer.
+    streams: HashMap<net::SocketAddr, Streams>,
+    /// Frames queued by streams, to be sent to peers.
+    outgoing: chan::Receiver<(net::SocketAddr, Frame)>,
+    /// Used by streams to queue frames.
+    outgoing_sender: stream::Outgoing,
+    /// Frames queued by the reactor itself. These don't go through `outgoing`, since
+    /// the reactor would block forever if it was full.
+    pending: VecDeque<(net::SocketAddr, Frame)>,
+    /// Wakes up the reactor when streams have frames to send, or fetches and resolutions
+    /// complete.
+    waker: stream::Waker,
+    /// Upload-packs being served to peers.
+    uploads: upload_pack::Uploads,
+    /// Ongoing fetches, and the peer and stream they're using. There is at most one
+    /// per repository.
+    fetches: HashMap<Id, (net::SocketAddr, StreamId)>,
+    /// Results of fetches, to be passed on to the service.
+    fetched: chan::Receiver<(net::SocketAddr, Id, FetchResult)>,
+    /// Used by fetches to report their results.
+    fetched_sender: chan::Sender<(net::SocketAddr, Id, FetchResult)>,
+    /// Results of hostname resolutions, to be passed on to the service.
+    resolved: chan::Receiver<(Hostname, u16, ResolveResult)>,
+    /// Used by resolutions to report their results.
+    resolved_sender: chan::Sender<(Hostname, u16, ResolveResult)>,
     inner: service::Service<S, T, G>,
 }
 
+/// Serialize a frame sent over a git stream.
+fn serialize_stream(frame: &Frame) -> Vec<u8> {
+    serialize(frame).expect("stream frames are within encoding limits")
+}
+
+/// Result of a fetch, as reported by the thread it ran on.
+type FetchResult = Result<Vec<RefUpdate>, service::FetchError>;
+
+/// Result of a hostname resolution, as reported by the thread it ran on.
+type ResolveResult = Result<Vec<net::SocketAddr>, io::Error>;
+
 impl<S, T, G> Wire<S, T, G> {
-    pub fn new(inner: service::Service<S, T, G>) -> Self {
+    pub fn new(inner: service::Service<S, T, G>, waker: stream::Waker) -> Self {
+        let (outgoing_sender, outgoing) = chan::bounded(MAX_OUTGOING_FRAMES);
+        let (fetched_sender, fetched) = chan::unbounded();
+        let (resolved_sender, resolved) = chan::unbounded();
+
         Self {
             inboxes: HashMap::new(),
+            streams: HashMap::new(),
+            outgoing,
+            outgoing_sender,
+            pending: VecDeque::new(),
+            waker,
+            uploads: upload_pack::Uploads::default(),
+            fetches: HashMap::new(),
+            fetched,
+            fetched_sender,
+            resolved,
+            resolved_sender,
             inner,
         }
     }
@@ -455,6 +562,7 @@ impl<S, T, G> Wire<S, T, G>
 where
     S: address_book::Store,
     T: WriteStorage + 'static,
+    T::Repository: Send + 'static,
     G: Signer,
 {
     pub fn connected(
@@ -463,7 +571,12 @@ where
         local_addr: &std::net::SocketAddr,
         link: Link,
     ) {
-        self.inboxes.insert(addr.ip(), Decoder::new(256));
+        self.inboxes
+            .insert(addr, Decoder::with_limit(256, MAX_INBOX_SIZE));
+        self.streams.insert(
+            addr,
+            Streams::new(addr, link, self.outgoing_sender.clone(), self.waker.clone()),
+        );
         self.inner.connected(addr, local_addr, link)
     }
 
@@ -472,56 +585,307 @@ where
         addr: &std::net::SocketAddr,
         reason: nakamoto::DisconnectReason<service::DisconnectReason>,
     ) {
-        self.inboxes.remove(&addr.ip());
+        self.inboxes.remove(addr);
+        // Dropping the streams lets their local ends know that the connection was lost.
+        self.streams.remove(addr);
         self.inner.disconnected(addr, reason)
     }
 
     pub fn received_bytes(&mut self, addr: &std::net::SocketAddr, bytes: &[u8]) {
-        let peer_ip = addr.ip();
-
-        if let Some(inbox) = self.inboxes.get_mut(&peer_ip) {
+        if let Some(inbox) = self.inboxes.get_mut(addr) {
             inbox.input(bytes);
+        } else {
+            log::debug!("Received message from unknown peer {}", addr);
+            return;
+        }
+
+        while let Some(inbox) = self.inboxes.get_mut(addr) {
+            match inbox.decode_next() {
+                Ok(Some(frame)) => self.received_frame(addr, frame),
+                Ok(None) => break,
 
-            loop {
-                match inbox.decode_next() {
-                    Ok(Some(msg)) => self.inner.received_message(addr, msg),
-                    Ok(None) => break,
+                // Frames and messages are length-prefixed, so the ones we don't understand
+                // can be skipped. This allows new types to be introduced without breaking
+                // older nodes.
+                Err(Error::UnknownFrameType(t)) => {
+                    log::debug!("Skipping frame of unknown type {} from {}", t, addr);
+                }
+                Err(Error::UnknownMessageType(t)) => {
+                    log::debug!("Skipping message of unknown type {} from {}", t, addr);
+                }
+
+                Err(err) => {
+                    log::error!("Invalid message received from {}: {}", addr, err);
 
-                    Err(err) => {
-                        // TODO: Disconnect peer.
-                        log::error!("Invalid message received from {}: {}", peer_ip, err);
+                    let err = match err {
+                        Error::MessageTooLarge(size) => SessionError::MessageTooLarge(size),
+                        _ => SessionError::InvalidMessage,
+                    };
+                    self.inner.session_error(addr, err);
+
+                    return;
+                }
+            }
+        }
+    }
 
-                        return;
+    fn received_frame(&mut self, addr: &net::SocketAddr, frame: Frame) {
+        match frame {
+            Frame::Message(msg) => self.inner.received_message(addr, msg),
+            Frame::Open { stream, id } => self.upload_pack(addr, stream, id),
+            Frame::Data { stream, data } => {
+                if let Some(streams) = self.streams.get_mut(addr) {
+                    match streams.received(stream, data) {
+                        Ok(()) => {}
+                        Err(err @ stream::Error::WindowExceeded(_)) => {
+                            log::error!("Invalid stream data from {}: {}", addr, err);
+
+                            self.pending.push_back((*addr, Frame::Close { stream }));
+                            self.inner.session_error(addr, SessionError::Misbehavior);
+                        }
+                        Err(err) => {
+                            log::debug!("Dropping data from {}: {}", addr, err);
+                        }
+                    }
+                }
+            }
+            Frame::Close { stream } => {
+                if let Some(streams) = self.streams.get_mut(addr) {
+                    streams.close(stream);
+                }
+            }
+            Frame::Credit { stream, frames } => {
+                if let Some(streams) = self.streams.get_mut(addr) {
+                    if let Err(err) = streams.credit(stream, frames) {
+                        log::debug!("Dropping credit from {}: {}", addr, err);
                     }
                 }
             }
+        }
+    }
+
+    /// Serve a repository to a peer that opened a stream to fetch it. The repository
+    /// is served from its own thread, as long as not too many are being served already.
+    fn upload_pack(&mut self, remote: &net::SocketAddr, stream: StreamId, id: Id) {
+        let repo = if !self.inner.config().is_tracking(&id) {
+            Err("the project is not tracked")
+        } else {
+            match self.inner.storage().repository(id) {
+                Ok(repo) if !repo.is_empty().unwrap_or(true) => Ok(repo),
+                Ok(_) => Err("the project was not found"),
+                Err(_) => Err("the project could not be opened"),
+            }
+        };
+        let streams = if let Some(streams) = self.streams.get_mut(remote) {
+            streams
+        } else {
+            return;
+        };
+        let upload = repo.and_then(|repo| {
+            self.uploads
+                .start(remote.ip())
+                .map(|upload| (repo, upload))
+                .ok_or("too many upload-packs are being served")
+        });
+        let (repo, upload) = match upload {
+            Ok(upload) => upload,
+            Err(reason) => {
+                log::debug!("Refusing to serve {} to {}: {}", id, remote, reason);
+
+                // Closing a stream that is already open would interfere with it.
+                if !streams.is_open(stream) {
+                    self.pending.push_back((*remote, Frame::Close { stream }));
+                }
+                return;
+            }
+        };
+
+        match streams.accept(stream) {
+            Ok(stream) => {
+                let remote = *remote;
+
+                thread::spawn(move || {
+                    if let Err(err) = upload_pack::upload_pack(&repo, stream) {
+                        log::warn!("Failed to serve {} to {}: {}", id, remote, err);
+                    }
+                    // The upload-pack counts towards the limits until it's done.
+                    drop(upload);
+                });
+            }
+            Err(err @ stream::Error::InvalidStream(_)) => {
+                log::debug!("Refusing stream from {}: {}", remote, err);
+            }
+            Err(err) => {
+                log::debug!("Refusing stream {} from {}: {}", stream, remote, err);
+
+                self.pending.push_back((*remote, Frame::Close { stream }));
+            }
+        }
+    }
+
+    /// Fetch a repository from a peer, over a new git stream. The fetch runs on its own
+    /// thread, and the result is passed on to the service once it completes.
+    fn fetch(&mut self, remote: net::SocketAddr, id: Id, namespaces: Namespaces) {
+        let streams = if let Some(streams) = self.streams.get_mut(&remote) {
+            streams
         } else {
-            log::debug!("Received message from unknown peer {}", peer_ip);
+            self.inner
+                .fetched(remote, id, Err(service::FetchError::NotConnected));
+            return;
+        };
+        let mut repo = match self.inner.storage().repository(id) {
+            Ok(repo) => repo,
+            Err(err) => {
+                self.inner.fetched(remote, id, Err(err.into()));
+                return;
+            }
+        };
+        let stream = streams.open();
+
+        self.fetches.insert(id, (remote, stream.id()));
+        self.pending.push_back((
+            remote,
+            Frame::Open {
+                stream: stream.id(),
+                id,
+            },
+        ));
+
+        let fetched = self.fetched_sender.clone();
+        let waker = self.waker.clone();
+
+        thread::spawn(move || {
+            // The `rad://` transport looks up the stream by the URL it was given.
+            let smart = Smart::singleton();
+            let url = smart.insert(id, Box::new(stream));
+            let result = match namespaces {
+                Namespaces::All => repo.fetch(&url).map_err(service::FetchError::from),
+                Namespaces::Only(remotes) => repo
+                    .fetch_remotes(&url, &remotes)
+                    .map_err(service::FetchError::from)
+                    .and_then(|fetched| {
+                        // Remotes that fail verification don't prevent the others from
+                        // being updated, but if nothing could be verified, the fetch failed.
+                        if fetched.updated.is_empty() {
+                            if let Some((_, err)) = fetched.rejected.into_iter().next() {
+                                return Err(storage::FetchError::Verify(err).into());
+                            }
+                        }
+                        Ok(fetched.updated.into_values().flatten().collect())
+                    }),
+            };
+
+            // Make sure the stream isn't left behind if the fetch failed before using it.
+            smart.take(&url);
+
+            if fetched.send((remote, id, result)).is_ok() {
+                if let Err(err) = waker.wake() {
+                    log::error!("Error waking up reactor after fetch of {}: {}", id, err);
+                }
+            }
+        });
+    }
+
+    /// Cancel an ongoing fetch, by cancelling its stream. The fetch fails once it has
+    /// processed the data received so far.
+    fn cancel(&mut self, remote: net::SocketAddr, id: Id) {
+        let stream = match self.fetches.get(&id) {
+            Some((addr, stream)) if *addr == remote => *stream,
+            _ => return,
+        };
+        if let Some(streams) = self.streams.get_mut(&remote) {
+            if streams.cancel(stream) {
+                self.pending.push_back((remote, Frame::Close { stream }));
+            }
         }
     }
+
+    /// Resolve a hostname on a separate thread, since resolution may block.
+    fn resolve(&self, host: Hostname, port: u16) {
+        let resolved = self.resolved_sender.clone();
+        let waker = self.waker.clone();
+
+        thread::spawn(move || {
+            let result = (host.as_str(), port)
+                .to_socket_addrs()
+                .map(|addrs| addrs.collect());
+
+            if resolved.send((host, port, result)).is_ok() {
+                if let Err(err) = waker.wake() {
+                    log::error!("Error waking up reactor after resolution: {}", err);
+                }
+            }
+        });
+    }
 }
 
-impl<S, T, G> Iterator for Wire<S, T, G> {
+impl<S, T, G> Iterator for Wire<S, T, G>
+where
+    S: address_book::Store,
+    T: WriteStorage + 'static,
+    T::Repository: Send + 'static,
+    G: Signer,
+{
     type Item = nakamoto::Io<service::Event, service::DisconnectReason>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.inner.next() {
-            Some(Io::Write(addr, msgs)) => {
-                let mut buf = Vec::new();
-                for msg in msgs {
-                    log::debug!("Write {:?} to {}", &msg, addr.ip());
-
-                    msg.encode(&mut buf)
-                        .expect("writing to an in-memory buffer doesn't fail");
+        while let Ok((remote, id, result)) = self.fetched.try_recv() {
+            self.fetches.remove(&id);
+            self.inner.fetched(remote, id, result);
+        }
+        while let Ok((host, port, result)) = self.resolved.try_recv() {
+            self.inner.resolved(host, port, result);
+        }
+        if let Some((addr, frame)) = self.pending.pop_front() {
+            return Some(nakamoto::Io::Write(addr, serialize_stream(&frame)));
+        }
+        if let Ok((addr, frame)) = self.outgoing.try_recv() {
+            return Some(nakamoto::Io::Write(addr, serialize_stream(&frame)));
+        }
+
+        loop {
+            match self.inner.next() {
+                Some(Io::Write(addr, msgs)) => {
+                    let mut buf = Vec::new();
+                    for msg in msgs {
+                        log::debug!("Write {:?} to {}", &msg, addr.ip());
+
+                        // Messages that can't be encoded are dropped, the others are sent.
+                        match serialize(&Frame::from(msg)) {
+                            Ok(bytes) => buf.extend(bytes),
+                            Err(err) => log::error!("Dropping message to {}: {}", addr, err),
+                        }
+                    }
+                    if !buf.is_empty() {
+                        return Some(nakamoto::Io::Write(addr, buf));
+                    }
+                }
+                Some(Io::Event(e)) => return Some(nakamoto::Io::Event(e)),
+                Some(Io::Connect(a)) => return Some(nakamoto::Io::Connect(a)),
+                Some(Io::Resolve { host, port }) => self.resolve(host, port),
+                Some(Io::Relay { proxy, target }) => {
+                    let result = relay::spawn(proxy);
+                    self.inner.relayed(target, result);
+                }
+                Some(Io::Cancel { remote, id }) => self.cancel(remote, id),
+                Some(Io::Disconnect(a, r)) => return Some(nakamoto::Io::Disconnect(a, r)),
+                Some(Io::Wakeup(d)) => return Some(nakamoto::Io::Wakeup(d)),
+                Some(Io::Fetch {
+                    remote,
+                    id,
+                    namespaces,
+                }) => {
+                    self.fetch(remote, id, namespaces);
+
+                    // Opening the stream queued a frame for the remote. It must be sent
+                    // before any data the fetch sends over the stream.
+                    if let Some((addr, frame)) = self.pending.pop_front() {
+                        return Some(nakamoto::Io::Write(addr, serialize_stream(&frame)));
+                    }
                 }
-                Some(nakamoto::Io::Write(addr, buf))
-            }
-            Some(Io::Event(e)) => Some(nakamoto::Io::Event(e)),
-            Some(Io::Connect(a)) => Some(nakamoto::Io::Connect(a)),
-            Some(Io::Disconnect(a, r)) => Some(nakamoto::Io::Disconnect(a, r)),
-            Some(Io::Wakeup(d)) => Some(nakamoto::Io::Wakeup(d)),
 
-            None => None,
+                None => return None,
+            }
         }
     }
 }
@@ -548,25 +912,39 @@ mod tests {
     use crate::crypto::Unverified;
     use crate::storage::refs::SignedRefs;
     use crate::test::arbitrary;
+    use crate::test::peer;
+    use crate::test::storage::MockStorage;
 
     #[quickcheck]
     fn prop_u8(input: u8) {
-        assert_eq!(deserialize::<u8>(&serialize(&input)).unwrap(), input);
+        assert_eq!(
+            deserialize::<u8>(&serialize(&input).unwrap()).unwrap(),
+            input
+        );
     }
 
     #[quickcheck]
     fn prop_u16(input: u16) {
-        assert_eq!(deserialize::<u16>(&serialize(&input)).unwrap(), input);
+        assert_eq!(
+            deserialize::<u16>(&serialize(&input).unwrap()).unwrap(),
+            input
+        );
     }
 
     #[quickcheck]
     fn prop_u32(input: u32) {
-        assert_eq!(deserialize::<u32>(&serialize(&input)).unwrap(), input);
+        assert_eq!(
+            deserialize::<u32>(&serialize(&input).unwrap()).unwrap(),
+            input
+        );
     }
 
     #[quickcheck]
     fn prop_u64(input: u64) {
-        assert_eq!(deserialize::<u64>(&serialize(&input)).unwrap(), input);
+        assert_eq!(
+            deserialize::<u64>(&serialize(&input).unwrap()).unwrap(),
+            input
+        );
     }
 
     #[quickcheck]
@@ -574,17 +952,23 @@ mod tests {
         if input > u32::MAX as usize {
             return quickcheck::TestResult::discard();
         }
-        assert_eq!(deserialize::<usize>(&serialize(&input)).unwrap(), input);
+        assert_eq!(
+            deserialize::<usize>(&serialize(&input).unwrap()).unwrap(),
+            input
+        );
 
         quickcheck::TestResult::passed()
     }
 
     #[quickcheck]
     fn prop_string(input: String) -> quickcheck::TestResult {
-        if input.len() > u8::MAX as usize {
+        if input.len() > u16::MAX as usize {
             return quickcheck::TestResult::discard();
         }
-        assert_eq!(deserialize::<String>(&serialize(&input)).unwrap(), input);
+        assert_eq!(
+            deserialize::<String>(&serialize(&input).unwrap()).unwrap(),
+            input
+        );
 
         quickcheck::TestResult::passed()
     }
@@ -592,29 +976,41 @@ mod tests {
     #[quickcheck]
     fn prop_vec(input: Vec<String>) {
         assert_eq!(
-            deserialize::<Vec<String>>(&serialize(&input.as_slice())).unwrap(),
+            deserialize::<Vec<String>>(&serialize(&input.as_slice()).unwrap()).unwrap(),
             input
         );
     }
 
     #[quickcheck]
     fn prop_pubkey(input: PublicKey) {
-        assert_eq!(deserialize::<PublicKey>(&serialize(&input)).unwrap(), input);
+        assert_eq!(
+            deserialize::<PublicKey>(&serialize(&input).unwrap()).unwrap(),
+            input
+        );
     }
 
     #[quickcheck]
     fn prop_id(input: Id) {
-        assert_eq!(deserialize::<Id>(&serialize(&input)).unwrap(), input);
+        assert_eq!(
+            deserialize::<Id>(&serialize(&input).unwrap()).unwrap(),
+            input
+        );
     }
 
     #[quickcheck]
     fn prop_digest(input: Digest) {
-        assert_eq!(deserialize::<Digest>(&serialize(&input)).unwrap(), input);
+        assert_eq!(
+            deserialize::<Digest>(&serialize(&input).unwrap()).unwrap(),
+            input
+        );
     }
 
     #[quickcheck]
     fn prop_refs(input: Refs) {
-        assert_eq!(deserialize::<Refs>(&serialize(&input)).unwrap(), input);
+        assert_eq!(
+            deserialize::<Refs>(&serialize(&input).unwrap()).unwrap(),
+            input
+        );
     }
 
     #[quickcheck]
@@ -622,7 +1018,7 @@ mod tests {
         let signature = Signature::from(input.into_inner());
 
         assert_eq!(
-            deserialize::<Signature>(&serialize(&signature)).unwrap(),
+            deserialize::<Signature>(&serialize(&signature).unwrap()).unwrap(),
             signature
         );
     }
@@ -631,13 +1027,16 @@ mod tests {
     fn prop_oid(input: arbitrary::ByteArray<20>) {
         let oid = git::Oid::try_from(input.into_inner().as_slice()).unwrap();
 
-        assert_eq!(deserialize::<git::Oid>(&serialize(&oid)).unwrap(), oid);
+        assert_eq!(
+            deserialize::<git::Oid>(&serialize(&oid).unwrap()).unwrap(),
+            oid
+        );
     }
 
     #[quickcheck]
     fn prop_signed_refs(input: SignedRefs<Unverified>) {
         assert_eq!(
-            deserialize::<SignedRefs<Unverified>>(&serialize(&input)).unwrap(),
+            deserialize::<SignedRefs<Unverified>>(&serialize(&input).unwrap()).unwrap(),
             input
         );
     }
@@ -645,9 +1044,146 @@ mod tests {
     #[test]
     fn test_string() {
         assert_eq!(
-            serialize(&String::from("hello")),
-            vec![5, b'h', b'e', b'l', b'l', b'o']
+            serialize(&String::from("hello")).unwrap(),
+            vec![0, 5, b'h', b'e', b'l', b'l', b'o']
+        );
+    }
+
+    #[test]
+    fn test_encode_out_of_range() {
+        let long = "x".repeat(u16::MAX as usize + 1);
+
+        assert!(matches!(
+            serialize(&long),
+            Err(Error::LengthOutOfRange { len, max }) if len == long.len() && max == u16::MAX as usize
+        ));
+        assert_eq!(
+            deserialize::<String>(&serialize(&long[1..]).unwrap()).unwrap(),
+            long[1..]
+        );
+
+        // URLs longer than a single-byte length used to be unencodable.
+        let url = git::Url {
+            scheme: git::url::Scheme::Https,
+            host: Some("seed.radicle.xyz".to_owned()),
+            path: format!("/{}", "x".repeat(300)).into(),
+            ..git::Url::default()
+        };
+        assert_eq!(
+            deserialize::<git::Url>(&serialize(&url).unwrap()).unwrap(),
+            url
+        );
+    }
+
+    #[test]
+    fn test_vec_bounded() {
+        let bytes = serialize(&[1u8, 2, 3].as_slice()).unwrap();
+
+        assert!(matches!(
+            decode_bounded::<u8, _>(&mut io::Cursor::new(&bytes), 2),
+            Err(Error::TooManyItems { max: 2, actual: 3 })
+        ));
+        assert_eq!(
+            decode_bounded::<u8, _>(&mut io::Cursor::new(&bytes), 3).unwrap(),
+            vec![1, 2, 3]
+        );
+
+        // The length prefix alone doesn't cause a large allocation.
+        let bytes = serialize(&Size::MAX).unwrap();
+        assert!(deserialize::<Vec<u64>>(&bytes).unwrap_err().is_eof());
+    }
+
+    /// Encode a frame of the given type and payload.
+    fn frame(type_id: u8, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = serialize(&type_id).unwrap();
+        bytes.extend(serialize(&payload.len()).unwrap());
+        bytes.extend(payload);
+        bytes
+    }
+
+    #[test]
+    fn test_message_too_large() {
+        let peer = peer::Peer::new("alice", [7, 7, 7, 7], MockStorage::empty());
+        let mut wire = Wire::new(peer.service, stream::Waker::new(|| Ok(())));
+        let local = net::SocketAddr::from(([7, 7, 7, 7], 8776));
+        let remote = net::SocketAddr::from(([8, 8, 8, 8], 8776));
+
+        wire.connected(remote, &local, Link::Inbound);
+
+        // A message frame, with an envelope announcing a message that is too large.
+        // The message is rejected without waiting for it to be received.
+        let mut envelope = serialize(&service::Network::Main.magic()).unwrap();
+        envelope.extend(serialize(&(message::MAX_MESSAGE_SIZE + 1)).unwrap());
+        wire.received_bytes(&remote, &frame(frame::FrameType::Message.into(), &envelope));
+
+        assert!(wire.any(|io| matches!(
+            io,
+            nakamoto::Io::Disconnect(
+                addr,
+                service::DisconnectReason::Error(SessionError::MessageTooLarge(_))
+            ) if addr == remote
+        )));
+
+        // Frames that are too large are rejected as well.
+        let remote = net::SocketAddr::from(([9, 9, 9, 9], 8776));
+        wire.connected(remote, &local, Link::Inbound);
+
+        let mut bytes = serialize(&u8::from(frame::FrameType::Data)).unwrap();
+        bytes.extend(serialize(&(frame::MAX_FRAME_PAYLOAD_SIZE + 1)).unwrap());
+        wire.received_bytes(&remote, &bytes);
+
+        assert!(wire.any(|io| matches!(
+            io,
+            nakamoto::Io::Disconnect(
+                addr,
+                service::DisconnectReason::Error(SessionError::MessageTooLarge(_))
+            ) if addr == remote
+        )));
+    }
+
+    #[test]
+    fn test_unknown_message_type() {
+        let peer = peer::Peer::new("alice", [7, 7, 7, 7], MockStorage::empty());
+        let mut wire = Wire::new(peer.service, stream::Waker::new(|| Ok(())));
+        let local = net::SocketAddr::from(([7, 7, 7, 7], 8776));
+        let remote = net::SocketAddr::from(([8, 8, 8, 8], 8776));
+        let magic = serialize(&service::Network::Main.magic()).unwrap();
+
+        wire.connected(remote, &local, Link::Inbound);
+
+        // A frame of a type we don't know about.
+        let mut unknown = frame(u8::MAX, &[1, 2, 3]);
+
+        // A message frame, with a message of a type we don't know about.
+        let mut msg = serialize(&u16::MAX).unwrap();
+        msg.extend([1, 2, 3, 4, 5]);
+
+        let mut envelope = magic.clone();
+        envelope.extend(serialize(&msg.len()).unwrap());
+        envelope.extend(msg);
+        unknown.extend(frame(frame::FrameType::Message.into(), &envelope));
+
+        wire.received_bytes(&remote, &unknown);
+        assert!(
+            !wire.any(|io| matches!(io, nakamoto::Io::Disconnect(..))),
+            "Unknown frames and messages are skipped"
         );
+
+        // Messages following unknown frames and messages are still processed.
+        let mut envelope = magic;
+        envelope.extend(serialize(&(message::MAX_MESSAGE_SIZE + 1)).unwrap());
+
+        let mut bytes = unknown;
+        bytes.extend(frame(frame::FrameType::Message.into(), &envelope));
+        wire.received_bytes(&remote, &bytes);
+
+        assert!(wire.any(|io| matches!(
+            io,
+            nakamoto::Io::Disconnect(
+                addr,
+                service::DisconnectReason::Error(SessionError::MessageTooLarge(_))
+            ) if addr == remote
+        )));
     }
 
     #[test]
@@ -659,6 +1195,9 @@ mod tests {
             port: Some(8888),
             ..git::Url::default()
         };
-        assert_eq!(deserialize::<git::Url>(&serialize(&url)).unwrap(), url);
+        assert_eq!(
+            deserialize::<git::Url>(&serialize(&url).unwrap()).unwrap(),
+            url
+        );
     }
 }
@@ -0,0 +1,243 @@
+//! Frames exchanged over a peer connection.
+//!
+//! A peer connection carries both service messages and git streams. Git streams are
+//! identified by a [`StreamId`] that is unique within the connection, and are opened by
+//! the node that wishes to fetch a repository. To avoid id collisions, the node that
+//! initiated the connection uses odd stream ids, while the other node uses even ones.
+//!
+//! Frames are made of a type, followed by a length-prefixed payload, so that frames of
+//! types we don't know about can be skipped.
+use std::io;
+
+use byteorder::ReadBytesExt;
+
+use crate::identity::Id;
+use crate::service::message::Envelope;
+use crate::wire;
+use crate::wire::message::MAX_MESSAGE_SIZE;
+use crate::wire::stream::MAX_DATA_SIZE;
+use crate::wire::{Decode, Encode};
+
+/// Identifies a git stream within a peer connection.
+pub type StreamId = u32;
+
+/// Maximum size of a frame payload, in bytes. Message frames are the largest: an envelope
+/// is made of a network magic and a length-prefixed message.
+pub const MAX_FRAME_PAYLOAD_SIZE: usize = 4 + 4 + MAX_MESSAGE_SIZE;
+/// Maximum size of an encoded frame: a frame type, and a length-prefixed payload.
+pub const MAX_FRAME_SIZE: usize = 1 + 4 + MAX_FRAME_PAYLOAD_SIZE;
+
+/// Frame type.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameType {
+    Message = 0,
+    Open = 1,
+    Data = 2,
+    Close = 3,
+    Credit = 4,
+}
+
+impl From<FrameType> for u8 {
+    fn from(other: FrameType) -> Self {
+        other as u8
+    }
+}
+
+impl TryFrom<u8> for FrameType {
+    type Error = u8;
+
+    fn try_from(other: u8) -> Result<Self, Self::Error> {
+        match other {
+            0 => Ok(FrameType::Message),
+            1 => Ok(FrameType::Open),
+            2 => Ok(FrameType::Data),
+            3 => Ok(FrameType::Close),
+            4 => Ok(FrameType::Credit),
+            _ => Err(other),
+        }
+    }
+}
+
+/// A unit of data sent over a peer connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    /// A service message.
+    Message(Envelope),
+    /// Open a git stream, to fetch the given repository.
+    Open { stream: StreamId, id: Id },
+    /// Data sent over a git stream.
+    Data { stream: StreamId, data: Vec<u8> },
+    /// Close a git stream. Also sent to refuse a stream.
+    Close { stream: StreamId },
+    /// Allow the remote to send more data frames over a git stream.
+    Credit { stream: StreamId, frames: u32 },
+}
+
+impl Frame {
+    pub fn type_id(&self) -> u8 {
+        match self {
+            Self::Message(_) => FrameType::Message,
+            Self::Open { .. } => FrameType::Open,
+            Self::Data { .. } => FrameType::Data,
+            Self::Close { .. } => FrameType::Close,
+            Self::Credit { .. } => FrameType::Credit,
+        }
+        .into()
+    }
+}
+
+impl From<Envelope> for Frame {
+    fn from(envelope: Envelope) -> Self {
+        Self::Message(envelope)
+    }
+}
+
+impl wire::Encode for Frame {
+    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, wire::Error> {
+        let mut payload = Vec::new();
+
+        match self {
+            Self::Message(envelope) => {
+                envelope.encode(&mut payload)?;
+            }
+            Self::Open { stream, id } => {
+                stream.encode(&mut payload)?;
+                id.encode(&mut payload)?;
+            }
+            Self::Data { stream, data } => {
+                stream.encode(&mut payload)?;
+                data.as_slice().encode(&mut payload)?;
+            }
+            Self::Close { stream } => {
+                stream.encode(&mut payload)?;
+            }
+            Self::Credit { stream, frames } => {
+                stream.encode(&mut payload)?;
+                frames.encode(&mut payload)?;
+            }
+        }
+        let mut n = self.type_id().encode(writer)?;
+        n += payload.len().encode(writer)?;
+
+        writer.write_all(&payload)?;
+        n += payload.len();
+
+        Ok(n)
+    }
+}
+
+impl wire::Decode for Frame {
+    fn decode<R: io::Read + ?Sized>(reader: &mut R) -> Result<Self, wire::Error> {
+        let type_id = reader.read_u8()?;
+        let len = usize::decode(reader)?;
+
+        if len > MAX_FRAME_PAYLOAD_SIZE {
+            return Err(wire::Error::MessageTooLarge(len));
+        }
+        let mut payload = vec![0; len];
+        reader.read_exact(&mut payload)?;
+
+        let mut cursor = io::Cursor::new(payload.as_slice());
+        let frame = match Self::decode_payload(type_id, &mut cursor) {
+            Ok(frame) => frame,
+            // The frame is complete, so running out of data means it's truncated.
+            Err(err) if err.is_eof() => return Err(wire::Error::TruncatedMessage(len)),
+            Err(err) => return Err(err),
+        };
+        let trailing = len - cursor.position() as usize;
+
+        if trailing > 0 {
+            return Err(wire::Error::TrailingBytes(trailing));
+        }
+        Ok(frame)
+    }
+}
+
+impl Frame {
+    /// Decode the payload of a frame of the given type.
+    fn decode_payload<R: io::Read + ?Sized>(
+        type_id: u8,
+        reader: &mut R,
+    ) -> Result<Self, wire::Error> {
+        match FrameType::try_from(type_id) {
+            Ok(FrameType::Message) => {
+                let envelope = Envelope::decode(reader)?;
+
+                Ok(Self::Message(envelope))
+            }
+            Ok(FrameType::Open) => {
+                let stream = StreamId::decode(reader)?;
+                let id = Id::decode(reader)?;
+
+                Ok(Self::Open { stream, id })
+            }
+            Ok(FrameType::Data) => {
+                let stream = StreamId::decode(reader)?;
+                let data = wire::decode_bounded(reader, MAX_DATA_SIZE)?;
+
+                Ok(Self::Data { stream, data })
+            }
+            Ok(FrameType::Close) => {
+                let stream = StreamId::decode(reader)?;
+
+                Ok(Self::Close { stream })
+            }
+            Ok(FrameType::Credit) => {
+                let stream = StreamId::decode(reader)?;
+                let frames = u32::decode(reader)?;
+
+                Ok(Self::Credit { stream, frames })
+            }
+            Err(other) => Err(wire::Error::UnknownFrameType(other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck_macros::quickcheck;
+
+    use crate::decoder::Decoder;
+    use crate::wire::{deserialize, serialize};
+
+    #[quickcheck]
+    fn prop_frame_encode_decode(frame: Frame) {
+        assert_eq!(
+            deserialize::<Frame>(&serialize(&frame).unwrap()).unwrap(),
+            frame
+        );
+    }
+
+    #[test]
+    fn test_unknown_frame_type() {
+        let mut decoder = Decoder::<Frame>::new(8);
+        let close = Frame::Close { stream: 7 };
+
+        decoder.input(&[0xff, 0, 0, 0, 3, 1, 2, 3]);
+        decoder.input(&serialize(&close).unwrap());
+
+        assert!(matches!(
+            decoder.decode_next(),
+            Err(wire::Error::UnknownFrameType(0xff))
+        ));
+        assert_eq!(
+            decoder.decode_next().unwrap(),
+            Some(close),
+            "Frames following an unknown frame are decoded"
+        );
+        assert!(matches!(decoder.decode_next(), Ok(None)));
+    }
+
+    #[test]
+    fn test_frame_too_large() {
+        let mut bytes = serialize(&u8::from(FrameType::Data)).unwrap();
+        bytes.extend(serialize(&(MAX_FRAME_PAYLOAD_SIZE + 1)).unwrap());
+
+        assert!(matches!(
+            deserialize::<Frame>(&bytes),
+            Err(wire::Error::MessageTooLarge(n)) if n == MAX_FRAME_PAYLOAD_SIZE + 1
+        ));
+    }
+}
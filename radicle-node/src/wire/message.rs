@@ -4,14 +4,45 @@ use byteorder::{NetworkEndian, ReadBytesExt};
 
 use crate::git;
 use crate::prelude::*;
+use crate::service::filter::FILTER_SIZE;
 use crate::service::message::*;
 use crate::wire;
 
+/// Encoded sizes of the types messages are made of.
+const TYPE_ID_SIZE: usize = 2;
+const LENGTH_SIZE: usize = 4;
+const NODE_ID_SIZE: usize = 32;
+const SIGNATURE_SIZE: usize = 64;
+const TIMESTAMP_SIZE: usize = 8;
+const VERSION_SIZE: usize = 4;
+const FEATURES_SIZE: usize = 32;
+const ALIAS_SIZE: usize = 32;
+/// Object ids are length-prefixed.
+const OID_SIZE: usize = LENGTH_SIZE + 20;
+/// Strings are prefixed with a two-byte length.
+const STRING_LENGTH_SIZE: usize = 2;
+/// Space budgeted for the git URL in an initialize message.
+const MAX_URL_SIZE: usize = STRING_LENGTH_SIZE + 1024;
+/// Space budgeted for each ref in a refs announcement. Ref names may be longer, as long
+/// as the announcement as a whole fits.
+const MAX_REF_SIZE: usize = STRING_LENGTH_SIZE + 255 + OID_SIZE;
+/// Addresses are prefixed with a one-byte type and a two-byte length.
+const ADDRESS_HEADER_SIZE: usize = 1 + 2;
+/// Hostnames are the largest addresses we know: a length-prefixed name, and a port.
+/// Addresses of unknown types may not be larger.
+const MAX_ADDRESS_SIZE: usize = ADDRESS_HEADER_SIZE + STRING_LENGTH_SIZE + MAX_HOSTNAME_LENGTH + 2;
+/// Extension fields are prefixed with a two-byte type and a two-byte length.
+const EXTENSION_HEADER_SIZE: usize = 2 + 2;
+
+/// Maximum size of a message payload, in bytes. Refs announcements are the largest messages.
+pub const MAX_MESSAGE_SIZE: usize = MessageType::RefsAnnouncement.max_size();
+
 /// Message type.
 #[repr(u16)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MessageType {
     Initialize = 0,
+    InitializeAck = 1,
     NodeAnnouncement = 2,
     InventoryAnnouncement = 4,
     RefsAnnouncement = 6,
@@ -30,6 +61,7 @@ impl TryFrom<u16> for MessageType {
     fn try_from(other: u16) -> Result<Self, Self::Error> {
         match other {
             0 => Ok(MessageType::Initialize),
+            1 => Ok(MessageType::InitializeAck),
             2 => Ok(MessageType::NodeAnnouncement),
             4 => Ok(MessageType::InventoryAnnouncement),
             6 => Ok(MessageType::RefsAnnouncement),
@@ -39,10 +71,58 @@ impl TryFrom<u16> for MessageType {
     }
 }
 
+impl MessageType {
+    /// Maximum size of an encoded message of this type, including its type id.
+    /// Larger messages are rejected before they are decoded.
+    pub const fn max_size(&self) -> usize {
+        let payload = match self {
+            Self::Initialize => {
+                NODE_ID_SIZE
+                    + VERSION_SIZE * 2
+                    + FEATURES_SIZE
+                    + LENGTH_SIZE
+                    + MAX_ADDRESSES * MAX_ADDRESS_SIZE
+                    + MAX_URL_SIZE
+            }
+            Self::InitializeAck => VERSION_SIZE + FEATURES_SIZE,
+            Self::NodeAnnouncement => {
+                NODE_ID_SIZE
+                    + FEATURES_SIZE
+                    + TIMESTAMP_SIZE
+                    + ALIAS_SIZE
+                    + LENGTH_SIZE
+                    + MAX_ADDRESSES * MAX_ADDRESS_SIZE
+                    + SIGNATURE_SIZE
+                    + MAX_EXTENSIONS_SIZE
+            }
+            Self::InventoryAnnouncement => {
+                NODE_ID_SIZE
+                    + LENGTH_SIZE
+                    + MAX_INVENTORY_SIZE * OID_SIZE
+                    + TIMESTAMP_SIZE
+                    + SIGNATURE_SIZE
+                    + MAX_EXTENSIONS_SIZE
+            }
+            Self::RefsAnnouncement => {
+                NODE_ID_SIZE
+                    + OID_SIZE
+                    + LENGTH_SIZE
+                    + MAX_REFS_SIZE * MAX_REF_SIZE
+                    + TIMESTAMP_SIZE
+                    + SIGNATURE_SIZE
+                    + MAX_EXTENSIONS_SIZE
+            }
+            Self::Subscribe => LENGTH_SIZE + FILTER_SIZE + TIMESTAMP_SIZE * 2,
+        };
+        TYPE_ID_SIZE + payload
+    }
+}
+
 impl Message {
     pub fn type_id(&self) -> u16 {
         match self {
             Self::Initialize { .. } => MessageType::Initialize,
+            Self::InitializeAck { .. } => MessageType::InitializeAck,
             Self::Subscribe { .. } => MessageType::Subscribe,
             Self::NodeAnnouncement { .. } => MessageType::NodeAnnouncement,
             Self::InventoryAnnouncement { .. } => MessageType::InventoryAnnouncement,
@@ -83,11 +163,13 @@ impl TryFrom<u8> for AddressType {
 }
 
 impl wire::Encode for RefsAnnouncement {
-    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, wire::Error> {
         let mut n = 0;
 
         n += self.id.encode(writer)?;
         n += self.refs.encode(writer)?;
+        n += self.timestamp.encode(writer)?;
+        n += self.extensions.encode(writer)?;
 
         Ok(n)
     }
@@ -97,17 +179,25 @@ impl wire::Decode for RefsAnnouncement {
     fn decode<R: std::io::Read + ?Sized>(reader: &mut R) -> Result<Self, wire::Error> {
         let id = Id::decode(reader)?;
         let refs = Refs::decode(reader)?;
+        let timestamp = Timestamp::decode(reader)?;
+        let extensions = Extensions::decode(reader)?;
 
-        Ok(Self { id, refs })
+        Ok(Self {
+            id,
+            refs,
+            timestamp,
+            extensions,
+        })
     }
 }
 
 impl wire::Encode for InventoryAnnouncement {
-    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, wire::Error> {
         let mut n = 0;
 
         n += self.inventory.as_slice().encode(writer)?;
         n += self.timestamp.encode(writer)?;
+        n += self.extensions.encode(writer)?;
 
         Ok(n)
     }
@@ -115,32 +205,94 @@ impl wire::Encode for InventoryAnnouncement {
 
 impl wire::Decode for InventoryAnnouncement {
     fn decode<R: std::io::Read + ?Sized>(reader: &mut R) -> Result<Self, wire::Error> {
-        let inventory = Vec::<Id>::decode(reader)?;
+        let inventory = wire::decode_bounded(reader, MAX_INVENTORY_SIZE)?;
         let timestamp = Timestamp::decode(reader)?;
+        let extensions = Extensions::decode(reader)?;
 
         Ok(Self {
             inventory,
             timestamp,
+            extensions,
         })
     }
 }
 
+/// Extensions are the last fields of an announcement, and extend to the end of the
+/// message. An announcement without extensions is encoded without them.
+impl wire::Encode for Extensions {
+    fn encode<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, wire::Error> {
+        let mut n = 0;
+
+        for (ty, value) in self.iter() {
+            let len = u16::try_from(value.len()).map_err(|_| wire::Error::LengthOutOfRange {
+                len: value.len(),
+                max: u16::MAX as usize,
+            })?;
+            n += ty.encode(writer)?;
+            n += len.encode(writer)?;
+
+            writer.write_all(value)?;
+            n += value.len();
+        }
+        Ok(n)
+    }
+}
+
+impl wire::Decode for Extensions {
+    fn decode<R: std::io::Read + ?Sized>(reader: &mut R) -> Result<Self, wire::Error> {
+        let mut extensions = Extensions::default();
+        let mut last = None;
+
+        loop {
+            // Running out of data between fields is the end of the extensions.
+            let mut first = [0; 1];
+            if reader.read(&mut first)? == 0 {
+                break;
+            }
+            let ty = u16::from_be_bytes([first[0], reader.read_u8()?]);
+            let len = u16::decode(reader)?;
+
+            // Types must be strictly ascending, so that the encoding is unique, and
+            // relayed announcements can still be verified.
+            if last.map_or(false, |last| ty <= last) {
+                return Err(wire::Error::UnorderedExtension(ty));
+            }
+            let mut value = vec![0; len as usize];
+            reader.read_exact(&mut value)?;
+
+            extensions.insert(ty, value);
+            last = Some(ty);
+        }
+        Ok(extensions)
+    }
+}
+
+/// Announcements are signed, and their signature precedes the announcement, so that
+/// extension fields can trail it.
 impl wire::Encode for Message {
-    fn encode<W: std::io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, std::io::Error> {
+    fn encode<W: std::io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, wire::Error> {
         let mut n = self.type_id().encode(writer)?;
 
         match self {
             Self::Initialize {
                 id,
                 version,
+                min_version,
+                features,
                 addrs,
                 git,
             } => {
                 n += id.encode(writer)?;
                 n += version.encode(writer)?;
+                n += min_version.encode(writer)?;
+                n += features.encode(writer)?;
                 n += addrs.as_slice().encode(writer)?;
                 n += git.encode(writer)?;
             }
+            Self::InitializeAck { version, features } => {
+                n += version.encode(writer)?;
+                n += features.encode(writer)?;
+            }
             Self::Subscribe(Subscribe {
                 filter,
                 since,
@@ -156,8 +308,8 @@ impl wire::Encode for Message {
                 signature,
             } => {
                 n += node.encode(writer)?;
-                n += message.encode(writer)?;
                 n += signature.encode(writer)?;
+                n += message.encode(writer)?;
             }
             Self::InventoryAnnouncement {
                 node,
@@ -165,8 +317,8 @@ impl wire::Encode for Message {
                 signature,
             } => {
                 n += node.encode(writer)?;
-                n += message.encode(writer)?;
                 n += signature.encode(writer)?;
+                n += message.encode(writer)?;
             }
             Self::NodeAnnouncement {
                 node,
@@ -174,8 +326,8 @@ impl wire::Encode for Message {
                 signature,
             } => {
                 n += node.encode(writer)?;
-                n += message.encode(writer)?;
                 n += signature.encode(writer)?;
+                n += message.encode(writer)?;
             }
         }
         Ok(n)
@@ -190,16 +342,26 @@ impl wire::Decode for Message {
             Ok(MessageType::Initialize) => {
                 let id = NodeId::decode(reader)?;
                 let version = u32::decode(reader)?;
-                let addrs = Vec::<Address>::decode(reader)?;
+                let min_version = u32::decode(reader)?;
+                let features = NodeFeatures::decode(reader)?;
+                let addrs = wire::decode_bounded(reader, MAX_ADDRESSES)?;
                 let git = git::Url::decode(reader)?;
 
                 Ok(Self::Initialize {
                     id,
                     version,
+                    min_version,
+                    features,
                     addrs,
                     git,
                 })
             }
+            Ok(MessageType::InitializeAck) => {
+                let version = u32::decode(reader)?;
+                let features = NodeFeatures::decode(reader)?;
+
+                Ok(Self::InitializeAck { version, features })
+            }
             Ok(MessageType::Subscribe) => {
                 let filter = Filter::decode(reader)?;
                 let since = Timestamp::decode(reader)?;
@@ -213,8 +375,8 @@ impl wire::Decode for Message {
             }
             Ok(MessageType::NodeAnnouncement) => {
                 let node = NodeId::decode(reader)?;
-                let message = NodeAnnouncement::decode(reader)?;
                 let signature = Signature::decode(reader)?;
+                let message = NodeAnnouncement::decode(reader)?;
 
                 Ok(Self::NodeAnnouncement {
                     node,
@@ -224,8 +386,8 @@ impl wire::Decode for Message {
             }
             Ok(MessageType::InventoryAnnouncement) => {
                 let node = NodeId::decode(reader)?;
-                let message = InventoryAnnouncement::decode(reader)?;
                 let signature = Signature::decode(reader)?;
+                let message = InventoryAnnouncement::decode(reader)?;
 
                 Ok(Self::InventoryAnnouncement {
                     node,
@@ -235,8 +397,8 @@ impl wire::Decode for Message {
             }
             Ok(MessageType::RefsAnnouncement) => {
                 let node = NodeId::decode(reader)?;
-                let message = RefsAnnouncement::decode(reader)?;
                 let signature = Signature::decode(reader)?;
+                let message = RefsAnnouncement::decode(reader)?;
 
                 Ok(Self::RefsAnnouncement {
                     node,
@@ -249,12 +411,24 @@ impl wire::Decode for Message {
     }
 }
 
+/// Envelopes carry a length-prefixed message, so that oversized messages can be rejected
+/// before they are received in full, let alone decoded.
 impl wire::Encode for Envelope {
-    fn encode<W: std::io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, std::io::Error> {
+    fn encode<W: std::io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, wire::Error> {
         let mut n = 0;
+        let msg = wire::serialize(&self.msg)?;
 
+        // Don't send messages that peers would reject.
+        if let Ok(t) = MessageType::try_from(self.msg.type_id()) {
+            if msg.len() > t.max_size() {
+                return Err(wire::Error::MessageTooLarge(msg.len()));
+            }
+        }
         n += self.magic.encode(writer)?;
-        n += self.msg.encode(writer)?;
+        n += msg.len().encode(writer)?;
+
+        writer.write_all(&msg)?;
+        n += msg.len();
 
         Ok(n)
     }
@@ -263,30 +437,99 @@ impl wire::Encode for Envelope {
 impl wire::Decode for Envelope {
     fn decode<R: std::io::Read + ?Sized>(reader: &mut R) -> Result<Self, wire::Error> {
         let magic = u32::decode(reader)?;
-        let msg = Message::decode(reader)?;
+        let len = usize::decode(reader)?;
+
+        if len > MAX_MESSAGE_SIZE {
+            return Err(wire::Error::MessageTooLarge(len));
+        }
+        let mut msg = vec![0; len];
+        reader.read_exact(&mut msg)?;
+
+        if let Some(type_id) = msg.get(..TYPE_ID_SIZE) {
+            let type_id = u16::from_be_bytes([type_id[0], type_id[1]]);
 
+            if matches!(MessageType::try_from(type_id), Ok(t) if len > t.max_size()) {
+                return Err(wire::Error::MessageTooLarge(len));
+            }
+        }
+        let mut cursor = io::Cursor::new(msg.as_slice());
+        let msg = match Message::decode(&mut cursor) {
+            Ok(msg) => msg,
+            // The message is complete, so running out of data means it's truncated.
+            Err(err) if err.is_eof() => return Err(wire::Error::TruncatedMessage(len)),
+            Err(err) => return Err(err),
+        };
+        let trailing = len - cursor.position() as usize;
+
+        if trailing > 0 {
+            return Err(wire::Error::TrailingBytes(trailing));
+        }
         Ok(Self { magic, msg })
     }
 }
 
+/// Addresses are prefixed with their type and length, so that addresses of unknown types
+/// can be skipped over, rather than failing the whole message.
 impl wire::Encode for Address {
-    fn encode<W: std::io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, std::io::Error> {
-        let mut n = 0;
+    fn encode<W: std::io::Write + ?Sized>(&self, writer: &mut W) -> Result<usize, wire::Error> {
+        let mut data = Vec::new();
 
-        match self {
+        let kind = match self {
             Self::Ipv4 { ip, port } => {
-                n += u8::from(AddressType::Ipv4).encode(writer)?;
-                n += ip.octets().encode(writer)?;
-                n += port.encode(writer)?;
+                ip.octets().encode(&mut data)?;
+                port.encode(&mut data)?;
+
+                u8::from(AddressType::Ipv4)
             }
             Self::Ipv6 { ip, port } => {
-                n += u8::from(AddressType::Ipv6).encode(writer)?;
-                n += ip.octets().encode(writer)?;
-                n += port.encode(writer)?;
+                ip.octets().encode(&mut data)?;
+                port.encode(&mut data)?;
+
+                u8::from(AddressType::Ipv6)
+            }
+            Self::Hostname { host, port } => {
+                host.as_str().encode(&mut data)?;
+                port.encode(&mut data)?;
+
+                u8::from(AddressType::Hostname)
             }
-            Self::Hostname { .. } => todo!(),
-            Self::Onion { .. } => todo!(),
+            Self::Onion {
+                key,
+                port,
+                checksum,
+                version,
+            } => {
+                key.encode(&mut data)?;
+                checksum.encode(&mut data)?;
+                version.encode(&mut data)?;
+                port.encode(&mut data)?;
+
+                u8::from(AddressType::Onion)
+            }
+            Self::Unknown {
+                kind,
+                data: unknown,
+            } => {
+                data.extend_from_slice(unknown);
+
+                *kind
+            }
+        };
+        let max = MAX_ADDRESS_SIZE - ADDRESS_HEADER_SIZE;
+        if data.len() > max {
+            return Err(wire::Error::LengthOutOfRange {
+                len: data.len(),
+                max,
+            });
         }
+        let mut n = 0;
+
+        n += kind.encode(writer)?;
+        n += (data.len() as u16).encode(writer)?;
+
+        writer.write_all(&data)?;
+        n += data.len();
+
         Ok(n)
     }
 }
@@ -294,30 +537,69 @@ impl wire::Encode for Address {
 impl wire::Decode for Address {
     fn decode<R: std::io::Read + ?Sized>(reader: &mut R) -> Result<Self, wire::Error> {
         let addrtype = reader.read_u8()?;
+        let len = u16::decode(reader)? as usize;
+        let max = MAX_ADDRESS_SIZE - ADDRESS_HEADER_SIZE;
 
-        match AddressType::try_from(addrtype) {
-            Ok(AddressType::Ipv4) => {
+        if len > max {
+            return Err(wire::Error::LengthOutOfRange { len, max });
+        }
+        let mut data = vec![0; len];
+        reader.read_exact(&mut data)?;
+
+        let addrtype = match AddressType::try_from(addrtype) {
+            Ok(addrtype) => addrtype,
+            Err(kind) => return Ok(Self::Unknown { kind, data }),
+        };
+        let mut cursor = io::Cursor::new(data.as_slice());
+        let reader = &mut cursor;
+        let addr = match addrtype {
+            AddressType::Ipv4 => {
                 let octets: [u8; 4] = wire::Decode::decode(reader)?;
                 let ip = net::Ipv4Addr::from(octets);
                 let port = u16::decode(reader)?;
 
-                Ok(Self::Ipv4 { ip, port })
+                Self::Ipv4 { ip, port }
             }
-            Ok(AddressType::Ipv6) => {
+            AddressType::Ipv6 => {
                 let octets: [u8; 16] = wire::Decode::decode(reader)?;
                 let ip = net::Ipv6Addr::from(octets);
                 let port = u16::decode(reader)?;
 
-                Ok(Self::Ipv6 { ip, port })
+                Self::Ipv6 { ip, port }
             }
-            Ok(AddressType::Hostname) => {
-                todo!();
+            AddressType::Hostname => {
+                let host = Hostname::try_from(String::decode(reader)?)?;
+                let port = u16::decode(reader)?;
+
+                Self::Hostname { host, port }
             }
-            Ok(AddressType::Onion) => {
-                todo!();
+            AddressType::Onion => {
+                let key = NodeId::decode(reader)?;
+                let checksum = u16::decode(reader)?;
+                let version = u8::decode(reader)?;
+                let port = u16::decode(reader)?;
+
+                if version != ONION_VERSION {
+                    return Err(wire::Error::UnsupportedOnionVersion(version));
+                }
+                if checksum != onion_checksum(&key, version) {
+                    return Err(wire::Error::InvalidOnionChecksum(checksum));
+                }
+                Self::Onion {
+                    key,
+                    port,
+                    checksum,
+                    version,
+                }
             }
-            Err(other) => Err(wire::Error::UnknownAddressType(other)),
+        };
+        // Known addresses must take up exactly the space they were given, so that their
+        // encoding is unique, and relayed announcements can still be verified.
+        let trailing = len - cursor.position() as usize;
+        if trailing > 0 {
+            return Err(wire::Error::TrailingBytes(trailing));
         }
+        Ok(addr)
     }
 }
 
@@ -327,12 +609,15 @@ mod tests {
     use quickcheck_macros::quickcheck;
 
     use crate::decoder::Decoder;
+    use crate::test::arbitrary;
+    use crate::test::signer::MockSigner;
+    use crate::test::signer::MockSigner;
     use crate::wire::{self, Encode};
 
     #[quickcheck]
     fn prop_message_encode_decode(message: Message) {
         assert_eq!(
-            wire::deserialize::<Message>(&wire::serialize(&message)).unwrap(),
+            wire::deserialize::<Message>(&wire::serialize(&message).unwrap()).unwrap(),
             message
         );
     }
@@ -340,7 +625,7 @@ mod tests {
     #[quickcheck]
     fn prop_envelope_encode_decode(envelope: Envelope) {
         assert_eq!(
-            wire::deserialize::<Envelope>(&wire::serialize(&envelope)).unwrap(),
+            wire::deserialize::<Envelope>(&wire::serialize(&envelope).unwrap()).unwrap(),
             envelope
         );
     }
@@ -363,11 +648,238 @@ mod tests {
             .quickcheck(property as fn(items: Vec<Envelope>));
     }
 
+    #[test]
+    fn test_message_max_size() {
+        for t in [
+            MessageType::Initialize,
+            MessageType::InitializeAck,
+            MessageType::NodeAnnouncement,
+            MessageType::InventoryAnnouncement,
+            MessageType::RefsAnnouncement,
+            MessageType::Subscribe,
+        ] {
+            assert!(
+                t.max_size() <= MAX_MESSAGE_SIZE,
+                "{:?} fits in an envelope",
+                t
+            );
+        }
+    }
+
+    #[test]
+    fn test_envelope_too_large() {
+        let magic = wire::serialize(&Network::Main.magic()).unwrap();
+
+        // Larger than any message.
+        let bytes = [
+            magic.clone(),
+            wire::serialize(&(MAX_MESSAGE_SIZE + 1)).unwrap(),
+        ]
+        .concat();
+        assert!(matches!(
+            wire::deserialize::<Envelope>(&bytes),
+            Err(wire::Error::MessageTooLarge(_))
+        ));
+
+        // Larger than messages of its type.
+        let size = MessageType::InitializeAck.max_size() + 1;
+        let mut msg = wire::serialize(&u16::from(MessageType::InitializeAck)).unwrap();
+        msg.resize(size, 0);
+
+        let bytes = [magic, wire::serialize(&size).unwrap(), msg].concat();
+        assert!(matches!(
+            wire::deserialize::<Envelope>(&bytes),
+            Err(wire::Error::MessageTooLarge(n)) if n == size
+        ));
+    }
+
+    #[test]
+    fn test_inventory_too_large() {
+        let id = arbitrary::gen::<Id>(1);
+        let msg = Message::InventoryAnnouncement {
+            node: arbitrary::gen::<NodeId>(1),
+            message: InventoryAnnouncement {
+                inventory: vec![id; MAX_INVENTORY_SIZE + 1],
+                timestamp: 0,
+                extensions: Extensions::default(),
+            },
+            signature: Signature::from([0; 64]),
+        };
+        assert!(matches!(
+            wire::deserialize::<Message>(&wire::serialize(&msg).unwrap()),
+            Err(wire::Error::TooManyItems { max, .. }) if max == MAX_INVENTORY_SIZE
+        ));
+
+        // We don't send messages that are too large for our peers.
+        assert!(matches!(
+            wire::serialize(&Network::Main.envelope(msg)),
+            Err(wire::Error::MessageTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn test_announcement_extensions() {
+        let signer = MockSigner::default();
+        let mut extensions = Extensions::default();
+
+        extensions.insert(7, b"seven".to_vec());
+        extensions.insert(3, vec![]);
+
+        let message = RefsAnnouncement {
+            id: arbitrary::gen(1),
+            refs: arbitrary::gen(2),
+            timestamp: 42,
+            extensions,
+        };
+        let signature = message.sign(&signer).unwrap();
+        let msg = Message::RefsAnnouncement {
+            node: *signer.public_key(),
+            message,
+            signature,
+        };
+        let bytes = wire::serialize(&msg).unwrap();
+
+        // Extension fields trail the announcement, in ascending type order.
+        assert!(bytes.ends_with(&[0, 3, 0, 0, 0, 7, 0, 5, b's', b'e', b'v', b'e', b'n']));
+
+        // Extension fields we don't understand are kept, so that relayed announcements
+        // can still be verified.
+        match wire::deserialize::<Message>(&bytes).unwrap() {
+            Message::RefsAnnouncement {
+                node,
+                message,
+                signature,
+            } => {
+                assert_eq!(message.extensions.get(7), Some(&b"seven"[..]));
+                assert!(message.verify(&node, &signature));
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
+
+        // Out-of-order extension fields are rejected.
+        let mut unordered = bytes[..bytes.len() - 13].to_vec();
+        unordered.extend([0, 7, 0, 0, 0, 3, 0, 0]);
+
+        assert!(matches!(
+            wire::deserialize::<Message>(&unordered),
+            Err(wire::Error::UnorderedExtension(3))
+        ));
+
+        // Truncated extension fields are rejected.
+        let truncated = &bytes[..bytes.len() - 1];
+
+        assert!(wire::deserialize::<Message>(truncated)
+            .unwrap_err()
+            .is_eof());
+    }
+
     #[quickcheck]
     fn prop_addr(addr: Address) {
         assert_eq!(
-            wire::deserialize::<Address>(&wire::serialize(&addr)).unwrap(),
+            wire::deserialize::<Address>(&wire::serialize(&addr).unwrap()).unwrap(),
             addr
         );
     }
+
+    #[test]
+    fn test_addr_invalid() {
+        let key = arbitrary::gen::<NodeId>(1);
+        let mut onion = wire::serialize(&Address::onion(key, 8776)).unwrap();
+        // Corrupt the checksum, which follows the type, length and key.
+        onion[35] ^= 0xff;
+
+        assert!(matches!(
+            wire::deserialize::<Address>(&onion),
+            Err(wire::Error::InvalidOnionChecksum(_))
+        ));
+
+        for host in [
+            "",
+            "-seed.radicle.xyz",
+            "seed..xyz",
+            "seed_1.xyz",
+            "a".repeat(64).as_str(),
+        ] {
+            let mut data = wire::serialize(&host).unwrap();
+            data.extend(wire::serialize(&8776u16).unwrap());
+
+            let mut bytes = wire::serialize(&u8::from(AddressType::Hostname)).unwrap();
+            bytes.extend(wire::serialize(&(data.len() as u16)).unwrap());
+            bytes.extend(data);
+
+            assert!(
+                matches!(
+                    wire::deserialize::<Address>(&bytes),
+                    Err(wire::Error::InvalidHostname(_))
+                ),
+                "{:?} is not a valid hostname",
+                host
+            );
+        }
+        assert_eq!(
+            wire::deserialize::<Address>(
+                &wire::serialize(&Address::Hostname {
+                    host: "seed.radicle.xyz.".parse().unwrap(),
+                    port: 8776
+                })
+                .unwrap()
+            )
+            .unwrap()
+            .to_string(),
+            "seed.radicle.xyz.:8776"
+        );
+
+        // Known addresses must fit their length exactly.
+        let mut ipv4 =
+            wire::serialize(&Address::from(net::SocketAddr::from(([8, 8, 8, 8], 8776)))).unwrap();
+        ipv4[2] += 1;
+        ipv4.push(0);
+
+        assert!(matches!(
+            wire::deserialize::<Address>(&ipv4),
+            Err(wire::Error::TrailingBytes(1))
+        ));
+    }
+
+    #[test]
+    fn test_addr_unknown() {
+        let signer = MockSigner::default();
+        let unknown = Address::Unknown {
+            kind: 0xfe,
+            data: vec![1, 2, 3],
+        };
+        assert_eq!(
+            wire::serialize(&unknown).unwrap(),
+            vec![0xfe, 0, 3, 1, 2, 3]
+        );
+
+        // Announcements carrying addresses we don't know are decoded, and can be verified.
+        let addresses = vec![
+            unknown,
+            Address::from(net::SocketAddr::from(([8, 8, 8, 8], 8776))),
+        ];
+        let msg = Message::node(
+            NodeAnnouncement {
+                features: NodeFeatures::default(),
+                timestamp: 42,
+                alias: [0; 32],
+                addresses: addresses.clone(),
+                extensions: Extensions::default(),
+            },
+            &signer,
+        )
+        .unwrap();
+
+        match wire::deserialize::<Message>(&wire::serialize(&msg).unwrap()).unwrap() {
+            Message::NodeAnnouncement {
+                node,
+                message,
+                signature,
+            } => {
+                assert_eq!(message.addresses, addresses);
+                assert!(message.verify(&node, &signature));
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
 }
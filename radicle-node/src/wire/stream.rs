@@ -0,0 +1,623 @@
+//! Git streams, multiplexed over peer connections.
+//!
+//! Each stream is backed by channels: data received from the remote is forwarded to the
+//! stream by the reactor thread, while data written to the stream is queued for the reactor
+//! to send. This lets git operations run on their own thread, while using the peer
+//! connection owned by the reactor.
+//!
+//! Streams are flow-controlled: each end may only send [`STREAM_WINDOW`] data frames
+//! ahead of what the other end has read, and grants more as it reads them, by sending
+//! [`Frame::Credit`] frames. This bounds the memory used by each stream.
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::{fmt, io, net, time};
+
+use crossbeam_channel as chan;
+use nakamoto_net::Link;
+
+use crate::wire::frame::{Frame, StreamId};
+
+/// How long to wait for data from the remote before giving up on a stream.
+pub const STREAM_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+/// Maximum amount of data sent in a single frame.
+pub const MAX_DATA_SIZE: usize = 32 * 1024;
+/// Number of data frames that can be sent on a stream before the remote grants more.
+pub const STREAM_WINDOW: usize = 32;
+/// Maximum number of streams a peer can have open with us at once.
+pub const MAX_STREAMS: usize = 16;
+/// Maximum number of frames queued by streams, waiting to be sent by the reactor.
+pub const MAX_OUTGOING_FRAMES: usize = 1024;
+
+/// A stream error.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    #[error("stream {0} can't be opened by the remote, or is already open")]
+    InvalidStream(StreamId),
+    #[error("too many streams open")]
+    TooManyStreams,
+    #[error("stream {0} is not open")]
+    UnknownStream(StreamId),
+    #[error("data sent on stream {0} exceeds its window")]
+    WindowExceeded(StreamId),
+}
+
+/// Wakes up the reactor, so that queued frames are sent.
+#[derive(Clone)]
+pub struct Waker(Arc<dyn Fn() -> io::Result<()> + Send + Sync>);
+
+impl Waker {
+    pub fn new(wake: impl Fn() -> io::Result<()> + Send + Sync + 'static) -> Self {
+        Self(Arc::new(wake))
+    }
+
+    /// Wake up the reactor.
+    pub fn wake(&self) -> io::Result<()> {
+        (self.0)()
+    }
+}
+
+impl fmt::Debug for Waker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Waker").finish()
+    }
+}
+
+/// Frames queued by streams, to be sent by the reactor to the given peer.
+pub type Outgoing = chan::Sender<(net::SocketAddr, Frame)>;
+
+/// Data frames we're allowed to send on a stream.
+#[derive(Debug)]
+struct Credit {
+    /// Frames we can send, and whether the connection was lost.
+    state: Mutex<(usize, bool)>,
+    /// Signaled when credit is granted, or the connection is lost.
+    granted: Condvar,
+}
+
+impl Credit {
+    fn new(frames: usize) -> Self {
+        Self {
+            state: Mutex::new((frames, false)),
+            granted: Condvar::new(),
+        }
+    }
+
+    /// Add credit granted by the remote. Since the remote can't have read more than
+    /// we sent, credit never exceeds the stream window.
+    fn grant(&self, frames: usize) {
+        let mut state = self.state.lock().unwrap();
+
+        state.0 = state.0.saturating_add(frames).min(STREAM_WINDOW);
+        self.granted.notify_all();
+    }
+
+    /// Wait for credit to send a frame, and use it.
+    fn acquire(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        while state.0 == 0 && !state.1 {
+            let (guard, result) = self.granted.wait_timeout(state, STREAM_TIMEOUT).unwrap();
+
+            state = guard;
+            if result.timed_out() && state.0 == 0 {
+                return Err(io::Error::from(io::ErrorKind::TimedOut));
+            }
+        }
+        if state.1 {
+            return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+        }
+        state.0 -= 1;
+
+        Ok(())
+    }
+
+    /// Let the local end know that it can't send anything anymore, eg. because the
+    /// connection was lost.
+    fn disconnect(&self) {
+        self.state.lock().unwrap().1 = true;
+        self.granted.notify_all();
+    }
+}
+
+/// One end of a git stream. Reading from it returns the data sent by the remote end,
+/// and writing to it sends data to the remote end.
+#[derive(Debug)]
+pub struct Stream {
+    reader: Reader,
+    writer: Writer,
+}
+
+impl Stream {
+    /// Stream identifier.
+    pub fn id(&self) -> StreamId {
+        self.writer.id
+    }
+
+    /// Split the stream into its reading and writing halves, so that they can be used
+    /// from different threads. The stream is closed when the writing half is dropped.
+    pub fn split(self) -> (Reader, Writer) {
+        (self.reader, self.writer)
+    }
+}
+
+impl io::Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl io::Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Reading half of a [`Stream`].
+#[derive(Debug)]
+pub struct Reader {
+    /// Stream identifier, unique within the connection.
+    id: StreamId,
+    /// The peer at the other end of the stream.
+    remote: net::SocketAddr,
+    /// Data received from the remote.
+    incoming: chan::Receiver<Vec<u8>>,
+    /// Data received, but not yet read.
+    buffer: Vec<u8>,
+    /// Data frames read since we last granted credit to the remote.
+    read: usize,
+    /// Used to grant credit to the remote.
+    outgoing: Outgoing,
+    /// Wakes up the reactor when frames are queued.
+    waker: Waker,
+}
+
+impl Reader {
+    /// Let the remote know that it can send more data, once we've read enough of it.
+    fn grant(&mut self) -> io::Result<()> {
+        self.read += 1;
+
+        if self.read < STREAM_WINDOW / 2 {
+            return Ok(());
+        }
+        let frames = self.read as u32;
+        self.read = 0;
+
+        self.outgoing
+            .send((
+                self.remote,
+                Frame::Credit {
+                    stream: self.id,
+                    frames,
+                },
+            ))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+
+        self.waker.wake()
+    }
+}
+
+impl io::Read for Reader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.buffer.is_empty() {
+            match self.incoming.recv_timeout(STREAM_TIMEOUT) {
+                Ok(data) => {
+                    self.buffer = data;
+                    self.grant()?;
+                }
+                // The stream was closed by the remote, or the connection was lost.
+                Err(chan::RecvTimeoutError::Disconnected) => return Ok(0),
+                Err(chan::RecvTimeoutError::Timeout) => {
+                    return Err(io::Error::from(io::ErrorKind::TimedOut))
+                }
+            }
+        }
+        let n = buf.len().min(self.buffer.len());
+
+        buf[..n].copy_from_slice(&self.buffer[..n]);
+        self.buffer.drain(..n);
+
+        Ok(n)
+    }
+}
+
+/// Writing half of a [`Stream`]. Closes the stream when dropped.
+#[derive(Debug)]
+pub struct Writer {
+    /// Stream identifier, unique within the connection.
+    id: StreamId,
+    /// The peer at the other end of the stream.
+    remote: net::SocketAddr,
+    /// Frames to be sent to the remote.
+    outgoing: Outgoing,
+    /// Wakes up the reactor when frames are queued.
+    waker: Waker,
+    /// Data frames we're allowed to send.
+    credit: Arc<Credit>,
+}
+
+impl Writer {
+    /// Queue a frame to be sent to the remote, and wake up the reactor.
+    fn send(&self, frame: Frame) -> io::Result<()> {
+        self.outgoing
+            .send((self.remote, frame))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+
+        self.waker.wake()
+    }
+}
+
+impl io::Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(MAX_DATA_SIZE);
+
+        self.credit.acquire()?;
+        self.send(Frame::Data {
+            stream: self.id,
+            data: buf[..n].to_vec(),
+        })?;
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        self.send(Frame::Close { stream: self.id }).ok();
+    }
+}
+
+/// The reactor's end of an open stream.
+#[derive(Debug)]
+struct Channel {
+    /// Where to forward the data received on the stream.
+    incoming: chan::Sender<Vec<u8>>,
+    /// Credit granted to the local end by the remote.
+    credit: Arc<Credit>,
+}
+
+/// The git streams of a peer connection.
+pub struct Streams {
+    /// The peer we're connected to.
+    remote: net::SocketAddr,
+    /// Identifier of the next stream we open.
+    next: StreamId,
+    /// Open streams.
+    streams: HashMap<StreamId, Channel>,
+    /// Frames to be sent to the remote.
+    outgoing: Outgoing,
+    /// Wakes up the reactor when frames are queued.
+    waker: Waker,
+}
+
+impl Streams {
+    pub fn new(remote: net::SocketAddr, link: Link, outgoing: Outgoing, waker: Waker) -> Self {
+        Self {
+            remote,
+            // The node that initiated the connection uses odd stream ids, the other
+            // node uses even ones.
+            next: if link.is_outbound() { 1 } else { 2 },
+            streams: HashMap::new(),
+            outgoing,
+            waker,
+        }
+    }
+
+    /// Open a new stream. It's up to the caller to let the remote know about it.
+    pub fn open(&mut self) -> Stream {
+        let id = self.next;
+        self.next = self.next.wrapping_add(2);
+
+        self.stream(id)
+    }
+
+    /// Accept a stream opened by the remote. Fails if the stream identifier can't be
+    /// used by the remote or is already in use, or if the remote has too many streams open.
+    pub fn accept(&mut self, id: StreamId) -> Result<Stream, Error> {
+        // Streams opened by the remote have the opposite parity to the ones we open.
+        if self.is_local(id) || self.streams.contains_key(&id) {
+            return Err(Error::InvalidStream(id));
+        }
+        let accepted = self.streams.keys().filter(|s| !self.is_local(**s)).count();
+        if accepted >= MAX_STREAMS {
+            return Err(Error::TooManyStreams);
+        }
+        Ok(self.stream(id))
+    }
+
+    /// Forward data received from the remote to a stream. Fails if the stream is not
+    /// open, or if the remote sent more data than it was allowed to.
+    pub fn received(&mut self, stream: StreamId, data: Vec<u8>) -> Result<(), Error> {
+        let channel = self
+            .streams
+            .get(&stream)
+            .ok_or(Error::UnknownStream(stream))?;
+
+        match channel.incoming.try_send(data) {
+            Ok(()) => Ok(()),
+            // The remote didn't wait for us to grant it credit.
+            Err(chan::TrySendError::Full(_)) => {
+                self.streams.remove(&stream);
+                Err(Error::WindowExceeded(stream))
+            }
+            // The local end of the stream was dropped.
+            Err(chan::TrySendError::Disconnected(_)) => {
+                self.streams.remove(&stream);
+                Ok(())
+            }
+        }
+    }
+
+    /// Cancel a stream. The local end will read an end-of-file once it has read all the
+    /// data received so far, and will fail to write. It's up to the caller to let the
+    /// remote know.
+    pub fn cancel(&mut self, stream: StreamId) -> bool {
+        match self.streams.remove(&stream) {
+            Some(channel) => {
+                channel.credit.disconnect();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Add credit granted by the remote to a stream, letting its local end send more data.
+    pub fn credit(&mut self, stream: StreamId, frames: u32) -> Result<(), Error> {
+        let channel = self
+            .streams
+            .get(&stream)
+            .ok_or(Error::UnknownStream(stream))?;
+
+        channel.credit.grant(frames as usize);
+
+        Ok(())
+    }
+
+    /// Close a stream. The local end will read an end-of-file once it has
+    /// read all the data received so far.
+    pub fn close(&mut self, stream: StreamId) -> bool {
+        self.streams.remove(&stream).is_some()
+    }
+
+    /// Whether a stream is open.
+    pub fn is_open(&self, stream: StreamId) -> bool {
+        self.streams.contains_key(&stream)
+    }
+
+    /// Number of open streams.
+    pub fn len(&self) -> usize {
+        self.streams.len()
+    }
+
+    /// Whether there are no open streams.
+    pub fn is_empty(&self) -> bool {
+        self.streams.is_empty()
+    }
+
+    /// Whether a stream was opened by us.
+    fn is_local(&self, id: StreamId) -> bool {
+        id % 2 == self.next % 2
+    }
+
+    fn stream(&mut self, id: StreamId) -> Stream {
+        // The remote can't send more than a window's worth of data before we read it.
+        let (sender, incoming) = chan::bounded(STREAM_WINDOW);
+        let credit = Arc::new(Credit::new(STREAM_WINDOW));
+
+        self.streams.insert(
+            id,
+            Channel {
+                incoming: sender,
+                credit: credit.clone(),
+            },
+        );
+
+        Stream {
+            reader: Reader {
+                id,
+                remote: self.remote,
+                incoming,
+                buffer: Vec::new(),
+                read: 0,
+                outgoing: self.outgoing.clone(),
+                waker: self.waker.clone(),
+            },
+            writer: Writer {
+                id,
+                remote: self.remote,
+                outgoing: self.outgoing.clone(),
+                waker: self.waker.clone(),
+                credit,
+            },
+        }
+    }
+}
+
+impl Drop for Streams {
+    fn drop(&mut self) {
+        // Don't leave writers waiting for credit that will never come.
+        for channel in self.streams.values() {
+            channel.credit.disconnect();
+        }
+    }
+}
+
+impl fmt::Debug for Streams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Streams")
+            .field("remote", &self.remote)
+            .field("next", &self.next)
+            .field("streams", &self.streams.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use super::*;
+    use crate::test::assert_matches;
+
+    #[test]
+    fn test_stream_read_write() {
+        let remote = net::SocketAddr::from(([8, 8, 8, 8], 8776));
+        let (outgoing, frames) = chan::unbounded();
+        let mut streams = Streams::new(remote, Link::Outbound, outgoing, Waker::new(|| Ok(())));
+        let mut stream = streams.open();
+        let other = streams.open();
+        let id = stream.id();
+
+        assert_eq!(id, 1);
+        assert_eq!(other.id(), 3);
+        assert_eq!(streams.accept(3).unwrap_err(), Error::InvalidStream(3));
+
+        let accepted = streams.accept(2);
+        assert!(accepted.is_ok());
+
+        stream.write_all(b"want").unwrap();
+        assert_eq!(
+            frames.try_recv().unwrap(),
+            (
+                remote,
+                Frame::Data {
+                    stream: id,
+                    data: b"want".to_vec()
+                }
+            )
+        );
+
+        assert_eq!(streams.received(id, b"pack".to_vec()), Ok(()));
+        assert!(streams.close(id));
+        assert_eq!(
+            streams.received(id, b"more".to_vec()),
+            Err(Error::UnknownStream(id))
+        );
+
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"pack");
+
+        drop(stream);
+        drop(other);
+        drop(accepted);
+        assert!(frames
+            .try_iter()
+            .any(|(_, f)| f == Frame::Close { stream: id }));
+    }
+
+    #[test]
+    fn test_stream_limit() {
+        let remote = net::SocketAddr::from(([8, 8, 8, 8], 8776));
+        let (outgoing, _frames) = chan::unbounded();
+        let mut streams = Streams::new(remote, Link::Inbound, outgoing, Waker::new(|| Ok(())));
+        let accepted = (0..MAX_STREAMS as StreamId)
+            .map(|i| streams.accept(i * 2 + 1).unwrap())
+            .collect::<Vec<_>>();
+        let id = MAX_STREAMS as StreamId * 2 + 1;
+
+        assert_eq!(streams.accept(id).unwrap_err(), Error::TooManyStreams);
+        assert_eq!(
+            streams.open().id(),
+            2,
+            "Streams we open don't count towards the limit"
+        );
+
+        streams.close(accepted[0].id());
+        assert!(streams.accept(id).is_ok());
+    }
+
+    #[test]
+    fn test_stream_window() {
+        let remote = net::SocketAddr::from(([8, 8, 8, 8], 8776));
+        let (outgoing, frames) = chan::unbounded();
+        let mut streams = Streams::new(remote, Link::Outbound, outgoing, Waker::new(|| Ok(())));
+        let mut stream = streams.open();
+        let id = stream.id();
+
+        for _ in 0..STREAM_WINDOW {
+            assert_eq!(streams.received(id, vec![1]), Ok(()));
+        }
+        // Reading half the window grants that much credit back to the remote.
+        let mut buf = [0; STREAM_WINDOW / 2];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(
+            frames.try_recv().unwrap(),
+            (
+                remote,
+                Frame::Credit {
+                    stream: id,
+                    frames: STREAM_WINDOW as u32 / 2
+                }
+            )
+        );
+        for _ in 0..STREAM_WINDOW / 2 {
+            assert_eq!(streams.received(id, vec![1]), Ok(()));
+        }
+        assert_eq!(
+            streams.received(id, vec![1]),
+            Err(Error::WindowExceeded(id)),
+            "The remote can't send more than it was granted"
+        );
+        assert_eq!(streams.len(), 0);
+    }
+
+    #[test]
+    fn test_stream_credit() {
+        let remote = net::SocketAddr::from(([8, 8, 8, 8], 8776));
+        let (outgoing, frames) = chan::unbounded();
+        let mut streams = Streams::new(remote, Link::Outbound, outgoing, Waker::new(|| Ok(())));
+        let (_, mut writer) = streams.open().split();
+        let id = writer.id;
+
+        for _ in 0..STREAM_WINDOW {
+            writer.write_all(&[1]).unwrap();
+        }
+        assert_eq!(frames.try_iter().count(), STREAM_WINDOW);
+
+        let handle = std::thread::spawn(move || writer.write_all(&[1]).map(|_| writer));
+
+        // The writer waits for credit before sending more.
+        std::thread::sleep(time::Duration::from_millis(50));
+        assert!(frames.try_recv().is_err());
+
+        streams.credit(id, 1).unwrap();
+        let mut writer = handle.join().unwrap().unwrap();
+        assert_matches!(frames.try_recv(), Ok((_, Frame::Data { .. })));
+
+        // Writers waiting for credit fail once the connection is lost.
+        drop(streams);
+        assert_eq!(
+            writer.write_all(&[1]).unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+    }
+
+    #[test]
+    fn test_stream_cancel() {
+        let remote = net::SocketAddr::from(([8, 8, 8, 8], 8776));
+        let (outgoing, _frames) = chan::unbounded();
+        let mut streams = Streams::new(remote, Link::Outbound, outgoing, Waker::new(|| Ok(())));
+        let mut stream = streams.open();
+        let id = stream.id();
+
+        assert_eq!(streams.received(id, b"pack".to_vec()), Ok(()));
+        assert!(streams.cancel(id));
+        assert!(!streams.cancel(id));
+
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"pack", "Data received before cancelling is read");
+        assert_eq!(
+            stream.write_all(b"want").unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+    }
+}
@@ -9,6 +9,7 @@
 //!       radicle.pub                            # Public key (PKCS 8)
 //!     node/
 //!       radicle.sock                           # Node control socket
+//!       cache.json                             # Node routing and peer state
 //!
 use std::path::PathBuf;
 use std::{env, io};
@@ -107,6 +107,22 @@ impl fmt::Display for RefUpdate {
     }
 }
 
+/// Outcome of fetching specific remotes of a project.
+#[derive(Debug, Default)]
+pub struct FetchedRemotes {
+    /// Ref updates of the remotes that were fetched and verified.
+    pub updated: HashMap<RemoteId, Vec<RefUpdate>>,
+    /// Remotes that failed verification. None of their refs were updated.
+    pub rejected: HashMap<RemoteId, git::VerifyError>,
+}
+
+impl FetchedRemotes {
+    /// All ref updates, across remotes.
+    pub fn updates(&self) -> impl Iterator<Item = &RefUpdate> {
+        self.updated.values().flatten()
+    }
+}
+
 /// Project remotes. Tracks the git state of a project.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Remotes<V>(HashMap<RemoteId, Remote<V>>);
@@ -260,6 +276,19 @@ pub trait ReadRepository {
 
 pub trait WriteRepository: ReadRepository {
     fn fetch(&mut self, url: &Url) -> Result<Vec<RefUpdate>, FetchError>;
+    /// Fetch only the given remotes, verifying each of them separately. Remotes that fail
+    /// verification are rejected, without affecting the others. Refs of remotes that weren't
+    /// asked for are left untouched.
+    fn fetch_remotes(
+        &mut self,
+        url: &Url,
+        remotes: &[RemoteId],
+    ) -> Result<FetchedRemotes, FetchError>;
+    /// Delete the refs of all remotes for which `keep` returns `false`.
+    /// Returns the deleted refs.
+    fn prune_remotes<F>(&mut self, keep: F) -> Result<Vec<RefUpdate>, Error>
+    where
+        F: Fn(&RemoteId) -> bool;
     fn raw(&self) -> &git2::Repository;
 }
 
@@ -15,8 +15,8 @@ use crate::identity::{Doc, Id};
 use crate::storage::refs;
 use crate::storage::refs::{Refs, SignedRefs};
 use crate::storage::{
-    Error, FetchError, Inventory, ReadRepository, ReadStorage, Remote, Remotes, WriteRepository,
-    WriteStorage,
+    Error, FetchError, FetchedRemotes, Inventory, ReadRepository, ReadStorage, Remote, Remotes,
+    WriteRepository, WriteStorage,
 };
 
 pub use crate::git::*;
@@ -268,6 +268,115 @@ impl Repository {
         Ok(())
     }
 
+    /// Verify the refs of a single remote against its signed refs, as well as its identity.
+    /// See [`Repository::verify`].
+    pub fn verify_remote(&self, remote: &RemoteId) -> Result<(), VerifyError> {
+        let mut signed: Refs = self.remote(remote)?.refs.into();
+
+        for r in self
+            .backend
+            .references_glob(&format!("refs/remotes/{}/*", remote))?
+        {
+            let r = r?;
+            let name = r.name().ok_or(VerifyError::InvalidRef)?;
+            let oid = r.target().ok_or(VerifyError::InvalidRef)?;
+            let (_, refname) = git::parse_ref::<RemoteId>(name)?;
+
+            if refname == *refs::SIGNATURE_REF {
+                continue;
+            }
+            let signed_oid = signed
+                .remove(&refname)
+                .ok_or_else(|| VerifyError::UnknownRef(*remote, refname.clone()))?;
+
+            if Oid::from(oid) != signed_oid {
+                return Err(VerifyError::InvalidRefTarget(*remote, refname, oid));
+            }
+        }
+        if let Some((name, _)) = signed.into_iter().next() {
+            return Err(VerifyError::MissingRef(*remote, name));
+        }
+        self.identity(remote)?.verified(self.id)?;
+
+        Ok(())
+    }
+
+    /// Create a staging copy of this repository at the given path, and fetch the given
+    /// refspecs into it from a URL. See [`Repository::fetch`].
+    fn stage(&self, url: &git::Url, refspecs: &[String], path: &Path) -> Result<Self, FetchError> {
+        let mut builder = git2::build::RepoBuilder::new();
+        let staging = builder
+            .bare(true)
+            // Using `clone_local` will try to hard-link the ODBs for better performance.
+            // TODO: Due to this, I think we'll have to run GC when there is a failure.
+            .clone_local(git2::build::CloneLocal::Local)
+            .clone(
+                &git::Url {
+                    scheme: git::url::Scheme::File,
+                    path: self.backend.path().to_string_lossy().to_string().into(),
+                    ..git::Url::default()
+                }
+                .to_string(),
+                path,
+            )?;
+
+        // In case we fetch an invalid update, we want to make sure nothing is deleted.
+        let mut opts = git2::FetchOptions::default();
+        opts.prune(git2::FetchPrune::Off);
+
+        // Fetch from the remote into the staging copy.
+        staging
+            .remote_anonymous(&url.to_string())?
+            .fetch(refspecs, Some(&mut opts), None)?;
+
+        Ok(Self {
+            id: self.id,
+            backend: staging,
+        })
+    }
+
+    /// Fetch the given refspecs from a verified staging copy into this repository.
+    fn unstage(
+        &mut self,
+        staging: &Path,
+        refspecs: &[String],
+    ) -> Result<Vec<RefUpdate>, FetchError> {
+        let mut updates = Vec::new();
+        let mut callbacks = git2::RemoteCallbacks::new();
+
+        callbacks.update_tips(|name, old, new| {
+            if let Ok(name) = git::RefString::try_from(name) {
+                updates.push(RefUpdate::from(name, old, new));
+            } else {
+                log::warn!("Invalid ref `{}` detected; aborting fetch", name);
+                return false;
+            }
+            // Returning `true` ensures the process is not aborted.
+            true
+        });
+
+        {
+            let mut remote = self.backend.remote_anonymous(
+                &git::Url {
+                    scheme: git::url::Scheme::File,
+                    path: staging.to_string_lossy().to_string().into(),
+                    ..git::Url::default()
+                }
+                .to_string(),
+            )?;
+            let mut opts = git2::FetchOptions::default();
+            opts.remote_callbacks(callbacks);
+
+            // TODO: Make sure we verify before pruning, as pruning may get us into
+            // a state we can't roll back.
+            opts.prune(git2::FetchPrune::On);
+            // Fetch from the staging copy into the canonical repo.
+            remote.fetch(refspecs, Some(&mut opts), None)?;
+        }
+
+        Ok(updates)
+    }
+
     pub fn identity(&self, remote: &RemoteId) -> Result<Identity<Oid>, IdentityError> {
         Identity::load(remote, self)
     }
@@ -506,8 +615,6 @@ impl WriteRepository for Repository {
     /// staging copy.
     ///
     fn fetch(&mut self, url: &git::Url) -> Result<Vec<RefUpdate>, FetchError> {
-        // TODO: Have function to fetch specific remotes.
-        //
         // The steps are summarized in the following diagram:
         //
         //     staging <- git-clone -- local (canonical) # create staging copy
@@ -517,81 +624,97 @@ impl WriteRepository for Repository {
         //
         //     local <- git-fetch -- staging             # fetch from staging copy
         //
-        let url = url.to_string();
-        let refs: &[&str] = &["refs/remotes/*:refs/remotes/*"];
-        let mut updates = Vec::new();
-        let mut callbacks = git2::RemoteCallbacks::new();
+        let refspecs = &[String::from("refs/remotes/*:refs/remotes/*")];
         let tempdir = tempfile::tempdir()?;
+        let path = tempdir.path().join("git");
 
-        // Create staging copy.
-        let staging = {
-            let mut builder = git2::build::RepoBuilder::new();
-            let path = tempdir.path().join("git");
-            let staging_repo = builder
-                .bare(true)
-                // Using `clone_local` will try to hard-link the ODBs for better performance.
-                // TODO: Due to this, I think we'll have to run GC when there is a failure.
-                .clone_local(git2::build::CloneLocal::Local)
-                .clone(
-                    &git::Url {
-                        scheme: git::url::Scheme::File,
-                        path: self.backend.path().to_string_lossy().to_string().into(),
-                        ..git::Url::default()
-                    }
-                    .to_string(),
-                    &path,
-                )?;
+        // Verify the staging copy as if it was the canonical copy.
+        self.stage(url, refspecs, &path)?.verify()?;
+        self.unstage(&path, refspecs)
+    }
 
-            // In case we fetch an invalid update, we want to make sure nothing is deleted.
-            let mut opts = git2::FetchOptions::default();
-            opts.prune(git2::FetchPrune::Off);
+    /// Fetch the given remotes of a project from the given URL. Works like [`Repository::fetch`],
+    /// except that remotes are verified one by one, and only the ones that pass verification
+    /// are transferred to the canonical repository. Other remotes are left untouched.
+    fn fetch_remotes(
+        &mut self,
+        url: &git::Url,
+        remotes: &[RemoteId],
+    ) -> Result<FetchedRemotes, FetchError> {
+        let refspec = |r: &RemoteId| format!("refs/remotes/{r}/*:refs/remotes/{r}/*");
+        let tempdir = tempfile::tempdir()?;
+        let path = tempdir.path().join("git");
+        let staging = self.stage(url, &remotes.iter().map(refspec).collect::<Vec<_>>(), &path)?;
+        let mut fetched = FetchedRemotes::default();
 
-            // Fetch from the remote into the staging copy.
-            staging_repo
-                .remote_anonymous(&url)?
-                .fetch(refs, Some(&mut opts), None)?;
+        for remote in remotes {
+            let glob = format!("refs/remotes/{}/*", remote);
 
-            // Verify the staging copy as if it was the canonical copy.
-            Repository {
-                id: self.id,
-                backend: staging_repo,
+            // Neither we nor the seed have this remote.
+            if staging.backend.references_glob(&glob)?.next().is_none() {
+                continue;
             }
-            .verify()?;
-
-            path
-        };
-
-        callbacks.update_tips(|name, old, new| {
-            if let Ok(name) = git::RefString::try_from(name) {
-                updates.push(RefUpdate::from(name, old, new));
-            } else {
-                log::warn!("Invalid ref `{}` detected; aborting fetch", name);
-                return false;
+            match staging.verify_remote(remote) {
+                Ok(()) => {
+                    fetched.updated.insert(*remote, Vec::new());
+                }
+                Err(err) => {
+                    log::warn!("Rejecting remote {} of {}: {}", remote, self.id, err);
+                    fetched.rejected.insert(*remote, err);
+                }
             }
-            // Returning `true` ensures the process is not aborted.
-            true
-        });
+        }
+        if fetched.updated.is_empty() {
+            return Ok(fetched);
+        }
+        let verified = fetched.updated.keys().map(refspec).collect::<Vec<_>>();
+
+        for update in self.unstage(&path, &verified)? {
+            let name = match &update {
+                RefUpdate::Updated { name, .. }
+                | RefUpdate::Created { name, .. }
+                | RefUpdate::Deleted { name, .. }
+                | RefUpdate::Skipped { name, .. } => name,
+            };
+            if let Ok((remote, _)) = git::parse_ref::<RemoteId>(name.as_str()) {
+                if let Some(updates) = fetched.updated.get_mut(&remote) {
+                    updates.push(update);
+                }
+            }
+        }
+        Ok(fetched)
+    }
+
+    /// Delete the refs of the remotes we don't want to keep, eg. because they fell out of our
+    /// tracking policy.
+    fn prune_remotes<F>(&mut self, keep: F) -> Result<Vec<RefUpdate>, Error>
+    where
+        F: Fn(&RemoteId) -> bool,
+    {
+        let mut pruned = Vec::new();
 
+        // Collect the refs first, since we can't delete them while iterating.
+        for reference in self
+            .backend
+            .references_glob(REMOTES_GLOB.as_str())?
+            .collect::<Result<Vec<_>, _>>()?
         {
-            let mut remote = self.backend.remote_anonymous(
-                &git::Url {
-                    scheme: git::url::Scheme::File,
-                    path: staging.to_string_lossy().to_string().into(),
-                    ..git::Url::default()
-                }
-                .to_string(),
-            )?;
-            let mut opts = git2::FetchOptions::default();
-            opts.remote_callbacks(callbacks);
+            let name = reference.name().ok_or(Error::InvalidRef)?;
+            let (remote, _) = git::parse_ref::<RemoteId>(name)?;
 
-            // TODO: Make sure we verify before pruning, as pruning may get us into
-            // a state we can't roll back.
-            opts.prune(git2::FetchPrune::On);
-            // Fetch from the staging copy into the canonical repo.
-            remote.fetch(refs, Some(&mut opts), None)?;
-        }
+            if keep(&remote) {
+                continue;
+            }
+            let name = git::RefString::try_from(name).map_err(|_| Error::InvalidRef)?;
+            let oid = reference.target().ok_or(Error::InvalidRef)?;
 
-        Ok(updates)
+            self.backend.find_reference(name.as_str())?.delete()?;
+            pruned.push(RefUpdate::Deleted {
+                name,
+                oid: oid.into(),
+            });
+        }
+        Ok(pruned)
     }
 
     fn raw(&self) -> &git2::Repository {
@@ -746,6 +869,124 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_fetch_remotes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let alice_signer = MockSigner::default();
+        let alice_id = *alice_signer.public_key();
+        let alice = fixtures::storage(tmp.path().join("alice"), alice_signer).unwrap();
+        let bob = Storage::open(tmp.path().join("bob")).unwrap();
+        let inventory = alice.inventory().unwrap();
+        let proj = *inventory.first().unwrap();
+        let refname = git::refname!("heads/master");
+        let url = git::Url {
+            scheme: git_url::Scheme::File,
+            path: paths::repository(&alice, &proj)
+                .to_string_lossy()
+                .into_owned()
+                .into(),
+            ..git::Url::default()
+        };
+        let mut bob_repo = bob.repository(proj).unwrap();
+
+        // Fetching a remote that Alice doesn't have doesn't fetch anything.
+        let fetched = bob_repo
+            .fetch_remotes(&url, &[arbitrary::gen::<RemoteId>(1)])
+            .unwrap();
+        assert!(fetched.updated.is_empty());
+        assert!(fetched.rejected.is_empty());
+        assert!(bob_repo.reference(&alice_id, &refname).unwrap().is_none());
+
+        // Fetching Alice's remote only fetches refs under her namespace.
+        let fetched = bob_repo.fetch_remotes(&url, &[alice_id]).unwrap();
+        assert!(fetched.rejected.is_empty());
+        assert_eq!(fetched.updated[&alice_id].len(), 3);
+
+        for update in fetched.updates() {
+            assert_matches!(
+                update,
+                RefUpdate::Created { name, .. }
+                if name.starts_with(&format!("refs/remotes/{}/", alice_id))
+            );
+        }
+        assert!(bob_repo.reference(&alice_id, &refname).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_fetch_remotes_rejected() {
+        let tmp = tempfile::tempdir().unwrap();
+        let alice_signer = MockSigner::default();
+        let alice_id = *alice_signer.public_key();
+        let alice = fixtures::storage(tmp.path().join("alice"), alice_signer).unwrap();
+        let bob = Storage::open(tmp.path().join("bob")).unwrap();
+        let proj = *alice.inventory().unwrap().first().unwrap();
+        let refname = git::refname!("heads/master");
+        let alice_repo = alice.repository(proj).unwrap();
+        let head = alice_repo
+            .reference_oid(&alice_id, &refname)
+            .unwrap()
+            .unwrap();
+
+        // Alice's remote has a ref that isn't signed.
+        alice_repo
+            .raw()
+            .reference(
+                &format!("refs/remotes/{}/heads/unsigned", alice_id),
+                head.into(),
+                false,
+                "Unsigned ref",
+            )
+            .unwrap();
+
+        let mut bob_repo = bob.repository(proj).unwrap();
+        let fetched = bob_repo
+            .fetch_remotes(
+                &git::Url {
+                    scheme: git_url::Scheme::File,
+                    path: paths::repository(&alice, &proj)
+                        .to_string_lossy()
+                        .into_owned()
+                        .into(),
+                    ..git::Url::default()
+                },
+                &[alice_id],
+            )
+            .unwrap();
+
+        assert!(fetched.updated.is_empty());
+        assert_matches!(
+            fetched.rejected.get(&alice_id),
+            Some(VerifyError::UnknownRef(remote, _)) if *remote == alice_id
+        );
+        assert!(bob_repo.reference(&alice_id, &refname).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_prune_remotes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let alice_signer = MockSigner::default();
+        let alice_id = *alice_signer.public_key();
+        let alice = fixtures::storage(tmp.path().join("alice"), alice_signer).unwrap();
+        let proj = *alice.inventory().unwrap().first().unwrap();
+        let refname = git::refname!("heads/master");
+        let mut repo = alice.repository(proj).unwrap();
+
+        let pruned = repo.prune_remotes(|r| *r == alice_id).unwrap();
+        assert!(pruned.is_empty());
+        assert!(repo.reference(&alice_id, &refname).unwrap().is_some());
+
+        let pruned = repo.prune_remotes(|r| *r != alice_id).unwrap();
+        assert_eq!(pruned.len(), 3);
+        for update in pruned {
+            assert_matches!(
+                update,
+                RefUpdate::Deleted { name, .. }
+                if name.starts_with(&format!("refs/remotes/{}/", alice_id))
+            );
+        }
+        assert!(repo.reference(&alice_id, &refname).unwrap().is_none());
+    }
+
     #[test]
     fn test_fetch_update() {
         let tmp = tempfile::tempdir().unwrap();
@@ -872,11 +1113,11 @@ mod tests {
             let stream = net::TcpStream::connect(addr).unwrap();
             let smart = transport::Smart::singleton();
 
-            smart.insert(proj, Box::new(stream.try_clone().unwrap()));
+            let url = smart.insert(proj, Box::new(stream.try_clone().unwrap()));
 
             // Fetch with the `rad://` transport.
             target
-                .remote_anonymous(&format!("rad://{}", proj))
+                .remote_anonymous(&url.to_string())
                 .unwrap()
                 .fetch(&["refs/*:refs/*"], Some(&mut opts), None)
                 .unwrap();
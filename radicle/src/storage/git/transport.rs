@@ -4,13 +4,15 @@
 //! existing TCP connections, we implement the [`git2::transport::SmartSubtransport`] trait.
 //!
 //! We choose `rad` as the URL scheme for this custom transport, and include only the identity
-//! of the repository we're looking to fetch, eg. `rad://zP1GztjSdYNHK7jpdrXbaJ6Ki2Ke`, since
-//! we expect a connection to a host to already be established.
+//! of the repository we're looking to fetch, and a number identifying the stream to fetch it
+//! over, eg. `rad://zP1GztjSdYNHK7jpdrXbaJ6Ki2Ke/1`, since we expect a connection to a host to
+//! already be established.
 //!
-//! We then maintain a map from identifier to stream, for all active streams, ie. streams that
-//! are associated with an underlying TCP connection. When a URL is requested, we lookup
+//! We then maintain a map from stream identifier to stream, for all active streams, ie. streams
+//! that are associated with an underlying TCP connection. When a URL is requested, we lookup
 //! the stream and return it to the [`git2`] smart-protocol implementation, so that it can carry
-//! out the git smart protocol.
+//! out the git smart protocol. Since each stream has its own identifier, the same repository
+//! can be fetched over several streams at once.
 //!
 //! This module is meant to be used by first registering our transport with [`register`] and then
 //! adding or removing streams through [`Smart`], which can be obtained via [`Smart::singleton`].
@@ -28,16 +30,21 @@ use crate::identity::Id;
 /// The map of git smart sub-transport streams. We keep a global map because we have
 /// no control over how [`git2::transport::register`] instantiates our [`Smart`] transport
 /// or its underlying streams.
-static STREAMS: Lazy<Arc<Mutex<HashMap<Id, Stream>>>> = Lazy::new(Default::default);
+static STREAMS: Lazy<Arc<Mutex<HashMap<StreamKey, Stream>>>> = Lazy::new(Default::default);
+/// Identifier of the next stream inserted.
+static NEXT_STREAM: atomic::AtomicU64 = atomic::AtomicU64::new(1);
 
 /// The stream associated with a repository.
 type Stream = Box<dyn SmartSubtransportStream>;
 
+/// Identifies a stream: the repository fetched over it, and a number unique to the stream.
+type StreamKey = (Id, u64);
+
 /// Git transport protocol over an I/O stream.
 #[derive(Clone)]
 pub struct Smart {
-    /// The underlying active streams, keyed by repository identifier.
-    streams: Arc<Mutex<HashMap<Id, Stream>>>,
+    /// The underlying active streams.
+    streams: Arc<Mutex<HashMap<StreamKey, Stream>>>,
 }
 
 impl Smart {
@@ -49,21 +56,45 @@ impl Smart {
         }
     }
 
-    /// Take a stream from the map.
+    /// Take the stream of the given URL from the map.
     /// This makes the stream unavailable until it is re-inserted.
-    pub fn take(&self, id: &Id) -> Option<Stream> {
-        self.streams.lock().unwrap().remove(id)
+    pub fn take(&self, url: &git::Url) -> Option<Stream> {
+        let key = Self::key(url)?;
+
+        self.streams.lock().unwrap().remove(&key)
     }
 
-    pub fn insert(&self, id: Id, stream: Stream) {
-        self.streams.lock().unwrap().insert(id, stream);
+    /// Insert a stream used to fetch the given repository. Returns the URL to fetch from,
+    /// which identifies the stream.
+    pub fn insert(&self, id: Id, stream: Stream) -> git::Url {
+        let n = NEXT_STREAM.fetch_add(1, atomic::Ordering::SeqCst);
+        self.streams.lock().unwrap().insert((id, n), stream);
+
+        git::Url {
+            scheme: git::url::Scheme::Radicle,
+            host: Some(id.to_string()),
+            path: format!("/{n}").into(),
+            ..git::Url::default()
+        }
+    }
+
+    /// Get the stream key of a URL.
+    fn key(url: &git::Url) -> Option<StreamKey> {
+        let id = Id::from_str(url.host.as_deref()?).ok()?;
+        let n = std::str::from_utf8(url.path.as_ref())
+            .ok()?
+            .strip_prefix('/')?
+            .parse()
+            .ok()?;
+
+        Some((id, n))
     }
 }
 
 impl git2::transport::SmartSubtransport for Smart {
     /// Run a git service on this transport.
     ///
-    /// Based on the URL, which must be of the form `rad://zP1GztjSdYNHK7jpdrXbaJ6Ki2Ke`,
+    /// Based on the URL, which must be of the form `rad://zP1GztjSdYNHK7jpdrXbaJ6Ki2Ke/1`,
     /// we retrieve an underlying stream and return it.
     ///
     /// We only support the upload-pack service, since only fetches are authorized by the
@@ -75,14 +106,14 @@ impl git2::transport::SmartSubtransport for Smart {
     ) -> Result<Box<dyn git2::transport::SmartSubtransportStream>, git2::Error> {
         let url = git::Url::from_bytes(url.as_bytes())
             .map_err(|e| git2::Error::from_str(e.to_string().as_str()))?;
-        let id = Id::from_str(url.host.unwrap_or_default().as_str())
+        let id = Id::from_str(url.host.as_deref().unwrap_or_default())
             .map_err(|_| git2::Error::from_str("Git URL does not contain a valid project id"))?;
 
         if url.scheme != git::url::Scheme::Radicle {
             return Err(git2::Error::from_str("Git URL scheme must be `rad`"));
         }
 
-        if let Some(stream) = self.take(&id) {
+        if let Some(stream) = self.take(&url) {
             match action {
                 git2::transport::Service::UploadPackLs => {}
                 git2::transport::Service::UploadPack => {}
@@ -85,7 +85,7 @@ impl ReadRepository for MockRepository {
     }
 
     fn remote(&self, _remote: &RemoteId) -> Result<Remote<Verified>, refs::Error> {
-        todo!()
+        Err(refs::Error::NotFound)
     }
 
     fn remotes(&self) -> Result<Remotes<Verified>, refs::Error> {
@@ -135,7 +135,7 @@ impl ReadRepository for MockRepository {
     fn project_identity(
         &self,
     ) -> Result<(Oid, crate::identity::Doc<crate::crypto::Unverified>), git::ProjectError> {
-        todo!()
+        Err(git::ProjectError::InvalidState)
     }
 }
 
@@ -144,6 +144,21 @@ impl WriteRepository for MockRepository {
         Ok(vec![])
     }
 
+    fn fetch_remotes(
+        &mut self,
+        _url: &Url,
+        _remotes: &[RemoteId],
+    ) -> Result<FetchedRemotes, FetchError> {
+        Ok(FetchedRemotes::default())
+    }
+
+    fn prune_remotes<F>(&mut self, _keep: F) -> Result<Vec<RefUpdate>, Error>
+    where
+        F: Fn(&RemoteId) -> bool,
+    {
+        Ok(vec![])
+    }
+
     fn raw(&self) -> &git2::Repository {
         todo!()
     }